@@ -0,0 +1,176 @@
+//! Command line arguments
+
+use crate::config::{Format, Overrides};
+use std::env;
+
+const DEFAULT_CONFIG: &str = "/etc/fourth/config.yaml";
+
+pub const USAGE: &str = "Usage: fourth [COMMAND] [OPTIONS]
+
+Commands:
+  run           Start the servers (default)
+  check         Validate the config and exit
+  print-config  Print the config with defaults filled in
+  migrate       Print the config rewritten in the current version
+  schema        Print the JSON Schema of config files
+  version       Print the version
+  help          Print this help
+
+Options:
+  -c, --config <PATH>          Config file [env: FOURTH_CONFIG, default: /etc/fourth/config.yaml]
+  -f, --format <FORMAT>        Config format: yaml, toml or json [default: from the extension]
+  -l, --log <LEVEL>            Log level, replacing `log` of the config
+      --listen <SERVER=ADDR>   Listen address of a server, replacing those of
+                               the config, repeated for several addresses
+  -h, --help                   Print this help
+  -V, --version                Print the version";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Check,
+    PrintConfig,
+    Migrate,
+    Schema,
+    Version,
+    Help,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub config: String,
+    pub overrides: Overrides,
+}
+
+impl Args {
+    pub fn from_env() -> Result<Args, String> {
+        let config = env::var("FOURTH_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG.to_string());
+        Args::parse(env::args().skip(1), config)
+    }
+
+    /// Parse `args` without the program name, `config` being the path used
+    /// without `--config`
+    pub fn parse<I>(args: I, config: String) -> Result<Args, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut parsed = Args {
+            command: Command::Run,
+            config,
+            overrides: Overrides::default(),
+        };
+        let mut command = None;
+        // `--help` and `--version` win over the command, wherever they are
+        let mut flag_command = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // `--option=value` and `--option value` are both accepted
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value = |name: &str| {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| format!("{} needs a value", name))
+            };
+            match flag {
+                "-c" | "--config" => parsed.config = value(flag)?,
+                "-f" | "--format" => {
+                    let format: Format = value(flag)?.parse()?;
+                    parsed.overrides.format = Some(format);
+                }
+                "-l" | "--log" => parsed.overrides.log = Some(value(flag)?),
+                "--listen" => {
+                    let listen = value(flag)?;
+                    let (server, addr) = listen
+                        .split_once('=')
+                        .filter(|(server, addr)| !server.is_empty() && !addr.is_empty())
+                        .ok_or_else(|| {
+                            format!("Invalid --listen {}, expected SERVER=ADDR", listen)
+                        })?;
+                    parsed
+                        .overrides
+                        .listen
+                        .entry(server.to_string())
+                        .or_default()
+                        .push(addr.to_string());
+                }
+                "-h" | "--help" => flag_command = Some(Command::Help),
+                "-V" | "--version" => {
+                    flag_command.get_or_insert(Command::Version);
+                }
+                _ if flag.starts_with('-') => return Err(format!("Unknown option {}", flag)),
+                _ if command.is_some() => return Err(format!("Unexpected argument {}", flag)),
+                "run" => command = Some(Command::Run),
+                "check" => command = Some(Command::Check),
+                "print-config" => command = Some(Command::PrintConfig),
+                "migrate" => command = Some(Command::Migrate),
+                "schema" => command = Some(Command::Schema),
+                "version" => command = Some(Command::Version),
+                "help" => command = Some(Command::Help),
+                _ => return Err(format!("Unknown command {}", flag)),
+            }
+        }
+        parsed.command = flag_command.or(command).unwrap_or(Command::Run);
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        let args = args.iter().map(|arg| arg.to_string());
+        Args::parse(args, DEFAULT_CONFIG.to_string())
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.command, Command::Run);
+        assert_eq!(args.config, DEFAULT_CONFIG);
+
+        let args = parse(&[
+            "check",
+            "-c",
+            "fourth.yaml",
+            "--log=debug",
+            "--listen",
+            "web=0.0.0.0:8443",
+            "--listen=web=[::]:8443",
+        ])
+        .unwrap();
+        assert_eq!(args.command, Command::Check);
+        assert_eq!(args.config, "fourth.yaml");
+        assert_eq!(args.overrides.log.as_deref(), Some("debug"));
+        assert_eq!(
+            args.overrides.listen["web"],
+            vec!["0.0.0.0:8443", "[::]:8443"]
+        );
+
+        assert_eq!(parse(&["--version"]).unwrap().command, Command::Version);
+        assert_eq!(parse(&["migrate"]).unwrap().command, Command::Migrate);
+        let args = parse(&["-c", "fourth.conf", "--format", "toml"]).unwrap();
+        assert_eq!(args.overrides.format, Some(Format::Toml));
+        assert!(parse(&["--format=ini"]).is_err());
+        assert_eq!(
+            parse(&["print-config", "-h"]).unwrap().command,
+            Command::Help
+        );
+        assert_eq!(parse(&["-h", "check"]).unwrap().command, Command::Help);
+        assert_eq!(
+            parse(&["--version", "schema"]).unwrap().command,
+            Command::Version
+        );
+        assert_eq!(parse(&["-V", "--help"]).unwrap().command, Command::Help);
+        assert!(parse(&["-c"]).is_err());
+        assert!(parse(&["--listen", "web"]).is_err());
+        assert!(parse(&["stop"]).is_err());
+        assert!(parse(&["run", "check"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+    }
+}
@@ -1,217 +1,1657 @@
-use log::{debug, warn};
-use serde::Deserialize;
+use crate::plugins::acl::{Acl, AclRule};
+use crate::plugins::ban::{BanPolicy, TlsAlert};
+use crate::plugins::discovery::{self, Backends, Source};
+use crate::plugins::kcp::{KcpConfig, KcpMigration, KcpNoDelayConfig, KcpPool};
+use crate::plugins::resolver::{ConnectOptions, ResolverOptions, Target};
+use crate::plugins::tls::{
+    load_certified_key, load_certs, load_private_key, AcmeChallenge, AcmeConfig, AcmeManager,
+    ClientAuth, EchKeys, TlsOrigination, TlsTermination, TlsVerify,
+};
+use crate::plugins::unix::{created_permissions, group_id, user_id, UnixPermissions};
+use crate::servers::Listen;
+use log::debug;
+use rustls::pki_types::ServerName;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation};
+use schemars::JsonSchema;
+use serde::de::{self, DeserializeOwned};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{Error as IOError, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use url::Url;
 
+pub use self::validate::{validate, Diagnostic, Report};
+
+mod interpolate;
+mod validate;
+
+use self::interpolate::interpolate;
+
+const ACME_DEFAULT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const ACME_DEFAULT_STORAGE: &str = "/var/lib/fourth/acme";
+
+/// Version of the config format, older versions are migrated on load
+pub const CONFIG_VERSION: i32 = 2;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub base: ParsedConfig,
+    /// The config file as read, with overrides applied
+    pub file: BaseConfig,
+    pub format: Format,
+    pub warnings: Vec<Diagnostic>,
 }
 
-#[derive(Debug, Default, Deserialize, Clone)]
-pub struct ParsedConfig {
-    pub version: i32,
+/// Format of config files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// The format of `path` by its extension, YAML when unknown
+    pub fn of(path: impl AsRef<Path>) -> Format {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Format::Toml,
+            Some("json") => Format::Json,
+            _ => Format::Yaml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, contents: &str) -> Result<T, ConfigError> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::from_str(contents)?,
+            Format::Toml => toml::from_str(contents)?,
+            Format::Json => serde_json::from_str(contents)?,
+        })
+    }
+
+    fn render(self, value: &serde_json::Value) -> Result<String, ConfigError> {
+        let rendered = match self {
+            Format::Yaml => serde_yaml::to_string(value)?,
+            Format::Toml => toml::to_string(value).map_err(|err| {
+                ConfigError::Custom(format!("Failed to serialize config: {}", err))
+            })?,
+            Format::Json => serde_json::to_string_pretty(value)? + "\n",
+        };
+        Ok(rendered)
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Format, String> {
+        match format {
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("Unknown config format {}", format)),
+        }
+    }
+}
+
+/// Settings of the command line, taking precedence over the config file
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Overrides {
     pub log: Option<String>,
+    /// Listen addresses replacing those of a server, by server name
+    pub listen: HashMap<String, Vec<String>>,
+    /// Format of the config file, by default from its extension
+    pub format: Option<Format>,
+}
+
+impl Overrides {
+    fn apply(&self, config: &mut BaseConfig) -> Result<(), ConfigError> {
+        if let Some(log) = &self.log {
+            config.log = Some(log.clone());
+        }
+        for (name, listen) in &self.listen {
+            match config.servers.get_mut(name) {
+                Some(server) => server.listen = listen.clone(),
+                None => {
+                    return Err(ConfigError::Custom(format!(
+                        "Listen override of unknown server {}",
+                        name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ParsedConfig {
     pub servers: HashMap<String, ServerConfig>,
     pub upstream: HashMap<String, Upstream>,
+    pub admin: Option<AdminConfig>,
+    pub resolver: ResolverOptions,
 }
 
-#[derive(Debug, Default, Deserialize, Clone)]
+/// A fourth config file
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct BaseConfig {
+    /// 2, version 1 files are migrated on load
     pub version: i32,
     pub log: Option<String>,
+    /// Files or globs relative to this file, adding servers and upstreams.
+    /// A directory includes its `.yaml`, `.yml`, `.toml` and `.json` files.
+    pub include: Option<Vec<String>>,
+    #[serde(default)]
     pub servers: HashMap<String, ServerConfig>,
+    #[serde(default)]
     pub upstream: HashMap<String, String>,
+    pub admin: Option<AdminConfig>,
+    pub resolver: Option<ResolverConfig>,
+}
+
+/// A file added by `include`
+#[derive(Debug, Default, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    #[serde(default)]
+    servers: HashMap<String, ServerConfig>,
+    #[serde(default)]
+    upstream: HashMap<String, String>,
+}
+
+/// Stands for secrets in debug output and `print-config`
+const REDACTED: &str = "<redacted>";
+
+/// A password or token, redacted from debug output
+#[derive(Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(secret: &str) -> Secret {
+        Secret(secret.to_string())
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
 }
 
-#[derive(Debug, Default, Deserialize, Clone)]
+/// DNS resolution of upstreams
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ResolverConfig {
+    /// `ip` or `ip:port` of DNS servers, those of `/etc/resolv.conf` when unset
+    pub nameservers: Option<Vec<String>>,
+    /// Bounds of the TTL of records, in seconds
+    pub min_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+}
+
+impl ResolverConfig {
+    pub fn build(&self) -> Result<ResolverOptions, ConfigError> {
+        let mut nameservers = Vec::new();
+        for nameserver in self.nameservers.iter().flatten() {
+            let addr = match nameserver.parse::<IpAddr>() {
+                Ok(ip) => SocketAddr::new(ip, 53),
+                Err(_) => nameserver.parse().map_err(|_| {
+                    ConfigError::Custom(format!("Invalid nameserver {}", nameserver))
+                })?,
+            };
+            nameservers.push(addr);
+        }
+        if let (Some(min), Some(max)) = (self.min_ttl, self.max_ttl) {
+            if min > max {
+                return Err(ConfigError::Custom(format!(
+                    "min_ttl {} is above max_ttl {}",
+                    min, max
+                )));
+            }
+        }
+        Ok(ResolverOptions {
+            nameservers,
+            min_ttl: self.min_ttl.map(Duration::from_secs),
+            max_ttl: self.max_ttl.map(Duration::from_secs),
+        })
+    }
+}
+
+/// Admin API listener
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// A loopback address or `unix:///path`
+    pub listen: String,
+    /// Bearer token, required on TCP
+    pub token: Option<Secret>,
+}
+
+impl AdminConfig {
+    pub fn build(&self) -> Result<Listen, ConfigError> {
+        let listen: Listen = self.listen.parse().map_err(ConfigError::Custom)?;
+        if let Listen::Inet(addr) = &listen {
+            if !addr.ip().is_loopback() {
+                return Err(ConfigError::Custom(format!(
+                    "Admin listen address {} is not a loopback address",
+                    addr
+                )));
+            }
+            if self.token.is_none() {
+                return Err(ConfigError::Custom(
+                    "Admin API on TCP needs a token".to_string(),
+                ));
+            }
+        }
+        Ok(listen)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
 pub struct ServerConfig {
     pub listen: Vec<String>,
-    pub protocol: Option<String>,
-    pub tls: Option<bool>,
-    pub sni: Option<HashMap<String, String>>,
+    pub protocol: Option<Protocol>,
+    pub routing: Option<Routing>,
+    pub sni: Option<HashMap<String, SniRoute>>,
     pub default: Option<String>,
+    pub kcp: Option<KcpServerConfig>,
+    pub terminate: Option<TerminateConfig>,
+    pub ban: Option<BanConfig>,
+    pub ech: Option<EchConfig>,
+    /// Transport of proxy servers
+    pub transport: Option<Transport>,
+    /// Credentials of proxy servers
+    pub users: Option<HashMap<String, Secret>>,
+    pub acl: Option<AclConfig>,
+    /// Socket file of `unix://` listen addresses
+    pub unix: Option<UnixConfig>,
+    pub websocket: Option<WebSocketConfig>,
+}
+
+/// How a server handles its connections
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    /// Relay TCP or Unix socket connections
+    #[default]
+    Tcp,
+    /// Relay KCP sessions
+    Kcp,
+    /// SOCKS5 proxy
+    Socks5,
+    /// HTTP CONNECT proxy
+    Http,
+    /// Relay the binary messages of WebSocket connections
+    Websocket,
+}
+
+impl Protocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Kcp => "kcp",
+            Protocol::Socks5 => "socks5",
+            Protocol::Http => "http",
+            Protocol::Websocket => "websocket",
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How a server picks the upstream of a connection
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Routing {
+    /// Relay every connection to `default`
+    #[default]
+    Default,
+    /// Read the TLS ClientHello to route by SNI, terminate TLS or decrypt ECH
+    Sni,
+}
+
+/// Transport of proxy servers
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Kcp,
+}
+
+/// Upgrades accepted by websocket servers
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Any path when unset
+    pub path: Option<String>,
+}
+
+/// Permission bits of Unix sockets, written in octal like `"0660"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketMode(pub u32);
+
+impl Serialize for SocketMode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:04o}", self.0))
+    }
+}
+
+impl<'de> Deserialize<'de> for SocketMode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SocketMode, D::Error> {
+        let mode = String::deserialize(deserializer)?;
+        let octal = !mode.is_empty() && mode.bytes().all(|b| matches!(b, b'0'..=b'7'));
+        match u32::from_str_radix(&mode, 8) {
+            Ok(bits) if octal && bits <= 0o7777 => Ok(SocketMode(bits)),
+            _ => Err(de::Error::custom(format!("invalid socket mode {}", mode))),
+        }
+    }
+}
+
+impl JsonSchema for SocketMode {
+    fn schema_name() -> String {
+        "SocketMode".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some("^0*[0-7]{1,4}$".to_string()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct UnixConfig {
+    /// Octal, like `"0660"`
+    pub mode: Option<SocketMode>,
+    /// Name or uid
+    pub owner: Option<String>,
+    /// Name or gid
+    pub group: Option<String>,
+}
+
+impl UnixConfig {
+    pub fn build(&self) -> Result<UnixPermissions, ConfigError> {
+        let mode = self.mode.map(|mode| mode.0);
+        let uid =
+            match &self.owner {
+                Some(owner) => Some(user_id(owner).ok_or_else(|| {
+                    ConfigError::Custom(format!("Unknown socket owner {}", owner))
+                })?),
+                None => None,
+            };
+        let gid =
+            match &self.group {
+                Some(group) => Some(group_id(group).ok_or_else(|| {
+                    ConfigError::Custom(format!("Unknown socket group {}", group))
+                })?),
+                None => None,
+            };
+
+        Ok(UnixPermissions { mode, uid, gid })
+    }
+}
+
+/// Destinations allowed through proxy servers
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AclConfig {
+    pub allow: Option<Vec<String>>,
+    pub deny: Option<Vec<String>>,
+}
+
+impl AclConfig {
+    pub fn build(&self) -> Result<Acl, ConfigError> {
+        let parse = |rules: &Option<Vec<String>>| {
+            rules
+                .iter()
+                .flatten()
+                .map(|rule| rule.parse::<AclRule>().map_err(ConfigError::Custom))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(Acl::new(parse(&self.allow)?, parse(&self.deny)?))
+    }
+}
+
+/// Upstream of an SNI, or rules checked in order
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SniRoute {
+    Upstream(String),
+    Rules(Vec<SniRule>),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SniRule {
+    /// Only match when the ClientHello offers ECH, or does not
+    pub ech: Option<bool>,
+    pub upstream: String,
+}
+
+impl SniRoute {
+    pub fn upstream(&self, ech: bool) -> Option<&str> {
+        match self {
+            SniRoute::Upstream(upstream) => Some(upstream),
+            SniRoute::Rules(rules) => rules
+                .iter()
+                .find(|rule| rule.ech.is_none_or(|rule_ech| rule_ech == ech))
+                .map(|rule| rule.upstream.as_str()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EchConfig {
+    /// ECHConfig files with their private key, to decrypt the inner
+    /// ClientHello
+    pub keys: Vec<String>,
+}
+
+impl EchConfig {
+    pub fn build(&self) -> Result<EchKeys, ConfigError> {
+        EchKeys::load(&self.keys)
+            .map_err(|err| ConfigError::Custom(format!("Failed to load ECH keys: {}", err)))
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BanConfig {
+    pub alert: Option<TlsAlert>,
+    /// Seconds to hold banned connections open
+    pub tarpit: Option<u64>,
+    pub response: Option<String>,
+}
+
+impl BanConfig {
+    pub fn build(&self) -> BanPolicy {
+        BanPolicy {
+            alert: self.alert.unwrap_or_default(),
+            tarpit: self.tarpit.map(Duration::from_secs),
+            response: self.response.clone().map(String::into_bytes),
+        }
+    }
+}
+
+/// How KCP servers relay the data of a session
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KcpMode {
+    /// A byte stream
+    #[default]
+    Stream,
+    /// Each KCP message is kept whole
+    Message,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct KcpServerConfig {
+    pub cookie: Option<bool>,
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_peer: Option<usize>,
+    pub migration: Option<KcpMigration>,
+    pub shards: Option<usize>,
+    pub mode: Option<KcpMode>,
+    /// Accept streams multiplexed by `kcp://` upstreams with `mux=true`
+    pub mux: Option<bool>,
+}
+
+impl KcpServerConfig {
+    /// Build the listener side KCP config
+    pub fn build(&self) -> Result<KcpConfig, ConfigError> {
+        let default = KcpConfig::default();
+        let migration = self.migration.unwrap_or_default();
+        let stream = self.mode.unwrap_or_default() == KcpMode::Stream;
+
+        let mux = self.mux.unwrap_or(default.mux);
+        if mux && !stream {
+            return Err(ConfigError::Custom(
+                "KCP mux needs mode: stream".to_string(),
+            ));
+        }
+
+        let shards = self.shards.unwrap_or(default.shards);
+        if shards == 0 {
+            return Err(ConfigError::Custom(
+                "KCP shards must be at least 1".to_string(),
+            ));
+        }
+        // Packets from a new address of a peer may reach another shard
+        if shards > 1 && migration != KcpMigration::Disabled {
+            return Err(ConfigError::Custom(
+                "KCP migration needs shards: 1".to_string(),
+            ));
+        }
+
+        Ok(KcpConfig {
+            stream,
+            shards,
+            mux,
+            cookie: self.cookie.unwrap_or(default.cookie),
+            max_sessions: self.max_sessions.unwrap_or(default.max_sessions),
+            max_sessions_per_peer: self
+                .max_sessions_per_peer
+                .unwrap_or(default.max_sessions_per_peer),
+            migration,
+            ..default
+        })
+    }
+
+    /// Set the unset options to the values `build` defaults to
+    fn fill_defaults(&mut self) {
+        let default = KcpConfig::default();
+        self.cookie.get_or_insert(default.cookie);
+        self.max_sessions.get_or_insert(default.max_sessions);
+        self.max_sessions_per_peer
+            .get_or_insert(default.max_sessions_per_peer);
+        self.migration.get_or_insert_default();
+        self.shards.get_or_insert(default.shards);
+        self.mode.get_or_insert_default();
+        self.mux.get_or_insert(default.mux);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TerminateConfig {
+    pub certs: Option<Vec<CertConfig>>,
+    pub sni: Option<Vec<String>>,
+    pub client_auth: Option<HashMap<String, ClientAuthConfig>>,
+    pub acme: Option<AcmeServerConfig>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AcmeServerConfig {
+    pub directory: Option<String>,
+    pub contact: Option<Vec<String>>,
+    pub domains: Option<Vec<String>>,
+    pub challenge: Option<AcmeChallenge>,
+    pub http_listen: Option<String>,
+    pub storage: Option<String>,
+    pub ca: Option<String>,
+    pub renew_before_days: Option<u64>,
+}
+
+impl AcmeServerConfig {
+    /// Build the ACME config, domains default to the terminated SNI
+    pub fn build(&self, sni: Option<&Vec<String>>) -> Result<AcmeConfig, ConfigError> {
+        let domains = match self.domains.as_ref().or(sni) {
+            Some(domains) if !domains.is_empty() => domains.clone(),
+            _ => {
+                return Err(ConfigError::Custom(
+                    "ACME needs domains or terminated sni".to_string(),
+                ))
+            }
+        };
+
+        let challenge = self.challenge.unwrap_or_default();
+
+        let http_listen = match &self.http_listen {
+            Some(listen) => Some(listen.parse().map_err(|_| {
+                ConfigError::Custom(format!("Invalid ACME http_listen {}", listen))
+            })?),
+            None => None,
+        };
+        if challenge == AcmeChallenge::Http01 && http_listen.is_none() {
+            return Err(ConfigError::Custom(
+                "ACME http-01 challenge needs http_listen".to_string(),
+            ));
+        }
+
+        Ok(AcmeConfig {
+            directory: self
+                .directory
+                .clone()
+                .unwrap_or_else(|| ACME_DEFAULT_DIRECTORY.to_string()),
+            contact: self.contact.clone().unwrap_or_default(),
+            domains,
+            challenge,
+            http_listen,
+            storage: self
+                .storage
+                .clone()
+                .unwrap_or_else(|| ACME_DEFAULT_STORAGE.to_string())
+                .into(),
+            ca: self.ca.clone(),
+            renew_before: Duration::from_secs(self.renew_before_days.unwrap_or(30) * 86400),
+        })
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub ca: String,
+    pub optional: Option<bool>,
+    pub identity: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CertConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+impl TerminateConfig {
+    /// Load the certificates and build the TLS termination of a server,
+    /// certificates already issued by ACME are loaded from its storage
+    pub fn build(&self) -> Result<TlsTermination, ConfigError> {
+        let acme = self
+            .acme_config()?
+            .map(|config| Arc::new(AcmeManager::new(config)));
+        self.termination(acme)
+    }
+
+    /// Check the certificates and the ACME options without touching the
+    /// ACME storage
+    pub fn check(&self) -> Result<(), ConfigError> {
+        self.acme_config()?;
+        self.termination(None).map(|_| ())
+    }
+
+    fn acme_config(&self) -> Result<Option<AcmeConfig>, ConfigError> {
+        match &self.acme {
+            Some(acme) => Ok(Some(acme.build(self.sni.as_ref())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn termination(&self, acme: Option<Arc<AcmeManager>>) -> Result<TlsTermination, ConfigError> {
+        let cert_configs = self.certs.clone().unwrap_or_default();
+        if cert_configs.is_empty() && self.acme.is_none() {
+            return Err(ConfigError::Custom(
+                "TLS termination needs at least one certificate or ACME".to_string(),
+            ));
+        }
+
+        let mut certs = Vec::new();
+        for cert in &cert_configs {
+            let certified_key = load_certified_key(&cert.cert, &cert.key).map_err(|err| {
+                ConfigError::Custom(format!("Failed to load certificate {}: {}", cert.cert, err))
+            })?;
+            certs.push(certified_key);
+        }
+
+        let sni = self
+            .sni
+            .as_ref()
+            .map(|sni| sni.iter().cloned().collect::<HashSet<String>>());
+
+        let mut client_auth = HashMap::new();
+        for (route, auth) in self.client_auth.clone().unwrap_or_default() {
+            let ca = load_certs(&auth.ca).map_err(|err| {
+                ConfigError::Custom(format!("Failed to load client CA {}: {}", auth.ca, err))
+            })?;
+            client_auth.insert(
+                route,
+                ClientAuth {
+                    ca,
+                    optional: auth.optional.unwrap_or(false),
+                    identity: auth.identity.unwrap_or_default(),
+                },
+            );
+        }
+
+        TlsTermination::new(certs, sni, client_auth, acme)
+            .map_err(|err| ConfigError::Custom(format!("Invalid TLS termination: {}", err)))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Upstream {
     Ban,
     Echo,
-    Custom(CustomUpstream),
+    Custom(Box<CustomUpstream>),
 }
 
-#[derive(Debug, Clone, Deserialize)]
+impl Upstream {
+    pub fn name(&self) -> &str {
+        match self {
+            Upstream::Ban => "ban",
+            Upstream::Echo => "echo",
+            Upstream::Custom(custom) => &custom.name,
+        }
+    }
+}
+
+/// How a custom upstream is connected to, the scheme of its URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamProtocol {
+    Tcp,
+    Tls,
+    Ws,
+    Wss,
+    Unix,
+    Kcp,
+    Udp,
+}
+
+impl UpstreamProtocol {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UpstreamProtocol::Tcp => "tcp",
+            UpstreamProtocol::Tls => "tls",
+            UpstreamProtocol::Ws => "ws",
+            UpstreamProtocol::Wss => "wss",
+            UpstreamProtocol::Unix => "unix",
+            UpstreamProtocol::Kcp => "kcp",
+            UpstreamProtocol::Udp => "udp",
+        }
+    }
+}
+
+impl fmt::Display for UpstreamProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct CustomUpstream {
     pub name: String,
     pub addr: String,
-    pub protocol: String,
+    pub protocol: UpstreamProtocol,
+    pub kcp: Option<KcpConfig>,
+    /// Sessions shared by the connections of `kcp://` upstreams with `mux`
+    pub pool: Option<Arc<KcpPool>>,
+    pub tls: Option<TlsOrigination>,
+    pub proxy_protocol: bool,
+    /// Path and query of `ws` and `wss` upstreams
+    pub path: Option<String>,
+    /// Whether `addr` is a name of SRV records
+    pub srv: bool,
+    /// How the resolved addresses are connected to
+    pub connect: ConnectOptions,
+    /// Backends discovered at runtime, `addr` being their source
+    pub backends: Option<Arc<Backends>>,
+}
+
+impl CustomUpstream {
+    /// What the addresses of network upstreams are resolved from, the
+    /// backends of discovered upstreams in the order to try them
+    pub fn targets(&self) -> std::io::Result<Vec<Target>> {
+        if let Some(backends) = &self.backends {
+            let targets = backends.turn();
+            if targets.is_empty() {
+                return Err(IOError::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No backends of upstream {}", self.name),
+                ));
+            }
+            return Ok(targets);
+        }
+        let target = match self.srv {
+            true => Target::Srv(self.addr.clone()),
+            false => Target::host(&self.addr)
+                .map_err(|err| IOError::new(std::io::ErrorKind::InvalidInput, err))?,
+        };
+        Ok(vec![target])
+    }
+
+    /// The first of `targets`, for upstreams connected to without failover
+    pub fn target(&self) -> std::io::Result<Target> {
+        Ok(self.targets()?.remove(0))
+    }
 }
 
 #[derive(Debug)]
 pub enum ConfigError {
     IO(IOError),
     Yaml(serde_yaml::Error),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
     Custom(String),
+    /// Found by `validate`, with at least one error
+    Invalid(Report),
 }
 
 impl Config {
-    pub fn new(path: &str) -> Result<Config, ConfigError> {
-        let base = (load_config(path))?;
+    pub fn load(path: &str, overrides: &Overrides) -> Result<Config, ConfigError> {
+        let format = overrides.format.unwrap_or_else(|| Format::of(path));
+        let (mut file, migrated) = read_config(path, format, true)?;
+        let (sources, conflicts) = include(path, &mut file, migrated.is_some())?;
+        overrides.apply(&mut file)?;
 
-        Ok(Config { base })
+        let log_level = file.log.clone().unwrap_or_else(|| "info".to_string());
+        if !log_level.eq("disable") {
+            std::env::set_var("FOURTH_LOG", log_level.clone());
+            // Already set up when reloading
+            let _ = pretty_env_logger::try_init_custom_env("FOURTH_LOG");
+            debug!("Set log level to {}", log_level);
+        }
+
+        let mut report = validate(&file);
+        for diagnostic in report.errors.iter_mut().chain(&mut report.warnings) {
+            if let Some(source) = sources.source(&diagnostic.path) {
+                diagnostic.message = format!("{} (in {})", diagnostic.message, source);
+            }
+        }
+        report.errors.splice(0..0, conflicts);
+        if let Some(version) = migrated {
+            report.warnings.insert(
+                0,
+                Diagnostic {
+                    path: "version".to_string(),
+                    message: format!(
+                        "Version {} is deprecated, rewrite the config with `fourth migrate`",
+                        version
+                    ),
+                },
+            );
+        }
+        if !report.errors.is_empty() {
+            return Err(ConfigError::Invalid(report));
+        }
+        let base = (load_config(file.clone()))?;
+
+        Ok(Config {
+            base,
+            file,
+            format,
+            warnings: report.warnings,
+        })
+    }
+
+    /// The config file with the defaults filled in and the secrets
+    /// redacted, sorted, in its format
+    pub fn normalized(&self) -> Result<String, ConfigError> {
+        let mut file = self.file.clone();
+        file.log.get_or_insert_with(|| "info".to_string());
+        if let Some(token) = file.admin.as_mut().and_then(|admin| admin.token.as_mut()) {
+            *token = Secret::from(REDACTED);
+        }
+        for server in file.servers.values_mut() {
+            for user in server.users.iter_mut().flat_map(HashMap::values_mut) {
+                *user = Secret::from(REDACTED);
+            }
+            let protocol = *server.protocol.get_or_insert_default();
+            if protocol == Protocol::Socks5 || protocol == Protocol::Http {
+                server.transport.get_or_insert_default();
+            }
+            server.routing.get_or_insert_default();
+            server.default.get_or_insert_with(|| "ban".to_string());
+            if protocol == Protocol::Kcp || server.transport == Some(Transport::Kcp) {
+                server.kcp.get_or_insert_default().fill_defaults();
+            }
+            if matches!(
+                protocol,
+                Protocol::Tcp | Protocol::Kcp | Protocol::Websocket
+            ) {
+                let ban = server.ban.get_or_insert_default();
+                ban.alert.get_or_insert_default();
+            }
+            if server
+                .listen
+                .iter()
+                .any(|listen| listen.starts_with("unix://"))
+            {
+                let created = created_permissions();
+                let unix = server.unix.get_or_insert_default();
+                if let Some(mode) = created.mode {
+                    unix.mode.get_or_insert(SocketMode(mode));
+                }
+                if let Some(uid) = created.uid {
+                    unix.owner.get_or_insert_with(|| uid.to_string());
+                }
+                if let Some(gid) = created.gid {
+                    unix.group.get_or_insert_with(|| gid.to_string());
+                }
+            }
+        }
+        render(&file, self.format)
+    }
+}
+
+/// A config file rewritten in the current version, sorted, in its format.
+/// It is not validated, and references to environment variables and files
+/// are kept.
+pub fn migrate(path: &str, format: Option<Format>) -> Result<String, ConfigError> {
+    let format = format.unwrap_or_else(|| Format::of(path));
+    render(&read_config(path, format, false)?.0, format)
+}
+
+/// JSON Schema of config files, for editors
+pub fn schema() -> String {
+    let schema = schemars::schema_for!(BaseConfig);
+    serde_json::to_string_pretty(&schema).unwrap()
+}
+
+fn render(file: &BaseConfig, format: Format) -> Result<String, ConfigError> {
+    // JSON objects are sorted, and unset options are dropped
+    let mut value = serde_json::to_value(file)
+        .map_err(|err| ConfigError::Custom(format!("Failed to serialize config: {}", err)))?;
+    drop_nulls(&mut value);
+    format.render(&value)
+}
+
+fn drop_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            map.values_mut().for_each(drop_nulls);
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(drop_nulls),
+        _ => {}
     }
 }
 
-fn load_config(path: &str) -> Result<ParsedConfig, ConfigError> {
+/// Read a config file, migrated to the current version, with references
+/// to environment variables and files resolved when `resolve` is set. The
+/// version it was migrated from is returned.
+fn read_config(
+    path: &str,
+    format: Format,
+    resolve: bool,
+) -> Result<(BaseConfig, Option<i64>), ConfigError> {
     let mut contents = String::new();
     let mut file = (File::open(path))?;
     (file.read_to_string(&mut contents))?;
 
-    let base: BaseConfig = serde_yaml::from_str(&contents)?;
-
-    if base.version != 1 {
-        return Err(ConfigError::Custom(
-            "Unsupported config version".to_string(),
-        ));
+    // Every format is read as YAML values, which JSON and TOML values map to
+    let mut value: serde_yaml::Value = format.parse(&contents)?;
+    let resolved = match resolve {
+        true => interpolate(&mut value).map_err(invalid)?,
+        false => false,
+    };
+    let migrated = match value.get("version").and_then(serde_yaml::Value::as_i64) {
+        Some(1) => {
+            migrate_v1(&mut value);
+            Some(1)
+        }
+        _ => None,
+    };
+    if resolved || migrated.is_some() {
+        return Ok((serde_yaml::from_value(value)?, migrated));
     }
+    // Parsed again for errors with line numbers
+    Ok((format.parse(&contents)?, None))
+}
+
+fn invalid(errors: Vec<Diagnostic>) -> ConfigError {
+    ConfigError::Invalid(Report {
+        errors,
+        warnings: Vec::new(),
+    })
+}
 
-    let log_level = base.log.clone().unwrap_or_else(|| "info".to_string());
-    if !log_level.eq("disable") {
-        std::env::set_var("FOURTH_LOG", log_level.clone());
-        pretty_env_logger::init_custom_env("FOURTH_LOG");
-        debug!("Set log level to {}", log_level);
+/// Version 2 replaced `tls: true` of servers with `routing: sni`
+fn migrate_v1(config: &mut serde_yaml::Value) {
+    config["version"] = CONFIG_VERSION.into();
+    migrate_v1_servers(config);
+}
+
+fn migrate_v1_servers(config: &mut serde_yaml::Value) {
+    let servers = match config.get_mut("servers").and_then(|s| s.as_mapping_mut()) {
+        Some(servers) => servers,
+        None => return,
+    };
+    for (_, server) in servers.iter_mut() {
+        let server = match server.as_mapping_mut() {
+            Some(server) => server,
+            None => continue,
+        };
+        let key = serde_yaml::Value::from("tls");
+        // Other values are left to be refused
+        if let Some(tls) = server.get(&key).and_then(serde_yaml::Value::as_bool) {
+            server.remove(&key);
+            let routing = if tls { "sni" } else { "default" };
+            server.insert("routing".into(), routing.into());
+        }
     }
+}
 
-    debug!("Config version {}", base.version);
+/// Included files defining servers and upstreams, by `servers.<name>` and
+/// `upstream.<name>` paths
+#[derive(Debug, Default)]
+struct Sources(HashMap<String, String>);
 
-    let mut parsed_upstream: HashMap<String, Upstream> = HashMap::new();
+impl Sources {
+    /// The included file defining what `path` points into
+    fn source(&self, path: &str) -> Option<&str> {
+        let mut parts = path.splitn(3, ['.', '[']);
+        let key = format!("{}.{}", parts.next()?, parts.next()?);
+        self.0.get(&key).map(String::as_str)
+    }
+}
 
-    for (name, upstream) in base.upstream.iter() {
-        let upstream_url = match Url::parse(upstream) {
-            Ok(url) => url,
-            Err(_) => {
-                return Err(ConfigError::Custom(format!(
-                    "Invalid upstream url {}",
-                    upstream
-                )))
+/// Merge the files matched by `include` of the config at `path` into it.
+/// Servers defined twice and upstreams defined differently are returned as
+/// errors.
+fn include(
+    path: &str,
+    file: &mut BaseConfig,
+    v1: bool,
+) -> Result<(Sources, Vec<Diagnostic>), ConfigError> {
+    let mut sources = Sources::default();
+    let mut conflicts = Vec::new();
+    let patterns = match file.include.take() {
+        Some(patterns) => patterns,
+        None => return Ok((sources, conflicts)),
+    };
+
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let mut seen = HashSet::new();
+    seen.insert(Path::new(path).canonicalize()?);
+    for pattern in patterns {
+        for included in include_files(dir, &pattern)? {
+            if !seen.insert(included.canonicalize()?) {
+                continue;
             }
-        };
+            let source = included.display().to_string();
+            let contents = std::fs::read_to_string(&included)
+                .map_err(|err| ConfigError::Custom(format!("{}: {}", source, err)))?;
+            let mut value: serde_yaml::Value = Format::of(&included)
+                .parse(&contents)
+                .map_err(|err| ConfigError::Custom(format!("{}: {}", source, err)))?;
+            interpolate(&mut value).map_err(|mut errors| {
+                for error in &mut errors {
+                    error.message = format!("{} (in {})", error.message, source);
+                }
+                invalid(errors)
+            })?;
+            if v1 {
+                migrate_v1_servers(&mut value);
+            }
+            let config: IncludedConfig = serde_yaml::from_value(value)
+                .map_err(|err| ConfigError::Custom(format!("{}: {}", source, err)))?;
 
-        let upstream_host = match upstream_url.host_str() {
-            Some(host) => host,
-            None => {
-                return Err(ConfigError::Custom(format!(
-                    "Invalid upstream url {}",
-                    upstream
-                )))
+            for (name, server) in sorted_entries(config.servers) {
+                let key = format!("servers.{}", name);
+                if file.servers.contains_key(&name) {
+                    let defined = sources.0.get(&key).map_or(path, String::as_str);
+                    conflicts.push(Diagnostic {
+                        path: key,
+                        message: format!("Defined in both {} and {}", defined, source),
+                    });
+                    continue;
+                }
+                file.servers.insert(name, server);
+                sources.0.insert(key, source.clone());
             }
-        };
+            for (name, upstream) in sorted_entries(config.upstream) {
+                let key = format!("upstream.{}", name);
+                match file.upstream.get(&name) {
+                    Some(defined) if *defined == upstream => {}
+                    Some(defined) => conflicts.push(Diagnostic {
+                        message: format!(
+                            "Defined as {} in {} and as {} in {}",
+                            defined,
+                            sources.0.get(&key).map_or(path, String::as_str),
+                            upstream,
+                            source
+                        ),
+                        path: key,
+                    }),
+                    None => {
+                        file.upstream.insert(name, upstream);
+                        sources.0.insert(key, source.clone());
+                    }
+                }
+            }
+        }
+    }
+    Ok((sources, conflicts))
+}
 
-        let upsteam_port = match upstream_url.port_or_known_default() {
-            Some(port) => port,
-            None => {
-                return Err(ConfigError::Custom(format!(
-                    "Invalid upstream url {}",
-                    upstream
-                )))
+/// Files matched by an `include` pattern, sorted. A pattern without
+/// wildcards must match a file or a directory.
+fn include_files(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>, ConfigError> {
+    let path = dir.join(pattern);
+    if path.is_dir() {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&path)? {
+            let file = entry?.path();
+            let config = matches!(
+                file.extension().and_then(|ext| ext.to_str()),
+                Some("yaml" | "yml" | "toml" | "json")
+            );
+            if config && file.is_file() {
+                files.push(file);
             }
-        };
+        }
+        files.sort();
+        return Ok(files);
+    }
 
-        if upstream_url.scheme() != "tcp" {
-            return Err(ConfigError::Custom(format!(
-                "Invalid upstream scheme {}",
-                upstream
-            )));
+    let invalid = |err: &dyn fmt::Display| {
+        ConfigError::Custom(format!("Invalid include pattern {}: {}", pattern, err))
+    };
+    let matches = glob::glob(&path.to_string_lossy()).map_err(|err| invalid(&err))?;
+    let mut files = Vec::new();
+    for file in matches {
+        let file = file.map_err(|err| invalid(&err))?;
+        if file.is_file() {
+            files.push(file);
         }
+    }
+    if files.is_empty() && !pattern.contains(['*', '?', '[']) {
+        return Err(ConfigError::Custom(format!(
+            "Included file {} does not exist",
+            path.display()
+        )));
+    }
+    files.sort();
+    Ok(files)
+}
 
-        parsed_upstream.insert(
-            name.to_string(),
-            Upstream::Custom(CustomUpstream {
-                name: name.to_string(),
-                addr: format!("{}:{}", upstream_host, upsteam_port),
-                protocol: upstream_url.scheme().to_string(),
-            }),
-        );
+fn sorted_entries<V>(map: HashMap<String, V>) -> Vec<(String, V)> {
+    let mut entries: Vec<_> = map.into_iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+    entries
+}
+
+fn load_config(base: BaseConfig) -> Result<ParsedConfig, ConfigError> {
+    debug!("Config version {}", base.version);
+
+    let mut parsed_upstream: HashMap<String, Upstream> = HashMap::new();
+
+    for (name, upstream) in base.upstream.iter() {
+        let custom = parse_upstream(name, upstream)?;
+        parsed_upstream.insert(name.to_string(), Upstream::Custom(Box::new(custom)));
     }
 
     parsed_upstream.insert("ban".to_string(), Upstream::Ban);
 
     parsed_upstream.insert("echo".to_string(), Upstream::Echo);
 
-    let parsed = ParsedConfig {
-        version: base.version,
-        log: base.log,
+    let resolver = match &base.resolver {
+        Some(resolver) => resolver.build()?,
+        None => ResolverOptions::default(),
+    };
+
+    Ok(ParsedConfig {
         servers: base.servers,
         upstream: parsed_upstream,
+        admin: base.admin,
+        resolver,
+    })
+}
+
+/// Parse the URL of an upstream, e.g. `tcp://127.0.0.1:8080`
+fn parse_upstream(name: &str, upstream: &str) -> Result<CustomUpstream, ConfigError> {
+    let upstream_url = match Url::parse(upstream) {
+        Ok(url) => url,
+        Err(_) => {
+            return Err(ConfigError::Custom(format!(
+                "Invalid upstream url {}",
+                upstream
+            )))
+        }
     };
 
-    verify_config(parsed)
-}
+    if upstream_url.scheme() == "unix" {
+        return parse_unix_upstream(name, &upstream_url);
+    }
+    if upstream_url.scheme() == "srv+tcp" {
+        return parse_srv_upstream(name, &upstream_url);
+    }
+    if matches!(upstream_url.scheme(), "file+tcp" | "http+tcp") {
+        return parse_discovered_upstream(name, &upstream_url);
+    }
 
-fn verify_config(config: ParsedConfig) -> Result<ParsedConfig, ConfigError> {
-    let mut used_upstreams: HashSet<String> = HashSet::new();
-    let mut upstream_names: HashSet<String> = HashSet::new();
-    let mut listen_addresses: HashSet<String> = HashSet::new();
+    let upstream_host = match upstream_url.host_str() {
+        Some(host) => host,
+        None => {
+            return Err(ConfigError::Custom(format!(
+                "Invalid upstream url {}",
+                upstream
+            )))
+        }
+    };
 
-    // Check for duplicate upstream names
-    for (name, _) in config.upstream.iter() {
-        if upstream_names.contains(name) {
+    let upsteam_port = match upstream_url.port_or_known_default() {
+        Some(port) => port,
+        None => {
             return Err(ConfigError::Custom(format!(
-                "Duplicate upstream name {}",
-                name
-            )));
+                "Invalid upstream url {}",
+                upstream
+            )))
         }
+    };
 
-        upstream_names.insert(name.to_string());
-    }
+    let protocol = match upstream_url.scheme() {
+        "tcp" => UpstreamProtocol::Tcp,
+        "tls" => UpstreamProtocol::Tls,
+        "ws" => UpstreamProtocol::Ws,
+        "wss" => UpstreamProtocol::Wss,
+        "kcp" => UpstreamProtocol::Kcp,
+        "udp" => UpstreamProtocol::Udp,
+        _ => {
+            return Err(ConfigError::Custom(format!(
+                "Invalid upstream scheme {}",
+                upstream
+            )))
+        }
+    };
 
-    for (_, server) in config.servers.clone() {
-        // check for duplicate listen addresses
-        for listen in server.listen {
-            if listen_addresses.contains(&listen) {
-                return Err(ConfigError::Custom(format!(
-                    "Duplicate listen address {}",
-                    listen
-                )));
+    let proxy_protocol = match protocol {
+        UpstreamProtocol::Tcp | UpstreamProtocol::Tls => parse_proxy_protocol(&upstream_url)?,
+        _ => false,
+    };
+
+    let (kcp, tls) = match protocol {
+        UpstreamProtocol::Kcp => (Some(parse_kcp_options(&upstream_url)?), None),
+        UpstreamProtocol::Tls | UpstreamProtocol::Wss => {
+            (None, Some(parse_tls_options(&upstream_url, upstream_host)?))
+        }
+        _ => (None, None),
+    };
+
+    // TLS options of wss upstreams and connect options are not sent
+    let path = match protocol {
+        UpstreamProtocol::Ws => Some(ws_path(&upstream_url)),
+        UpstreamProtocol::Wss => Some(upstream_url.path().to_string()),
+        _ => None,
+    };
+
+    let pool = match kcp {
+        Some(kcp) if kcp.mux => Some(Arc::new(KcpPool::new(kcp))),
+        _ => None,
+    };
+
+    let addr = format!("{}:{}", upstream_host, upsteam_port);
+    Target::host(&addr)
+        .map_err(|err| ConfigError::Custom(format!("Invalid upstream url {}: {}", upstream, err)))?;
+
+    Ok(CustomUpstream {
+        name: name.to_string(),
+        addr,
+        protocol,
+        kcp,
+        pool,
+        tls,
+        proxy_protocol,
+        path,
+        srv: false,
+        connect: parse_connect_options(&upstream_url)?,
+        backends: None,
+    })
+}
+
+/// Parse a `srv+tcp://_service._tcp.example.com` upstream, a TCP upstream
+/// whose addresses and ports are taken from SRV records
+fn parse_srv_upstream(name: &str, url: &Url) -> Result<CustomUpstream, ConfigError> {
+    let service = match url.host_str() {
+        Some(service) if url.port().is_none() && matches!(url.path(), "" | "/") => service,
+        _ => {
+            return Err(ConfigError::Custom(format!(
+                "Invalid SRV upstream {}, expected srv+tcp://_service._tcp.example.com",
+                url
+            )))
+        }
+    };
+    Ok(CustomUpstream {
+        name: name.to_string(),
+        addr: service.to_string(),
+        protocol: UpstreamProtocol::Tcp,
+        kcp: None,
+        pool: None,
+        tls: None,
+        proxy_protocol: parse_proxy_protocol(url)?,
+        path: None,
+        srv: true,
+        connect: parse_connect_options(url)?,
+        backends: None,
+    })
+}
+
+/// Parse a TCP upstream whose backends are read at runtime, from a file
+/// with `file+tcp:///etc/fourth/backends.yaml` or an HTTP endpoint with
+/// `http+tcp://discovery.internal:8080/backends`. `interval` sets how often
+/// in seconds, other options than those of TCP upstreams are sent to HTTP
+/// endpoints.
+fn parse_discovered_upstream(name: &str, url: &Url) -> Result<CustomUpstream, ConfigError> {
+    let invalid = || ConfigError::Custom(format!("Invalid discovered upstream url {}", url));
+    let mut interval = None;
+    let mut query = Vec::new();
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "interval" => {
+                let secs: u64 = match value.parse() {
+                    Ok(secs) if secs > 0 => secs,
+                    _ => {
+                        return Err(ConfigError::Custom(format!(
+                            "Invalid interval {} in {}",
+                            value, url
+                        )))
+                    }
+                };
+                interval = Some(Duration::from_secs(secs));
             }
+            key if key == "proxy_protocol" || is_connect_option(key) => {}
+            _ => query.push((key.into_owned(), value.into_owned())),
+        }
+    }
 
-            listen_addresses.insert(listen.to_string());
+    let source = match url.scheme() {
+        "file+tcp" => match url.to_file_path() {
+            Ok(path) if !url.has_host() && path.parent().is_some() => Source::File(path),
+            _ => return Err(invalid()),
+        },
+        _ => {
+            let rest = &url[url::Position::BeforeHost..url::Position::AfterPath];
+            let mut endpoint = Url::parse(&format!("http://{}", rest)).map_err(|_| invalid())?;
+            if url.host_str().is_none() {
+                return Err(invalid());
+            }
+            if !query.is_empty() {
+                endpoint.query_pairs_mut().extend_pairs(query);
+            }
+            Source::Http(endpoint)
         }
+    };
+    let interval = interval.unwrap_or(match source {
+        Source::File(..) => discovery::FILE_INTERVAL,
+        Source::Http(..) => discovery::HTTP_INTERVAL,
+    });
+
+    Ok(CustomUpstream {
+        name: name.to_string(),
+        addr: source.to_string(),
+        protocol: UpstreamProtocol::Tcp,
+        kcp: None,
+        pool: None,
+        tls: None,
+        proxy_protocol: parse_proxy_protocol(url)?,
+        path: None,
+        srv: false,
+        connect: parse_connect_options(url)?,
+        backends: Some(Arc::new(Backends::new(source, interval))),
+    })
+}
 
-        if server.tls.unwrap_or_default() && server.sni.is_some() {
-            for (_, val) in server.sni.unwrap() {
-                used_upstreams.insert(val.to_string());
+/// Options of connecting to the addresses of network upstreams, e.g.
+/// `tcp://app.example.com:80?family=ipv4` or
+/// `tcp://app.example.com:80?prefer=ipv4&connect_delay=100`.
+fn parse_connect_options(url: &Url) -> Result<ConnectOptions, ConfigError> {
+    let mut options = ConnectOptions::default();
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "family" => {
+                options.family = Some(value.parse().map_err(|err| {
+                    ConfigError::Custom(format!("Invalid family in {}: {}", url, err))
+                })?);
             }
+            "prefer" => {
+                options.prefer = value.parse().map_err(|err| {
+                    ConfigError::Custom(format!("Invalid prefer in {}: {}", url, err))
+                })?;
+            }
+            "connect_delay" => {
+                let ms: u64 = value.parse().map_err(|_| {
+                    ConfigError::Custom(format!("Invalid connect_delay {} in {}", value, url))
+                })?;
+                options.delay = Duration::from_millis(ms);
+            }
+            _ => {}
         }
+    }
+    Ok(options)
+}
+
+/// Whether `key` is an option of [`parse_connect_options`]
+fn is_connect_option(key: &str) -> bool {
+    matches!(key, "family" | "prefer" | "connect_delay")
+}
 
-        if server.default.is_some() {
-            used_upstreams.insert(server.default.unwrap().to_string());
+/// Path and query sent to a `ws://` upstream, without connect options
+fn ws_path(url: &Url) -> String {
+    let mut sent = url.clone();
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_connect_option(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    match pairs.is_empty() {
+        true => sent.set_query(None),
+        false => {
+            sent.query_pairs_mut().clear().extend_pairs(pairs);
         }
+    }
+    sent[url::Position::BeforePath..].to_string()
+}
+
+/// Build the client side KCP config from the query of a `kcp://` upstream,
+/// e.g. `kcp://remote.example.com:8082?keepalive=10&nodelay=fastest`.
+fn parse_kcp_options(url: &Url) -> Result<KcpConfig, ConfigError> {
+    let mut kcp_config = KcpConfig {
+        keepalive: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
 
-        for key in &used_upstreams {
-            if !config.upstream.contains_key(key) {
-                return Err(ConfigError::Custom(format!("Upstream {} not found", key)));
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "keepalive" => {
+                let secs: u64 = value.parse().map_err(|_| {
+                    ConfigError::Custom(format!("Invalid KCP keepalive {} in {}", value, url))
+                })?;
+                kcp_config.keepalive = match secs {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                };
+            }
+            "cookie" => {
+                kcp_config.cookie = value.parse().map_err(|_| {
+                    ConfigError::Custom(format!("Invalid KCP cookie {} in {}", value, url))
+                })?;
+            }
+            "mux" => {
+                kcp_config.mux = value.parse().map_err(|_| {
+                    ConfigError::Custom(format!("Invalid KCP mux {} in {}", value, url))
+                })?;
+            }
+            "max_streams" => {
+                kcp_config.max_streams = match value.parse() {
+                    Ok(streams) if streams > 0 => streams,
+                    _ => {
+                        return Err(ConfigError::Custom(format!(
+                            "Invalid KCP max_streams {} in {}",
+                            value, url
+                        )))
+                    }
+                };
+            }
+            "nodelay" => {
+                kcp_config.nodelay = match value.as_ref() {
+                    "fastest" => KcpNoDelayConfig::fastest(),
+                    "normal" => KcpNoDelayConfig::normal(),
+                    _ => {
+                        return Err(ConfigError::Custom(format!(
+                            "Invalid KCP nodelay mode {} in {}",
+                            value, url
+                        )))
+                    }
+                };
+            }
+            key if is_connect_option(key) => {}
+            _ => {
+                return Err(ConfigError::Custom(format!(
+                    "Unknown KCP option {} in {}",
+                    key, url
+                )))
             }
         }
     }
 
-    for key in &upstream_names {
-        if !used_upstreams.contains(key) && !key.eq("echo") && !key.eq("ban") {
-            warn!("Upstream {} not used", key);
+    Ok(kcp_config)
+}
+
+/// Whether a `tcp://` or `tls://` upstream expects a PROXY protocol header,
+/// e.g. `tcp://127.0.0.1:8080?proxy_protocol=v2`.
+fn parse_proxy_protocol(url: &Url) -> Result<bool, ConfigError> {
+    match url.query_pairs().find(|(key, _)| key == "proxy_protocol") {
+        None => Ok(false),
+        Some((_, version)) if version == "v2" => Ok(true),
+        Some((_, version)) => Err(ConfigError::Custom(format!(
+            "Unsupported PROXY protocol version {} in {}",
+            version, url
+        ))),
+    }
+}
+
+/// Build the TLS client of a `tls://` upstream, e.g.
+/// `tls://backend.example.com:443?sni=www.example.com&ca=/etc/fourth/ca.pem`.
+fn parse_tls_options(url: &Url, host: &str) -> Result<TlsOrigination, ConfigError> {
+    let mut sni = host.to_string();
+    let mut ca = None;
+    let mut cert = None;
+    let mut key = None;
+    let mut verify = TlsVerify::Full;
+
+    for (name, value) in url.query_pairs() {
+        match name.as_ref() {
+            name if name == "proxy_protocol" || is_connect_option(name) => {}
+            "sni" => sni = value.to_string(),
+            "ca" => ca = Some(value.to_string()),
+            "cert" => cert = Some(value.to_string()),
+            "key" => key = Some(value.to_string()),
+            "verify" => {
+                verify = match value.as_ref() {
+                    "full" => TlsVerify::Full,
+                    "ca" => TlsVerify::Ca,
+                    "none" => TlsVerify::None,
+                    _ => {
+                        return Err(ConfigError::Custom(format!(
+                            "Invalid TLS verify mode {} in {}",
+                            value, url
+                        )))
+                    }
+                };
+            }
+            _ => {
+                return Err(ConfigError::Custom(format!(
+                    "Unknown TLS option {} in {}",
+                    name, url
+                )))
+            }
         }
     }
 
-    Ok(config)
+    let load_error =
+        |path: &str, err: IOError| ConfigError::Custom(format!("Failed to load {}: {}", path, err));
+
+    let server_name = ServerName::try_from(sni.as_str())
+        .map_err(|_| ConfigError::Custom(format!("Invalid TLS sni {} in {}", sni, url)))?
+        .to_owned();
+
+    let ca = match ca {
+        Some(path) => Some(load_certs(&path).map_err(|err| load_error(&path, err))?),
+        None => None,
+    };
+
+    let client_auth = match (cert, key) {
+        (Some(cert), Some(key)) => Some((
+            load_certs(&cert).map_err(|err| load_error(&cert, err))?,
+            load_private_key(&key).map_err(|err| load_error(&key, err))?,
+        )),
+        (None, None) => None,
+        _ => {
+            return Err(ConfigError::Custom(format!(
+                "TLS cert and key must be set together in {}",
+                url
+            )))
+        }
+    };
+
+    TlsOrigination::new(server_name, ca, client_auth, verify)
+        .map_err(|err| ConfigError::Custom(format!("Invalid TLS options in {}: {}", url, err)))
 }
 
+/// `unix:///path`, with the path in `addr`
+fn parse_unix_upstream(name: &str, url: &Url) -> Result<CustomUpstream, ConfigError> {
+    let path = match url.to_file_path() {
+        Ok(path) if !url.has_host() && path.parent().is_some() => path,
+        _ => {
+            return Err(ConfigError::Custom(format!(
+                "Invalid unix upstream url {}",
+                url
+            )))
+        }
+    };
+
+    Ok(CustomUpstream {
+        name: name.to_string(),
+        addr: path.to_string_lossy().into_owned(),
+        protocol: UpstreamProtocol::Unix,
+        kcp: None,
+        pool: None,
+        tls: None,
+        proxy_protocol: parse_proxy_protocol(url)?,
+        path: None,
+        srv: false,
+        connect: ConnectOptions::default(),
+        backends: None,
+    })
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::IO(err) => write!(f, "{}", err),
+            ConfigError::Yaml(err) => write!(f, "{}", err),
+            ConfigError::Toml(err) => write!(f, "{}", err),
+            ConfigError::Json(err) => write!(f, "{}", err),
+            ConfigError::Custom(msg) => write!(f, "{}", msg),
+            ConfigError::Invalid(report) => {
+                let errors: Vec<String> = report.errors.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", errors.join("; "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
 impl From<IOError> for ConfigError {
     fn from(err: IOError) -> ConfigError {
         ConfigError::IO(err)
     }
 }
 
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> ConfigError {
+        ConfigError::Toml(err)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(err: serde_json::Error) -> ConfigError {
+        ConfigError::Json(err)
+    }
+}
+
 impl From<serde_yaml::Error> for ConfigError {
     fn from(err: serde_yaml::Error) -> ConfigError {
         ConfigError::Yaml(err)
@@ -221,13 +1661,428 @@ impl From<serde_yaml::Error> for ConfigError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::plugins::resolver::Family;
 
     #[test]
     fn test_load_config() {
-        let config = Config::new("tests/config.yaml").unwrap();
-        assert_eq!(config.base.version, 1);
-        assert_eq!(config.base.log.unwrap(), "disable");
-        assert_eq!(config.base.servers.len(), 5);
-        assert_eq!(config.base.upstream.len(), 3 + 2); // Add ban and echo upstreams
+        let config = Config::load("tests/config.yaml", &Overrides::default()).unwrap();
+        assert_eq!(config.file.version, CONFIG_VERSION);
+        assert!(config.warnings.is_empty());
+        assert_eq!(config.file.log.as_deref(), Some("disable"));
+        assert_eq!(config.base.servers.len(), 19);
+        assert_eq!(config.base.upstream.len(), 10 + 2); // Add ban and echo upstreams
+        match &config.base.upstream["wss_tunnel"] {
+            Upstream::Custom(custom) => {
+                assert_eq!(custom.addr, "127.0.0.1:54970");
+                assert_eq!(custom.path.as_deref(), Some("/"));
+                assert!(custom.tls.is_some());
+            }
+            upstream => panic!("Unexpected upstream {:?}", upstream),
+        }
+
+        let sni = config.base.servers["ech_server"].sni.clone().unwrap();
+        assert_eq!(sni["secret.test.com"].upstream(false), Some("echo"));
+        assert_eq!(sni["public.test.com"].upstream(true), Some("echo"));
+        assert_eq!(sni["public.test.com"].upstream(false), Some("ban"));
+    }
+
+    #[test]
+    fn test_unix_config() {
+        let url = Url::parse("unix:///run/app.sock?proxy_protocol=v2").unwrap();
+        let upstream = parse_unix_upstream("app", &url).unwrap();
+        assert_eq!(upstream.addr, "/run/app.sock");
+        assert!(upstream.proxy_protocol);
+        let url = Url::parse("unix://run/app.sock").unwrap();
+        assert!(parse_unix_upstream("app", &url).is_err());
+
+        let unix = UnixConfig {
+            mode: Some(SocketMode(0o660)),
+            owner: Some("root".to_string()),
+            group: Some("0".to_string()),
+        };
+        let permissions = unix.build().unwrap();
+        assert_eq!(permissions.mode, Some(0o660));
+        assert_eq!(permissions.uid, Some(0));
+        assert_eq!(permissions.gid, Some(0));
+        let mode: SocketMode = serde_json::from_str("\"0660\"").unwrap();
+        assert_eq!(mode, SocketMode(0o660));
+        assert_eq!(serde_json::to_string(&mode).unwrap(), "\"0660\"");
+        for mode in ["\"0999\"", "\"17777\"", "\"+660\"", "\"\"", "660"] {
+            assert!(serde_json::from_str::<SocketMode>(mode).is_err());
+        }
+    }
+
+    #[test]
+    fn test_overrides() {
+        let mut overrides = Overrides {
+            log: Some("debug".to_string()),
+            ..Default::default()
+        };
+        overrides.listen.insert(
+            "tcp_server".to_string(),
+            vec!["127.0.0.1:54600".to_string()],
+        );
+        let config = Config::load("tests/config.yaml", &overrides).unwrap();
+        assert_eq!(config.file.log.as_deref(), Some("debug"));
+        assert_eq!(
+            config.base.servers["tcp_server"].listen,
+            vec!["127.0.0.1:54600"]
+        );
+
+        let normalized: BaseConfig = serde_yaml::from_str(&config.normalized().unwrap()).unwrap();
+        let server = &normalized.servers["tcp_server"];
+        assert_eq!(server.protocol, Some(Protocol::Tcp));
+        assert_eq!(server.routing, Some(Routing::Default));
+        assert_eq!(server.transport, None);
+        assert_eq!(server.kcp, None);
+        let ban = server.ban.as_ref().unwrap();
+        assert_eq!(ban.alert, Some(TlsAlert::UnrecognizedName));
+        let kcp = normalized.servers["kcp_server"].kcp.as_ref().unwrap();
+        assert_eq!(kcp.shards, Some(2));
+        assert_eq!(kcp.cookie, Some(false));
+        assert_eq!(kcp.mode, Some(KcpMode::Stream));
+        assert_eq!(kcp.max_sessions, Some(KcpConfig::default().max_sessions));
+        let kcp = normalized.servers["socks5_kcp_server"]
+            .kcp
+            .as_ref()
+            .unwrap();
+        assert_eq!(kcp.migration, Some(KcpMigration::Disabled));
+        assert_eq!(normalized.servers["socks5_server"].ban, None);
+        let users = normalized.servers["socks5_server"].users.as_ref().unwrap();
+        assert_eq!(users["alice"].expose(), REDACTED);
+        let admin = normalized.admin.as_ref().unwrap();
+        assert_eq!(admin.token.as_ref().unwrap().expose(), REDACTED);
+        assert_eq!(normalized.upstream, config.file.upstream);
+
+        let mut unix = config.clone();
+        unix.file.servers.get_mut("tcp_server").unwrap().listen =
+            vec!["unix:///run/fourth/tcp.sock".to_string()];
+        let normalized: BaseConfig = serde_yaml::from_str(&unix.normalized().unwrap()).unwrap();
+        let server = &normalized.servers["tcp_server"];
+        let uid = unsafe { libc::geteuid() }.to_string();
+        assert_eq!(server.unix.as_ref().unwrap().owner, Some(uid));
+
+        overrides.listen.insert("nothing".to_string(), Vec::new());
+        assert!(Config::load("tests/config.yaml", &overrides).is_err());
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let config = Config::load("tests/config-v1.yaml", &Overrides::default()).unwrap();
+        assert_eq!(config.file.version, CONFIG_VERSION);
+        assert_eq!(config.warnings[0].path, "version");
+        let servers = &config.file.servers;
+        assert_eq!(servers["sni_server"].routing, Some(Routing::Sni));
+        assert_eq!(servers["plain_server"].routing, Some(Routing::Default));
+
+        let migrated: BaseConfig =
+            serde_yaml::from_str(&migrate("tests/config-v1.yaml", None).unwrap()).unwrap();
+        assert_eq!(migrated.version, CONFIG_VERSION);
+        assert_eq!(migrated.servers["sni_server"].routing, Some(Routing::Sni));
+    }
+
+    #[test]
+    fn test_strict_keys() {
+        let yaml =
+            "version: 2\nservers:\n  web:\n    listen: [\"127.0.0.1:80\"]\n    defualt: ban\n";
+        let err = serde_yaml::from_str::<BaseConfig>(yaml).unwrap_err();
+        assert!(err.to_string().contains("defualt"));
+        let yaml =
+            "version: 2\nservers:\n  web:\n    listen: [\"127.0.0.1:80\"]\n    protocol: tpc\n";
+        assert!(serde_yaml::from_str::<BaseConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        let config = Config::load("tests/include/config.yaml", &Overrides::default()).unwrap();
+        let mut servers: Vec<&String> = config.file.servers.keys().collect();
+        servers.sort();
+        assert_eq!(servers, vec!["main_server", "tenant_a", "tenant_b"]);
+        assert_eq!(config.file.upstream.len(), 2);
+        assert_eq!(config.file.include, None);
+
+        let report = match Config::load("tests/include/conflict.yaml", &Overrides::default()) {
+            Err(ConfigError::Invalid(report)) => report,
+            result => panic!("Unexpected result {:?}", result.map(|config| config.file)),
+        };
+        let paths: Vec<&str> = report.errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "servers.tenant_a",
+                "upstream.shared",
+                "servers.tenant_c.listen[0]"
+            ]
+        );
+        assert!(report.errors[2]
+            .message
+            .ends_with("(in tests/include/conflict.d/tenant-c.yaml)"));
+
+        let mut file = BaseConfig {
+            include: Some(vec!["missing.yaml".to_string()]),
+            ..Default::default()
+        };
+        assert!(include("tests/include/config.yaml", &mut file, false).is_err());
+    }
+
+    #[test]
+    fn test_formats() {
+        let formats = [
+            ("tests/formats/config.yaml", Format::Yaml),
+            ("tests/formats/config.toml", Format::Toml),
+            ("tests/formats/config.json", Format::Json),
+        ];
+        let expected = Config::load(formats[0].0, &Overrides::default()).unwrap();
+        for (path, format) in formats {
+            assert_eq!(Format::of(path), format);
+            let config = Config::load(path, &Overrides::default()).unwrap();
+            assert_eq!(config.format, format);
+            assert_eq!(config.file, expected.file, "{}", path);
+            assert!(config.warnings.is_empty());
+
+            let rendered = render(&expected.file, format).unwrap();
+            let parsed: BaseConfig = format.parse(&rendered).unwrap();
+            assert_eq!(parsed, expected.file, "{}", rendered);
+        }
+
+        let overrides = Overrides {
+            format: Some(Format::Yaml),
+            ..Default::default()
+        };
+        assert!(Config::load("tests/formats/config.toml", &overrides).is_err());
+        assert_eq!("yml".parse(), Ok(Format::Yaml));
+        assert!("ini".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_resolver_config() {
+        let upstream = parse_upstream("app", "srv+tcp://_app._tcp.example.com").unwrap();
+        assert_eq!(upstream.protocol, UpstreamProtocol::Tcp);
+        assert_eq!(
+            upstream.target().unwrap(),
+            Target::Srv("_app._tcp.example.com".to_string())
+        );
+        assert!(parse_upstream("app", "srv+tcp://_app._tcp.example.com:80").is_err());
+        let upstream = parse_upstream("app", "tcp://[::1]:8080").unwrap();
+        assert_eq!(
+            upstream.target().unwrap(),
+            Target::Host("::1".to_string(), 8080)
+        );
+        assert_eq!(upstream.connect, ConnectOptions::default());
+        assert!(parse_upstream("app", "tcp://127.0.0.1:0").is_err());
+
+        let resolver = ResolverConfig {
+            nameservers: Some(vec!["10.0.0.53".to_string(), "[::1]:5353".to_string()]),
+            min_ttl: Some(5),
+            max_ttl: Some(60),
+        };
+        let options = resolver.build().unwrap();
+        assert_eq!(options.nameservers[0], "10.0.0.53:53".parse().unwrap());
+        assert_eq!(options.nameservers[1], "[::1]:5353".parse().unwrap());
+        assert_eq!(options.max_ttl, Some(Duration::from_secs(60)));
+        let resolver = ResolverConfig {
+            min_ttl: Some(60),
+            max_ttl: Some(5),
+            ..resolver
+        };
+        assert!(resolver.build().is_err());
+    }
+
+    #[test]
+    fn test_connect_options() {
+        let upstream = parse_upstream(
+            "app",
+            "tcp://app.example.com:80?family=ipv4&prefer=ipv4&connect_delay=100",
+        )
+        .unwrap();
+        assert_eq!(upstream.connect.family, Some(Family::Ipv4));
+        assert_eq!(upstream.connect.prefer, Family::Ipv4);
+        assert_eq!(upstream.connect.delay, Duration::from_millis(100));
+        let upstream = parse_upstream("app", "srv+tcp://_app._tcp.example.com?family=ipv6");
+        assert_eq!(upstream.unwrap().connect.family, Some(Family::Ipv6));
+        assert!(parse_upstream("app", "kcp://app.example.com:80?prefer=ipv4").is_ok());
+        assert!(parse_upstream("app", "tls://app.example.com:443?family=ipv6").is_ok());
+        let upstream = parse_upstream("app", "ws://app.example.com/ws?a=1&family=ipv6").unwrap();
+        assert_eq!(upstream.path.as_deref(), Some("/ws?a=1"));
+        let upstream = parse_upstream("app", "ws://app.example.com/ws?family=ipv6").unwrap();
+        assert_eq!(upstream.path.as_deref(), Some("/ws"));
+
+        assert!(parse_upstream("app", "tcp://app.example.com:80?family=ipv5").is_err());
+        assert!(parse_upstream("app", "tcp://app.example.com:80?prefer=any").is_err());
+        assert!(parse_upstream("app", "tcp://app.example.com:80?connect_delay=-1").is_err());
+    }
+
+    #[test]
+    fn test_discovered_upstream() {
+        let upstream = parse_upstream("app", "file+tcp:///etc/fourth/backends.yaml").unwrap();
+        let backends = upstream.backends.as_ref().unwrap();
+        assert_eq!(
+            backends.source,
+            Source::File("/etc/fourth/backends.yaml".into())
+        );
+        assert_eq!(backends.interval, discovery::FILE_INTERVAL);
+        assert_eq!(upstream.addr, "/etc/fourth/backends.yaml");
+        assert!(upstream.target().is_err());
+
+        let upstream = parse_upstream(
+            "app",
+            "http+tcp://discovery.internal:8080/v1/backends?service=app&interval=10&proxy_protocol=v2&family=ipv4",
+        )
+        .unwrap();
+        let backends = upstream.backends.as_ref().unwrap();
+        let endpoint = "http://discovery.internal:8080/v1/backends?service=app";
+        assert_eq!(backends.source, Source::Http(endpoint.parse().unwrap()));
+        assert_eq!(backends.interval, Duration::from_secs(10));
+        assert_eq!(upstream.protocol, UpstreamProtocol::Tcp);
+        assert!(upstream.proxy_protocol);
+        assert_eq!(upstream.connect.family, Some(Family::Ipv4));
+
+        assert!(parse_upstream("app", "file+tcp://host/backends.yaml").is_err());
+        assert!(parse_upstream("app", "http+tcp:///backends").is_err());
+        assert!(parse_upstream("app", "http+tcp://discovery.internal/?interval=0").is_err());
+    }
+
+    #[test]
+    fn test_schema_file() {
+        let file = std::fs::read_to_string("config.schema.json").unwrap();
+        assert_eq!(file.trim_end(), schema());
+    }
+
+    #[test]
+    fn test_admin_config() {
+        let admin = AdminConfig {
+            listen: "127.0.0.1:9090".to_string(),
+            token: Some("secret".into()),
+        };
+        assert!(admin.build().is_ok());
+        let admin = AdminConfig {
+            listen: "0.0.0.0:9090".to_string(),
+            token: Some("secret".into()),
+        };
+        assert!(admin.build().is_err());
+        let admin = AdminConfig {
+            listen: "[::1]:9090".to_string(),
+            token: None,
+        };
+        assert!(admin.build().is_err());
+        let admin = AdminConfig {
+            listen: "unix:///run/fourth/admin.sock".to_string(),
+            token: None,
+        };
+        assert!(admin.build().is_ok());
+    }
+
+    #[test]
+    fn test_kcp_upstream_options() {
+        let url = Url::parse("kcp://127.0.0.1:54959?keepalive=0&nodelay=fastest").unwrap();
+        let kcp_config = parse_kcp_options(&url).unwrap();
+        assert!(kcp_config.keepalive.is_none());
+        assert!(kcp_config.nodelay.nodelay);
+
+        let url = Url::parse("kcp://127.0.0.1:54959?cookie=true").unwrap();
+        assert!(parse_kcp_options(&url).unwrap().cookie);
+
+        let url = Url::parse("kcp://127.0.0.1:54959?keeplive=10").unwrap();
+        assert!(parse_kcp_options(&url).is_err());
+
+        let upstream = parse_upstream("tunnel", "kcp://127.0.0.1:54959?mux=true&max_streams=8");
+        let upstream = upstream.unwrap();
+        assert!(upstream.kcp.unwrap().mux);
+        assert_eq!(upstream.kcp.unwrap().max_streams, 8);
+        assert!(upstream.pool.is_some());
+        assert!(parse_upstream("tunnel", "kcp://127.0.0.1:54959")
+            .unwrap()
+            .pool
+            .is_none());
+        assert!(parse_upstream("tunnel", "kcp://127.0.0.1:54959?max_streams=0").is_err());
+    }
+
+    #[test]
+    fn test_kcp_server_config() {
+        let kcp = KcpServerConfig {
+            shards: Some(4),
+            ..Default::default()
+        };
+        assert_eq!(kcp.build().unwrap().shards, 4);
+        let kcp = KcpServerConfig {
+            migration: Some(KcpMigration::SameIp),
+            ..Default::default()
+        };
+        assert_eq!(kcp.build().unwrap().migration, KcpMigration::SameIp);
+        for migration in [KcpMigration::SameIp, KcpMigration::Any] {
+            let kcp = KcpServerConfig {
+                migration: Some(migration),
+                shards: Some(2),
+                ..Default::default()
+            };
+            assert!(kcp.build().is_err());
+        }
+        let kcp = KcpServerConfig {
+            mode: Some(KcpMode::Message),
+            mux: Some(true),
+            ..Default::default()
+        };
+        assert!(kcp.build().is_err());
+        let kcp: KcpServerConfig =
+            serde_json::from_str(r#"{"migration": "same_ip", "mode": "message"}"#).unwrap();
+        assert_eq!(kcp.migration, Some(KcpMigration::SameIp));
+        assert_eq!(kcp.mode, Some(KcpMode::Message));
+        assert!(serde_json::from_str::<KcpServerConfig>(r#"{"mode": "strem"}"#).is_err());
+    }
+
+    #[test]
+    fn test_acme_config() {
+        let sni = vec!["www.example.com".to_string()];
+        let acme = AcmeServerConfig::default().build(Some(&sni)).unwrap();
+        assert_eq!(acme.domains, sni);
+        assert_eq!(acme.challenge, AcmeChallenge::TlsAlpn01);
+        assert!(AcmeServerConfig::default().build(None).is_err());
+
+        let acme = AcmeServerConfig {
+            challenge: Some(AcmeChallenge::Http01),
+            ..Default::default()
+        };
+        assert!(acme.build(Some(&sni)).is_err());
+        let acme = AcmeServerConfig {
+            http_listen: Some("0.0.0.0:80".to_string()),
+            ..acme
+        };
+        assert!(acme.build(Some(&sni)).is_ok());
+
+        // Checks leave the ACME storage alone
+        let terminate = TerminateConfig {
+            sni: Some(sni),
+            acme: Some(AcmeServerConfig {
+                storage: Some("tests/acme-storage".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        assert!(terminate.check().is_ok());
+        assert!(!Path::new("tests/acme-storage").exists());
+        let terminate = TerminateConfig {
+            acme: None,
+            ..terminate
+        };
+        assert!(terminate.check().is_err());
+    }
+
+    #[test]
+    fn test_tls_upstream_options() {
+        let url =
+            Url::parse("tls://127.0.0.1:54961?sni=tls.test.com&ca=tests/certs/ca.pem").unwrap();
+        assert!(parse_tls_options(&url, "127.0.0.1").is_ok());
+
+        let url = Url::parse("tls://127.0.0.1:54961?verify=none").unwrap();
+        assert!(parse_tls_options(&url, "127.0.0.1").is_ok());
+
+        let url = Url::parse("tls://127.0.0.1:54961?verify=partial").unwrap();
+        assert!(parse_tls_options(&url, "127.0.0.1").is_err());
+
+        let url = Url::parse("tls://127.0.0.1:54961?cert=tests/certs/tls.pem").unwrap();
+        assert!(parse_tls_options(&url, "127.0.0.1").is_err());
+
+        let url = Url::parse("tls://127.0.0.1:54961?ca=tests/certs/missing.pem").unwrap();
+        assert!(parse_tls_options(&url, "127.0.0.1").is_err());
     }
 }
@@ -0,0 +1,310 @@
+//! `${VAR}` and `${VAR:-default}` references to environment variables and
+//! `file:` references to the secret files of passwords and tokens
+
+use super::validate::{index, key};
+use super::{BaseConfig, Diagnostic};
+use serde_yaml::Value;
+use std::env;
+use std::fs;
+
+/// Replace the references in the strings of `value`, returning whether any
+/// was found. Unset variables and unreadable files are returned at their
+/// path.
+pub fn interpolate(value: &mut Value) -> Result<bool, Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    let schema = serde_json::to_value(schemars::schema_for!(BaseConfig)).unwrap();
+    let found = walk(value, "", &mut Vec::new(), &schema, &mut errors);
+    match errors.is_empty() {
+        true => Ok(found),
+        false => Err(errors),
+    }
+}
+
+/// `keys` are those of the mappings leading to `value`, `None` for the
+/// items of sequences
+fn walk(
+    value: &mut Value,
+    path: &str,
+    keys: &mut Vec<Option<String>>,
+    schema: &serde_json::Value,
+    errors: &mut Vec<Diagnostic>,
+) -> bool {
+    match value {
+        Value::Mapping(map) => {
+            let mut found = false;
+            for (name, value) in map.iter_mut() {
+                let path = match name.as_str() {
+                    Some(name) => key(path, name),
+                    None => path.to_string(),
+                };
+                keys.push(name.as_str().map(str::to_string));
+                found |= walk(value, &path, keys, schema, errors);
+                keys.pop();
+            }
+            found
+        }
+        Value::Sequence(values) => {
+            let mut found = false;
+            for (i, value) in values.iter_mut().enumerate() {
+                keys.push(None);
+                found |= walk(value, &index(path, i), keys, schema, errors);
+                keys.pop();
+            }
+            found
+        }
+        Value::String(string) => match resolve(string, is_secret(keys), typed(schema, keys)) {
+            Ok(Some(resolved)) => {
+                *value = resolved;
+                true
+            }
+            Ok(None) => false,
+            Err(message) => {
+                errors.push(Diagnostic {
+                    path: path.to_string(),
+                    message,
+                });
+                false
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Whether the value at `keys` is a password or token, the only values
+/// read from `file:` references
+fn is_secret(keys: &[Option<String>]) -> bool {
+    match keys {
+        [Some(admin), Some(token)] => admin == "admin" && token == "token",
+        [Some(servers), Some(_), Some(users), Some(_)] => servers == "servers" && users == "users",
+        _ => false,
+    }
+}
+
+/// Whether the value at `keys` is a number or bool in the config schema,
+/// which strings are not accepted for
+fn typed(schema: &serde_json::Value, keys: &[Option<String>]) -> bool {
+    let mut nodes = vec![schema];
+    for key in keys {
+        nodes = nodes
+            .into_iter()
+            .flat_map(|node| alternatives(schema, node))
+            .filter_map(|node| match key {
+                Some(key) => node["properties"]
+                    .get(key)
+                    .or_else(|| node.get("additionalProperties")),
+                None => node.get("items"),
+            })
+            .collect();
+    }
+    let types: Vec<&str> = nodes
+        .into_iter()
+        .flat_map(|node| alternatives(schema, node))
+        .flat_map(|node| match &node["type"] {
+            serde_json::Value::String(name) => vec![name.as_str()],
+            serde_json::Value::Array(names) => {
+                names.iter().filter_map(serde_json::Value::as_str).collect()
+            }
+            // Enums of strings
+            _ if node.get("enum").is_some() => vec!["string"],
+            _ => Vec::new(),
+        })
+        .collect();
+    !types.contains(&"string")
+        && types
+            .iter()
+            .any(|name| ["integer", "number", "boolean"].contains(name))
+}
+
+/// The schemas `node` stands for, following references and `anyOf`
+fn alternatives<'a>(
+    schema: &'a serde_json::Value,
+    node: &'a serde_json::Value,
+) -> Vec<&'a serde_json::Value> {
+    if let Some(name) = node["$ref"]
+        .as_str()
+        .and_then(|name| name.strip_prefix("#/definitions/"))
+    {
+        return alternatives(schema, &schema["definitions"][name]);
+    }
+    let nested = ["anyOf", "oneOf", "allOf"]
+        .iter()
+        .filter_map(|key| node[key].as_array())
+        .flatten()
+        .flat_map(|node| alternatives(schema, node));
+    std::iter::once(node).chain(nested).collect()
+}
+
+/// The value of `string` when it holds references. A single reference is
+/// parsed as a number or bool where the schema expects one, e.g.
+/// `shards: ${SHARDS}`.
+fn resolve(string: &str, secret: bool, typed: bool) -> Result<Option<Value>, String> {
+    if let Some(path) = string.strip_prefix("file:") {
+        // Other values name files themselves, like certificates and keys
+        if !secret {
+            return Err(format!(
+                "{} is only read for admin.token and users, use the path itself",
+                string
+            ));
+        }
+        let path = expand(path)?;
+        let secret =
+            fs::read_to_string(&path).map_err(|err| format!("Failed to read {}: {}", path, err))?;
+        let secret = secret.trim_end_matches(['\r', '\n']);
+        return Ok(Some(Value::String(secret.to_string())));
+    }
+    if !string.contains('$') {
+        return Ok(None);
+    }
+    let expanded = expand(string)?;
+    if expanded == string {
+        return Ok(None);
+    }
+    let single = string.starts_with("${") && string.find('}') == Some(string.len() - 1);
+    if single && typed {
+        if let Ok(value @ (Value::Number(_) | Value::Bool(_))) = serde_yaml::from_str(&expanded) {
+            return Ok(Some(value));
+        }
+    }
+    Ok(Some(Value::String(expanded)))
+}
+
+/// Expand `${VAR}` and `${VAR:-default}`, the default being used when the
+/// variable is unset or empty. `$$` is a literal `$`.
+fn expand(string: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(string.len());
+    let mut rest = string;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+            continue;
+        }
+        let reference = match rest.strip_prefix('{') {
+            Some(reference) => reference,
+            None => {
+                expanded.push('$');
+                continue;
+            }
+        };
+        let end = reference
+            .find('}')
+            .ok_or_else(|| format!("Unclosed ${{ in {}", string))?;
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(format!("Invalid environment variable name {:?}", name));
+        }
+        let value = env::var(name)
+            .ok()
+            .filter(|value| !value.is_empty() || default.is_none());
+        match (value, default) {
+            (Some(value), _) => expanded.push_str(&value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => return Err(format!("Environment variable {} is not set", name)),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interpolate() {
+        env::set_var("FOURTH_TEST_HOST", "10.0.0.2");
+        env::set_var("FOURTH_TEST_SHARDS", "4");
+        env::set_var("FOURTH_TEST_EMPTY", "");
+        env::remove_var("FOURTH_TEST_UNSET");
+        let yaml = r#"
+servers:
+  web:
+    listen: ["${FOURTH_TEST_HOST}:443", "$$HOME"]
+    kcp:
+      shards: ${FOURTH_TEST_SHARDS}
+    default: ${FOURTH_TEST_EMPTY:-ban}
+    users:
+      alice: file:tests/secrets/admin-token
+upstream:
+  web: "tcp://${FOURTH_TEST_UNSET:-127.0.0.1}:8080"
+"#;
+        let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(interpolate(&mut value), Ok(true));
+        let web = &value["servers"]["web"];
+        assert_eq!(web["listen"][0].as_str(), Some("10.0.0.2:443"));
+        assert_eq!(web["listen"][1].as_str(), Some("$HOME"));
+        assert_eq!(web["kcp"]["shards"].as_u64(), Some(4));
+        assert_eq!(web["default"].as_str(), Some("ban"));
+        assert_eq!(web["users"]["alice"].as_str(), Some("test-token"));
+        assert_eq!(
+            value["upstream"]["web"].as_str(),
+            Some("tcp://127.0.0.1:8080")
+        );
+
+        // Strings are kept where the schema expects them
+        env::set_var("FOURTH_TEST_TOKEN", "123456");
+        env::set_var("FOURTH_TEST_TRUE", "true");
+        env::set_var("FOURTH_TEST_MODE", "0660");
+        let yaml = r#"
+version: 2
+admin:
+  listen: "127.0.0.1:8000"
+  token: ${FOURTH_TEST_TOKEN}
+servers:
+  proxy:
+    protocol: socks5
+    listen: ["127.0.0.1:1080", "unix:///run/fourth.sock"]
+    users:
+      alice: ${FOURTH_TEST_TRUE}
+    unix:
+      mode: ${FOURTH_TEST_MODE}
+    kcp:
+      cookie: ${FOURTH_TEST_TRUE}
+      shards: ${FOURTH_TEST_TOKEN}
+"#;
+        let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(interpolate(&mut value), Ok(true));
+        let proxy = &value["servers"]["proxy"];
+        assert_eq!(value["admin"]["token"].as_str(), Some("123456"));
+        assert_eq!(proxy["users"]["alice"].as_str(), Some("true"));
+        assert_eq!(proxy["unix"]["mode"].as_str(), Some("0660"));
+        assert_eq!(proxy["kcp"]["cookie"].as_bool(), Some(true));
+        assert_eq!(proxy["kcp"]["shards"].as_u64(), Some(123456));
+        let config: BaseConfig = serde_yaml::from_value(value).unwrap();
+        assert_eq!(config.admin.unwrap().token.unwrap().expose(), "123456");
+
+        let mut value: Value = serde_yaml::from_str("log: info").unwrap();
+        assert_eq!(interpolate(&mut value), Ok(false));
+
+        let yaml = r#"
+servers:
+  web:
+    listen: ["${FOURTH_TEST_UNSET}", "${bad name}"]
+    users:
+      alice: file:missing
+    terminate:
+      certs:
+        - cert: tests/certs/tls.pem
+          key: file:tests/certs/tls.key
+"#;
+        let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+        let errors = interpolate(&mut value).unwrap_err();
+        let paths: Vec<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "servers.web.listen[0]",
+                "servers.web.listen[1]",
+                "servers.web.users.alice",
+                "servers.web.terminate.certs[0].key"
+            ]
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Checks of a config file, reporting every problem with its YAML path,
+//! e.g. `servers.example_server.listen[1]`
+
+use super::{
+    parse_upstream, BaseConfig, Protocol, Routing, ServerConfig, SniRoute, Transport,
+    UpstreamProtocol, CONFIG_VERSION,
+};
+use crate::servers::Listen;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+const BUILTIN_UPSTREAMS: [&str; 2] = ["ban", "echo"];
+
+/// A problem found at `path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Errors prevent loading the config, warnings point at options that have
+/// no effect
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub errors: Vec<Diagnostic>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, path: &str, message: impl ToString) {
+        self.errors.push(Diagnostic {
+            path: path.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    fn warning(&mut self, path: &str, message: impl ToString) {
+        self.warnings.push(Diagnostic {
+            path: path.to_string(),
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Path of the entry `key` of the map at `path`, quoted unless it is a
+/// plain name
+pub(super) fn key(path: &str, key: &str) -> String {
+    let plain = !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    match plain {
+        true if path.is_empty() => key.to_string(),
+        true => format!("{}.{}", path, key),
+        false => format!("{}[{:?}]", path, key),
+    }
+}
+
+pub(super) fn index(path: &str, index: usize) -> String {
+    format!("{}[{}]", path, index)
+}
+
+fn sorted<V>(map: &HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(key, _)| *key);
+    entries
+}
+
+/// What servers need to know of the upstreams and of each other
+#[derive(Default)]
+struct Context {
+    /// Protocol of each upstream, `None` for the built-in ones and invalid URLs
+    upstreams: HashMap<String, Option<UpstreamProtocol>>,
+    used: HashSet<String>,
+    /// Path of each listen address
+    listen: HashMap<String, String>,
+}
+
+pub fn validate(config: &BaseConfig) -> Report {
+    let mut report = Report::default();
+    let mut context = Context::default();
+
+    if config.version != CONFIG_VERSION {
+        report.error(
+            "version",
+            format!("Unsupported config version {}", config.version),
+        );
+    }
+
+    for name in BUILTIN_UPSTREAMS {
+        context.upstreams.insert(name.to_string(), None);
+    }
+    for (name, url) in sorted(&config.upstream) {
+        let path = key("upstream", name);
+        if BUILTIN_UPSTREAMS.contains(&name.as_str()) {
+            report.error(&path, format!("{} is a built-in upstream", name));
+            continue;
+        }
+        let protocol = match parse_upstream(name, url) {
+            Ok(upstream) => Some(upstream.protocol),
+            Err(err) => {
+                report.error(&path, err);
+                None
+            }
+        };
+        context.upstreams.insert(name.clone(), protocol);
+    }
+
+    for (name, server) in sorted(&config.servers) {
+        validate_server(&mut report, &mut context, name, server);
+    }
+
+    for (name, _) in sorted(&config.upstream) {
+        if !context.used.contains(name) && !BUILTIN_UPSTREAMS.contains(&name.as_str()) {
+            report.warning(&key("upstream", name), "Not used by any server");
+        }
+    }
+
+    if let Some(resolver) = &config.resolver {
+        if let Err(err) = resolver.build() {
+            report.error("resolver", err);
+        }
+    }
+    if let Some(admin) = &config.admin {
+        if let Err(err) = admin.build() {
+            report.error("admin", err);
+        }
+    }
+
+    report
+}
+
+fn validate_server(report: &mut Report, context: &mut Context, name: &str, server: &ServerConfig) {
+    let path = key("servers", name);
+    let at = |option: &str| format!("{}.{}", path, option);
+
+    let protocol = server.protocol.unwrap_or_default();
+    // Servers relaying to upstreams chosen by SNI, and proxies relaying to
+    // destinations chosen by clients
+    let routed = matches!(protocol, Protocol::Tcp | Protocol::Websocket);
+    let proxy = matches!(protocol, Protocol::Socks5 | Protocol::Http);
+    let tls = server.routing == Some(Routing::Sni);
+
+    if server.listen.is_empty() {
+        report.error(&at("listen"), "No listen address");
+    }
+    for (i, listen) in server.listen.iter().enumerate() {
+        let listen_path = index(&at("listen"), i);
+        let addr: Listen = match listen.parse() {
+            Ok(addr) => addr,
+            Err(err) => {
+                report.error(&listen_path, err);
+                continue;
+            }
+        };
+        if matches!(addr, Listen::Unix(..)) && !routed {
+            report.error(
+                &listen_path,
+                "Only tcp and websocket servers listen on Unix sockets",
+            );
+        }
+        match context.listen.get(&addr.to_string()) {
+            Some(other) => report.error(
+                &listen_path,
+                format!("Duplicate listen address {}, also at {}", listen, other),
+            ),
+            None => {
+                context.listen.insert(addr.to_string(), listen_path);
+            }
+        }
+    }
+
+    if server.transport.is_some() && !proxy {
+        report.warning(&at("transport"), format!("Ignored by {} servers", protocol));
+    }
+
+    // Datagram upstreams need message boundaries from KCP message mode
+    let mut message_mode = false;
+    if let Some(kcp) = &server.kcp {
+        match kcp.build() {
+            Ok(kcp_config) => message_mode = protocol == Protocol::Kcp && !kcp_config.stream,
+            Err(err) => report.error(&at("kcp"), err),
+        }
+        if protocol != Protocol::Kcp && server.transport != Some(Transport::Kcp) {
+            report.warning(&at("kcp"), "Ignored without a KCP listener");
+        }
+    }
+
+    if !routed {
+        for (option, set) in [
+            ("routing", server.routing.is_some()),
+            ("sni", server.sni.is_some()),
+            ("terminate", server.terminate.is_some()),
+            ("ech", server.ech.is_some()),
+        ] {
+            if set {
+                report.warning(&at(option), format!("Ignored by {} servers", protocol));
+            }
+        }
+    } else {
+        if server.sni.is_some() && !tls {
+            report.warning(&at("sni"), "Ignored without routing: sni");
+        }
+        if server.terminate.is_some() && !tls {
+            report.error(&at("terminate"), "TLS termination needs routing: sni");
+        }
+        if server.ech.is_some() && !tls {
+            report.error(&at("ech"), "ECH decryption needs routing: sni");
+        }
+        // Upgrades are read after TLS termination
+        if protocol == Protocol::Websocket && tls && server.terminate.is_none() {
+            report.error(
+                &at("routing"),
+                "WebSocket servers with routing: sni need terminate",
+            );
+        }
+    }
+    if let Some(terminate) = &server.terminate {
+        if let Err(err) = terminate.check() {
+            report.error(&at("terminate"), err);
+        }
+    }
+    if let Some(ech) = &server.ech {
+        if let Err(err) = ech.build() {
+            report.error(&at("ech"), err);
+        }
+    }
+
+    if let Some(unix) = &server.unix {
+        if let Err(err) = unix.build() {
+            report.error(&at("unix"), err);
+        }
+        if !server
+            .listen
+            .iter()
+            .any(|listen| listen.starts_with("unix://"))
+        {
+            report.warning(&at("unix"), "Ignored without a unix:// listen address");
+        }
+    }
+    if server.websocket.is_some() && protocol != Protocol::Websocket {
+        report.warning(&at("websocket"), format!("Ignored by {} servers", protocol));
+    }
+    if let Some(acl) = &server.acl {
+        if let Err(err) = acl.build() {
+            report.error(&at("acl"), err);
+        }
+    }
+    for (option, set) in [
+        ("users", server.users.is_some()),
+        ("acl", server.acl.is_some()),
+    ] {
+        if set && !proxy {
+            report.warning(&at(option), format!("Ignored by {} servers", protocol));
+        }
+    }
+
+    if proxy {
+        if server.default.is_some() {
+            report.warning(
+                &at("default"),
+                "Ignored, clients of proxy servers choose their destination",
+            );
+        }
+        return;
+    }
+
+    // Upstreams referenced by the server, with their paths
+    let mut references: Vec<(String, &str)> = Vec::new();
+    if let Some(default) = &server.default {
+        references.push((at("default"), default));
+    }
+    let sni = server.sni.as_ref().filter(|_| routed && tls);
+    for (host, route) in sni.map(sorted).unwrap_or_default() {
+        let route_path = key(&at("sni"), host);
+        match route {
+            SniRoute::Upstream(upstream) => references.push((route_path, upstream)),
+            SniRoute::Rules(rules) => {
+                // A rule without condition matches every ClientHello
+                let mut matched: HashSet<Option<bool>> = HashSet::new();
+                for (i, rule) in rules.iter().enumerate() {
+                    let rule_path = index(&route_path, i);
+                    if matched.contains(&None) || matched.contains(&rule.ech) {
+                        report.warning(&rule_path, "Unreachable, an earlier rule matches");
+                    }
+                    matched.insert(rule.ech);
+                    if matched.contains(&Some(true)) && matched.contains(&Some(false)) {
+                        matched.insert(None);
+                    }
+                    references.push((format!("{}.upstream", rule_path), &rule.upstream));
+                }
+            }
+        }
+    }
+    if let Some(terminate) = server.terminate.as_ref().filter(|_| routed && tls) {
+        let auth_path = format!("{}.client_auth", at("terminate"));
+        for (route, auth) in terminate
+            .client_auth
+            .as_ref()
+            .map(sorted)
+            .unwrap_or_default()
+        {
+            let route_path = key(&auth_path, route);
+            if let Some(sni) = &terminate.sni {
+                if route != "*" && !sni.contains(route) {
+                    report.error(&route_path, format!("{} is not terminated", route));
+                }
+            }
+            for (identity, upstream) in auth.identity.as_ref().map(sorted).unwrap_or_default() {
+                references.push((key(&format!("{}.identity", route_path), identity), upstream));
+            }
+        }
+    }
+
+    for (reference, upstream) in references {
+        match context.upstreams.get(upstream) {
+            None => report.error(&reference, format!("Unknown upstream {}", upstream)),
+            Some(Some(UpstreamProtocol::Udp)) if !message_mode => report.error(
+                &reference,
+                format!(
+                    "udp upstream {} needs a kcp server in message mode",
+                    upstream
+                ),
+            ),
+            Some(_) => {}
+        }
+        context.used.insert(upstream.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SniRule;
+
+    fn server(listen: &str) -> ServerConfig {
+        ServerConfig {
+            listen: vec![listen.to_string()],
+            ..Default::default()
+        }
+    }
+
+    fn paths(diagnostics: &[Diagnostic]) -> Vec<&str> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = BaseConfig {
+            version: CONFIG_VERSION,
+            ..Default::default()
+        };
+        config
+            .upstream
+            .insert("web".to_string(), "tcp://127.0.0.1:8080".to_string());
+        config
+            .upstream
+            .insert("dns".to_string(), "udp://127.0.0.1:53".to_string());
+        config
+            .upstream
+            .insert("broken".to_string(), "ftp://127.0.0.1".to_string());
+        config
+            .upstream
+            .insert("unused".to_string(), "tcp://127.0.0.1:8081".to_string());
+
+        let mut tls_server = ServerConfig {
+            listen: vec!["127.0.0.1:443".to_string(), "127.0.0.1:8443".to_string()],
+            routing: Some(Routing::Sni),
+            default: Some("broken".to_string()),
+            ..Default::default()
+        };
+        let mut sni = HashMap::new();
+        sni.insert(
+            "www.example.com".to_string(),
+            SniRoute::Upstream("dns".to_string()),
+        );
+        sni.insert(
+            "app.example.com".to_string(),
+            SniRoute::Rules(vec![
+                SniRule {
+                    ech: None,
+                    upstream: "web".to_string(),
+                },
+                SniRule {
+                    ech: Some(true),
+                    upstream: "missing".to_string(),
+                },
+            ]),
+        );
+        tls_server.sni = Some(sni);
+        config.servers.insert("tls_server".to_string(), tls_server);
+
+        let mut plain = server("127.0.0.1:8443");
+        plain.sni = Some(HashMap::new());
+        plain.listen.push("localhost:80".to_string());
+        config.servers.insert("plain".to_string(), plain);
+
+        let mut socks = server("unix:///run/socks.sock");
+        socks.protocol = Some(Protocol::Socks5);
+        socks.default = Some("web".to_string());
+        config.servers.insert("socks".to_string(), socks);
+
+        let report = validate(&config);
+        assert_eq!(
+            paths(&report.errors),
+            vec![
+                "upstream.broken",
+                "servers.plain.listen[1]",
+                "servers.socks.listen[0]",
+                "servers.tls_server.listen[1]",
+                "servers.tls_server.sni[\"app.example.com\"][1].upstream",
+                "servers.tls_server.sni[\"www.example.com\"]",
+            ]
+        );
+        assert_eq!(
+            report.errors[3].message,
+            "Duplicate listen address 127.0.0.1:8443, also at servers.plain.listen[0]"
+        );
+        assert_eq!(
+            paths(&report.warnings),
+            vec![
+                "servers.plain.sni",
+                "servers.socks.default",
+                "servers.tls_server.sni[\"app.example.com\"][1]",
+                "upstream.unused",
+            ]
+        );
+    }
+}
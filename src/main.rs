@@ -1,29 +1,123 @@
+mod cli;
 mod config;
 mod plugins;
 mod servers;
 
-use crate::config::Config;
+use crate::cli::{Args, Command, USAGE};
+use crate::config::{Config, ConfigError};
 use crate::servers::Server;
 
-use log::{debug, error};
-use std::env;
+use log::{debug, error, warn};
+use std::process::exit;
+
+/// Exit codes, from sysexits.h for usage and config errors
+const EXIT_RUNTIME: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+const EXIT_CONFIG: i32 = 78;
 
 fn main() {
-    let config_path =
-        env::var("FOURTH_CONFIG").unwrap_or_else(|_| "/etc/fourth/config.yaml".to_string());
+    let args = match Args::from_env() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            exit(EXIT_USAGE);
+        }
+    };
+
+    match args.command {
+        Command::Help => {
+            println!("{}", USAGE);
+            return;
+        }
+        Command::Version => {
+            println!("fourth {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Command::Schema => {
+            println!("{}", config::schema());
+            return;
+        }
+        Command::Migrate => {
+            match config::migrate(&args.config, args.overrides.format) {
+                Ok(yaml) => print!("{}", yaml),
+                Err(e) => {
+                    eprintln!("Could not migrate config {}: {}", args.config, e);
+                    exit(EXIT_CONFIG);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
 
-    let config = match Config::new(&config_path) {
+    let config = match Config::load(&args.config, &args.overrides) {
         Ok(config) => config,
+        Err(ConfigError::Invalid(report)) => {
+            eprintln!("Invalid config {}:", args.config);
+            for error in &report.errors {
+                eprintln!("  error: {}", error);
+            }
+            for warning in &report.warnings {
+                eprintln!("  warning: {}", warning);
+            }
+            exit(EXIT_CONFIG);
+        }
         Err(e) => {
-            println!("Could not load config: {:?}", e);
-            std::process::exit(1);
+            eprintln!("Could not load config {}: {}", args.config, e);
+            exit(EXIT_CONFIG);
         }
     };
-    debug!("{:?}", config);
+    // Interpolated values may hold secrets besides passwords and tokens
+    debug!(
+        "Loaded config {}: {} servers, {} upstreams",
+        args.config,
+        config.base.servers.len(),
+        config.file.upstream.len()
+    );
+
+    match args.command {
+        Command::Check => {
+            for warning in &config.warnings {
+                println!("  warning: {}", warning);
+            }
+            println!(
+                "Config {} is valid: {} servers, {} upstreams",
+                args.config,
+                config.base.servers.len(),
+                config.file.upstream.len()
+            );
+            return;
+        }
+        Command::PrintConfig => {
+            match config.normalized() {
+                Ok(yaml) => print!("{}", yaml),
+                Err(e) => {
+                    eprintln!("Could not print config: {}", e);
+                    exit(EXIT_CONFIG);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
 
-    let mut server = Server::new(config.base);
-    debug!("{:?}", server);
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+    let mut server = match Server::new(config.base) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Could not start servers of {}: {}", args.config, e);
+            exit(EXIT_CONFIG);
+        }
+    };
+    server.config_path = Some(args.config);
+    server.overrides = args.overrides;
 
-    let _ = server.run();
-    error!("Server ended with errors");
+    if let Err(e) = server.run() {
+        error!("Server ended with errors: {}", e);
+    } else {
+        error!("Server ended with errors");
+    }
+    exit(EXIT_RUNTIME);
 }
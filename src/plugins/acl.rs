@@ -0,0 +1,214 @@
+//! Destination access lists of proxy servers
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HostPattern {
+    Any,
+    Name(String),
+    /// `*.example.com`, subdomains only
+    Suffix(String),
+    Cidr(IpAddr, u8),
+}
+
+/// `host[:ports]`, where host is `*`, a name, `*.suffix`, an IP or a CIDR
+/// (IPv6 in brackets when ports are given) and ports `*`, a port or a range
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AclRule {
+    host: HostPattern,
+    ports: RangeInclusive<u16>,
+}
+
+impl FromStr for AclRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<AclRule, String> {
+        let invalid = || format!("Invalid ACL rule {}", rule);
+        let (host, ports) = match rule.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+                match rest {
+                    "" => (host, None),
+                    _ => (host, Some(rest.strip_prefix(':').ok_or_else(invalid)?)),
+                }
+            }
+            None => match rule.matches(':').count() {
+                1 => {
+                    let (host, ports) = rule.split_once(':').ok_or_else(invalid)?;
+                    (host, Some(ports))
+                }
+                _ => (rule, None),
+            },
+        };
+
+        let ports = match ports {
+            None | Some("*") => 0..=u16::MAX,
+            Some(ports) => match ports.split_once('-') {
+                Some((start, end)) => {
+                    let start = start.parse().map_err(|_| invalid())?;
+                    let end = end.parse().map_err(|_| invalid())?;
+                    start..=end
+                }
+                None => {
+                    let port = ports.parse().map_err(|_| invalid())?;
+                    port..=port
+                }
+            },
+        };
+
+        let host = if host == "*" {
+            HostPattern::Any
+        } else if let Some(suffix) = host.strip_prefix("*.") {
+            HostPattern::Suffix(format!(".{}", normalize(suffix)))
+        } else if let Some((ip, prefix)) = host.split_once('/') {
+            let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+            let max = if ip.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                return Err(invalid());
+            }
+            HostPattern::Cidr(ip, prefix)
+        } else if let Ok(ip) = host.parse::<IpAddr>() {
+            HostPattern::Cidr(ip, if ip.is_ipv4() { 32 } else { 128 })
+        } else if !host.is_empty() {
+            HostPattern::Name(normalize(host))
+        } else {
+            return Err(invalid());
+        };
+
+        Ok(AclRule { host, ports })
+    }
+}
+
+impl AclRule {
+    fn matches(&self, name: Option<&str>, addr: &SocketAddr) -> bool {
+        match &self.host {
+            HostPattern::Cidr(network, prefix) => {
+                self.ports.contains(&addr.port()) && in_network(addr.ip(), *network, *prefix)
+            }
+            _ => name.is_some_and(|name| self.matches_name(name, addr.port())),
+        }
+    }
+
+    fn matches_name(&self, name: &str, port: u16) -> bool {
+        if !self.ports.contains(&port) {
+            return false;
+        }
+        match &self.host {
+            HostPattern::Any => true,
+            HostPattern::Name(pattern) => normalize(name) == *pattern,
+            HostPattern::Suffix(suffix) => normalize(name).ends_with(suffix),
+            HostPattern::Cidr(..) => false,
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+        ip => ip,
+    };
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// Denied when a `deny` rule matches, else allowed when there are no `allow`
+/// rules or one matches
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<AclRule>,
+    deny: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(allow: Vec<AclRule>, deny: Vec<AclRule>) -> Acl {
+        Acl { allow, deny }
+    }
+
+    /// Check a destination `addr`, resolved from `name` when it was
+    /// requested by name. Names are matched by name patterns and the
+    /// address by IP patterns, so names resolving to denied networks are
+    /// denied too.
+    pub fn allows(&self, name: Option<&str>, addr: &SocketAddr) -> bool {
+        if self.deny.iter().any(|rule| rule.matches(name, addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(name, addr))
+    }
+
+    /// Whether a name is denied before resolving it
+    pub fn denies_name(&self, name: &str, port: u16) -> bool {
+        self.deny.iter().any(|rule| rule.matches_name(name, port))
+    }
+}
+
+/// Destination requested by a proxy client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Addr(SocketAddr),
+    Name(String, u16),
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Addr(addr) => write!(f, "{}", addr),
+            Destination::Name(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let rules = |rules: &[&str]| -> Vec<AclRule> {
+            rules.iter().map(|rule| rule.parse().unwrap()).collect()
+        };
+        let acl = Acl::new(
+            rules(&[
+                "*.internal.test.com:443",
+                "db.test.com:5432",
+                "10.0.0.0/8:1000-2000",
+            ]),
+            rules(&["10.0.0.1", "[::1]"]),
+        );
+        let addr = |addr: &str| addr.parse::<SocketAddr>().unwrap();
+
+        assert!(acl.allows(Some("web.internal.test.com"), &addr("192.0.2.1:443")));
+        assert!(acl.allows(Some("DB.test.com."), &addr("192.0.2.1:5432")));
+        assert!(!acl.allows(Some("internal.test.com"), &addr("192.0.2.1:443")));
+        assert!(!acl.allows(Some("db.test.com"), &addr("192.0.2.1:5433")));
+        assert!(acl.allows(None, &addr("10.1.2.3:1500")));
+        assert!(acl.allows(None, &addr("[::ffff:10.1.2.3]:1500")));
+        assert!(!acl.allows(None, &addr("10.1.2.3:2001")));
+        // Names resolving to a denied address are denied
+        assert!(!acl.allows(Some("db.test.com"), &addr("10.0.0.1:5432")));
+        assert!(!acl.allows(None, &addr("[::1]:1500")));
+        assert!(!acl.denies_name("db.test.com", 5432));
+        assert!(Acl::new(vec![], rules(&["*.test.com"])).denies_name("db.test.com", 5432));
+
+        assert!(Acl::default().allows(None, &addr("127.0.0.1:22")));
+        for rule in ["", "10.0.0.0/33", "host:port", "[::1", "host:1-x"] {
+            assert!(rule.parse::<AclRule>().is_err(), "{}", rule);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! What banned connections get before being closed
+
+use std::time::Duration;
+
+use log::debug;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time,
+};
+
+/// Pending input is read before closing so the peer gets a FIN, not a RST
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+const TLS_CONTENT_ALERT: u8 = 21;
+const TLS_ALERT_FATAL: u8 = 2;
+
+/// Alert sent to banned TLS clients
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsAlert {
+    #[default]
+    UnrecognizedName,
+    AccessDenied,
+    /// Close without alert
+    None,
+}
+
+impl TlsAlert {
+    fn description(self) -> Option<u8> {
+        match self {
+            TlsAlert::UnrecognizedName => Some(112),
+            TlsAlert::AccessDenied => Some(49),
+            TlsAlert::None => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    pub alert: TlsAlert,
+    /// Keep the connection open before closing it, to slow down scanners
+    pub tarpit: Option<Duration>,
+    /// Sent to clients not speaking TLS
+    pub response: Option<Vec<u8>>,
+}
+
+impl Default for BanPolicy {
+    fn default() -> BanPolicy {
+        BanPolicy {
+            alert: TlsAlert::UnrecognizedName,
+            tarpit: None,
+            response: None,
+        }
+    }
+}
+
+/// A fatal alert record, readable by clients before the handshake
+fn alert_record(description: u8) -> [u8; 7] {
+    [
+        TLS_CONTENT_ALERT,
+        0x03,
+        0x03,
+        0x00,
+        0x02,
+        TLS_ALERT_FATAL,
+        description,
+    ]
+}
+
+/// Answer a banned connection, `tls` tells whether a ClientHello was seen
+pub async fn ban<S>(mut inbound: S, policy: &BanPolicy, tls: bool) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let message = match tls {
+        true => policy
+            .alert
+            .description()
+            .map(|description| alert_record(description).to_vec()),
+        false => policy.response.clone(),
+    };
+    if let Some(message) = message {
+        inbound.write_all(&message).await?;
+        inbound.flush().await?;
+    }
+
+    let wait = policy.tarpit.unwrap_or(DRAIN_TIMEOUT);
+    let drained = time::timeout(wait, drain(&mut inbound)).await;
+    if let Ok(Ok(n)) = drained {
+        debug!("Banned connection closed by peer after {} bytes", n);
+    }
+
+    inbound.shutdown().await
+}
+
+/// Read and discard input until EOF
+async fn drain<S>(inbound: &mut S) -> io::Result<u64>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = [0u8; 1024];
+    let mut total = 0;
+    loop {
+        match inbound.read(&mut buf).await? {
+            0 => return Ok(total),
+            n => total += n as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_ban_alert() {
+        let (mut client, server) = io::duplex(1024);
+        client.write_all(b"ClientHello").await.unwrap();
+        client.shutdown().await.unwrap();
+        ban(server, &BanPolicy::default(), true).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, alert_record(112));
+
+        let policy = BanPolicy {
+            alert: TlsAlert::None,
+            tarpit: Some(Duration::from_millis(10)),
+            response: Some(b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec()),
+        };
+        let (mut client, server) = io::duplex(1024);
+        ban(server, &policy, false).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"HTTP/1.1 403 Forbidden\r\n\r\n");
+
+        let (mut client, server) = io::duplex(1024);
+        ban(server, &policy, true).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert!(buf.is_empty());
+    }
+}
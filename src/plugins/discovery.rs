@@ -0,0 +1,248 @@
+//! Backends of upstreams discovered at runtime, from a watched file or a
+//! polled HTTP endpoint returning a JSON or YAML list of `host:port`
+
+use crate::plugins::resolver::{ConnectOptions, Resolver, Target};
+use log::{info, warn};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time;
+use url::Url;
+
+/// Default polling interval of files
+pub const FILE_INTERVAL: Duration = Duration::from_secs(1);
+/// Default polling interval of HTTP endpoints
+pub const HTTP_INTERVAL: Duration = Duration::from_secs(5);
+/// Time allowed to read a backend list
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest HTTP response accepted
+const MAX_RESPONSE_LEN: u64 = 1024 * 1024;
+
+/// Where a backend list is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    File(PathBuf),
+    /// An `http://` URL
+    Http(Url),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Http(url) => write!(f, "{}", url),
+        }
+    }
+}
+
+/// The current backends of an upstream, taking turns
+#[derive(Debug)]
+pub struct Backends {
+    pub source: Source,
+    pub interval: Duration,
+    addrs: RwLock<Vec<String>>,
+    next: AtomicUsize,
+}
+
+impl Backends {
+    /// No backends until the source is first read by [`watch`]
+    pub fn new(source: Source, interval: Duration) -> Self {
+        Backends {
+            source,
+            interval,
+            addrs: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.addrs.read().unwrap().clone()
+    }
+
+    /// The backends starting with the one whose turn it is, the others to
+    /// fail over to. Empty when there are none.
+    pub fn turn(&self) -> Vec<Target> {
+        let addrs = self.addrs.read().unwrap();
+        if addrs.is_empty() {
+            return Vec::new();
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % addrs.len();
+        let (before, after) = addrs.split_at(next);
+        // Checked by `parse`
+        after
+            .iter()
+            .chain(before)
+            .filter_map(|addr| Target::host(addr).ok())
+            .collect()
+    }
+
+    /// Replace the backends, returning whether they changed
+    fn update(&self, addrs: Vec<String>) -> bool {
+        let mut current = self.addrs.write().unwrap();
+        if *current == addrs {
+            return false;
+        }
+        *current = addrs;
+        true
+    }
+}
+
+/// Read the source of the backends of upstream `name` every interval until
+/// they are dropped, files only when they changed since they were last
+/// read. The last backends are kept while it cannot be read.
+pub async fn watch(name: String, backends: Weak<Backends>, resolver: Arc<Resolver>) {
+    let mut failing = false;
+    let mut read = None;
+    loop {
+        let backends = match backends.upgrade() {
+            Some(backends) => backends,
+            None => return,
+        };
+        let version = match &backends.source {
+            Source::File(path) => file_version(path).await,
+            Source::Http(..) => None,
+        };
+        // Files are not read again until they are written
+        if version.is_none() || version != read {
+            let result = time::timeout(FETCH_TIMEOUT, fetch(&backends.source, &resolver)).await;
+            match result.unwrap_or_else(|elapsed| Err(elapsed.into())) {
+                Ok(addrs) => {
+                    failing = false;
+                    read = version;
+                    if backends.update(addrs.clone()) {
+                        info!("Backends of upstream {}: {:?}", name, addrs);
+                    }
+                }
+                // Only the first of consecutive failures is logged
+                Err(err) if !failing => {
+                    failing = true;
+                    warn!(
+                        "Failed to read backends of upstream {} from {}: {}",
+                        name, backends.source, err
+                    );
+                }
+                Err(_) => {}
+            }
+        }
+        let interval = backends.interval;
+        drop(backends);
+        time::sleep(interval).await;
+    }
+}
+
+/// Modification time and length of a file, which change when it is written
+async fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+async fn fetch(source: &Source, resolver: &Resolver) -> io::Result<Vec<String>> {
+    let text = match source {
+        Source::File(path) => tokio::fs::read_to_string(path).await?,
+        Source::Http(url) => get(url, resolver).await?,
+    };
+    parse(&text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+/// Parse a JSON or YAML list of `host:port`
+fn parse(text: &str) -> Result<Vec<String>, String> {
+    let addrs: Vec<String> = match text.trim() {
+        "" => Vec::new(),
+        text => serde_yaml::from_str(text).map_err(|err| err.to_string())?,
+    };
+    for addr in &addrs {
+        Target::host(addr)?;
+    }
+    Ok(addrs)
+}
+
+/// Body of a successful HTTP/1.0 GET of `url`
+async fn get(url: &Url, resolver: &Resolver) -> io::Result<String> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let target = Target::Host(
+        host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port,
+    );
+    let mut stream = resolver
+        .connect(&target, &ConnectOptions::default())
+        .await?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/json, application/yaml\r\n\r\n",
+        &url[url::Position::BeforePath..url::Position::AfterQuery],
+        &url[url::Position::BeforeHost..url::Position::AfterPort],
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut response = Vec::new();
+    stream
+        .take(MAX_RESPONSE_LEN + 1)
+        .read_to_end(&mut response)
+        .await?;
+    if response.len() as u64 > MAX_RESPONSE_LEN {
+        return Err(invalid("Response too long".to_string()));
+    }
+    let response = String::from_utf8(response).map_err(|_| invalid("Invalid UTF-8".to_string()))?;
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| invalid("Invalid HTTP response".to_string()))?;
+    let status = head.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some("200") => Ok(body.to_string()),
+        _ => Err(invalid(format!("Unexpected response {}", status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::resolver::ResolverOptions;
+    use crate::servers::testing;
+
+    #[test]
+    fn test_parse() {
+        let addrs = parse("[\"10.0.0.1:80\", \"[::1]:8080\"]").unwrap();
+        assert_eq!(addrs, vec!["10.0.0.1:80", "[::1]:8080"]);
+        let addrs = parse("- app-1.internal:80\n- app-2.internal:80\n").unwrap();
+        assert_eq!(addrs, vec!["app-1.internal:80", "app-2.internal:80"]);
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("[]").unwrap().is_empty());
+        assert!(parse("[\"10.0.0.1\"]").is_err());
+        assert!(parse("{\"backends\": []}").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("backends.json");
+        std::fs::write(&path, "[\"10.0.0.1:80\", \"10.0.0.2:80\"]").unwrap();
+        let interval = Duration::from_millis(10);
+        let backends = Arc::new(Backends::new(Source::File(path.clone()), interval));
+        let resolver = Arc::new(Resolver::new(&ResolverOptions::default()));
+        let watcher = tokio::spawn(watch(
+            "app".to_string(),
+            Arc::downgrade(&backends),
+            resolver,
+        ));
+        testing::wait_for(|| !backends.list().is_empty()).await;
+        let first = Target::host("10.0.0.1:80").unwrap();
+        let second = Target::host("10.0.0.2:80").unwrap();
+        assert_eq!(backends.turn(), vec![first.clone(), second.clone()]);
+        assert_eq!(backends.turn(), vec![second, first]);
+
+        std::fs::write(&path, "[]").unwrap();
+        testing::wait_for(|| backends.list().is_empty()).await;
+        assert!(backends.turn().is_empty());
+
+        drop(backends);
+        time::timeout(Duration::from_secs(5), watcher)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! Batched UDP receive and send, `recvmmsg`/`sendmmsg` on Linux and one
+//! packet per call elsewhere.
+
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// Max packets handled by one batch
+pub const BATCH_SIZE: usize = 32;
+
+/// A received packet, stored in the slot of the same index of the buffer
+#[derive(Debug, Clone, Copy)]
+pub struct RecvMeta {
+    pub len: usize,
+    pub addr: SocketAddr,
+    pub truncated: bool,
+}
+
+/// Receive up to `BATCH_SIZE` packets into `buf`, split in slots of `slot`
+/// bytes. Packets are copied out before the next call reuses the buffer.
+pub async fn recv_batch(
+    udp: &UdpSocket,
+    buf: &mut [u8],
+    slot: usize,
+    meta: &mut Vec<RecvMeta>,
+) -> io::Result<usize> {
+    meta.clear();
+
+    #[cfg(target_os = "linux")]
+    {
+        udp.async_io(tokio::io::Interest::READABLE, || {
+            linux::recvmmsg(udp, buf, slot, meta)
+        })
+        .await
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let (len, addr) = udp.recv_from(&mut buf[..slot]).await?;
+        meta.push(RecvMeta {
+            len,
+            addr,
+            truncated: false,
+        });
+        Ok(1)
+    }
+}
+
+/// Send as many packets as possible without blocking, returns the number of
+/// packets sent. Packets after that should be retried later.
+pub fn try_send_batch(udp: &UdpSocket, packets: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+    #[cfg(target_os = "linux")]
+    {
+        udp.try_io(tokio::io::Interest::WRITABLE, || {
+            linux::sendmmsg(udp, packets, addr)
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        for (sent, packet) in packets.iter().enumerate() {
+            match udp.try_send_to(packet, addr) {
+                Ok(..) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && sent > 0 => return Ok(sent),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(packets.len())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{io, mem, net::SocketAddr, os::unix::io::AsRawFd, ptr};
+
+    use socket2::SockAddr;
+    use tokio::net::UdpSocket;
+
+    use super::{RecvMeta, BATCH_SIZE};
+
+    pub fn recvmmsg(
+        udp: &UdpSocket,
+        buf: &mut [u8],
+        slot: usize,
+        meta: &mut Vec<RecvMeta>,
+    ) -> io::Result<usize> {
+        let count = (buf.len() / slot).min(BATCH_SIZE);
+
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+
+        for (i, chunk) in buf.chunks_mut(slot).take(count).enumerate() {
+            iovecs[i].iov_base = chunk.as_mut_ptr() as *mut libc::c_void;
+            iovecs[i].iov_len = chunk.len();
+            msgs[i].msg_hdr.msg_name = &mut addrs[i] as *mut _ as *mut libc::c_void;
+            msgs[i].msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+            msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+            msgs[i].msg_hdr.msg_iovlen = 1;
+        }
+
+        let n = unsafe {
+            libc::recvmmsg(
+                udp.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        for i in 0..n as usize {
+            let addr = unsafe { SockAddr::new(addrs[i], msgs[i].msg_hdr.msg_namelen) };
+            let addr = match addr.as_socket() {
+                Some(addr) => addr,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "recvmmsg returned a non IP address",
+                    ))
+                }
+            };
+            meta.push(RecvMeta {
+                len: msgs[i].msg_len as usize,
+                addr,
+                truncated: msgs[i].msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+            });
+        }
+
+        Ok(n as usize)
+    }
+
+    pub fn sendmmsg(udp: &UdpSocket, packets: &[&[u8]], addr: SocketAddr) -> io::Result<usize> {
+        let addr = SockAddr::from(addr);
+        let mut sent = 0;
+
+        while sent < packets.len() {
+            let batch = &packets[sent..packets.len().min(sent + BATCH_SIZE)];
+
+            let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+            let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+            for (i, packet) in batch.iter().enumerate() {
+                iovecs[i].iov_base = packet.as_ptr() as *mut libc::c_void;
+                iovecs[i].iov_len = packet.len();
+                msgs[i].msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+                msgs[i].msg_hdr.msg_namelen = addr.len();
+                msgs[i].msg_hdr.msg_iov = &mut iovecs[i];
+                msgs[i].msg_hdr.msg_iovlen = 1;
+            }
+
+            let n = unsafe {
+                libc::sendmmsg(
+                    udp.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    batch.len() as _,
+                    libc::MSG_DONTWAIT,
+                )
+            };
+            if n < 0 {
+                let err = io::Error::last_os_error();
+                if sent > 0 && err.kind() == io::ErrorKind::WouldBlock {
+                    break;
+                }
+                return Err(err);
+            }
+
+            sent += n as usize;
+            if (n as usize) < batch.len() {
+                break;
+            }
+        }
+
+        Ok(sent)
+    }
+}
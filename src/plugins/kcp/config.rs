@@ -1,6 +1,8 @@
 use std::{io::Write, time::Duration};
 
 use kcp::Kcp;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 /// Kcp Delay Config
 #[derive(Debug, Clone, Copy)]
@@ -59,6 +61,19 @@ impl KcpNoDelayConfig {
     }
 }
 
+/// Whether a session may move to another peer address
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KcpMigration {
+    /// Packets from any other address are dropped
+    #[default]
+    Disabled,
+    /// Allow the port to change, e.g. NAT rebinding
+    SameIp,
+    /// Allow any address
+    Any,
+}
+
 /// Kcp Config
 #[derive(Debug, Clone, Copy)]
 pub struct KcpConfig {
@@ -76,6 +91,22 @@ pub struct KcpConfig {
     pub flush_acks_input: bool,
     /// Stream mode
     pub stream: bool,
+    /// Probe the remote peer after this period of inactivity (client only)
+    pub keepalive: Option<Duration>,
+    /// Require a cookie handshake before a session is created
+    pub cookie: bool,
+    /// Max sessions of a listener
+    pub max_sessions: usize,
+    /// Max sessions of a listener from one IP address
+    pub max_sessions_per_peer: usize,
+    /// Session migration between peer addresses (server only)
+    pub migration: KcpMigration,
+    /// Number of `SO_REUSEPORT` sockets of a listener, each served by its own task
+    pub shards: usize,
+    /// Multiplex streams over sessions, see [`Mux`](super::Mux)
+    pub mux: bool,
+    /// Streams opened on one pooled session before another is connected (client only)
+    pub max_streams: usize,
 }
 
 impl Default for KcpConfig {
@@ -88,6 +119,14 @@ impl Default for KcpConfig {
             flush_write: false,
             flush_acks_input: false,
             stream: true,
+            keepalive: None,
+            cookie: false,
+            max_sessions: 4096,
+            max_sessions_per_peer: 256,
+            migration: KcpMigration::Disabled,
+            shards: 1,
+            mux: false,
+            max_streams: 64,
         }
     }
 }
@@ -0,0 +1,129 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    io,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use kcp::KcpResult;
+use log::trace;
+use tokio::{net::UdpSocket, time};
+
+/// Handshake packets share the 24 bytes KCP header layout, with conv 0 and a
+/// command KCP itself never uses.
+pub const HANDSHAKE_LEN: usize = 24;
+/// Client asking for a cookie
+pub const CMD_HELLO: u8 = 0xf0;
+/// Server answering with the cookie as conv
+pub const CMD_COOKIE: u8 = 0xf1;
+
+/// Cookies are valid for the current and the previous epoch
+const COOKIE_EPOCH_SECS: u64 = 60;
+const HANDSHAKE_RETRIES: u32 = 5;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn is_hello(packet: &[u8]) -> bool {
+    packet.len() == HANDSHAKE_LEN && kcp::get_conv(packet) == 0 && packet[4] == CMD_HELLO
+}
+
+fn handshake_packet(conv: u32, cmd: u8) -> [u8; HANDSHAKE_LEN] {
+    let mut packet = [0u8; HANDSHAKE_LEN];
+    packet[0..4].copy_from_slice(&conv.to_le_bytes());
+    packet[4] = cmd;
+    packet
+}
+
+/// Stateless cookie generator of a listener.
+///
+/// A cookie is a keyed hash of the peer address and the current epoch, it is
+/// handed out as the conv of the session so no state is kept before the peer
+/// proves it can receive packets at its address.
+pub struct CookieGenerator {
+    key: RandomState,
+}
+
+impl CookieGenerator {
+    pub fn new() -> CookieGenerator {
+        CookieGenerator {
+            key: RandomState::new(),
+        }
+    }
+
+    fn epoch() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("time went afterwards")
+            .as_secs()
+            / COOKIE_EPOCH_SECS
+    }
+
+    fn cookie_at(&self, peer_addr: SocketAddr, epoch: u64) -> u32 {
+        let mut hasher = self.key.build_hasher();
+        peer_addr.hash(&mut hasher);
+        epoch.hash(&mut hasher);
+        match hasher.finish() as u32 {
+            0 => 1,
+            cookie => cookie,
+        }
+    }
+
+    pub fn cookie(&self, peer_addr: SocketAddr) -> u32 {
+        self.cookie_at(peer_addr, CookieGenerator::epoch())
+    }
+
+    pub fn verify(&self, peer_addr: SocketAddr, conv: u32) -> bool {
+        let epoch = CookieGenerator::epoch();
+        conv == self.cookie_at(peer_addr, epoch) || conv == self.cookie_at(peer_addr, epoch - 1)
+    }
+
+    /// Answer a HELLO with a cookie packet of the same size
+    pub fn reply(&self, peer_addr: SocketAddr) -> [u8; HANDSHAKE_LEN] {
+        handshake_packet(self.cookie(peer_addr), CMD_COOKIE)
+    }
+}
+
+/// Ask the server for a cookie, returns the conv to be used by the session
+pub async fn handshake(udp: &UdpSocket, addr: SocketAddr) -> KcpResult<u32> {
+    let hello = handshake_packet(0, CMD_HELLO);
+    let mut buf = [0u8; 1500];
+
+    for attempt in 0..HANDSHAKE_RETRIES {
+        udp.send_to(&hello, addr).await?;
+        trace!("[HANDSHAKE] sent hello to {}, attempt {}", addr, attempt);
+
+        let deadline = time::Instant::now() + HANDSHAKE_TIMEOUT;
+        while let Ok(res) = time::timeout_at(deadline, udp.recv_from(&mut buf)).await {
+            let (n, peer_addr) = res?;
+            let packet = &buf[..n];
+            if peer_addr == addr && n == HANDSHAKE_LEN && packet[4] == CMD_COOKIE {
+                let conv = kcp::get_conv(packet);
+                if conv != 0 {
+                    trace!("[HANDSHAKE] got cookie conv={} from {}", conv, addr);
+                    return Ok(conv);
+                }
+            }
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "KCP handshake timed out").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cookie_bound_to_peer() {
+        let generator = CookieGenerator::new();
+        let peer: SocketAddr = "127.0.0.1:10000".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+
+        let reply = generator.reply(peer);
+        assert_eq!(reply[4], CMD_COOKIE);
+        let cookie = kcp::get_conv(&reply);
+        assert!(generator.verify(peer, cookie));
+        assert!(!generator.verify(other, cookie));
+        assert!(!CookieGenerator::new().verify(peer, cookie));
+    }
+}
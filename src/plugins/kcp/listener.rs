@@ -1,128 +1,487 @@
 use std::{
+    fmt,
     io::{self, ErrorKind},
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
     time::Duration,
 };
 
 use byte_string::ByteStr;
+use bytes::BytesMut;
 use kcp::{Error as KcpError, KcpResult};
 use log::{debug, error, trace};
+#[cfg(unix)]
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
-    net::{ToSocketAddrs, UdpSocket},
+    net::{lookup_host, ToSocketAddrs, UdpSocket},
     sync::mpsc,
     task::JoinHandle,
     time,
 };
 
-use crate::plugins::kcp::{config::KcpConfig, session::KcpSessionManager, stream::KcpStream};
+use crate::plugins::kcp::{
+    batch,
+    config::KcpConfig,
+    cookie::{self, CookieGenerator},
+    session::{KcpReject, KcpSessionManager, SessionCounts},
+    stream::KcpStream,
+};
+
+const KCP_HEADER_LEN: usize = 24;
+/// Receive slot of one packet, larger than any MTU in common use
+const MIN_SLOT_SIZE: usize = 2048;
+
+/// Counters of packets refused by a listener
+#[derive(Debug, Default)]
+pub struct KcpListenerStats {
+    /// Packets too short to carry a KCP header
+    pub rejected_malformed: AtomicU64,
+    /// Packets without a valid cookie while cookies are required
+    pub rejected_cookie: AtomicU64,
+    /// New sessions refused because the session table is full
+    pub rejected_table_full: AtomicU64,
+    /// New sessions refused because the peer reached its limit
+    pub rejected_peer_limit: AtomicU64,
+    /// Packets for a conv bound to another peer address
+    pub rejected_peer_mismatch: AtomicU64,
+    /// Packets dropped because the session input queue is full
+    pub dropped_input: AtomicU64,
+    /// Sessions moved to another peer address
+    pub migrated: AtomicU64,
+}
+
+impl KcpListenerStats {
+    fn reject(&self, reason: KcpReject) {
+        let counter = match reason {
+            KcpReject::TableFull => &self.rejected_table_full,
+            KcpReject::PeerLimit => &self.rejected_peer_limit,
+            KcpReject::PeerMismatch => &self.rejected_peer_mismatch,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl fmt::Display for KcpListenerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "malformed: {}, cookie: {}, table full: {}, peer limit: {}, peer mismatch: {}, input dropped: {}, migrated: {}",
+            self.rejected_malformed.load(Ordering::Relaxed),
+            self.rejected_cookie.load(Ordering::Relaxed),
+            self.rejected_table_full.load(Ordering::Relaxed),
+            self.rejected_peer_limit.load(Ordering::Relaxed),
+            self.rejected_peer_mismatch.load(Ordering::Relaxed),
+            self.dropped_input.load(Ordering::Relaxed),
+            self.migrated.load(Ordering::Relaxed),
+        )
+    }
+}
 
 #[allow(unused)]
 pub struct KcpListener {
     udp: Arc<UdpSocket>,
     accept_rx: mpsc::Receiver<(KcpStream, SocketAddr)>,
-    task_watcher: JoinHandle<()>,
+    stats: Arc<KcpListenerStats>,
+    counts: Arc<StdMutex<SessionCounts>>,
+    task_watchers: Vec<JoinHandle<()>>,
 }
 
 impl Drop for KcpListener {
     fn drop(&mut self) {
-        self.task_watcher.abort();
+        for task_watcher in &self.task_watchers {
+            task_watcher.abort();
+        }
     }
 }
 
+/// State shared by all shards of a listener
+struct Shared {
+    config: KcpConfig,
+    cookies: CookieGenerator,
+    counts: Arc<StdMutex<SessionCounts>>,
+    stats: Arc<KcpListenerStats>,
+    accept_tx: mpsc::Sender<(KcpStream, SocketAddr)>,
+}
+
 impl KcpListener {
     pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpListener> {
-        let udp = UdpSocket::bind(addr).await?;
-        let udp = Arc::new(udp);
-        let server_udp = udp.clone();
+        let addr = match lookup_host(addr).await?.next() {
+            Some(addr) => addr,
+            None => {
+                return Err(KcpError::IoError(io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    "no address to bind",
+                )))
+            }
+        };
 
+        let mut sockets = Vec::new();
+        if config.shards > 1 {
+            // Every shard owns a socket, the kernel keeps packets of one peer on the same socket
+            for _ in 0..config.shards {
+                sockets.push(Arc::new(bind_reuse_port(addr)?));
+            }
+        } else {
+            sockets.push(Arc::new(UdpSocket::bind(addr).await?));
+        }
+
+        let stats = Arc::new(KcpListenerStats::default());
+        let counts = Arc::new(StdMutex::new(SessionCounts::default()));
         let (accept_tx, accept_rx) = mpsc::channel(1024 /* backlogs */);
-        let task_watcher = tokio::spawn(async move {
-            let (close_tx, mut close_rx) = mpsc::channel(64);
-
-            let mut sessions = KcpSessionManager::new();
-            let mut packet_buffer = [0u8; 65536];
-            loop {
-                tokio::select! {
-                    conv = close_rx.recv() => {
-                        let conv = conv.expect("close_tx closed unexpectly");
-                        sessions.close_conv(conv);
-                        trace!("session conv: {} removed", conv);
+        let shared = Arc::new(Shared {
+            config,
+            cookies: CookieGenerator::new(),
+            counts: counts.clone(),
+            stats: stats.clone(),
+            accept_tx,
+        });
+
+        let task_watchers = sockets
+            .iter()
+            .map(|udp| tokio::spawn(run_shard(udp.clone(), shared.clone())))
+            .collect();
+
+        Ok(KcpListener {
+            udp: sockets[0].clone(),
+            accept_rx,
+            stats,
+            counts,
+            task_watchers,
+        })
+    }
+
+    pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
+        match self.accept_rx.recv().await {
+            Some(s) => Ok(s),
+            None => Err(KcpError::IoError(io::Error::other(
+                "accept channel closed unexpectly",
+            ))),
+        }
+    }
+
+    pub fn stats(&self) -> Arc<KcpListenerStats> {
+        self.stats.clone()
+    }
+
+    /// Live session counts, in total and by peer IP
+    pub fn session_counts(&self) -> Arc<StdMutex<SessionCounts>> {
+        self.counts.clone()
+    }
+
+    #[allow(unused)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+#[cfg(unix)]
+fn bind_reuse_port(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuse_port(_addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "KCP shards require SO_REUSEPORT",
+    ))
+}
+
+async fn run_shard(udp: Arc<UdpSocket>, shared: Arc<Shared>) {
+    let config = &shared.config;
+    let stats = &shared.stats;
+    let (close_tx, mut close_rx) = mpsc::channel(64);
+
+    let mut sessions = KcpSessionManager::new(shared.counts.clone());
+    let slot = config.mtu.max(MIN_SLOT_SIZE);
+    let mut packet_buffer = vec![0u8; slot * batch::BATCH_SIZE];
+    let mut packet_meta = Vec::with_capacity(batch::BATCH_SIZE);
+    loop {
+        tokio::select! {
+            conv = close_rx.recv() => {
+                let conv = conv.expect("close_tx closed unexpectly");
+                sessions.close_conv(conv);
+                trace!("session conv: {} removed", conv);
+            }
+
+            recv_res = batch::recv_batch(&udp, &mut packet_buffer, slot, &mut packet_meta) => {
+                if let Err(err) = recv_res {
+                    error!("udp.recv_from failed, error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+
+                for (meta, data) in packet_meta.iter().zip(packet_buffer.chunks(slot)) {
+                    // Copied out so that the buffer is reused by the next batch
+                    let mut packet = BytesMut::from(&data[..meta.len.min(slot)]);
+                    let peer_addr = meta.addr;
+
+                    log::trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(&packet));
+
+                    if meta.truncated || packet.len() < KCP_HEADER_LEN {
+                        stats.rejected_malformed.fetch_add(1, Ordering::Relaxed);
+                        trace!("dropped {} bytes packet from peer: {}", packet.len(), peer_addr);
+                        continue;
                     }
 
-                    recv_res = udp.recv_from(&mut packet_buffer) => {
-                        match recv_res {
-                            Err(err) => {
-                                error!("udp.recv_from failed, error: {}", err);
-                                time::sleep(Duration::from_secs(1)).await;
+                    let mut conv = kcp::get_conv(&packet);
+
+                    let session = match sessions.get(config, conv, peer_addr).await {
+                        Ok(Some((s, migrated))) => {
+                            if migrated {
+                                stats.migrated.fetch_add(1, Ordering::Relaxed);
+                            }
+                            s
+                        }
+                        Ok(None) => {
+                            if config.cookie {
+                                if cookie::is_hello(&packet) {
+                                    // Stateless answer of the same size, no session yet
+                                    let _ = udp.try_send_to(&shared.cookies.reply(peer_addr), peer_addr);
+                                    continue;
+                                }
+                                if conv == 0 || !shared.cookies.verify(peer_addr, conv) {
+                                    stats.rejected_cookie.fetch_add(1, Ordering::Relaxed);
+                                    trace!("dropped packet without valid cookie, peer: {}, conv: {}", peer_addr, conv);
+                                    continue;
+                                }
                             }
-                            Ok((n, peer_addr)) => {
-                                let packet = &mut packet_buffer[..n];
 
-                                log::trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(packet));
+                            if let Err(reason) = sessions.admit(config, peer_addr) {
+                                stats.reject(reason);
+                                debug!("refused new session, peer: {}, conv: {}, reason: {:?}", peer_addr, conv, reason);
+                                continue;
+                            }
 
-                                let mut conv = kcp::get_conv(packet);
-                                if conv == 0 {
-                                    // Allocate a conv for client.
-                                    conv = sessions.alloc_conv();
-                                    debug!("allocate {} conv for peer: {}", conv, peer_addr);
+                            if conv == 0 {
+                                // Allocate a conv for client.
+                                conv = sessions.alloc_conv();
+                                debug!("allocate {} conv for peer: {}", conv, peer_addr);
 
-                                    kcp::set_conv(packet, conv);
-                                }
+                                kcp::set_conv(&mut packet, conv);
+                            }
+
+                            match sessions.create(config, conv, &udp, peer_addr, &close_tx) {
+                                Ok(s) => {
+                                    // Created a new session, constructed a new accepted client
+                                    let stream = KcpStream::with_session(s.clone());
+                                    if shared.accept_tx.try_send((stream, peer_addr)).is_err() {
+                                        debug!("failed to create accepted stream due to channel failure");
 
-                                let session = match sessions.get_or_create(&config, conv, &udp, peer_addr, &close_tx) {
-                                    Ok((s, created)) => {
-                                        if created {
-                                            // Created a new session, constructed a new accepted client
-                                            let stream = KcpStream::with_session(s.clone());
-                                            if let Err(..) = accept_tx.try_send((stream, peer_addr)) {
-                                                debug!("failed to create accepted stream due to channel failure");
-
-                                                // remove it from session
-                                                sessions.close_conv(conv);
-                                                continue;
-                                            }
-                                        }
-
-                                        s
-                                    },
-                                    Err(err) => {
-                                        error!("failed to create session, error: {}, peer: {}, conv: {}", err, peer_addr, conv);
+                                        // remove it from session
+                                        sessions.close_conv(conv);
                                         continue;
                                     }
-                                };
 
-                                // let mut kcp = session.kcp_socket().lock().await;
-                                // if let Err(err) = kcp.input(packet) {
-                                //     error!("kcp.input failed, peer: {}, conv: {}, error: {}, packet: {:?}", peer_addr, conv, err, ByteStr::new(packet));
-                                // }
-                                session.input(packet).await;
+                                    s
+                                }
+                                Err(err) => {
+                                    error!("failed to create session, error: {}, peer: {}, conv: {}", err, peer_addr, conv);
+                                    continue;
+                                }
                             }
                         }
+                        Err(reason) => {
+                            stats.reject(reason);
+                            trace!("dropped packet, peer: {}, conv: {}, reason: {:?}", peer_addr, conv, reason);
+                            continue;
+                        }
+                    };
+
+                    if !session.input(packet.freeze()) {
+                        stats.dropped_input.fetch_add(1, Ordering::Relaxed);
                     }
                 }
             }
-        });
+        }
+    }
+}
 
-        Ok(KcpListener {
-            udp: server_udp,
-            accept_rx,
-            task_watcher,
-        })
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::plugins::kcp::{KcpMigration, KcpNoDelayConfig};
+
+    const SESSIONS: usize = 128;
+
+    /// A KCP packet of `conv` telling the window size, valid and harmless
+    fn packet(conv: u32) -> [u8; KCP_HEADER_LEN] {
+        let mut packet = [0u8; KCP_HEADER_LEN];
+        packet[0..4].copy_from_slice(&conv.to_le_bytes());
+        packet[4] = 84; // IKCP_CMD_WINS
+        packet
     }
 
-    pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
-        match self.accept_rx.recv().await {
-            Some(s) => Ok(s),
-            None => Err(KcpError::IoError(io::Error::new(
-                ErrorKind::Other,
-                "accept channel closed unexpectly",
-            ))),
+    async fn peer(ip: &str) -> UdpSocket {
+        UdpSocket::bind((ip, 0)).await.unwrap()
+    }
+
+    /// Wait until the listener counted what is expected
+    async fn wait_for(check: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if check() {
+                return;
+            }
+            time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("listener did not count the packets in time");
     }
 
-    #[allow(unused)]
-    pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.udp.local_addr()
+    #[tokio::test]
+    async fn test_session_limits() {
+        let config = KcpConfig {
+            max_sessions: 2,
+            max_sessions_per_peer: 2,
+            ..Default::default()
+        };
+        let listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = listener.stats();
+        let counts = listener.session_counts();
+
+        // Two sessions of 127.0.0.1 reach its limit
+        let (a, b) = (peer("127.0.0.1").await, peer("127.0.0.1").await);
+        a.send_to(&packet(0), addr).await.unwrap();
+        b.send_to(&packet(0), addr).await.unwrap();
+        wait_for(|| counts.lock().unwrap().total() == 2).await;
+        let c = peer("127.0.0.1").await;
+        c.send_to(&packet(0), addr).await.unwrap();
+        wait_for(|| stats.rejected_table_full.load(Ordering::Relaxed) == 1).await;
+
+        let config = KcpConfig {
+            max_sessions_per_peer: 1,
+            ..Default::default()
+        };
+        let listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = listener.stats();
+        a.send_to(&packet(0), addr).await.unwrap();
+        b.send_to(&packet(0), addr).await.unwrap();
+        wait_for(|| stats.rejected_peer_limit.load(Ordering::Relaxed) == 1).await;
+        assert_eq!(listener.session_counts().lock().unwrap().total(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_peer_binding() {
+        for (migration, other_ip, migrated) in [
+            (KcpMigration::Disabled, "127.0.0.1", false),
+            (KcpMigration::SameIp, "127.0.0.1", true),
+            (KcpMigration::SameIp, "127.0.0.2", false),
+            (KcpMigration::Any, "127.0.0.2", true),
+        ] {
+            let config = KcpConfig {
+                migration,
+                ..Default::default()
+            };
+            let listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let stats = listener.stats();
+            let counts = listener.session_counts();
+
+            // The first conv allocated is 1
+            let owner = peer("127.0.0.1").await;
+            owner.send_to(&packet(0), addr).await.unwrap();
+            wait_for(|| counts.lock().unwrap().total() == 1).await;
+            let other = peer(other_ip).await;
+            other.send_to(&packet(1), addr).await.unwrap();
+            match migrated {
+                true => wait_for(|| stats.migrated.load(Ordering::Relaxed) == 1).await,
+                false => {
+                    wait_for(|| stats.rejected_peer_mismatch.load(Ordering::Relaxed) == 1).await
+                }
+            }
+            let peers = counts.lock().unwrap().peers().clone();
+            let expected = if migrated { other_ip } else { "127.0.0.1" };
+            assert_eq!(
+                peers.get(&expected.parse().unwrap()),
+                Some(&1),
+                "{:?}",
+                migration
+            );
+        }
+    }
+    const BYTES_PER_SESSION: usize = 64 * 1024;
+
+    /// Throughput of a listener echoing many sessions with one shard and one
+    /// shard per core, run with
+    /// `cargo test --release -- --ignored --nocapture bench_many_sessions`
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_many_sessions() {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let baseline = echo_sessions(1).await;
+        let sharded = echo_sessions(cores).await;
+        println!("{} shards vs 1 shard: {:.2}x", cores, sharded / baseline);
+    }
+
+    /// Echo `SESSIONS` sessions through a listener of `shards` shards,
+    /// returning the throughput in MB/s
+    async fn echo_sessions(shards: usize) -> f64 {
+        let client_config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let config = KcpConfig {
+            shards,
+            max_sessions_per_peer: SESSIONS,
+            ..client_config
+        };
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stats = listener.stats();
+
+        let server = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        let start = Instant::now();
+        let clients: Vec<_> = (0..SESSIONS)
+            .map(|_| {
+                tokio::spawn(async move {
+                    let stream = KcpStream::connect(&client_config, addr).await.unwrap();
+                    let (mut reader, mut writer) = tokio::io::split(stream);
+                    let sender = tokio::spawn(async move {
+                        let chunk = [0x5au8; 1024];
+                        for _ in 0..BYTES_PER_SESSION / chunk.len() {
+                            writer.write_all(&chunk).await.unwrap();
+                        }
+                        writer
+                    });
+                    let mut buf = vec![0u8; BYTES_PER_SESSION];
+                    reader.read_exact(&mut buf).await.unwrap();
+                    let _writer = sender.await.unwrap();
+                })
+            })
+            .collect();
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        let elapsed = start.elapsed();
+        server.abort();
+        let total = (SESSIONS * BYTES_PER_SESSION * 2) as f64;
+        let throughput = total / elapsed.as_secs_f64() / 1024.0 / 1024.0;
+        println!(
+            "{} sessions, {} shards: {:.2} MB/s in {:?}",
+            SESSIONS, shards, throughput, elapsed
+        );
+        println!("refused packets, {}", stats);
+        throughput
     }
 }
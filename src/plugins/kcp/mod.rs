@@ -1,14 +1,21 @@
 //! Library of KCP on Tokio
 
 pub use self::{
-    config::{KcpConfig, KcpNoDelayConfig},
-    listener::KcpListener,
+    config::{KcpConfig, KcpMigration, KcpNoDelayConfig},
+    listener::{KcpListener, KcpListenerStats},
+    mux::{KcpPool, Mux},
+    session::SessionCounts,
+    stats::KcpSessionStats,
     stream::KcpStream,
 };
 
+mod batch;
 mod config;
+mod cookie;
 mod listener;
+mod mux;
 mod session;
 mod skcp;
+mod stats;
 mod stream;
 mod utils;
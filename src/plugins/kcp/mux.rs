@@ -0,0 +1,480 @@
+//! Streams multiplexed over one KCP session, so that the connections of a
+//! `kcp://` upstream share a pool of sessions.
+//!
+//! Every frame is a 7 bytes header, the command, the stream id (u32) and the
+//! payload length (u16), followed by the payload. Clients open streams with
+//! odd ids.
+//!
+//! Each side of a stream sends at most `STREAM_WINDOW` bytes the other has
+//! not read yet, the reader granting them again with `UPD` frames carrying
+//! the number of bytes read (u32), so that a stream nobody reads does not
+//! hold back the others. A stream going past its window is reset.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::{Bytes, BytesMut};
+use log::{debug, trace};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::{mpsc, Notify, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::plugins::kcp::{KcpConfig, KcpStream};
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_RST: u8 = 3;
+const CMD_UPD: u8 = 4;
+const HEADER_LEN: usize = 7;
+/// Largest payload of a frame
+const MAX_PAYLOAD: usize = 16 * 1024;
+/// Frames and accepted streams queued before the session stops reading
+const STREAM_BACKLOG: usize = 64;
+/// Bytes a stream sends before the peer has read them
+const STREAM_WINDOW: usize = 256 * 1024;
+/// Bytes read before they are granted again to the peer
+const WINDOW_UPDATE: usize = STREAM_WINDOW / 4;
+
+struct Frame {
+    cmd: u8,
+    id: u32,
+    payload: Bytes,
+}
+
+/// What the session reader holds of a stream
+#[derive(Clone)]
+struct StreamHandle {
+    input: mpsc::UnboundedSender<Bytes>,
+    /// Bytes received and not yet read by the user
+    unread: Arc<AtomicUsize>,
+    /// Bytes the stream may still send
+    window: Arc<Semaphore>,
+    reset: Arc<Notify>,
+}
+
+type Streams = Arc<Mutex<HashMap<u32, StreamHandle>>>;
+
+/// Drop stream `id`, its user seeing the connection closed
+fn reset(streams: &Streams, id: u32) {
+    if let Some(stream) = streams.lock().unwrap().remove(&id) {
+        stream.reset.notify_one();
+    }
+}
+
+/// One KCP session carrying many streams
+pub struct Mux {
+    frames: mpsc::Sender<Frame>,
+    streams: Streams,
+    next_id: AtomicU32,
+    active: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for Mux {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Mux {
+    /// Open streams over `session`
+    pub fn client<S>(session: S) -> Mux
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        Mux::new(session, None)
+    }
+
+    /// Accept the streams opened by the client of `session`
+    pub fn server<S>(session: S) -> (Mux, mpsc::Receiver<DuplexStream>)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (accept_tx, accept_rx) = mpsc::channel(STREAM_BACKLOG);
+        (Mux::new(session, Some(accept_tx)), accept_rx)
+    }
+
+    fn new<S>(session: S, accept: Option<mpsc::Sender<DuplexStream>>) -> Mux
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut rd, mut wr) = tokio::io::split(session);
+        let (frames, mut frames_rx) = mpsc::channel::<Frame>(STREAM_BACKLOG);
+        let streams: Streams = Arc::default();
+        let active = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        // The writer stops once the mux and all of its streams are dropped
+        let writer = tokio::spawn(async move {
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + MAX_PAYLOAD);
+            while let Some(frame) = frames_rx.recv().await {
+                buf.clear();
+                buf.extend_from_slice(&[frame.cmd]);
+                buf.extend_from_slice(&frame.id.to_be_bytes());
+                buf.extend_from_slice(&(frame.payload.len() as u16).to_be_bytes());
+                buf.extend_from_slice(&frame.payload);
+                if wr.write_all(&buf).await.is_err() || wr.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let reader = {
+            let frames = frames.clone();
+            let streams = streams.clone();
+            let active = active.clone();
+            let closed = closed.clone();
+            tokio::spawn(async move {
+                let result = read_frames(&mut rd, &frames, &streams, &active, accept).await;
+                if let Err(err) = result {
+                    debug!("[MUX] session closed: {}", err);
+                }
+                closed.store(true, Ordering::Release);
+                // Streams see EOF and stop waiting for their window
+                for (_, stream) in streams.lock().unwrap().drain() {
+                    let _ = stream.input.send(Bytes::new());
+                    stream.window.close();
+                }
+                drop(frames);
+                let _ = writer.await;
+            })
+        };
+
+        Mux {
+            frames,
+            streams,
+            next_id: AtomicU32::new(1),
+            active,
+            closed,
+            reader,
+        }
+    }
+
+    /// Open a new stream to the server, registered once its SYN is queued
+    pub async fn open(&self) -> io::Result<DuplexStream> {
+        let closed = || io::Error::new(io::ErrorKind::NotConnected, "KCP session closed");
+        if self.is_closed() {
+            return Err(closed());
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        let syn = Frame {
+            cmd: CMD_SYN,
+            id,
+            payload: Bytes::new(),
+        };
+        self.frames.send(syn).await.map_err(|_| closed())?;
+        Ok(spawn_stream(id, &self.frames, &self.streams, &self.active))
+    }
+
+    /// Streams currently open
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+}
+
+async fn read_frames<R>(
+    rd: &mut R,
+    frames: &mpsc::Sender<Frame>,
+    streams: &Streams,
+    active: &Arc<AtomicUsize>,
+    accept: Option<mpsc::Sender<DuplexStream>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0u8; HEADER_LEN];
+    loop {
+        rd.read_exact(&mut header).await?;
+        let cmd = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u16::from_be_bytes([header[5], header[6]]) as usize;
+        let mut payload = BytesMut::zeroed(len);
+        rd.read_exact(&mut payload).await?;
+        trace!("[MUX] frame cmd: {}, stream: {}, {} bytes", cmd, id, len);
+
+        match cmd {
+            CMD_SYN => {
+                let accept = match &accept {
+                    Some(accept) => accept,
+                    None => continue,
+                };
+                let stream = spawn_stream(id, frames, streams, active);
+                if accept.send(stream).await.is_err() {
+                    return Ok(());
+                }
+            }
+            CMD_PSH => {
+                let stream = streams.lock().unwrap().get(&id).cloned();
+                let stream = match stream {
+                    Some(stream) => stream,
+                    None => continue,
+                };
+                if stream.unread.fetch_add(len, Ordering::AcqRel) + len > STREAM_WINDOW {
+                    debug!("[MUX] stream {} went past its window", id);
+                    reset(streams, id);
+                    let rst = Frame {
+                        cmd: CMD_RST,
+                        id,
+                        payload: Bytes::new(),
+                    };
+                    frames
+                        .send(rst)
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+                    continue;
+                }
+                let _ = stream.input.send(payload.freeze());
+            }
+            CMD_UPD => {
+                let granted = match <[u8; 4]>::try_from(&payload[..]) {
+                    Ok(granted) => u32::from_be_bytes(granted) as usize,
+                    Err(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "invalid mux window update",
+                        ))
+                    }
+                };
+                if let Some(stream) = streams.lock().unwrap().get(&id) {
+                    stream.window.add_permits(granted);
+                }
+            }
+            CMD_FIN => {
+                // Still granted a window while sending
+                if let Some(stream) = streams.lock().unwrap().get(&id) {
+                    let _ = stream.input.send(Bytes::new());
+                }
+            }
+            CMD_RST => reset(streams, id),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown mux command {}", cmd),
+                ))
+            }
+        }
+    }
+}
+
+/// Register stream `id` and relay between its frames and the returned end
+fn spawn_stream(
+    id: u32,
+    frames: &mpsc::Sender<Frame>,
+    streams: &Streams,
+    active: &Arc<AtomicUsize>,
+) -> DuplexStream {
+    let (user, inner) = tokio::io::duplex(MAX_PAYLOAD * 4);
+    let (input, mut input_rx) = mpsc::unbounded_channel::<Bytes>();
+    let handle = StreamHandle {
+        input,
+        unread: Arc::default(),
+        window: Arc::new(Semaphore::new(STREAM_WINDOW)),
+        reset: Arc::default(),
+    };
+    streams.lock().unwrap().insert(id, handle.clone());
+    active.fetch_add(1, Ordering::Relaxed);
+
+    let frames = frames.clone();
+    let streams = streams.clone();
+    let active = active.clone();
+    tokio::spawn(async move {
+        let (mut rd, mut wr) = tokio::io::split(inner);
+        let outgoing = async {
+            let mut buf = vec![0u8; MAX_PAYLOAD];
+            loop {
+                let n = rd.read(&mut buf).await.unwrap_or(0);
+                let (cmd, payload) = match n {
+                    0 => (CMD_FIN, Bytes::new()),
+                    n => (CMD_PSH, Bytes::copy_from_slice(&buf[..n])),
+                };
+                // Wait for the peer to read what was sent before
+                match handle.window.acquire_many(n as u32).await {
+                    Ok(permits) => permits.forget(),
+                    Err(_) => return,
+                }
+                if frames.send(Frame { cmd, id, payload }).await.is_err() || cmd == CMD_FIN {
+                    return;
+                }
+            }
+        };
+        let incoming = async {
+            let mut read = 0;
+            // Empty once the peer is done sending
+            while let Some(data) = input_rx.recv().await.filter(|data| !data.is_empty()) {
+                if wr.write_all(&data).await.is_err() {
+                    // Closed by the user, the peer stops sending
+                    let _ = frames
+                        .send(Frame {
+                            cmd: CMD_RST,
+                            id,
+                            payload: Bytes::new(),
+                        })
+                        .await;
+                    return;
+                }
+                handle.unread.fetch_sub(data.len(), Ordering::AcqRel);
+                read += data.len();
+                if read >= WINDOW_UPDATE {
+                    let update = Frame {
+                        cmd: CMD_UPD,
+                        id,
+                        payload: Bytes::copy_from_slice(&(read as u32).to_be_bytes()),
+                    };
+                    if frames.send(update).await.is_err() {
+                        return;
+                    }
+                    read = 0;
+                }
+            }
+            let _ = wr.shutdown().await;
+        };
+        tokio::select! {
+            _ = async { tokio::join!(outgoing, incoming) } => {}
+            _ = handle.reset.notified() => {}
+        }
+        streams.lock().unwrap().remove(&id);
+        active.fetch_sub(1, Ordering::Relaxed);
+    });
+    user
+}
+
+/// Sessions to the server of a `kcp://` upstream, shared by its connections
+pub struct KcpPool {
+    config: KcpConfig,
+    sessions: Mutex<Vec<Arc<Mux>>>,
+}
+
+impl KcpPool {
+    pub fn new(config: KcpConfig) -> KcpPool {
+        KcpPool {
+            config,
+            sessions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Open a stream on a session with room left, connecting a new session
+    /// to `addr` when all of them are full
+    pub async fn open(&self, addr: SocketAddr) -> io::Result<DuplexStream> {
+        let mux = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|mux| !mux.is_closed());
+            sessions
+                .iter()
+                .find(|mux| mux.active() < self.config.max_streams)
+                .cloned()
+        };
+        let mux = match mux {
+            Some(mux) => mux,
+            None => {
+                let stream = KcpStream::connect(&self.config, addr)
+                    .await
+                    .map_err(io::Error::other)?;
+                let mux = Arc::new(Mux::client(stream));
+                self.sessions.lock().unwrap().push(mux.clone());
+                mux
+            }
+        };
+        mux.open().await
+    }
+
+    /// Sessions currently pooled
+    pub fn sessions(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
+
+impl std::fmt::Debug for KcpPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KcpPool")
+            .field("max_streams", &self.config.max_streams)
+            .field("sessions", &self.sessions())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::kcp::KcpListener;
+
+    #[tokio::test]
+    async fn test_pool_shares_sessions() {
+        let config = KcpConfig {
+            mux: true,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let counts = listener.session_counts();
+        tokio::spawn(async move {
+            while let Ok((session, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (_mux, mut streams) = Mux::server(session);
+                    while let Some(stream) = streams.recv().await {
+                        tokio::spawn(async move {
+                            let (mut rd, mut wr) = tokio::io::split(stream);
+                            let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                            let _ = wr.shutdown().await;
+                        });
+                    }
+                });
+            }
+        });
+
+        let pool = KcpPool::new(KcpConfig {
+            max_streams: 2,
+            ..Default::default()
+        });
+        let mut conns = Vec::new();
+        for i in 0..3u8 {
+            let mut conn = pool.open(addr).await.unwrap();
+            conn.write_all(&[i; 3]).await.unwrap();
+            conns.push(conn);
+        }
+        for (i, conn) in conns.iter_mut().enumerate() {
+            let mut buf = [0u8; 3];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [i as u8; 3]);
+        }
+        // Two streams per session
+        assert_eq!(pool.sessions(), 2);
+        assert_eq!(counts.lock().unwrap().total(), 2);
+
+        // Closed streams make room on their session
+        drop(conns);
+        for _ in 0..50 {
+            if pool
+                .sessions
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|mux| mux.active() == 0)
+            {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        let mut conn = pool.open(addr).await.unwrap();
+        conn.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        assert_eq!(pool.sessions(), 2);
+        assert_eq!(counts.lock().unwrap().total(), 2);
+    }
+}
@@ -1,30 +1,31 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex as StdMutex, MutexGuard, Weak,
     },
     time::Duration,
 };
 
 use byte_string::ByteStr;
+use bytes::Bytes;
 use kcp::KcpResult;
-use log::{error, trace};
+use log::{debug, error, info, trace};
 use tokio::{
     net::UdpSocket,
     sync::{mpsc, Mutex},
     time::{self, Instant},
 };
 
-use crate::plugins::kcp::{skcp::KcpSocket, KcpConfig};
+use crate::plugins::kcp::{skcp::KcpSocket, KcpConfig, KcpMigration, KcpSessionStats};
 
 pub struct KcpSession {
     socket: Mutex<KcpSocket>,
     closed: AtomicBool,
     session_expire: Duration,
     session_close_notifier: Option<mpsc::Sender<u32>>,
-    input_tx: mpsc::Sender<Vec<u8>>,
+    input_tx: mpsc::Sender<Bytes>,
 }
 
 impl KcpSession {
@@ -32,7 +33,7 @@ impl KcpSession {
         socket: KcpSocket,
         session_expire: Duration,
         session_close_notifier: Option<mpsc::Sender<u32>>,
-        input_tx: mpsc::Sender<Vec<u8>>,
+        input_tx: mpsc::Sender<Bytes>,
     ) -> KcpSession {
         KcpSession {
             socket: Mutex::new(socket),
@@ -150,6 +151,12 @@ impl KcpSession {
                                 }
                             }
 
+                            if is_client {
+                                if let Err(err) = socket.keepalive() {
+                                    error!("[SESSION] KCP keepalive failed, error: {}", err);
+                                }
+                            }
+
                             match socket.update() {
                                 Ok(next_next) => {
                                     update_timer.as_mut().reset(Instant::from_std(next_next));
@@ -169,6 +176,12 @@ impl KcpSession {
 
                     let mut socket = session.socket.lock().await;
                     socket.close();
+                    info!(
+                        "[SESSION] closed conv: {}, peer: {}, {}",
+                        socket.conv(),
+                        socket.target_addr(),
+                        socket.stats()
+                    );
                 }
 
                 if let Some(ref notifier) = session.session_close_notifier {
@@ -189,29 +202,141 @@ impl KcpSession {
         self.closed.store(true, Ordering::Release);
     }
 
-    pub async fn input(&self, buf: &[u8]) {
-        self.input_tx
-            .send(buf.to_owned())
-            .await
-            .expect("input channel closed")
+    pub async fn stats(&self) -> KcpSessionStats {
+        self.socket.lock().await.stats()
+    }
+
+    /// Queue a packet for the session, returns false if the packet was dropped
+    /// because the session is not keeping up.
+    pub fn input(&self, buf: Bytes) -> bool {
+        self.input_tx.try_send(buf).is_ok()
+    }
+}
+
+/// Reason of a packet being refused by the session manager
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KcpReject {
+    /// Session table of the listener is full
+    TableFull,
+    /// Peer IP has reached its session limit
+    PeerLimit,
+    /// Conv is bound to another peer address
+    PeerMismatch,
+}
+
+struct SessionEntry {
+    session: Arc<KcpSession>,
+    peer_addr: SocketAddr,
+}
+
+/// A live session of a listener
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub conv: u32,
+    pub peer: SocketAddr,
+    session: Weak<KcpSession>,
+}
+
+impl SessionInfo {
+    /// Statistics of the session, `None` once it is gone
+    pub async fn stats(&self) -> Option<KcpSessionStats> {
+        Some(self.session.upgrade()?.stats().await)
+    }
+}
+
+/// Session counts of a listener, in total and by peer IP, and its live
+/// sessions, shared by all of its shards
+#[derive(Debug, Default)]
+pub struct SessionCounts {
+    total: usize,
+    peers: HashMap<IpAddr, usize>,
+    /// By address of the session, convs of shards may be the same
+    sessions: HashMap<usize, SessionInfo>,
+}
+
+impl SessionCounts {
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn peers(&self) -> &HashMap<IpAddr, usize> {
+        &self.peers
+    }
+
+    /// Live sessions, by peer address and conv
+    pub fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions: Vec<_> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|info| (info.peer, info.conv));
+        sessions
+    }
+
+    fn add(&mut self, conv: u32, peer: SocketAddr, session: &Arc<KcpSession>) {
+        self.acquire(peer.ip());
+        let info = SessionInfo {
+            conv,
+            peer,
+            session: Arc::downgrade(session),
+        };
+        self.sessions.insert(Arc::as_ptr(session) as usize, info);
+    }
+
+    fn remove(&mut self, peer: SocketAddr, session: &Arc<KcpSession>) {
+        self.release(peer.ip());
+        self.sessions.remove(&(Arc::as_ptr(session) as usize));
+    }
+
+    fn set_peer(&mut self, session: &Arc<KcpSession>, peer: SocketAddr) {
+        if let Some(info) = self.sessions.get_mut(&(Arc::as_ptr(session) as usize)) {
+            info.peer = peer;
+        }
+    }
+
+    fn acquire(&mut self, ip: IpAddr) {
+        self.total += 1;
+        *self.peers.entry(ip).or_insert(0) += 1;
+    }
+
+    fn release(&mut self, ip: IpAddr) {
+        self.total -= 1;
+        if let Entry::Occupied(mut occ) = self.peers.entry(ip) {
+            *occ.get_mut() -= 1;
+            if *occ.get() == 0 {
+                occ.remove();
+            }
+        }
+    }
+
+    fn check_peer_limit(&self, config: &KcpConfig, ip: IpAddr) -> Result<(), KcpReject> {
+        if self.peers.get(&ip).copied().unwrap_or(0) >= config.max_sessions_per_peer {
+            return Err(KcpReject::PeerLimit);
+        }
+        Ok(())
     }
 }
 
 pub struct KcpSessionManager {
-    sessions: HashMap<u32, Arc<KcpSession>>,
+    sessions: HashMap<u32, SessionEntry>,
+    counts: Arc<StdMutex<SessionCounts>>,
     next_free_conv: u32,
 }
 
 impl KcpSessionManager {
-    pub fn new() -> KcpSessionManager {
+    pub fn new(counts: Arc<StdMutex<SessionCounts>>) -> KcpSessionManager {
         KcpSessionManager {
             sessions: HashMap::new(),
+            counts,
             next_free_conv: 0,
         }
     }
 
+    fn counts(&self) -> MutexGuard<'_, SessionCounts> {
+        self.counts.lock().expect("session counts poisoned")
+    }
+
     pub fn close_conv(&mut self, conv: u32) {
-        self.sessions.remove(&conv);
+        if let Some(entry) = self.sessions.remove(&conv) {
+            self.counts().remove(entry.peer_addr, &entry.session);
+        }
     }
 
     pub fn alloc_conv(&mut self) -> u32 {
@@ -223,34 +348,91 @@ impl KcpSessionManager {
             }
             self.next_free_conv = c;
 
-            if self.sessions.get(&self.next_free_conv).is_none() {
+            if !self.sessions.contains_key(&self.next_free_conv) {
                 let conv = self.next_free_conv;
                 return conv;
             }
         }
     }
 
-    pub fn get_or_create(
+    /// Find the session of `conv`, checking it is bound to `peer_addr`.
+    ///
+    /// Returns `Ok(Some((session, migrated)))` if the session moved to `peer_addr`.
+    pub async fn get(
         &mut self,
         config: &KcpConfig,
         conv: u32,
-        udp: &Arc<UdpSocket>,
         peer_addr: SocketAddr,
-        session_close_notifier: &mpsc::Sender<u32>,
-    ) -> KcpResult<(Arc<KcpSession>, bool)> {
-        match self.sessions.entry(conv) {
-            Entry::Occupied(occ) => Ok((occ.get().clone(), false)),
-            Entry::Vacant(vac) => {
-                let socket = KcpSocket::new(config, conv, udp.clone(), peer_addr, config.stream)?;
-                let session = KcpSession::new_shared(
-                    socket,
-                    config.session_expire,
-                    Some(session_close_notifier.clone()),
-                );
-                trace!("created session for conv: {}, peer: {}", conv, peer_addr);
-                vac.insert(session.clone());
-                Ok((session, true))
+    ) -> Result<Option<(Arc<KcpSession>, bool)>, KcpReject> {
+        let (session, old_addr) = match self.sessions.get(&conv) {
+            Some(entry) if entry.peer_addr == peer_addr => {
+                return Ok(Some((entry.session.clone(), false)))
             }
+            Some(entry) => (entry.session.clone(), entry.peer_addr),
+            None => return Ok(None),
+        };
+
+        match config.migration {
+            KcpMigration::Disabled => return Err(KcpReject::PeerMismatch),
+            KcpMigration::SameIp if old_addr.ip() != peer_addr.ip() => {
+                return Err(KcpReject::PeerMismatch)
+            }
+            KcpMigration::SameIp | KcpMigration::Any => {}
         }
+
+        {
+            let mut counts = self.counts();
+            if old_addr.ip() != peer_addr.ip() {
+                counts.check_peer_limit(config, peer_addr.ip())?;
+                counts.release(old_addr.ip());
+                counts.acquire(peer_addr.ip());
+            }
+            counts.set_peer(&session, peer_addr);
+        }
+        if let Some(entry) = self.sessions.get_mut(&conv) {
+            entry.peer_addr = peer_addr;
+        }
+        session.kcp_socket().lock().await.set_target_addr(peer_addr);
+        debug!(
+            "session conv: {} migrated from {} to {}",
+            conv, old_addr, peer_addr
+        );
+
+        Ok(Some((session, true)))
+    }
+
+    /// Check whether a new session from `peer_addr` is allowed
+    pub fn admit(&self, config: &KcpConfig, peer_addr: SocketAddr) -> Result<(), KcpReject> {
+        let counts = self.counts();
+        if counts.total >= config.max_sessions {
+            return Err(KcpReject::TableFull);
+        }
+        counts.check_peer_limit(config, peer_addr.ip())
+    }
+
+    pub fn create(
+        &mut self,
+        config: &KcpConfig,
+        conv: u32,
+        udp: &Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        session_close_notifier: &mpsc::Sender<u32>,
+    ) -> KcpResult<Arc<KcpSession>> {
+        let socket = KcpSocket::new(config, conv, udp.clone(), peer_addr, config.stream)?;
+        let session = KcpSession::new_shared(
+            socket,
+            config.session_expire,
+            Some(session_close_notifier.clone()),
+        );
+        trace!("created session for conv: {}, peer: {}", conv, peer_addr);
+        self.sessions.insert(
+            conv,
+            SessionEntry {
+                session: session.clone(),
+                peer_addr,
+            },
+        );
+        self.counts().add(conv, peer_addr, &session);
+        Ok(session)
     }
 }
@@ -1,34 +1,70 @@
 use std::{
     io::{self, ErrorKind, Write},
     net::SocketAddr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
 
+use bytes::{Bytes, BytesMut};
 use futures::future;
 use kcp::{Error as KcpError, Kcp, KcpResult};
 use log::{error, trace};
 use tokio::{net::UdpSocket, sync::mpsc};
 
-use crate::plugins::kcp::{utils::now_millis, KcpConfig};
+use crate::plugins::kcp::{
+    batch,
+    stats::{KcpSessionStats, KcpStatsTracker},
+    utils::now_millis,
+    KcpConfig,
+};
+
+/// KCP command asking the remote for its window size
+const KCP_CMD_WASK: u8 = 83;
+
+/// Packets written by KCP, stored back to back in one reused buffer
+#[derive(Default)]
+struct OutputQueue {
+    buffer: BytesMut,
+    /// End offset of each packet in `buffer`
+    packets: Vec<usize>,
+}
+
+/// Writer for KCP, queueing packets until `UdpOutput::send`
+struct QueueWriter {
+    queue: Arc<Mutex<OutputQueue>>,
+}
+
+impl Write for QueueWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut queue = self.queue.lock().expect("output queue poisoned");
+        queue.buffer.extend_from_slice(buf);
+        let end = queue.buffer.len();
+        queue.packets.push(end);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-/// Writer for sending packets to the underlying UdpSocket
+/// Sender of queued packets to the underlying UdpSocket
 struct UdpOutput {
     socket: Arc<UdpSocket>,
-    target_addr: SocketAddr,
-    delay_tx: mpsc::UnboundedSender<Vec<u8>>,
+    queue: Arc<Mutex<OutputQueue>>,
+    delay_tx: mpsc::UnboundedSender<(Bytes, SocketAddr)>,
 }
 
 impl UdpOutput {
-    /// Create a new Writer for writing packets to UdpSocket
-    pub fn new(socket: Arc<UdpSocket>, target_addr: SocketAddr) -> UdpOutput {
-        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    /// Create a new sender and the writer feeding it
+    pub fn new(socket: Arc<UdpSocket>) -> (UdpOutput, QueueWriter) {
+        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<(Bytes, SocketAddr)>();
 
         {
             let socket = socket.clone();
             tokio::spawn(async move {
-                while let Some(buf) = delay_rx.recv().await {
+                while let Some((buf, target_addr)) = delay_rx.recv().await {
                     if let Err(err) = socket.send_to(&buf, target_addr).await {
                         error!("[SEND] UDP delayed send failed, error: {}", err);
                     }
@@ -36,45 +72,73 @@ impl UdpOutput {
             });
         }
 
-        UdpOutput {
-            socket,
-            target_addr,
-            delay_tx,
-        }
+        let queue = Arc::new(Mutex::new(OutputQueue::default()));
+        let writer = QueueWriter {
+            queue: queue.clone(),
+        };
+
+        (
+            UdpOutput {
+                socket,
+                queue,
+                delay_tx,
+            },
+            writer,
+        )
     }
-}
 
-impl Write for UdpOutput {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.socket.try_send_to(buf, self.target_addr) {
-            Ok(n) => Ok(n),
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+    /// Send all queued packets in batches
+    pub fn send(&self, target_addr: SocketAddr, stats: &mut KcpStatsTracker) -> io::Result<()> {
+        let mut queue = self.queue.lock().expect("output queue poisoned");
+        if queue.packets.is_empty() {
+            return Ok(());
+        }
+
+        let OutputQueue { buffer, packets } = &mut *queue;
+        let mut slices = Vec::with_capacity(packets.len());
+        let mut start = 0;
+        for &end in packets.iter() {
+            let packet = &buffer[start..end];
+            stats.on_output(packet);
+            slices.push(packet);
+            start = end;
+        }
+
+        let result = match batch::try_send_batch(&self.socket, &slices, target_addr) {
+            Ok(sent) => Ok(sent),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(0),
+            Err(err) => Err(err),
+        };
+
+        if let Ok(sent) = result {
+            for packet in &slices[sent..] {
                 // send return EAGAIN
                 // ignored as packet was lost in transmission
                 trace!(
                     "[SEND] UDP send EAGAIN, packet.size: {} bytes, delayed send",
-                    buf.len()
+                    packet.len()
                 );
 
                 self.delay_tx
-                    .send(buf.to_owned())
+                    .send((Bytes::copy_from_slice(packet), target_addr))
                     .expect("channel closed unexpectly");
-
-                Ok(buf.len())
             }
-            Err(err) => Err(err),
         }
-    }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+        buffer.clear();
+        packets.clear();
+        result.map(|_| ())
     }
 }
 
 pub struct KcpSocket {
-    kcp: Kcp<UdpOutput>,
+    kcp: Kcp<QueueWriter>,
+    output: UdpOutput,
     last_update: Instant,
     socket: Arc<UdpSocket>,
+    target_addr: SocketAddr,
+    keepalive: Option<Duration>,
+    stats: KcpStatsTracker,
     flush_write: bool,
     flush_ack_input: bool,
     sent_first: bool,
@@ -91,11 +155,11 @@ impl KcpSocket {
         target_addr: SocketAddr,
         stream: bool,
     ) -> KcpResult<KcpSocket> {
-        let output = UdpOutput::new(socket.clone(), target_addr);
+        let (output, writer) = UdpOutput::new(socket.clone());
         let mut kcp = if stream {
-            Kcp::new_stream(conv, output)
+            Kcp::new_stream(conv, writer)
         } else {
-            Kcp::new(conv, output)
+            Kcp::new(conv, writer)
         };
         c.apply_config(&mut kcp);
 
@@ -104,12 +168,21 @@ impl KcpSocket {
             kcp.input_conv();
         }
 
+        // Minimal RTO of `Kcp`, lower with nodelay
+        let min_rto = if c.nodelay.nodelay { 30 } else { 100 };
+        let mut stats = KcpStatsTracker::new(c.nodelay.interval as u32, min_rto);
+
         kcp.update(now_millis())?;
+        output.send(target_addr, &mut stats)?;
 
         Ok(KcpSocket {
             kcp,
+            output,
             last_update: Instant::now(),
             socket,
+            target_addr,
+            keepalive: c.keepalive,
+            stats,
             flush_write: c.flush_write,
             flush_ack_input: c.flush_acks_input,
             sent_first: false,
@@ -134,9 +207,11 @@ impl KcpSocket {
             Err(err) => return Err(err),
         }
         self.last_update = Instant::now();
+        self.stats.on_input(buf);
 
         if self.flush_ack_input {
             self.kcp.flush_ack()?;
+            self.output.send(self.target_addr, &mut self.stats)?;
         }
 
         Ok(self.try_wake_pending_waker())
@@ -174,6 +249,7 @@ impl KcpSocket {
 
         if self.flush_write {
             self.kcp.flush()?;
+            self.output.send(self.target_addr, &mut self.stats)?;
         }
 
         Ok(n).into()
@@ -215,6 +291,7 @@ impl KcpSocket {
 
     pub fn flush(&mut self) -> KcpResult<()> {
         self.kcp.flush()?;
+        self.output.send(self.target_addr, &mut self.stats)?;
         self.last_update = Instant::now();
         Ok(())
     }
@@ -249,6 +326,7 @@ impl KcpSocket {
     pub fn update(&mut self) -> KcpResult<Instant> {
         let now = now_millis();
         self.kcp.update(now)?;
+        self.output.send(self.target_addr, &mut self.stats)?;
         let next = self.kcp.check(now);
 
         self.try_wake_pending_waker();
@@ -256,6 +334,40 @@ impl KcpSocket {
         Ok(Instant::now() + Duration::from_millis(next as u64))
     }
 
+    /// Send a window probe if the session has been idle for longer than the
+    /// keepalive interval, the remote answers it and both sides refresh
+    /// their last update time.
+    pub fn keepalive(&mut self) -> KcpResult<()> {
+        let interval = match self.keepalive {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+
+        if self.closed || self.kcp.waiting_conv() || self.last_update.elapsed() < interval {
+            return Ok(());
+        }
+
+        let mut probe = [0u8; 24];
+        probe[0..4].copy_from_slice(&self.kcp.conv().to_le_bytes());
+        probe[4] = KCP_CMD_WASK;
+        probe[6..8].copy_from_slice(&self.kcp.rcv_wnd().to_le_bytes());
+        probe[8..12].copy_from_slice(&now_millis().to_le_bytes());
+
+        trace!(
+            "[KEEPALIVE] probe conv={} {}",
+            self.kcp.conv(),
+            self.target_addr
+        );
+        match self.socket.try_send_to(&probe, self.target_addr) {
+            Ok(..) => {}
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.last_update = Instant::now();
+
+        Ok(())
+    }
+
     pub fn close(&mut self) {
         self.closed = true;
         if let Some(w) = self.pending_sender.take() {
@@ -266,6 +378,15 @@ impl KcpSocket {
         }
     }
 
+    pub fn target_addr(&self) -> SocketAddr {
+        self.target_addr
+    }
+
+    /// Send following packets to a new peer address
+    pub fn set_target_addr(&mut self, addr: SocketAddr) {
+        self.target_addr = addr;
+    }
+
     pub fn udp_socket(&self) -> &Arc<UdpSocket> {
         &self.socket
     }
@@ -282,6 +403,10 @@ impl KcpSocket {
         self.kcp.peeksize()
     }
 
+    pub fn stats(&self) -> KcpSessionStats {
+        self.stats.stats(self.kcp.wait_snd())
+    }
+
     pub fn last_update_time(&self) -> Instant {
         self.last_update
     }
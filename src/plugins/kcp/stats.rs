@@ -0,0 +1,267 @@
+use std::{collections::HashSet, fmt};
+
+use serde::Serialize;
+
+use crate::plugins::kcp::utils::now_millis;
+
+const KCP_HEADER_LEN: usize = 24;
+const KCP_CMD_PUSH: u8 = 81;
+const KCP_CMD_ACK: u8 = 82;
+const KCP_RTO_MAX: u32 = 60000;
+
+/// Statistics of a KCP session
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct KcpSessionStats {
+    /// Smoothed round trip time (ms)
+    pub srtt: u32,
+    /// Round trip time variation (ms)
+    pub rttvar: u32,
+    /// Retransmission timeout (ms)
+    pub rto: u32,
+    /// Segments sent but not acknowledged
+    pub in_flight: u32,
+    /// Segments waiting to be sent or acknowledged
+    pub send_queue: usize,
+    /// Segments sent again
+    pub retransmits: u64,
+    /// Segments missing since a later one arrived
+    pub lost: u64,
+    /// Segments received more than once
+    pub duplicates: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+}
+
+impl fmt::Display for KcpSessionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "srtt: {}ms, rttvar: {}ms, rto: {}ms, in flight: {}, send queue: {}, retransmits: {}, lost: {}, duplicates: {}, in: {} bytes/{} packets, out: {} bytes/{} packets",
+            self.srtt,
+            self.rttvar,
+            self.rto,
+            self.in_flight,
+            self.send_queue,
+            self.retransmits,
+            self.lost,
+            self.duplicates,
+            self.bytes_in,
+            self.packets_in,
+            self.bytes_out,
+            self.packets_out,
+        )
+    }
+}
+
+/// Follows the segments going through a `KcpSocket`, `Kcp` keeps its own
+/// counters private.
+#[derive(Debug, Default)]
+pub struct KcpStatsTracker {
+    stats: KcpSessionStats,
+    interval: u32,
+    min_rto: u32,
+    /// Next sn to be sent for the first time
+    snd_nxt: u32,
+    /// First sn not acknowledged by remote
+    snd_una: u32,
+    /// Next sn expected from remote
+    rcv_nxt: u32,
+    /// Highest sn received
+    rcv_max: Option<u32>,
+    /// Segments received after `rcv_nxt`
+    rcv_ahead: HashSet<u32>,
+}
+
+struct SegmentHeader {
+    cmd: u8,
+    ts: u32,
+    sn: u32,
+    una: u32,
+}
+
+fn segments(packet: &[u8]) -> impl Iterator<Item = SegmentHeader> + '_ {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if packet.len() < pos + KCP_HEADER_LEN {
+            return None;
+        }
+        let header = &packet[pos..pos + KCP_HEADER_LEN];
+        let read_u32 = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let len = read_u32(20) as usize;
+        pos += KCP_HEADER_LEN + len;
+
+        Some(SegmentHeader {
+            cmd: header[4],
+            ts: read_u32(8),
+            sn: read_u32(12),
+            una: read_u32(16),
+        })
+    })
+}
+
+#[inline]
+fn after(later: u32, earlier: u32) -> bool {
+    (later.wrapping_sub(earlier) as i32) > 0
+}
+
+impl KcpStatsTracker {
+    pub fn new(interval: u32, min_rto: u32) -> KcpStatsTracker {
+        KcpStatsTracker {
+            stats: KcpSessionStats {
+                rto: 200,
+                ..Default::default()
+            },
+            interval,
+            min_rto,
+            ..Default::default()
+        }
+    }
+
+    /// Call with every packet given to `Kcp::input`
+    pub fn on_input(&mut self, packet: &[u8]) {
+        self.stats.bytes_in += packet.len() as u64;
+        self.stats.packets_in += 1;
+
+        for segment in segments(packet) {
+            if after(segment.una, self.snd_una) {
+                self.snd_una = segment.una;
+            }
+
+            match segment.cmd {
+                KCP_CMD_ACK => {
+                    let rtt = now_millis().wrapping_sub(segment.ts) as i32;
+                    if rtt >= 0 {
+                        self.update_rtt(rtt as u32);
+                    }
+                }
+                KCP_CMD_PUSH => {
+                    if after(self.rcv_nxt, segment.sn) {
+                        self.stats.duplicates += 1;
+                        continue;
+                    }
+                    if !self.rcv_ahead.insert(segment.sn) {
+                        self.stats.duplicates += 1;
+                        continue;
+                    }
+                    match self.rcv_max {
+                        Some(max) if after(segment.sn, max) => {
+                            self.stats.lost += (segment.sn.wrapping_sub(max) - 1) as u64;
+                            self.rcv_max = Some(segment.sn);
+                        }
+                        // Arrived late rather than lost
+                        Some(..) => self.stats.lost = self.stats.lost.saturating_sub(1),
+                        None => {
+                            self.stats.lost += segment.sn.wrapping_sub(self.rcv_nxt) as u64;
+                            self.rcv_max = Some(segment.sn);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Call with every packet written by `Kcp`
+    pub fn on_output(&mut self, packet: &[u8]) {
+        self.stats.bytes_out += packet.len() as u64;
+        self.stats.packets_out += 1;
+
+        for segment in segments(packet) {
+            // Every segment carries what we expect next from remote
+            if after(segment.una, self.rcv_nxt) {
+                self.rcv_nxt = segment.una;
+                let rcv_nxt = self.rcv_nxt;
+                self.rcv_ahead.retain(|&sn| !after(rcv_nxt, sn));
+            }
+
+            if segment.cmd == KCP_CMD_PUSH {
+                if after(self.snd_nxt, segment.sn) {
+                    self.stats.retransmits += 1;
+                } else {
+                    self.snd_nxt = segment.sn.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    /// Same estimation as `Kcp` does on ACK
+    fn update_rtt(&mut self, rtt: u32) {
+        let stats = &mut self.stats;
+        if stats.srtt == 0 {
+            stats.srtt = rtt.max(1);
+            stats.rttvar = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(stats.srtt);
+            stats.rttvar = (3 * stats.rttvar + delta) / 4;
+            stats.srtt = ((7 * stats.srtt + rtt) / 8).max(1);
+        }
+        let rto = stats.srtt + self.interval.max(4 * stats.rttvar);
+        stats.rto = rto.clamp(self.min_rto, KCP_RTO_MAX);
+    }
+
+    pub fn stats(&self, send_queue: usize) -> KcpSessionStats {
+        KcpSessionStats {
+            in_flight: self.snd_nxt.wrapping_sub(self.snd_una),
+            send_queue,
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(cmd: u8, ts: u32, sn: u32, una: u32) -> Vec<u8> {
+        let mut buf = vec![0u8; KCP_HEADER_LEN];
+        buf[4] = cmd;
+        buf[8..12].copy_from_slice(&ts.to_le_bytes());
+        buf[12..16].copy_from_slice(&sn.to_le_bytes());
+        buf[16..20].copy_from_slice(&una.to_le_bytes());
+        buf
+    }
+
+    #[test]
+    fn test_stats_tracker() {
+        let mut tracker = KcpStatsTracker::new(100, 100);
+
+        // Send sn 0 and 1, then resend 0
+        let mut packet = segment(KCP_CMD_PUSH, 0, 0, 0);
+        packet.extend(segment(KCP_CMD_PUSH, 0, 1, 0));
+        tracker.on_output(&packet);
+        tracker.on_output(&segment(KCP_CMD_PUSH, 0, 0, 0));
+
+        // Remote acks sn 0 and sends sn 0, 2, 2
+        tracker.on_input(&segment(KCP_CMD_ACK, now_millis(), 0, 1));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 0, 1));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 2, 1));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 2, 1));
+
+        let stats = tracker.stats(0);
+        assert_eq!(stats.retransmits, 1);
+        assert_eq!(stats.in_flight, 1);
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.duplicates, 1);
+        assert_eq!(stats.packets_out, 2);
+        assert_eq!(stats.packets_in, 4);
+        assert!(stats.srtt >= 1);
+
+        // The missing sn 1 arrives late, then again
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 1, 1));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 1, 1));
+        let stats = tracker.stats(0);
+        assert_eq!(stats.lost, 0);
+        assert_eq!(stats.duplicates, 2);
+
+        // Acked sn are forgotten, later ones still count
+        tracker.on_output(&segment(KCP_CMD_ACK, 0, 0, 3));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 5, 1));
+        tracker.on_input(&segment(KCP_CMD_PUSH, 0, 1, 1));
+        let stats = tracker.stats(0);
+        assert_eq!(stats.lost, 2);
+        assert_eq!(stats.duplicates, 3);
+        assert!(tracker.rcv_ahead.iter().all(|&sn| sn >= 3));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    io::{self, ErrorKind},
+    io,
     net::{IpAddr, SocketAddr},
     pin::Pin,
     sync::Arc,
@@ -14,7 +14,9 @@ use tokio::{
     net::UdpSocket,
 };
 
-use crate::plugins::kcp::{config::KcpConfig, session::KcpSession, skcp::KcpSocket};
+use crate::plugins::kcp::{
+    config::KcpConfig, cookie, session::KcpSession, skcp::KcpSocket, stats::KcpSessionStats,
+};
 
 pub struct KcpStream {
     session: Arc<KcpSession>,
@@ -37,8 +39,14 @@ impl KcpStream {
             IpAddr::V6(..) => UdpSocket::bind("[::]:0").await?,
         };
 
+        // Without a cookie the server allocates conv on the first packet
+        let conv = match config.cookie {
+            true => cookie::handshake(&udp, addr).await?,
+            false => 0,
+        };
+
         let udp = Arc::new(udp);
-        let socket = KcpSocket::new(config, 0, udp, addr, config.stream)?;
+        let socket = KcpSocket::new(config, conv, udp, addr, config.stream)?;
 
         let session = KcpSession::new_shared(socket, config.session_expire, None);
 
@@ -79,7 +87,7 @@ impl KcpStream {
                 let remaining = self.recv_buffer_cap - self.recv_buffer_pos;
                 let copy_length = remaining.min(buf.len());
 
-                buf.copy_from_slice(
+                buf[..copy_length].copy_from_slice(
                     &self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_pos + copy_length],
                 );
                 self.recv_buffer_pos += copy_length;
@@ -127,6 +135,10 @@ impl KcpStream {
     pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
         future::poll_fn(|cx| self.poll_recv(cx, buf)).await
     }
+
+    pub async fn stats(&self) -> KcpSessionStats {
+        self.session.stats().await
+    }
 }
 
 impl AsyncRead for KcpStream {
@@ -141,7 +153,7 @@ impl AsyncRead for KcpStream {
                 Ok(()).into()
             }
             Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(io::Error::other(err)).into(),
         }
     }
 }
@@ -155,7 +167,7 @@ impl AsyncWrite for KcpStream {
         match ready!(self.poll_send(cx, buf)) {
             Ok(n) => Ok(n).into(),
             Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(io::Error::other(err)).into(),
         }
     }
 
@@ -173,7 +185,7 @@ impl AsyncWrite for KcpStream {
         match kcp.flush() {
             Ok(..) => Ok(()).into(),
             Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(io::Error::other(err)).into(),
         }
     }
 
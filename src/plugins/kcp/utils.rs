@@ -6,5 +6,5 @@ pub fn now_millis() -> u32 {
     let since_the_epoch = start
         .duration_since(UNIX_EPOCH)
         .expect("time went afterwards");
-    (since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_millis() as u64 / 1_000_000) as u32
+    (since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_millis() as u64) as u32
 }
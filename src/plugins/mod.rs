@@ -1 +1,8 @@
+pub mod acl;
+pub mod ban;
+pub mod discovery;
 pub mod kcp;
+pub mod proxy_protocol;
+pub mod resolver;
+pub mod tls;
+pub mod unix;
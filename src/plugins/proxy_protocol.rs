@@ -0,0 +1,177 @@
+//! PROXY protocol version 2 headers sent to upstreams
+
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+/// Version 2, PROXY command
+const VERSION_COMMAND: u8 = 0x21;
+const FAMILY_TCP4: u8 = 0x11;
+const FAMILY_TCP6: u8 = 0x21;
+/// Addresses the receiver must ignore
+const FAMILY_UNSPEC: u8 = 0x00;
+
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+/// First type of the range reserved for custom use, carries every name of
+/// the client certificate
+pub const PP2_TYPE_CLIENT_IDENTITY: u8 = 0xe0;
+
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+
+/// TLS of the inbound connection
+#[derive(Debug, Clone, Default)]
+pub struct SslInfo {
+    pub version: Option<String>,
+    /// Common name of the verified client certificate
+    pub cn: Option<String>,
+    /// Every name of the verified client certificate
+    pub identity: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyHeader {
+    /// Source and destination, none for Unix socket clients
+    pub addresses: Option<(SocketAddr, SocketAddr)>,
+    /// SNI of the inbound connection
+    pub authority: Option<String>,
+    pub ssl: Option<SslInfo>,
+}
+
+fn push_tlv(buf: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    buf.push(kind);
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
+
+impl ProxyHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        let family = match self.addresses {
+            Some((source, destination)) => {
+                let family = match (source.ip(), destination.ip()) {
+                    (IpAddr::V4(source), IpAddr::V4(destination)) => {
+                        body.extend_from_slice(&source.octets());
+                        body.extend_from_slice(&destination.octets());
+                        FAMILY_TCP4
+                    }
+                    (source, destination) => {
+                        body.extend_from_slice(&to_ipv6(source).octets());
+                        body.extend_from_slice(&to_ipv6(destination).octets());
+                        FAMILY_TCP6
+                    }
+                };
+                body.extend_from_slice(&source.port().to_be_bytes());
+                body.extend_from_slice(&destination.port().to_be_bytes());
+                family
+            }
+            None => FAMILY_UNSPEC,
+        };
+
+        if let Some(authority) = &self.authority {
+            push_tlv(&mut body, PP2_TYPE_AUTHORITY, authority.as_bytes());
+        }
+
+        if let Some(ssl) = &self.ssl {
+            let mut client = PP2_CLIENT_SSL;
+            if ssl.identity.is_some() {
+                client |= PP2_CLIENT_CERT_CONN;
+            }
+            // Zero when a client certificate was verified
+            let verify: u32 = match ssl.identity {
+                Some(..) => 0,
+                None => 1,
+            };
+
+            let mut value = vec![client];
+            value.extend_from_slice(&verify.to_be_bytes());
+            if let Some(version) = &ssl.version {
+                push_tlv(&mut value, PP2_SUBTYPE_SSL_VERSION, version.as_bytes());
+            }
+            if let Some(cn) = &ssl.cn {
+                push_tlv(&mut value, PP2_SUBTYPE_SSL_CN, cn.as_bytes());
+            }
+            push_tlv(&mut body, PP2_TYPE_SSL, &value);
+
+            if let Some(identity) = &ssl.identity {
+                push_tlv(&mut body, PP2_TYPE_CLIENT_IDENTITY, identity.as_bytes());
+            }
+        }
+
+        let mut header = Vec::with_capacity(16 + body.len());
+        header.extend_from_slice(&SIGNATURE);
+        header.push(VERSION_COMMAND);
+        header.push(family);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(&body);
+        header
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_header() {
+        let header = ProxyHeader {
+            addresses: Some((
+                "127.0.0.1:10000".parse().unwrap(),
+                "127.0.0.2:443".parse().unwrap(),
+            )),
+            authority: None,
+            ssl: None,
+        };
+        let buf = header.encode();
+        assert_eq!(&buf[..12], &SIGNATURE);
+        assert_eq!(buf[12], VERSION_COMMAND);
+        assert_eq!(buf[13], FAMILY_TCP4);
+        assert_eq!(&buf[14..16], &12u16.to_be_bytes());
+        assert_eq!(&buf[16..20], &[127, 0, 0, 1]);
+        assert_eq!(&buf[24..26], &10000u16.to_be_bytes());
+        assert_eq!(&buf[26..28], &443u16.to_be_bytes());
+
+        let header = ProxyHeader {
+            addresses: Some((
+                "[::1]:10000".parse().unwrap(),
+                "127.0.0.2:443".parse().unwrap(),
+            )),
+            authority: Some("tls.test.com".to_string()),
+            ssl: Some(SslInfo {
+                version: Some("TLSv1.3".to_string()),
+                cn: Some("team-a".to_string()),
+                identity: Some("CN=team-a".to_string()),
+            }),
+        };
+        let buf = header.encode();
+        assert_eq!(buf[13], FAMILY_TCP6);
+        assert_eq!(buf.len(), 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize);
+        // Addresses, then the authority TLV
+        assert_eq!(buf[16 + 36], PP2_TYPE_AUTHORITY);
+        let ssl = 16 + 36 + 3 + "tls.test.com".len();
+        assert_eq!(buf[ssl], PP2_TYPE_SSL);
+        assert_eq!(buf[ssl + 3], PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN);
+        assert_eq!(&buf[ssl + 4..ssl + 8], &0u32.to_be_bytes());
+        assert!(buf.ends_with(b"CN=team-a"));
+
+        let header = ProxyHeader {
+            addresses: None,
+            authority: None,
+            ssl: None,
+        };
+        let buf = header.encode();
+        assert_eq!(buf[13], FAMILY_UNSPEC);
+        assert_eq!(&buf[14..], &0u16.to_be_bytes());
+    }
+}
@@ -0,0 +1,715 @@
+//! Asynchronous DNS resolution of upstreams, with A/AAAA and SRV records
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::proto::xfer::Protocol;
+use hickory_resolver::TokioResolver;
+use log::{debug, error, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// How often cached names are checked for expiry
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Names unused for this long are not re-resolved and dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// Delay before retrying a failed re-resolution
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Connection Attempt Delay recommended by RFC 8305
+pub const CONNECT_DELAY: Duration = Duration::from_millis(250);
+
+/// DNS servers and TTL bounds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResolverOptions {
+    /// Those of `/etc/resolv.conf` when empty
+    pub nameservers: Vec<SocketAddr>,
+    pub min_ttl: Option<Duration>,
+    pub max_ttl: Option<Duration>,
+}
+
+/// Address family of upstream connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub fn of(addr: &SocketAddr) -> Family {
+        match addr {
+            SocketAddr::V4(..) => Family::Ipv4,
+            SocketAddr::V6(..) => Family::Ipv6,
+        }
+    }
+}
+
+impl FromStr for Family {
+    type Err = String;
+
+    fn from_str(family: &str) -> Result<Family, String> {
+        match family {
+            "ipv4" => Ok(Family::Ipv4),
+            "ipv6" => Ok(Family::Ipv6),
+            _ => Err(format!("Unknown address family {}", family)),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Family::Ipv4 => write!(f, "IPv4"),
+            Family::Ipv6 => write!(f, "IPv6"),
+        }
+    }
+}
+
+/// How the addresses of an upstream are connected to, following Happy
+/// Eyeballs (RFC 8305)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectOptions {
+    /// The only family connected to, any when unset
+    pub family: Option<Family>,
+    /// The family tried first, families taking turns afterwards
+    pub prefer: Family,
+    /// Delay before trying the next address while an attempt is pending
+    pub delay: Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        ConnectOptions {
+            family: None,
+            prefer: Family::Ipv6,
+            delay: CONNECT_DELAY,
+        }
+    }
+}
+
+/// What an upstream address is resolved from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Target {
+    Host(String, u16),
+    /// SRV records of a name, e.g. `_http._tcp.example.com`
+    Srv(String),
+}
+
+impl Target {
+    /// A `host:port` address, IPv6 addresses in brackets
+    pub fn host(addr: &str) -> Result<Target, String> {
+        let invalid = || format!("Invalid address {}, expected host:port", addr);
+        let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match port.parse() {
+            Ok(port) if port != 0 && !host.is_empty() => Ok(Target::Host(host.to_string(), port)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Target::Host(host, port) => write!(f, "{}:{}", host, port),
+            Target::Srv(name) => write!(f, "SRV {}", name),
+        }
+    }
+}
+
+/// Addresses of a target until the TTL of its records expires
+struct Entry {
+    addrs: Vec<SocketAddr>,
+    /// Leading addresses taking turns, those of the best SRV priority
+    rotated: usize,
+    next: usize,
+    expires: Instant,
+    used: Instant,
+}
+
+impl Entry {
+    /// The addresses starting with the next one in turn
+    fn take(&mut self) -> Vec<SocketAddr> {
+        let mut addrs = self.addrs.clone();
+        if self.rotated > 1 {
+            addrs[..self.rotated].rotate_left(self.next % self.rotated);
+            self.next = self.next.wrapping_add(1);
+        }
+        self.used = Instant::now();
+        addrs
+    }
+}
+
+/// Resolves upstreams with caching, the records of names in use being
+/// re-resolved by `refresh` when their TTL expires. Only the targets of
+/// upstreams are cached.
+pub struct Resolver {
+    dns: TokioResolver,
+    cache: Mutex<HashMap<Target, Entry>>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let cached = self.cache.lock().unwrap().len();
+        f.debug_struct("Resolver").field("cached", &cached).finish()
+    }
+}
+
+impl Resolver {
+    pub fn new(options: &ResolverOptions) -> Resolver {
+        let provider = TokioConnectionProvider::default();
+        let mut builder = match options.nameservers.is_empty() {
+            true => match TokioResolver::builder(provider.clone()) {
+                Ok(builder) => builder,
+                Err(err) => {
+                    error!("Failed to read the system DNS config: {}", err);
+                    let config = ResolverConfig::from_parts(None, Vec::new(), Vec::new());
+                    TokioResolver::builder_with_config(config, provider)
+                }
+            },
+            false => {
+                let mut config = ResolverConfig::new();
+                for addr in &options.nameservers {
+                    config.add_name_server(NameServerConfig::new(*addr, Protocol::Udp));
+                    config.add_name_server(NameServerConfig::new(*addr, Protocol::Tcp));
+                }
+                TokioResolver::builder_with_config(config, provider)
+            }
+        };
+        let opts: &mut ResolverOpts = builder.options_mut();
+        // Both families for Happy Eyeballs, not AAAA only when A fails
+        opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        opts.positive_min_ttl = options.min_ttl;
+        opts.positive_max_ttl = options.max_ttl;
+        Resolver {
+            dns: builder.build(),
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Addresses of `target`, starting with a different one on each call to
+    /// spread connections. Addresses are kept when resolving them again
+    /// fails.
+    pub async fn resolve(&self, target: &Target) -> io::Result<Vec<SocketAddr>> {
+        if let Target::Host(host, port) = target {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                return Ok(vec![SocketAddr::new(ip, *port)]);
+            }
+        }
+        if let Some(entry) = self.cache.lock().unwrap().get_mut(target) {
+            if entry.expires > Instant::now() {
+                return Ok(entry.take());
+            }
+        }
+
+        let result = self.lookup(target).await;
+        let mut cache = self.cache.lock().unwrap();
+        match result {
+            Ok(mut entry) => {
+                let addrs = entry.take();
+                cache.insert(target.clone(), entry);
+                Ok(addrs)
+            }
+            Err(err) => match cache.get_mut(target) {
+                Some(stale) => {
+                    warn!(
+                        "Failed to resolve {}, using stale addresses: {}",
+                        target, err
+                    );
+                    stale.expires = Instant::now() + RETRY_DELAY;
+                    Ok(stale.take())
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Addresses of a name asked by a client of a proxy server. They are
+    /// only cached by the DNS resolver, whose cache is bounded, as clients
+    /// may ask for any number of names.
+    pub async fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let (ips, _) = self.lookup_ip(host).await?;
+        if ips.is_empty() {
+            return Err(not_found(&Target::Host(host.to_string(), port)));
+        }
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Addresses of `target` in the order they should be tried, only those
+    /// of the pinned family. SRV records keep their priority order.
+    pub async fn addresses(
+        &self,
+        target: &Target,
+        options: &ConnectOptions,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let mut addrs = self.resolve(target).await?;
+        if let Some(family) = options.family {
+            addrs.retain(|addr| Family::of(addr) == family);
+        }
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "No {} address found for {}",
+                    options.family.unwrap(),
+                    target
+                ),
+            ));
+        }
+        match target {
+            Target::Host(..) => Ok(interleave(addrs, options.prefer)),
+            Target::Srv(..) => Ok(addrs),
+        }
+    }
+
+    /// Connect to `target` with Happy Eyeballs
+    pub async fn connect(
+        &self,
+        target: &Target,
+        options: &ConnectOptions,
+    ) -> io::Result<TcpStream> {
+        let addrs = self.addresses(target, options).await?;
+        connect(addrs, options.delay).await
+    }
+
+    /// Re-resolve names in use when their records expire, forever
+    pub async fn refresh(self: Arc<Self>) {
+        let mut interval = time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Instant::now();
+            let expired: Vec<Target> = {
+                let mut cache = self.cache.lock().unwrap();
+                cache.retain(|_, entry| entry.expires > now || now - entry.used < IDLE_TIMEOUT);
+                cache
+                    .iter()
+                    .filter(|(_, entry)| entry.expires <= now)
+                    .map(|(target, _)| target.clone())
+                    .collect()
+            };
+            for target in expired {
+                let result = self.lookup(&target).await;
+                let mut cache = self.cache.lock().unwrap();
+                let entry = match cache.get_mut(&target) {
+                    Some(entry) => entry,
+                    None => continue,
+                };
+                match result {
+                    Ok(fresh) => {
+                        if fresh.addrs != entry.addrs {
+                            debug!("Resolved {} to {:?}", target, fresh.addrs);
+                        }
+                        entry.addrs = fresh.addrs;
+                        entry.rotated = fresh.rotated;
+                        entry.expires = fresh.expires;
+                    }
+                    Err(err) => {
+                        warn!("Failed to resolve {} again: {}", target, err);
+                        entry.expires = Instant::now() + RETRY_DELAY;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn lookup(&self, target: &Target) -> io::Result<Entry> {
+        let (addrs, rotated, expires) = match target {
+            Target::Host(host, port) => {
+                let (ips, expires) = self.lookup_ip(host).await?;
+                let addrs: Vec<SocketAddr> = ips
+                    .into_iter()
+                    .map(|ip| SocketAddr::new(ip, *port))
+                    .collect();
+                (addrs.clone(), addrs.len(), expires)
+            }
+            Target::Srv(name) => {
+                let lookup = self
+                    .dns
+                    .srv_lookup(name.as_str())
+                    .await
+                    .map_err(dns_error)?;
+                let mut expires = lookup.as_lookup().valid_until();
+                let mut records: Vec<_> = lookup.iter().cloned().collect();
+                // Lowest priority first, then highest weight
+                records.sort_by_key(|srv| (srv.priority(), u16::MAX - srv.weight()));
+                let best = records.first().map(|srv| srv.priority());
+                let mut addrs = Vec::new();
+                let mut rotated = 0;
+                for srv in records {
+                    let host = srv.target().to_utf8();
+                    let (ips, valid_until) = match self.lookup_ip(&host).await {
+                        Ok(ips) => ips,
+                        Err(err) => {
+                            debug!("Failed to resolve {} of {}: {}", host, name, err);
+                            continue;
+                        }
+                    };
+                    expires = expires.min(valid_until);
+                    for ip in ips {
+                        addrs.push(SocketAddr::new(ip, srv.port()));
+                        if Some(srv.priority()) == best {
+                            rotated += 1;
+                        }
+                    }
+                }
+                (addrs, rotated, expires)
+            }
+        };
+        if addrs.is_empty() {
+            return Err(not_found(target));
+        }
+        debug!("Resolved {} to {:?}", target, addrs);
+        let now = Instant::now();
+        Ok(Entry {
+            addrs,
+            rotated,
+            next: 0,
+            expires,
+            used: now,
+        })
+    }
+
+    async fn lookup_ip(&self, host: &str) -> io::Result<(Vec<IpAddr>, Instant)> {
+        let lookup = self.dns.lookup_ip(host).await.map_err(dns_error)?;
+        Ok((lookup.iter().collect(), lookup.valid_until()))
+    }
+}
+
+/// Order `addrs` with families taking turns, starting with `prefer`, each
+/// family keeping its order
+pub fn interleave(addrs: Vec<SocketAddr>, prefer: Family) -> Vec<SocketAddr> {
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| Family::of(addr) == prefer);
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    loop {
+        match (first.pop_front(), second.pop_front()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to `addrs` in order, starting the next attempt when the pending
+/// ones take longer than `delay` or fail. The first connection wins.
+pub async fn connect(addrs: Vec<SocketAddr>, delay: Duration) -> io::Result<TcpStream> {
+    race(addrs, delay, TcpStream::connect).await
+}
+
+async fn race<T, F, Fut>(addrs: Vec<SocketAddr>, delay: Duration, connect: F) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            let attempt = connect(addr);
+            attempts.push(async move { (addr, attempt.await) });
+        }
+        loop {
+            let more = pending.len() > 0;
+            tokio::select! {
+                finished = attempts.next() => match finished {
+                    Some((_, Ok(stream))) => return Ok(stream),
+                    Some((addr, Err(err))) => {
+                        debug!("Failed to connect to {}: {}", addr, err);
+                        last_err = Some(err);
+                        if more {
+                            break;
+                        }
+                    }
+                    None => {
+                        return Err(last_err.unwrap_or_else(|| {
+                            io::Error::new(io::ErrorKind::NotFound, "No address to connect to")
+                        }))
+                    }
+                },
+                _ = time::sleep(delay), if more => break,
+            }
+        }
+    }
+}
+
+fn dns_error(err: hickory_resolver::ResolveError) -> io::Error {
+    match err.is_no_records_found() {
+        true => io::Error::new(io::ErrorKind::NotFound, err.to_string()),
+        false => io::Error::other(err.to_string()),
+    }
+}
+
+fn not_found(target: &Target) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No address found for {}", target),
+    )
+}
+
+/// A DNS server answering from a zone that tests can change
+#[cfg(test)]
+pub mod test_dns {
+    use hickory_resolver::proto::op::{Message, MessageType, ResponseCode};
+    use hickory_resolver::proto::rr::rdata::{A, AAAA, SRV};
+    use hickory_resolver::proto::rr::{Name, RData, Record, RecordType};
+    use std::collections::HashMap;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use tokio::net::UdpSocket;
+
+    pub const TTL: u32 = 1;
+
+    #[derive(Default)]
+    pub struct Zone {
+        records: Mutex<HashMap<(String, RecordType), Vec<RData>>>,
+        /// Queries received for each name
+        queries: Mutex<HashMap<String, usize>>,
+        pub total: AtomicUsize,
+    }
+
+    impl Zone {
+        pub fn set_a(&self, name: &str, ips: &[Ipv4Addr]) {
+            let rdata = ips.iter().map(|ip| RData::A(A(*ip))).collect();
+            let key = (fqdn(name), RecordType::A);
+            self.records.lock().unwrap().insert(key, rdata);
+        }
+
+        pub fn set_aaaa(&self, name: &str, ips: &[Ipv6Addr]) {
+            let rdata = ips.iter().map(|ip| RData::AAAA(AAAA(*ip))).collect();
+            let key = (fqdn(name), RecordType::AAAA);
+            self.records.lock().unwrap().insert(key, rdata);
+        }
+
+        /// `(priority, weight, port, target)` records
+        pub fn set_srv(&self, name: &str, records: &[(u16, u16, u16, &str)]) {
+            let rdata = records
+                .iter()
+                .map(|(priority, weight, port, target)| {
+                    let target = Name::from_str(&fqdn(target)).unwrap();
+                    RData::SRV(SRV::new(*priority, *weight, *port, target))
+                })
+                .collect();
+            let key = (fqdn(name), RecordType::SRV);
+            self.records.lock().unwrap().insert(key, rdata);
+        }
+
+        pub fn queries(&self, name: &str) -> usize {
+            let queries = self.queries.lock().unwrap();
+            queries.get(&fqdn(name)).copied().unwrap_or(0)
+        }
+    }
+
+    fn fqdn(name: &str) -> String {
+        format!("{}.", name.trim_end_matches('.'))
+    }
+
+    pub async fn serve(addr: SocketAddr, zone: Arc<Zone>) {
+        let socket = UdpSocket::bind(addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let request = match Message::from_vec(&buf[..n]) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+            let mut response = Message::new();
+            response
+                .set_id(request.id())
+                .set_message_type(MessageType::Response)
+                .set_op_code(request.op_code())
+                .set_recursion_desired(request.recursion_desired())
+                .set_authoritative(true);
+            let mut found = false;
+            for query in request.queries() {
+                response.add_query(query.clone());
+                let name = query.name().to_string().to_lowercase();
+                *zone
+                    .queries
+                    .lock()
+                    .unwrap()
+                    .entry(name.clone())
+                    .or_default() += 1;
+                zone.total.fetch_add(1, Ordering::Relaxed);
+                let records = zone.records.lock().unwrap();
+                if let Some(rdata) = records.get(&(name, query.query_type())) {
+                    found = true;
+                    for rdata in rdata {
+                        let record = Record::from_rdata(query.name().clone(), TTL, rdata.clone());
+                        response.add_answer(record);
+                    }
+                } else if records
+                    .keys()
+                    .any(|(known, _)| *known == query.name().to_string())
+                {
+                    found = true;
+                }
+            }
+            if !found {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+            let _ = socket.send_to(&response.to_vec().unwrap(), peer).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_dns::{self, Zone};
+    use super::*;
+
+    #[tokio::test]
+    async fn test_resolver() {
+        let dns_addr: SocketAddr = "127.0.0.1:54991".parse().unwrap();
+        let zone = Arc::new(Zone::default());
+        zone.set_a(
+            "app.fourth.test",
+            &["127.0.0.1".parse().unwrap(), "127.0.0.2".parse().unwrap()],
+        );
+        zone.set_a("backup.fourth.test", &["127.0.0.3".parse().unwrap()]);
+        zone.set_srv(
+            "_app._tcp.fourth.test",
+            &[
+                (20, 0, 9000, "backup.fourth.test"),
+                (10, 5, 8080, "app.fourth.test"),
+            ],
+        );
+        tokio::spawn(test_dns::serve(dns_addr, zone.clone()));
+
+        let resolver = Resolver::new(&ResolverOptions {
+            nameservers: vec![dns_addr],
+            ..Default::default()
+        });
+
+        // Literal addresses are not looked up
+        let target = Target::host("[::1]:8080").unwrap();
+        let addrs = resolver.resolve(&target).await.unwrap();
+        assert_eq!(addrs, vec!["[::1]:8080".parse().unwrap()]);
+
+        // Round robin over the addresses, cached until the TTL expires
+        let target = Target::host("app.fourth.test:80").unwrap();
+        let first = resolver.resolve(&target).await.unwrap();
+        let second = resolver.resolve(&target).await.unwrap();
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0], second[1]);
+        // One A and one AAAA query
+        assert_eq!(zone.queries("app.fourth.test"), 2);
+
+        // The best priority first, the others as fallback
+        let srv = Target::Srv("_app._tcp.fourth.test".to_string());
+        let addrs = resolver.resolve(&srv).await.unwrap();
+        assert_eq!(addrs.len(), 3);
+        assert_eq!(addrs[2], "127.0.0.3:9000".parse().unwrap());
+        assert_eq!(addrs[0].port(), 8080);
+
+        // Records are resolved again after their TTL, stale ones kept on failure
+        zone.set_a("app.fourth.test", &["127.0.0.4".parse().unwrap()]);
+        time::sleep(Duration::from_millis(1100 * test_dns::TTL as u64)).await;
+        let addrs = resolver.resolve(&target).await.unwrap();
+        assert_eq!(addrs, vec!["127.0.0.4:80".parse().unwrap()]);
+
+        let options = ConnectOptions {
+            family: Some(Family::Ipv6),
+            ..Default::default()
+        };
+        assert!(resolver.addresses(&target, &options).await.is_err());
+
+        // Both families are resolved, taking turns from the preferred one
+        zone.set_a(
+            "dual.fourth.test",
+            &["127.0.0.5".parse().unwrap(), "127.0.0.6".parse().unwrap()],
+        );
+        zone.set_aaaa("dual.fourth.test", &["::5".parse().unwrap()]);
+        let dual = Target::host("dual.fourth.test:80").unwrap();
+        let addrs = resolver.addresses(&dual, &ConnectOptions::default()).await;
+        let expected: Vec<SocketAddr> = ["[::5]:80", "127.0.0.5:80", "127.0.0.6:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let mut addrs = addrs.unwrap();
+        // Addresses of a family take turns between calls
+        addrs[1..].sort();
+        assert_eq!(addrs, expected);
+        let addrs = resolver.addresses(&dual, &options).await.unwrap();
+        assert_eq!(addrs, vec![expected[0]]);
+
+        let missing = Target::host("missing.fourth.test:80").unwrap();
+        let err = resolver.resolve(&missing).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // Names of clients are not added to the cache
+        let cached = resolver.cache.lock().unwrap().len();
+        let addrs = resolver.lookup_host("backup.fourth.test", 443).await;
+        assert_eq!(addrs.unwrap(), vec!["127.0.0.3:443".parse().unwrap()]);
+        assert_eq!(resolver.cache.lock().unwrap().len(), cached);
+        assert!(resolver
+            .lookup_host("missing.fourth.test", 443)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_target_host() {
+        assert_eq!(
+            Target::host("[::1]:8080"),
+            Ok(Target::Host("::1".to_string(), 8080))
+        );
+        assert!(Target::host("app.internal").is_err());
+        assert!(Target::host("app.internal:").is_err());
+        assert!(Target::host("app.internal:0").is_err());
+        assert!(Target::host(":80").is_err());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:80", "10.0.0.2:80", "[::1]:80", "10.0.0.3:80"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ordered = interleave(addrs.clone(), Family::Ipv6);
+        assert_eq!(ordered, vec![addrs[2], addrs[0], addrs[1], addrs[3]]);
+        let ordered = interleave(addrs.clone(), Family::Ipv4);
+        assert_eq!(ordered, vec![addrs[0], addrs[2], addrs[1], addrs[3]]);
+    }
+
+    #[tokio::test]
+    async fn test_race() {
+        let hanging: SocketAddr = "[::1]:1".parse().unwrap();
+        let failing: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let working: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let connect = |addr: SocketAddr| async move {
+            match addr {
+                addr if addr == hanging => std::future::pending().await,
+                addr if addr == failing => Err(io::Error::from(io::ErrorKind::ConnectionRefused)),
+                addr => Ok(addr),
+            }
+        };
+
+        // A hanging attempt delays the next one, a failing one does not
+        let started = Instant::now();
+        let addrs = vec![hanging, failing, working];
+        let delay = Duration::from_millis(200);
+        assert_eq!(race(addrs, delay, connect).await.unwrap(), working);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= delay && elapsed < delay * 2, "{:?}", elapsed);
+
+        let err = race(vec![failing], delay, connect).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(race(Vec::new(), delay, connect).await.is_err());
+    }
+}
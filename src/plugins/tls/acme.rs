@@ -0,0 +1,797 @@
+//! Certificates issued and renewed by an ACME server, with tls-alpn-01 or
+//! http-01 challenges answered by fourth itself.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount,
+    NewOrder, OrderStatus, RetryPolicy,
+};
+use log::{debug, error, info, warn};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::{
+    crypto::aws_lc_rs::sign::any_supported_type,
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use x509_parser::parse_x509_certificate;
+
+use super::{pem::invalid_data, server::load_certified_key};
+
+/// ALPN protocol of tls-alpn-01 validation connections
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq)]
+pub enum AcmeChallenge {
+    #[default]
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+    #[serde(rename = "http-01")]
+    Http01,
+}
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// Directory URL of the ACME server
+    pub directory: String,
+    pub contact: Vec<String>,
+    pub domains: Vec<String>,
+    pub challenge: AcmeChallenge,
+    /// Where http-01 challenges are answered
+    pub http_listen: Option<SocketAddr>,
+    /// Directory keeping the account and the certificates
+    pub storage: PathBuf,
+    /// Root certificate of the ACME server, for test servers like Pebble
+    pub ca: Option<String>,
+    /// Renew certificates expiring within this duration
+    pub renew_before: Duration,
+}
+
+pub(super) type CertMap = Arc<RwLock<HashMap<String, Arc<CertifiedKey>>>>;
+
+/// Answers tls-alpn-01 validation handshakes with the challenge certificate
+/// of the SNI.
+#[derive(Debug)]
+struct ChallengeResolver {
+    challenges: CertMap,
+}
+
+impl ResolvesServerCert for ChallengeResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?;
+        self.challenges.read().unwrap().get(name).cloned()
+    }
+}
+
+/// Obtains and renews the certificates of a server
+#[derive(Debug)]
+pub struct AcmeManager {
+    config: AcmeConfig,
+    /// Issued certificates by domain, hot-swapped on renewal
+    certs: CertMap,
+    /// tls-alpn-01 challenge certificates by domain
+    challenges: CertMap,
+    /// http-01 key authorizations by token
+    tokens: RwLock<HashMap<String, String>>,
+    challenge_config: Arc<ServerConfig>,
+}
+
+impl AcmeManager {
+    /// Certificates already in storage are loaded right away
+    pub fn new(config: AcmeConfig) -> AcmeManager {
+        let challenges: CertMap = Arc::default();
+        let mut challenge_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(ChallengeResolver {
+                challenges: challenges.clone(),
+            }));
+        challenge_config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+
+        let manager = AcmeManager {
+            config,
+            certs: Arc::default(),
+            challenges,
+            tokens: RwLock::default(),
+            challenge_config: Arc::new(challenge_config),
+        };
+        for domain in &manager.config.domains {
+            if let Some((cert, _)) = manager.load(domain) {
+                manager.install(domain, cert);
+            }
+        }
+        manager
+    }
+
+    /// Issued certificates by domain
+    pub fn certs(&self) -> CertMap {
+        self.certs.clone()
+    }
+
+    /// Complete a tls-alpn-01 validation handshake, no data is relayed
+    pub async fn accept_challenge<S>(&self, stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = TlsAcceptor::from(self.challenge_config.clone())
+            .accept(stream)
+            .await?;
+        debug!(
+            "[ACME] answered tls-alpn-01 validation for {:?}",
+            stream.get_ref().1.server_name()
+        );
+        stream.shutdown().await
+    }
+
+    /// Keep certificates valid until the task is aborted, which also stops
+    /// answering http-01 challenges
+    pub async fn run(self: Arc<Self>) {
+        let http = async {
+            if self.config.challenge == AcmeChallenge::Http01 {
+                match self.config.http_listen {
+                    Some(listen) => self.clone().serve_http(listen).await,
+                    None => error!("[ACME] http-01 challenges need http_listen"),
+                }
+            }
+            // Renewals go on even when challenges cannot be answered
+            std::future::pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = http => {}
+            _ = self.renew_all() => {}
+        }
+    }
+
+    async fn renew_all(&self) {
+        loop {
+            let mut interval = CHECK_INTERVAL;
+            for domain in &self.config.domains {
+                if !self.needs_renewal(domain) {
+                    continue;
+                }
+                match self.renew(domain).await {
+                    Ok(()) => info!("[ACME] issued certificate for {}", domain),
+                    Err(err) => {
+                        error!("[ACME] failed to issue certificate for {}: {}", domain, err);
+                        interval = RETRY_INTERVAL;
+                    }
+                }
+            }
+            time::sleep(interval).await;
+        }
+    }
+
+    fn domain_dir(&self, domain: &str) -> PathBuf {
+        self.config.storage.join(domain)
+    }
+
+    /// Load the stored certificate of a domain and its expiry
+    fn load(&self, domain: &str) -> Option<(CertifiedKey, SystemTime)> {
+        let dir = self.domain_dir(domain);
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        if !cert_path.exists() {
+            return None;
+        }
+
+        let cert = match load_certified_key(
+            &cert_path.to_string_lossy(),
+            &key_path.to_string_lossy(),
+        ) {
+            Ok(cert) => cert,
+            Err(err) => {
+                warn!("[ACME] ignored stored certificate of {}: {}", domain, err);
+                return None;
+            }
+        };
+        let expiry = expires_at(cert.cert.first()?)?;
+        Some((cert, expiry))
+    }
+
+    fn install(&self, domain: &str, cert: CertifiedKey) {
+        self.certs
+            .write()
+            .unwrap()
+            .insert(domain.to_string(), Arc::new(cert));
+    }
+
+    fn needs_renewal(&self, domain: &str) -> bool {
+        match self.load(domain) {
+            Some((_, expiry)) => expiry
+                .duration_since(SystemTime::now())
+                .map_or(true, |left| left < self.config.renew_before),
+            None => true,
+        }
+    }
+
+    async fn account(&self) -> Result<Account, Box<dyn Error + Send + Sync>> {
+        let builder = match &self.config.ca {
+            Some(ca) => Account::builder_with_root(ca)?,
+            None => Account::builder()?,
+        };
+
+        let path = self.config.storage.join("account.json");
+        if path.exists() {
+            let credentials: AccountCredentials = serde_json::from_slice(&fs::read(&path)?)?;
+            return Ok(builder.from_credentials(credentials).await?);
+        }
+
+        let contact: Vec<&str> = self.config.contact.iter().map(|c| c.as_str()).collect();
+        let (account, credentials) = builder
+            .create(
+                &NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.config.directory.clone(),
+                None,
+            )
+            .await?;
+        fs::create_dir_all(&self.config.storage)?;
+        write_private(&path, &serde_json::to_vec(&credentials)?)?;
+        info!("[ACME] created account {}", account.id());
+        Ok(account)
+    }
+
+    async fn renew(&self, domain: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let account = self.account().await?;
+        let mut tokens = Vec::new();
+        let res = self.order(&account, domain, &mut tokens).await;
+
+        self.challenges.write().unwrap().remove(domain);
+        let mut pending = self.tokens.write().unwrap();
+        for token in tokens {
+            pending.remove(&token);
+        }
+        drop(pending);
+
+        let (chain_pem, key_pem) = res?;
+        let dir = self.domain_dir(domain);
+        fs::create_dir_all(&dir)?;
+        write_private(&dir.join("key.pem"), key_pem.as_bytes())?;
+        fs::write(dir.join("cert.pem"), chain_pem)?;
+
+        match self.load(domain) {
+            Some((cert, _)) => {
+                self.install(domain, cert);
+                Ok(())
+            }
+            None => Err("issued certificate could not be loaded".into()),
+        }
+    }
+
+    /// Returns the certificate chain and the private key in PEM
+    async fn order(
+        &self,
+        account: &Account,
+        domain: &str,
+        tokens: &mut Vec<String>,
+    ) -> Result<(String, String), Box<dyn Error + Send + Sync>> {
+        let identifiers = [Identifier::Dns(domain.to_string())];
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let challenge_type = match self.config.challenge {
+            AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+            AcmeChallenge::Http01 => ChallengeType::Http01,
+        };
+
+        let mut authorizations = order.authorizations();
+        while let Some(authz) = authorizations.next().await {
+            let mut authz = authz?;
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => return Err(format!("authorization is {:?}", status).into()),
+            }
+
+            let mut challenge = authz
+                .challenge(challenge_type.clone())
+                .ok_or_else(|| format!("no {:?} challenge offered", challenge_type))?;
+            let key_authorization = challenge.key_authorization();
+            match self.config.challenge {
+                AcmeChallenge::TlsAlpn01 => {
+                    let cert = challenge_cert(domain, key_authorization.digest().as_ref())?;
+                    self.challenges
+                        .write()
+                        .unwrap()
+                        .insert(domain.to_string(), Arc::new(cert));
+                }
+                AcmeChallenge::Http01 => {
+                    self.tokens.write().unwrap().insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_string(),
+                    );
+                    tokens.push(challenge.token.clone());
+                }
+            }
+            challenge.set_ready().await?;
+        }
+
+        let status = order.poll_ready(&RetryPolicy::default()).await?;
+        if status != OrderStatus::Ready {
+            return Err(format!("order is {:?}", status).into());
+        }
+        let key_pem = order.finalize().await?;
+        let chain_pem = order.poll_certificate(&RetryPolicy::default()).await?;
+        Ok((chain_pem, key_pem))
+    }
+
+    async fn serve_http(self: Arc<Self>, listen: SocketAddr) {
+        let listener = match TcpListener::bind(listen).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[ACME] failed to listen on {}: {}", listen, err);
+                return;
+            }
+        };
+        info!("[ACME] answering http-01 challenges on {}", listen);
+
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    error!("[ACME] failed to accept connection: {}", err);
+                    continue;
+                }
+            };
+            let manager = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = manager.answer_http(stream).await {
+                    debug!("[ACME] http-01 connection failed: {}", err);
+                }
+            });
+        }
+    }
+
+    /// Answer one http-01 validation request
+    async fn answer_http<S>(&self, mut stream: S) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = vec![0u8; 4096];
+        let mut len = 0;
+        while !buf[..len].windows(4).any(|end| end == b"\r\n\r\n") {
+            if len == buf.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "request too long"));
+            }
+            let n = stream.read(&mut buf[len..]).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            len += n;
+        }
+
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let key_authorization = request
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("GET "))
+            .and_then(|line| line.split(' ').next())
+            .and_then(|path| path.strip_prefix(HTTP_CHALLENGE_PREFIX))
+            .and_then(|token| self.tokens.read().unwrap().get(token).cloned());
+
+        let response = match key_authorization {
+            Some(body) => format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+            None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        };
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await
+    }
+}
+
+/// Write a file only readable by its owner
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(contents)
+}
+
+/// Self-signed certificate carrying the acmeIdentifier extension
+fn challenge_cert(domain: &str, digest: &[u8]) -> io::Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![domain.to_string()]).map_err(invalid_data)?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let key_pair = KeyPair::generate().map_err(invalid_data)?;
+    let cert = params.self_signed(&key_pair).map_err(invalid_data)?;
+
+    let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let key = any_supported_type(&key).map_err(invalid_data)?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+fn expires_at(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert) = parse_x509_certificate(cert).ok()?;
+    let not_after = cert.validity().not_after.timestamp();
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config(challenge: AcmeChallenge, name: &str) -> AcmeConfig {
+        AcmeConfig {
+            directory: std::env::var("FOURTH_PEBBLE_DIRECTORY")
+                .unwrap_or_else(|_| "https://127.0.0.1:14000/dir".to_string()),
+            contact: Vec::new(),
+            domains: vec!["localhost".to_string()],
+            challenge,
+            http_listen: Some("127.0.0.1:5002".parse().unwrap()),
+            storage: std::env::temp_dir().join(format!("fourth-acme-{}-{}", std::process::id(), name)),
+            ca: std::env::var("FOURTH_PEBBLE_CA").ok(),
+            renew_before: Duration::from_secs(30 * 86400),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_challenge() {
+        let manager = AcmeManager::new(test_config(AcmeChallenge::Http01, "http"));
+        manager
+            .tokens
+            .write()
+            .unwrap()
+            .insert("token".to_string(), "token.thumbprint".to_string());
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        manager.answer_http(server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.ends_with("\r\n\r\ntoken.thumbprint"));
+
+        let (mut client, server) = tokio::io::duplex(4096);
+        client
+            .write_all(b"GET /.well-known/acme-challenge/other HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        manager.answer_http(server).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn test_stored_certificate() {
+        let manager = AcmeManager::new(test_config(AcmeChallenge::TlsAlpn01, "stored"));
+        assert!(manager.needs_renewal("localhost"));
+
+        let dir = manager.domain_dir("localhost");
+        fs::create_dir_all(&dir).unwrap();
+        fs::copy("tests/certs/tls.pem", dir.join("cert.pem")).unwrap();
+        fs::copy("tests/certs/tls.key", dir.join("key.pem")).unwrap();
+        assert!(!manager.needs_renewal("localhost"));
+        assert!(AcmeManager::new(test_config(AcmeChallenge::TlsAlpn01, "stored"))
+            .certs()
+            .read()
+            .unwrap()
+            .contains_key("localhost"));
+
+        let cert = challenge_cert("localhost", &[0u8; 32]).unwrap();
+        assert!(expires_at(&cert.cert[0]).is_some());
+        fs::remove_dir_all(&manager.config.storage).unwrap();
+    }
+
+    #[test]
+    fn test_challenge_cert() {
+        let cert = challenge_cert("localhost", &[7u8; 32]).unwrap();
+        let (_, cert) = parse_x509_certificate(&cert.cert[0]).unwrap();
+        let acme_identifier = cert
+            .extensions()
+            .iter()
+            .find(|ext| ext.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        assert!(acme_identifier.critical);
+        assert!(acme_identifier.value.ends_with(&[7u8; 32]));
+    }
+
+    /// ACME server over HTTPS for one `localhost` order. Signatures are not
+    /// checked, http-01 challenges are fetched from `answer` and certificates
+    /// are issued by a CA generated for the test.
+    struct MockAcme {
+        base: String,
+        answer: SocketAddr,
+        ca: rcgen::Issuer<'static, KeyPair>,
+        ca_pem: String,
+        /// Order status and the issued chain
+        state: std::sync::Mutex<(&'static str, Option<String>)>,
+    }
+
+    impl MockAcme {
+        /// Start the server, returns it and the path of its root certificate
+        async fn start(answer: SocketAddr, storage: &Path) -> (Arc<MockAcme>, PathBuf) {
+            let mut params = CertificateParams::default();
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca_pem = params.self_signed(&ca_key).unwrap().pem();
+            let ca = rcgen::Issuer::new(params, ca_key);
+            fs::create_dir_all(storage).unwrap();
+            let ca_path = storage.join("mock-ca.pem");
+            fs::write(&ca_path, &ca_pem).unwrap();
+
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(vec!["127.0.0.1".to_string()])
+                .unwrap()
+                .signed_by(&key, &ca)
+                .unwrap();
+            let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+            let config = ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.der().clone()], key)
+                .unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(config));
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mock = Arc::new(MockAcme {
+                base: format!("https://{}", listener.local_addr().unwrap()),
+                answer,
+                ca,
+                ca_pem,
+                state: std::sync::Mutex::new(("pending", None)),
+            });
+            let server = mock.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (acceptor, mock) = (acceptor.clone(), server.clone());
+                    tokio::spawn(async move {
+                        if let Ok(stream) = acceptor.accept(stream).await {
+                            let _ = mock.serve(stream).await;
+                        }
+                    });
+                }
+            });
+            (mock, ca_path)
+        }
+
+        async fn serve<S>(&self, stream: S) -> io::Result<()>
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            use tokio::io::AsyncBufReadExt;
+
+            let mut stream = tokio::io::BufReader::new(stream);
+            loop {
+                let mut line = String::new();
+                if stream.read_line(&mut line).await? == 0 {
+                    return Ok(());
+                }
+                let mut request = line.split(' ');
+                let method = request.next().unwrap_or_default().to_string();
+                let path = request.next().unwrap_or_default().to_string();
+                let mut len = 0;
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).await?;
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap_or(0);
+                        }
+                    }
+                }
+                let mut body = vec![0u8; len];
+                stream.read_exact(&mut body).await?;
+
+                let (status, location, body) = self.handle(&path, &body).await;
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nReplay-Nonce: nonce\r\nContent-Length: {}\r\n",
+                    status,
+                    body.len()
+                );
+                if let Some(location) = location {
+                    response += &format!("Location: {}\r\n", location);
+                }
+                response += "\r\n";
+                if method != "HEAD" {
+                    response += &body;
+                }
+                stream.write_all(response.as_bytes()).await?;
+            }
+        }
+
+        async fn handle(&self, path: &str, body: &[u8]) -> (&str, Option<String>, String) {
+            use base64::Engine;
+
+            let base = &self.base;
+            let payload = serde_json::from_slice::<serde_json::Value>(body)
+                .ok()
+                .and_then(|jws| {
+                    let payload = jws["payload"].as_str()?.to_string();
+                    base64::engine::general_purpose::URL_SAFE_NO_PAD
+                        .decode(payload)
+                        .ok()
+                })
+                .and_then(|payload| serde_json::from_slice::<serde_json::Value>(&payload).ok());
+            let challenge = |status| {
+                serde_json::json!({
+                    "type": "http-01",
+                    "url": format!("{}/chall", base),
+                    "token": "token",
+                    "status": status,
+                })
+            };
+
+            match path {
+                "/dir" => {
+                    let directory = serde_json::json!({
+                        "newNonce": format!("{}/nonce", base),
+                        "newAccount": format!("{}/account", base),
+                        "newOrder": format!("{}/order", base),
+                    });
+                    ("200 OK", None, directory.to_string())
+                }
+                "/nonce" => ("200 OK", None, String::new()),
+                "/account" => (
+                    "201 Created",
+                    Some(format!("{}/account/1", base)),
+                    "{\"status\":\"valid\"}".to_string(),
+                ),
+                "/order" => (
+                    "201 Created",
+                    Some(format!("{}/order/1", base)),
+                    self.order(),
+                ),
+                "/order/1" => ("200 OK", None, self.order()),
+                "/authz" => {
+                    let status = match self.state.lock().unwrap().0 {
+                        "ready" | "valid" => "valid",
+                        status => status,
+                    };
+                    let authz = serde_json::json!({
+                        "identifier": {"type": "dns", "value": "localhost"},
+                        "status": status,
+                        "challenges": [challenge(status)],
+                    });
+                    ("200 OK", None, authz.to_string())
+                }
+                "/chall" => {
+                    let (order, status) = match self.validate().await {
+                        true => ("ready", "valid"),
+                        false => ("invalid", "invalid"),
+                    };
+                    self.state.lock().unwrap().0 = order;
+                    ("200 OK", None, challenge(status).to_string())
+                }
+                "/finalize" => {
+                    let csr = payload
+                        .as_ref()
+                        .and_then(|payload| payload["csr"].as_str())
+                        .and_then(|csr| {
+                            base64::engine::general_purpose::URL_SAFE_NO_PAD
+                                .decode(csr)
+                                .ok()
+                        })
+                        .unwrap_or_default();
+                    let csr =
+                        rcgen::CertificateSigningRequestParams::from_der(&csr.into()).unwrap();
+                    let cert = csr.signed_by(&self.ca).unwrap();
+                    *self.state.lock().unwrap() =
+                        ("valid", Some(format!("{}{}", cert.pem(), self.ca_pem)));
+                    ("200 OK", None, self.order())
+                }
+                "/cert" => {
+                    let chain = self.state.lock().unwrap().1.clone().unwrap_or_default();
+                    ("200 OK", None, chain)
+                }
+                _ => (
+                    "404 Not Found",
+                    None,
+                    "{\"type\":\"urn:ietf:params:acme:error:malformed\"}".to_string(),
+                ),
+            }
+        }
+
+        fn order(&self) -> String {
+            let state = self.state.lock().unwrap();
+            serde_json::json!({
+                "status": state.0,
+                "identifiers": [{"type": "dns", "value": "localhost"}],
+                "authorizations": [format!("{}/authz", self.base)],
+                "finalize": format!("{}/finalize", self.base),
+                "certificate": state.1.as_ref().map(|_| format!("{}/cert", self.base)),
+            })
+            .to_string()
+        }
+
+        /// Fetch the key authorization of the challenge from the client
+        async fn validate(&self) -> bool {
+            let mut stream = match tokio::net::TcpStream::connect(self.answer).await {
+                Ok(stream) => stream,
+                Err(_) => return false,
+            };
+            let request =
+                "GET /.well-known/acme-challenge/token HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            let mut response = String::new();
+            if stream.write_all(request.as_bytes()).await.is_err()
+                || stream.read_to_string(&mut response).await.is_err()
+            {
+                return false;
+            }
+            response.starts_with("HTTP/1.1 200") && response.contains("\r\n\r\ntoken.")
+        }
+    }
+
+    #[tokio::test]
+    async fn test_issue_http01() {
+        // Both providers are built for tests, instant-acme uses the default one
+
+        let answer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = test_config(AcmeChallenge::Http01, "mock");
+        let (mock, ca) = MockAcme::start(answer.local_addr().unwrap(), &config.storage).await;
+        config.directory = format!("{}/dir", mock.base);
+        config.ca = Some(ca.to_string_lossy().to_string());
+        config.http_listen = Some("127.0.0.1:0".parse().unwrap());
+        let manager = Arc::new(AcmeManager::new(config));
+        let answering = manager.clone();
+        let answers = tokio::spawn(async move {
+            while let Ok((stream, _)) = answer.accept().await {
+                let _ = answering.answer_http(stream).await;
+            }
+        });
+
+        assert!(manager.needs_renewal("localhost"));
+        manager.renew("localhost").await.unwrap();
+        assert!(manager.certs().read().unwrap().contains_key("localhost"));
+        assert!(!manager.needs_renewal("localhost"));
+        assert!(manager.config.storage.join("account.json").exists());
+        assert!(manager.tokens.read().unwrap().is_empty());
+        answers.abort();
+        let _ = answers.await;
+
+        // Stopping the task also stops answering http-01 challenges
+        let task = tokio::spawn(manager.clone().run());
+        time::sleep(Duration::from_millis(50)).await;
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(Arc::strong_count(&manager), 1);
+        fs::remove_dir_all(&manager.config.storage).unwrap();
+    }
+
+    /// Needs a Pebble server, e.g. `pebble -config test/config/pebble-config.json`
+    /// with `FOURTH_PEBBLE_CA` set to its `test/certs/pebble.minica.pem`.
+    #[tokio::test]
+    #[ignore]
+    async fn test_pebble_http01() {
+        let manager = Arc::new(AcmeManager::new(test_config(AcmeChallenge::Http01, "pebble")));
+        tokio::spawn(manager.clone().serve_http("127.0.0.1:5002".parse().unwrap()));
+        manager.renew("localhost").await.unwrap();
+        assert!(manager.certs().read().unwrap().contains_key("localhost"));
+        assert!(!manager.needs_renewal("localhost"));
+        fs::remove_dir_all(&manager.config.storage).unwrap();
+    }
+}
@@ -0,0 +1,818 @@
+//! Encrypted ClientHello, as the client-facing server of ECH split mode:
+//! the inner ClientHello is decrypted and relayed to the backend in place of
+//! the outer one.
+
+use std::{
+    fmt, io,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+};
+
+use rustls::{
+    crypto::{
+        aws_lc_rs::hpke::ALL_SUPPORTED_SUITES,
+        hpke::{EncapsulatedSecret, Hpke, HpkeOpener, HpkePrivateKey, HpkePublicKey},
+    },
+    pki_types::{
+        pem::{PemObject, SectionKind},
+        EchConfigListBytes,
+    },
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::pem::invalid_data;
+
+const ECH_VERSION: u16 = 0xfe0d;
+const EXT_ECH: u16 = 0xfe0d;
+const EXT_ECH_OUTER_EXTENSIONS: u16 = 0xfd00;
+const ECH_OUTER: u8 = 0;
+const ECH_INNER: u8 = 1;
+
+const KEM_X25519_HKDF_SHA256: u16 = 0x0020;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const MAX_RECORD_LEN: usize = 16384;
+
+/// PKCS#8 wrapping of a X25519 private key
+const PKCS8_X25519_OID: [u8; 5] = [0x06, 0x03, 0x2b, 0x65, 0x6e];
+const PKCS8_X25519_KEY: [u8; 4] = [0x04, 0x22, 0x04, 0x20];
+
+/// One ECHConfig and its private key
+#[derive(Clone)]
+struct EchKey {
+    config_id: u8,
+    /// `"tls ech\0"` and the serialized ECHConfig
+    info: Vec<u8>,
+    public_name: String,
+    /// HPKE of the supported cipher suites
+    suites: Vec<&'static dyn Hpke>,
+    secret: Arc<HpkePrivateKey>,
+}
+
+impl fmt::Debug for EchKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EchKey")
+            .field("config_id", &self.config_id)
+            .field("public_name", &self.public_name)
+            .finish()
+    }
+}
+
+/// ECH keys of a server
+#[derive(Debug, Clone, Default)]
+pub struct EchKeys {
+    keys: Vec<EchKey>,
+}
+
+impl EchKeys {
+    /// Load ECHConfig files: a PKCS#8 X25519 `PRIVATE KEY` and the
+    /// `ECHCONFIG` list it belongs to
+    pub fn load(paths: &[String]) -> io::Result<EchKeys> {
+        let mut keys = Vec::new();
+        for path in paths {
+            let sections = <(SectionKind, Vec<u8>)>::pem_file_iter(path).map_err(invalid_data)?;
+            let (list, key) =
+                EchConfigListBytes::config_and_key_from_iter(sections).map_err(invalid_data)?;
+            let secret = x25519_secret(key.secret_pkcs8_der()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: expected a PKCS#8 X25519 private key", path),
+                )
+            })?;
+            let secret = Arc::new(HpkePrivateKey::from(secret.to_vec()));
+
+            let configs = parse_config_list(&list).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: invalid ECHConfigList", path),
+                )
+            })?;
+            let configs: Vec<EchKey> = configs
+                .into_iter()
+                .filter_map(|config| {
+                    let suites: Vec<&'static dyn Hpke> = config
+                        .suites
+                        .iter()
+                        .filter_map(|&suite| hpke(config.kem, suite))
+                        .collect();
+                    let hpke = suites.first()?;
+                    if !is_key_pair(*hpke, &config.public_key, &secret) {
+                        return None;
+                    }
+                    Some(EchKey {
+                        config_id: config.config_id,
+                        info: [&b"tls ech\0"[..], &config.config].concat(),
+                        public_name: config.public_name,
+                        suites,
+                        secret: secret.clone(),
+                    })
+                })
+                .collect();
+            if configs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: no supported ECHConfig matches the private key", path),
+                ));
+            }
+            keys.extend(configs);
+        }
+        Ok(EchKeys { keys })
+    }
+
+    /// Decrypt the ClientHello of `record`, a whole TLS record. `None` when
+    /// the hello carries no ECH for these keys, e.g. GREASE or a stale
+    /// config.
+    pub fn decrypt(&self, record: &[u8]) -> Option<EchAccepted> {
+        let outer = OuterHello::parse(record)?;
+        let enc = EncapsulatedSecret(outer.enc.to_vec());
+        let aad = outer.aad();
+        self.keys
+            .iter()
+            .filter(|key| key.config_id == outer.config_id)
+            .find_map(|key| {
+                let hpke = key
+                    .suites
+                    .iter()
+                    .find(|hpke| suite_id(**hpke) == outer.suite)?;
+                let mut opener = hpke.setup_opener(&enc, &key.info, &key.secret).ok()?;
+                let encoded = opener.open(&aad, outer.payload).ok()?;
+                let inner = decode_inner(&encoded, &outer.hello)?;
+                Some(EchAccepted {
+                    hello: handshake_records(&inner),
+                    opener,
+                    config_id: outer.config_id,
+                    suite: outer.suite,
+                })
+            })
+    }
+}
+
+/// An accepted ECH: the inner ClientHello, and the HPKE context the
+/// ClientHello sent again after a HelloRetryRequest is encrypted with
+#[derive(Debug)]
+pub struct EchAccepted {
+    /// The inner ClientHello as TLS records
+    pub hello: Vec<u8>,
+    opener: Box<dyn HpkeOpener>,
+    config_id: u8,
+    suite: (u16, u16),
+}
+
+impl EchAccepted {
+    /// Decrypt the second ClientHello of `record`, a whole TLS record. It
+    /// has to use the same config and cipher suite, with an empty `enc`.
+    fn retry(&mut self, record: &[u8]) -> Option<Vec<u8>> {
+        let outer = OuterHello::parse(record)?;
+        if outer.config_id != self.config_id || outer.suite != self.suite || !outer.enc.is_empty() {
+            return None;
+        }
+        let encoded = self.opener.open(&outer.aad(), outer.payload).ok()?;
+        let inner = decode_inner(&encoded, &outer.hello)?;
+        Some(handshake_records(&inner))
+    }
+}
+
+/// The client side of a connection whose ECH was accepted. The inner
+/// ClientHello is read first. A ClientHello sent again after a
+/// HelloRetryRequest is decrypted too, and the connection fails when it
+/// cannot be, as the backend would otherwise see the outer one.
+#[derive(Debug)]
+pub struct EchStream<S> {
+    inner: S,
+    /// Until the client sends something else than a ClientHello
+    ech: Option<EchAccepted>,
+    /// Decrypted records, or records passed as is, not read yet
+    pending: Vec<u8>,
+    pos: usize,
+    /// The record being read from the client
+    record: Vec<u8>,
+    filled: usize,
+}
+
+impl<S> EchStream<S> {
+    pub fn new(ech: EchAccepted, inner: S) -> EchStream<S> {
+        EchStream {
+            inner,
+            pending: ech.hello.clone(),
+            ech: Some(ech),
+            pos: 0,
+            record: vec![0u8; 5],
+            filled: 0,
+        }
+    }
+
+    /// Handle the whole record read from the client
+    fn on_record(&mut self) -> io::Result<()> {
+        let record = std::mem::replace(&mut self.record, vec![0u8; 5]);
+        self.filled = 0;
+        self.pos = 0;
+        match record[0] {
+            // Sent along with the second ClientHello for middleboxes
+            CONTENT_CHANGE_CIPHER_SPEC => self.pending = record,
+            CONTENT_HANDSHAKE => {
+                let retry = self.ech.take().and_then(|mut ech| ech.retry(&record));
+                self.pending = retry.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "ECH ClientHello after HelloRetryRequest not decrypted",
+                    )
+                })?;
+            }
+            _ => {
+                self.ech = None;
+                self.pending = record;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for EchStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.pos < this.pending.len() {
+                let n = buf.remaining().min(this.pending.len() - this.pos);
+                buf.put_slice(&this.pending[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.ech.is_none() {
+                return Pin::new(&mut this.inner).poll_read(cx, buf);
+            }
+
+            let mut read = ReadBuf::new(&mut this.record[this.filled..]);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            let n = read.filled().len();
+            if n == 0 {
+                return match this.filled {
+                    0 => Poll::Ready(Ok(())),
+                    _ => Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+                };
+            }
+            this.filled += n;
+            if this.filled < this.record.len() {
+                continue;
+            }
+
+            if this.record.len() == 5 {
+                let len = u16::from_be_bytes([this.record[3], this.record[4]]) as usize;
+                match this.record[0] {
+                    CONTENT_CHANGE_CIPHER_SPEC | CONTENT_HANDSHAKE if len <= MAX_RECORD_LEN => {
+                        this.record.resize(5 + len, 0);
+                        if len > 0 {
+                            continue;
+                        }
+                    }
+                    CONTENT_CHANGE_CIPHER_SPEC | CONTENT_HANDSHAKE => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "TLS record too long",
+                        )));
+                    }
+                    // Anything else is relayed as is, starting with its header
+                    _ => {}
+                }
+            }
+            this.on_record()?;
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for EchStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// The raw key of a PKCS#8 X25519 private key
+fn x25519_secret(der: &[u8]) -> Option<[u8; 32]> {
+    der.windows(PKCS8_X25519_OID.len())
+        .position(|window| window == PKCS8_X25519_OID)?;
+    let start = der
+        .windows(PKCS8_X25519_KEY.len())
+        .position(|window| window == PKCS8_X25519_KEY)?
+        + PKCS8_X25519_KEY.len();
+    der.get(start..start + 32)?.try_into().ok()
+}
+
+/// The HPKE of a KEM and a KDF and AEAD pair
+fn hpke(kem: u16, suite: (u16, u16)) -> Option<&'static dyn Hpke> {
+    ALL_SUPPORTED_SUITES.iter().copied().find(|hpke| {
+        u16::from(hpke.suite().kem) == kem
+            && kem == KEM_X25519_HKDF_SHA256
+            && suite_id(*hpke) == suite
+    })
+}
+
+/// KDF and AEAD of an HPKE
+fn suite_id(hpke: &dyn Hpke) -> (u16, u16) {
+    let suite = hpke.suite().sym;
+    (u16::from(suite.kdf_id), u16::from(suite.aead_id))
+}
+
+/// Whether `secret` is the private key of `public_key`
+fn is_key_pair(hpke: &dyn Hpke, public_key: &[u8], secret: &HpkePrivateKey) -> bool {
+    hpke.seal(b"", b"", b"", &HpkePublicKey(public_key.to_vec()))
+        .and_then(|(enc, sealed)| hpke.open(&enc, b"", b"", &sealed, secret))
+        .is_ok()
+}
+
+/// An ECHConfig of a list
+struct ParsedConfig {
+    config_id: u8,
+    /// The serialized ECHConfig
+    config: Vec<u8>,
+    kem: u16,
+    public_key: Vec<u8>,
+    /// KDF and AEAD pairs
+    suites: Vec<(u16, u16)>,
+    public_name: String,
+}
+
+/// ECHConfigs of a list, unsupported versions are skipped
+fn parse_config_list(list: &[u8]) -> Option<Vec<ParsedConfig>> {
+    let mut reader = Reader::new(list);
+    let mut configs = Reader::new(reader.vec16()?);
+    let mut parsed = Vec::new();
+    while !configs.is_empty() {
+        let start = configs.pos;
+        let version = configs.u16()?;
+        let mut contents = Reader::new(configs.vec16()?);
+        if version != ECH_VERSION {
+            continue;
+        }
+        let config = list[2 + start..2 + configs.pos].to_vec();
+
+        let config_id = contents.u8()?;
+        let kem = contents.u16()?;
+        let public_key = contents.vec16()?.to_vec();
+        let mut suites = Reader::new(contents.vec16()?);
+        let mut pairs = Vec::new();
+        while !suites.is_empty() {
+            pairs.push((suites.u16()?, suites.u16()?));
+        }
+        contents.u8()?;
+        let public_name = String::from_utf8(contents.vec8()?.to_vec()).ok()?;
+
+        parsed.push(ParsedConfig {
+            config_id,
+            config,
+            kem,
+            public_key,
+            suites: pairs,
+            public_name,
+        });
+    }
+    Some(parsed)
+}
+
+/// A ClientHello as TLS handshake records
+fn handshake_records(hello: &[u8]) -> Vec<u8> {
+    let mut handshake = vec![HANDSHAKE_CLIENT_HELLO];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(hello);
+    let mut records = Vec::with_capacity(handshake.len() + 5);
+    for chunk in handshake.chunks(MAX_RECORD_LEN) {
+        records.extend_from_slice(&[CONTENT_HANDSHAKE, 0x03, 0x01]);
+        records.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        records.extend_from_slice(chunk);
+    }
+    records
+}
+
+/// Rebuild the inner ClientHello from the EncodedClientHelloInner,
+/// restoring the session id and the extensions compressed by reference to
+/// the outer ClientHello
+fn decode_inner(encoded: &[u8], outer: &ClientHello) -> Option<Vec<u8>> {
+    let mut reader = Reader::new(encoded);
+    let version = reader.take(2)?;
+    let random = reader.take(32)?;
+    if !reader.vec8()?.is_empty() {
+        return None;
+    }
+    let cipher_suites = reader.vec16()?;
+    let compression = reader.vec8()?;
+    let mut extensions = Reader::new(reader.vec16()?);
+    if reader.rest().iter().any(|&padding| padding != 0) {
+        return None;
+    }
+
+    let mut inner_extensions = Vec::new();
+    let mut is_inner = false;
+    while !extensions.is_empty() {
+        let start = extensions.pos;
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        match kind {
+            EXT_ECH_OUTER_EXTENSIONS => {
+                let mut kinds = Reader::new(Reader::new(data).vec8()?);
+                while !kinds.is_empty() {
+                    let kind = kinds.u16()?;
+                    if kind == EXT_ECH {
+                        return None;
+                    }
+                    inner_extensions.extend_from_slice(outer.extension(kind)?.raw);
+                }
+            }
+            _ => {
+                is_inner |= kind == EXT_ECH && data.first() == Some(&ECH_INNER);
+                inner_extensions.extend_from_slice(&extensions.buf[start..extensions.pos]);
+            }
+        }
+    }
+    if !is_inner {
+        return None;
+    }
+
+    let mut hello = Vec::with_capacity(encoded.len() + inner_extensions.len());
+    hello.extend_from_slice(version);
+    hello.extend_from_slice(random);
+    hello.push(outer.session_id.len() as u8);
+    hello.extend_from_slice(outer.session_id);
+    hello.extend_from_slice(&(cipher_suites.len() as u16).to_be_bytes());
+    hello.extend_from_slice(cipher_suites);
+    hello.push(compression.len() as u8);
+    hello.extend_from_slice(compression);
+    hello.extend_from_slice(&(inner_extensions.len() as u16).to_be_bytes());
+    hello.extend_from_slice(&inner_extensions);
+    Some(hello)
+}
+
+/// The parts of a ClientHello body needed to decode the inner one
+struct ClientHello<'a> {
+    session_id: &'a [u8],
+    extensions: Vec<Extension<'a>>,
+}
+
+struct Extension<'a> {
+    kind: u16,
+    /// Type, length and data
+    raw: &'a [u8],
+    data: &'a [u8],
+    /// Offset of `data` in the ClientHello body
+    data_start: usize,
+}
+
+impl<'a> ClientHello<'a> {
+    fn parse(body: &'a [u8]) -> Option<ClientHello<'a>> {
+        let mut reader = Reader::new(body);
+        reader.take(2 + 32)?;
+        let session_id = reader.vec8()?;
+        reader.vec16()?;
+        reader.vec8()?;
+        let length = reader.u16()? as usize;
+        let mut extensions = Vec::new();
+        let end = reader.pos + length;
+        while reader.pos < end {
+            let start = reader.pos;
+            let kind = reader.u16()?;
+            let data = reader.vec16()?;
+            extensions.push(Extension {
+                kind,
+                raw: &body[start..reader.pos],
+                data,
+                data_start: start + 4,
+            });
+        }
+        Some(ClientHello {
+            session_id,
+            extensions,
+        })
+    }
+
+    fn extension(&self, kind: u16) -> Option<&Extension<'a>> {
+        self.extensions.iter().find(|ext| ext.kind == kind)
+    }
+}
+
+/// The outer ClientHello of a TLS record and its ECH extension
+struct OuterHello<'a> {
+    body: &'a [u8],
+    hello: ClientHello<'a>,
+    /// KDF and AEAD
+    suite: (u16, u16),
+    config_id: u8,
+    enc: &'a [u8],
+    payload: &'a [u8],
+    /// Offset of `payload` in `body`
+    payload_start: usize,
+}
+
+impl<'a> OuterHello<'a> {
+    fn parse(record: &'a [u8]) -> Option<OuterHello<'a>> {
+        let mut reader = Reader::new(record);
+        if reader.u8()? != CONTENT_HANDSHAKE {
+            return None;
+        }
+        reader.take(2)?;
+        let mut handshake = Reader::new(reader.vec16()?);
+        if handshake.u8()? != HANDSHAKE_CLIENT_HELLO {
+            return None;
+        }
+        let len = handshake.u24()?;
+        let body = handshake.take(len)?;
+        let hello = ClientHello::parse(body)?;
+
+        let ech = hello.extension(EXT_ECH)?;
+        let mut reader = Reader::new(ech.data);
+        if reader.u8()? != ECH_OUTER {
+            return None;
+        }
+        let suite = (reader.u16()?, reader.u16()?);
+        let config_id = reader.u8()?;
+        let enc = reader.vec16()?;
+        let payload = reader.vec16()?;
+        let payload_start = ech.data_start + ech.data.len() - payload.len();
+        Some(OuterHello {
+            body,
+            hello,
+            suite,
+            config_id,
+            enc,
+            payload,
+            payload_start,
+        })
+    }
+
+    /// The outer ClientHello with a zeroed payload
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = self.body.to_vec();
+        aad[self.payload_start..self.payload_start + self.payload.len()].fill(0);
+        aad
+    }
+}
+
+/// Reads the length prefixed fields of TLS structures
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.buf[self.pos.min(self.buf.len())..]
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let data = self.buf.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(data)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|data| data[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|data| u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|data| u32::from_be_bytes([0, data[0], data[1], data[2]]) as usize)
+    }
+
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::{
+        client::{EchConfig, EchMode},
+        crypto::{aws_lc_rs, hpke::HpkeSealer, CryptoProvider},
+        pki_types::ServerName,
+        ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::plugins::tls::{load_certs, load_private_key};
+
+    const ECH_PEM: &str = "tests/certs/ech.pem";
+
+    /// A rustls client offering ECH for `name`
+    fn client(name: &'static str) -> ClientConnection {
+        let sections = <(SectionKind, Vec<u8>)>::pem_file_iter(ECH_PEM).unwrap();
+        let (list, _) = EchConfigListBytes::config_and_key_from_iter(sections).unwrap();
+        let ech = EchConfig::new(list, aws_lc_rs::hpke::ALL_SUPPORTED_SUITES).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+            .with_ech(EchMode::from(ech))
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let server_name = ServerName::try_from(name).unwrap();
+        ClientConnection::new(Arc::new(config), server_name).unwrap()
+    }
+
+    fn write_tls(client: &mut ClientConnection) -> Vec<u8> {
+        let mut records = Vec::new();
+        client.write_tls(&mut records).unwrap();
+        records
+    }
+
+    fn contains(buf: &[u8], name: &[u8]) -> bool {
+        buf.windows(name.len()).any(|window| window == name)
+    }
+
+    /// Encrypt `encoded`, an EncodedClientHelloInner, into a copy of the
+    /// outer ClientHello `template` carrying `enc`
+    fn seal_outer(
+        template: &[u8],
+        enc: &[u8],
+        sealer: &mut dyn HpkeSealer,
+        encoded: &[u8],
+    ) -> Vec<u8> {
+        let outer = OuterHello::parse(template).unwrap();
+        let mut ech = vec![ECH_OUTER];
+        ech.extend_from_slice(&outer.suite.0.to_be_bytes());
+        ech.extend_from_slice(&outer.suite.1.to_be_bytes());
+        ech.push(outer.config_id);
+        ech.extend_from_slice(&(enc.len() as u16).to_be_bytes());
+        ech.extend_from_slice(enc);
+        ech.extend_from_slice(&((encoded.len() + 16) as u16).to_be_bytes());
+        ech.resize(ech.len() + encoded.len() + 16, 0);
+
+        let mut extensions = Vec::new();
+        let mut payload_start = 0;
+        for ext in &outer.hello.extensions {
+            match ext.kind {
+                EXT_ECH => {
+                    extensions.extend_from_slice(&EXT_ECH.to_be_bytes());
+                    extensions.extend_from_slice(&(ech.len() as u16).to_be_bytes());
+                    payload_start = extensions.len() + ech.len() - encoded.len() - 16;
+                    extensions.extend_from_slice(&ech);
+                }
+                _ => extensions.extend_from_slice(ext.raw),
+            }
+        }
+        let raw_len: usize = outer.hello.extensions.iter().map(|ext| ext.raw.len()).sum();
+        let mut body = outer.body[..outer.body.len() - raw_len - 2].to_vec();
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        payload_start += body.len();
+        body.extend_from_slice(&extensions);
+
+        let payload = sealer.seal(&body, encoded).unwrap();
+        body[payload_start..].copy_from_slice(&payload);
+        handshake_records(&body)
+    }
+
+    #[test]
+    fn test_ech_decrypt() {
+        let keys = EchKeys::load(&[ECH_PEM.to_string()]).unwrap();
+        assert_eq!(keys.keys[0].public_name, "public.test.com");
+
+        let outer = write_tls(&mut client("secret.test.com"));
+        assert!(contains(&outer, b"public.test.com"));
+        assert!(!contains(&outer, b"secret.test.com"));
+
+        let inner = keys.decrypt(&outer).unwrap().hello;
+        assert!(contains(&inner, b"secret.test.com"));
+        assert!(!contains(&inner, b"public.test.com"));
+        let hello = ClientHello::parse(&inner[9..]).unwrap();
+        assert_eq!(hello.extension(EXT_ECH).unwrap().data, [ECH_INNER]);
+        // Compressed extensions are restored from the outer ClientHello
+        let outer_hello = ClientHello::parse(&outer[9..]).unwrap();
+        assert_eq!(hello.session_id, outer_hello.session_id);
+        assert!(hello.extension(EXT_ECH_OUTER_EXTENSIONS).is_none());
+
+        // Tampering with the outer ClientHello breaks the AAD
+        let mut tampered = outer.clone();
+        let random = 11;
+        tampered[random] ^= 1;
+        assert!(keys.decrypt(&tampered).is_none());
+    }
+
+    #[test]
+    fn test_ech_key_mismatch() {
+        let sections = <(SectionKind, Vec<u8>)>::pem_file_iter(ECH_PEM).unwrap();
+        let (list, _) = EchConfigListBytes::config_and_key_from_iter(sections).unwrap();
+        let config = &parse_config_list(&list).unwrap()[0];
+        let hpke = hpke(config.kem, config.suites[0]).unwrap();
+        let (_, secret) = hpke.generate_key_pair().unwrap();
+        assert!(!is_key_pair(hpke, &config.public_key, &secret));
+    }
+
+    #[tokio::test]
+    async fn test_ech_retry() {
+        let keys = EchKeys::load(&[ECH_PEM.to_string()]).unwrap();
+        let key = &keys.keys[0];
+        let sections = <(SectionKind, Vec<u8>)>::pem_file_iter(ECH_PEM).unwrap();
+        let (list, _) = EchConfigListBytes::config_and_key_from_iter(sections).unwrap();
+        let public_key = HpkePublicKey(parse_config_list(&list).unwrap()[0].public_key.clone());
+
+        // Both ClientHellos are encrypted with one HPKE context, the second
+        // one without `enc`
+        let template = write_tls(&mut client("secret.test.com"));
+        let inner = keys.decrypt(&template).unwrap().hello;
+        let body = &inner[9..];
+        let encoded = [&body[..34], &[0], &body[35 + body[34] as usize..]].concat();
+        let hpke = key.suites[0];
+        let (enc, mut sealer) = hpke.setup_sealer(&key.info, &public_key).unwrap();
+        let outer = seal_outer(&template, &enc.0, sealer.as_mut(), &encoded);
+        let retry = seal_outer(&template, &[], sealer.as_mut(), &encoded);
+        let accepted = keys.decrypt(&outer).unwrap();
+        assert_eq!(accepted.hello, inner);
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = EchStream::new(accepted, server);
+        let change_cipher_spec = [CONTENT_CHANGE_CIPHER_SPEC, 3, 3, 0, 1, 1];
+        let application_data = [0x17, 3, 3, 0, 2, 1, 2];
+        let sent = [&change_cipher_spec[..], &retry, &application_data].concat();
+        tokio::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+        });
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).await.unwrap();
+        let expected = [&inner[..], &change_cipher_spec, &inner, &application_data].concat();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn test_ech_hello_retry_request() {
+        let keys = EchKeys::load(&[ECH_PEM.to_string()]).unwrap();
+        let mut client = client("secret.test.com");
+        let accepted = keys.decrypt(&write_tls(&mut client)).unwrap();
+
+        // A backend without the key exchange of the client asks for another
+        // ClientHello
+        let provider = CryptoProvider {
+            kx_groups: vec![aws_lc_rs::kx_group::SECP256R1],
+            ..aws_lc_rs::default_provider()
+        };
+        let config = ServerConfig::builder_with_provider(Arc::new(provider))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                load_certs("tests/certs/tls.pem").unwrap(),
+                load_private_key("tests/certs/tls.key").unwrap(),
+            )
+            .unwrap();
+        let mut backend = ServerConnection::new(Arc::new(config)).unwrap();
+
+        let (mut client_io, server_io) = tokio::io::duplex(64);
+        let mut stream = EchStream::new(accepted, server_io);
+        let mut record = [0u8; 5];
+        stream.read_exact(&mut record).await.unwrap();
+        let mut inner = vec![0u8; 5 + u16::from_be_bytes([record[3], record[4]]) as usize];
+        inner[..5].copy_from_slice(&record);
+        stream.read_exact(&mut inner[5..]).await.unwrap();
+        backend.read_tls(&mut &inner[..]).unwrap();
+        backend.process_new_packets().unwrap();
+        assert_eq!(backend.server_name(), Some("secret.test.com"));
+        let mut retry_request = Vec::new();
+        backend.write_tls(&mut retry_request).unwrap();
+
+        // The backend cannot confirm ECH, so the client sends its outer
+        // ClientHello again, which must not reach the backend
+        client.read_tls(&mut &retry_request[..]).unwrap();
+        client.process_new_packets().unwrap();
+        let retry = write_tls(&mut client);
+        assert!(contains(&retry, b"public.test.com"));
+        tokio::spawn(async move {
+            client_io.write_all(&retry).await.unwrap();
+        });
+        let mut change_cipher_spec = [0u8; 6];
+        stream.read_exact(&mut change_cipher_spec).await.unwrap();
+        assert_eq!(change_cipher_spec[0], CONTENT_CHANGE_CIPHER_SPEC);
+        let err = stream.read(&mut [0u8; 64]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
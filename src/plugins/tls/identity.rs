@@ -0,0 +1,65 @@
+use std::fmt;
+
+use x509_parser::{extensions::GeneralName, parse_x509_certificate};
+
+/// Identity of a verified client certificate
+#[derive(Debug, Clone, Default)]
+pub struct ClientIdentity {
+    /// Subject common name
+    pub cn: Option<String>,
+    /// Subject alternative names as `DNS:name`, `URI:uri`, `email:addr` or `IP:addr`
+    pub sans: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(der: &[u8]) -> Option<ClientIdentity> {
+        let (_, cert) = parse_x509_certificate(der).ok()?;
+
+        let cn = cert
+            .subject()
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(|cn| cn.to_string());
+
+        let mut sans = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match name {
+                    GeneralName::DNSName(name) => sans.push(format!("DNS:{}", name)),
+                    GeneralName::URI(uri) => sans.push(format!("URI:{}", uri)),
+                    GeneralName::RFC822Name(email) => sans.push(format!("email:{}", email)),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => {
+                            let ip: [u8; 4] = (*ip).try_into().unwrap();
+                            sans.push(format!("IP:{}", std::net::Ipv4Addr::from(ip)))
+                        }
+                        16 => {
+                            let ip: [u8; 16] = (*ip).try_into().unwrap();
+                            sans.push(format!("IP:{}", std::net::Ipv6Addr::from(ip)))
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+
+        Some(ClientIdentity { cn, sans })
+    }
+
+    /// Names matched against routing rules, `CN=name` first then every SAN
+    pub fn names(&self) -> impl Iterator<Item = String> + '_ {
+        self.cn
+            .iter()
+            .map(|cn| format!("CN={}", cn))
+            .chain(self.sans.iter().cloned())
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self.names().collect();
+        write!(f, "{}", names.join(","))
+    }
+}
@@ -0,0 +1,17 @@
+//! TLS termination, client authentication, ACME, ECH and origination with rustls
+
+pub use self::{
+    acme::{AcmeChallenge, AcmeConfig, AcmeManager, ACME_TLS_ALPN},
+    client::{TlsOrigination, TlsVerify},
+    ech::{EchKeys, EchStream},
+    identity::ClientIdentity,
+    pem::{load_certs, load_private_key},
+    server::{load_certified_key, ClientAuth, TlsTermination},
+};
+
+mod acme;
+mod client;
+mod ech;
+mod identity;
+mod pem;
+mod server;
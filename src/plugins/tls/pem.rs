@@ -0,0 +1,34 @@
+use std::{fs::File, io, io::BufReader};
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+/// Load every certificate of a PEM file
+pub fn load_certs(path: &str) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no certificate found in {}", path),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key of a PEM file
+pub fn load_private_key(path: &str) -> io::Result<PrivateKeyDer<'static>> {
+    match rustls_pemfile::private_key(&mut BufReader::new(File::open(path)?))? {
+        Some(key) => Ok(key),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no private key found in {}", path),
+        )),
+    }
+}
+
+pub fn invalid_data<E>(err: E) -> io::Error
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
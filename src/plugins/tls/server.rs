@@ -0,0 +1,232 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::Arc,
+};
+
+use log::trace;
+use rustls::{
+    client::verify_server_name,
+    crypto::aws_lc_rs::sign::any_supported_type,
+    pki_types::{CertificateDer, ServerName},
+    server::{ClientHello, ParsedCertificate, ResolvesServerCert, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    ProtocolVersion, RootCertStore, ServerConfig,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+
+use super::{
+    acme::{AcmeManager, CertMap},
+    identity::ClientIdentity,
+    pem::{invalid_data, load_certs, load_private_key},
+};
+
+/// Load a PEM certificate chain and its private key
+pub fn load_certified_key(cert_path: &str, key_path: &str) -> io::Result<CertifiedKey> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let key = any_supported_type(&key).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported private key {}: {}", key_path, err),
+        )
+    })?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Picks the ACME certificate of the SNI of the client, then the first
+/// certificate valid for it, or the first certificate when none matches.
+#[derive(Debug)]
+struct SniCertResolver {
+    certs: Vec<Arc<CertifiedKey>>,
+    issued: Option<CertMap>,
+}
+
+impl SniCertResolver {
+    fn find(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        let name = ServerName::try_from(name).ok()?;
+        self.certs
+            .iter()
+            .find(|cert| {
+                cert.end_entity_cert()
+                    .ok()
+                    .and_then(|der| ParsedCertificate::try_from(der).ok())
+                    .map(|parsed| verify_server_name(&parsed, &name).is_ok())
+                    .unwrap_or(false)
+            })
+            .cloned()
+    }
+}
+
+impl ResolvesServerCert for SniCertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(name) = client_hello.server_name() {
+            if let Some(issued) = &self.issued {
+                if let Some(cert) = issued.read().unwrap().get(name) {
+                    return Some(cert.clone());
+                }
+            }
+            if let Some(cert) = self.find(name) {
+                return Some(cert);
+            }
+            trace!("[TLS] no certificate for {}, using the first one", name);
+        }
+        self.certs.first().cloned()
+    }
+}
+
+/// Client certificate verification of a route
+#[derive(Debug, Clone, Default)]
+pub struct ClientAuth {
+    /// Certificates of the CA clients must be issued by
+    pub ca: Vec<CertificateDer<'static>>,
+    /// Accept clients without certificate
+    pub optional: bool,
+    /// Upstream by client identity, `CN=name` or a SAN like `DNS:name`
+    pub identity: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+struct TlsRoute {
+    config: Arc<ServerConfig>,
+    identity: HashMap<String, String>,
+}
+
+/// A terminated connection
+pub struct TlsAccepted<S> {
+    pub stream: TlsStream<S>,
+    /// Negotiated protocol version, like `TLSv1.3`
+    pub version: Option<String>,
+    /// Identity of the verified client certificate
+    pub identity: Option<ClientIdentity>,
+    /// Upstream chosen by the client identity
+    pub upstream: Option<String>,
+}
+
+/// TLS termination of a server
+#[derive(Debug, Clone)]
+pub struct TlsTermination {
+    /// Config of routes without client authentication
+    config: Arc<ServerConfig>,
+    /// Routes with client authentication by SNI, `*` for any other SNI
+    routes: Arc<HashMap<String, TlsRoute>>,
+    /// SNI terminated, every connection when `None`
+    sni: Option<HashSet<String>>,
+    acme: Option<Arc<AcmeManager>>,
+}
+
+impl TlsTermination {
+    pub fn new(
+        certs: Vec<CertifiedKey>,
+        sni: Option<HashSet<String>>,
+        client_auth: HashMap<String, ClientAuth>,
+        acme: Option<Arc<AcmeManager>>,
+    ) -> io::Result<TlsTermination> {
+        let resolver = Arc::new(SniCertResolver {
+            certs: certs.into_iter().map(Arc::new).collect(),
+            issued: acme.as_ref().map(|acme| acme.certs()),
+        });
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+
+        let mut routes = HashMap::new();
+        for (name, auth) in client_auth {
+            let mut roots = RootCertStore::empty();
+            for cert in auth.ca {
+                roots.add(cert).map_err(invalid_data)?;
+            }
+            let mut verifier = WebPkiClientVerifier::builder(Arc::new(roots));
+            if auth.optional {
+                verifier = verifier.allow_unauthenticated();
+            }
+            let config = ServerConfig::builder()
+                .with_client_cert_verifier(verifier.build().map_err(invalid_data)?)
+                .with_cert_resolver(resolver.clone());
+
+            routes.insert(
+                name,
+                TlsRoute {
+                    config: Arc::new(config),
+                    identity: auth.identity,
+                },
+            );
+        }
+
+        Ok(TlsTermination {
+            config: Arc::new(config),
+            routes: Arc::new(routes),
+            sni,
+            acme,
+        })
+    }
+
+    /// Certificate manager, to be run once per server
+    pub fn acme(&self) -> Option<&Arc<AcmeManager>> {
+        self.acme.as_ref()
+    }
+
+    /// Whether a connection with these SNI should be terminated
+    pub fn terminates(&self, snis: &[String]) -> bool {
+        match &self.sni {
+            None => true,
+            Some(terminated) => snis.iter().any(|sni| terminated.contains(sni)),
+        }
+    }
+
+    /// Name of the route with client authentication of these SNI
+    fn route<'a>(&self, mut snis: impl Iterator<Item = &'a str>) -> Option<&String> {
+        snis.find_map(|sni| self.routes.get_key_value(sni))
+            .or_else(|| self.routes.get_key_value("*"))
+            .map(|(name, _)| name)
+    }
+
+    pub async fn accept<S>(&self, stream: S, snis: &[String]) -> io::Result<TlsAccepted<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let route_name = self.route(snis.iter().map(|sni| sni.as_str()));
+        let route = route_name.map(|name| &self.routes[name]);
+        let config = match route {
+            Some(route) => route.config.clone(),
+            None => self.config.clone(),
+        };
+        let stream = TlsAcceptor::from(config).accept(stream).await?;
+
+        // The route was chosen from a peeked ClientHello, it must be the one
+        // of the SNI actually negotiated
+        let (_, conn) = stream.get_ref();
+        if self.route(conn.server_name().into_iter()) != route_name {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SNI changed during the handshake",
+            ));
+        }
+
+        let version = conn.protocol_version().map(|version| match version {
+            ProtocolVersion::TLSv1_2 => "TLSv1.2".to_string(),
+            ProtocolVersion::TLSv1_3 => "TLSv1.3".to_string(),
+            version => format!("{:?}", version),
+        });
+        let identity = conn
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientIdentity::from_der(cert));
+        let upstream = match (route, &identity) {
+            (Some(route), Some(identity)) => identity
+                .names()
+                .find_map(|name| route.identity.get(&name).cloned()),
+            _ => None,
+        };
+
+        Ok(TlsAccepted {
+            stream,
+            version,
+            identity,
+            upstream,
+        })
+    }
+}
+
@@ -0,0 +1,187 @@
+//! Unix domain socket listeners
+
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use tokio::net::UnixListener;
+
+/// Mode and ownership of a socket file, left as created when unset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnixPermissions {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Bind a socket file, replacing a stale one left by a previous run
+pub fn bind(path: &Path, permissions: &UnixPermissions) -> io::Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = permissions.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if permissions.uid.is_some() || permissions.gid.is_some() {
+        std::os::unix::fs::chown(path, permissions.uid, permissions.gid)?;
+    }
+    Ok(listener)
+}
+
+/// Mode and ownership of the socket files this process creates when they
+/// are left unset, the mode following its umask
+pub fn created_permissions() -> UnixPermissions {
+    // Read from /proc, as umask(2) can only be queried by changing it
+    let umask = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Umask:"))
+                .and_then(|umask| u32::from_str_radix(umask.trim(), 8).ok())
+        });
+    UnixPermissions {
+        mode: umask.map(|umask| 0o777 & !umask),
+        uid: Some(unsafe { libc::geteuid() }),
+        gid: Some(unsafe { libc::getegid() }),
+    }
+}
+
+/// First size of the buffer of `getpwnam_r` and `getgrnam_r`
+const LOOKUP_BUFFER: usize = 1024;
+/// Size at which a lookup still failing with ERANGE is given up
+const MAX_LOOKUP_BUFFER: usize = 1 << 20;
+
+/// Uid of a user name or number
+pub fn user_id(user: &str) -> Option<u32> {
+    if let Ok(uid) = user.parse() {
+        return Some(uid);
+    }
+    let name = CString::new(user).ok()?;
+    lookup(|buf| {
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        (ret, (!result.is_null()).then_some(passwd.pw_uid))
+    })
+}
+
+/// Gid of a group name or number
+pub fn group_id(group: &str) -> Option<u32> {
+    if let Ok(gid) = group.parse() {
+        return Some(gid);
+    }
+    let name = CString::new(group).ok()?;
+    lookup(|buf| {
+        let mut entry: libc::group = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let ret = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        (ret, (!result.is_null()).then_some(entry.gr_gid))
+    })
+}
+
+/// Run a lookup with a buffer for its strings, twice as large each time
+/// the entry does not fit, as with groups of many members
+fn lookup<F>(mut call: F) -> Option<u32>
+where
+    F: FnMut(&mut [libc::c_char]) -> (libc::c_int, Option<u32>),
+{
+    let mut buf = vec![0 as libc::c_char; LOOKUP_BUFFER];
+    loop {
+        match call(&mut buf) {
+            (0, id) => return id,
+            (libc::ERANGE, _) if buf.len() < MAX_LOOKUP_BUFFER => buf.resize(buf.len() * 2, 0),
+            _ => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let path = std::env::temp_dir().join(format!("fourth-bind-{}.sock", std::process::id()));
+        let permissions = UnixPermissions {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        let listener = bind(&path, &permissions).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Refused while listening, replaced once stale
+        assert!(bind(&path, &permissions).is_err());
+        drop(listener);
+        let listener = bind(&path, &UnixPermissions::default()).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let created = created_permissions();
+        assert_eq!(Some(metadata.permissions().mode() & 0o777), created.mode);
+        assert_eq!(
+            Some(std::os::unix::fs::MetadataExt::uid(&metadata)),
+            created.uid
+        );
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, b"data").unwrap();
+        assert!(bind(&path, &permissions).is_err());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(user_id("root"), Some(0));
+        assert_eq!(user_id("1000"), Some(1000));
+        assert_eq!(group_id("0"), Some(0));
+        assert_eq!(user_id("no-such-user-fourth"), None);
+    }
+
+    #[test]
+    fn test_lookup_grows_buffer() {
+        let mut sizes = Vec::new();
+        let id = lookup(|buf| {
+            sizes.push(buf.len());
+            match buf.len() < 4 * LOOKUP_BUFFER {
+                true => (libc::ERANGE, None),
+                false => (0, Some(7)),
+            }
+        });
+        assert_eq!(id, Some(7));
+        assert_eq!(sizes, [LOOKUP_BUFFER, 2 * LOOKUP_BUFFER, 4 * LOOKUP_BUFFER]);
+
+        // Given up past the largest buffer
+        assert_eq!(lookup(|_| (libc::ERANGE, None)), None);
+    }
+}
@@ -0,0 +1,444 @@
+//! Admin HTTP API to inspect and control running servers
+
+use crate::config::{Secret, Upstream};
+use crate::plugins::unix::{bind, UnixPermissions};
+use crate::servers::protocol::forward::ACCEPT_DELAY;
+use crate::servers::protocol::http::{header, read_head};
+use crate::servers::registry::Registry;
+use crate::servers::{Listen, Proxy};
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time;
+
+/// Only the owner may use the admin socket
+const SOCKET_MODE: u32 = 0o600;
+
+pub struct AdminState {
+    pub token: Option<Secret>,
+    pub registry: Arc<Registry>,
+    pub proxies: Arc<RwLock<Vec<Arc<Proxy>>>>,
+    pub upstream: Arc<RwLock<HashMap<String, Upstream>>>,
+    /// Reloads are done by `Server::run`, which answers with the outcome
+    pub reload: mpsc::Sender<oneshot::Sender<Result<(), String>>>,
+}
+
+pub async fn serve(listen: Listen, state: Arc<AdminState>) {
+    info!("Starting admin API on {}", listen);
+    let result = match &listen {
+        Listen::Inet(addr) => serve_tcp(*addr, state).await,
+        Listen::Unix(path) => serve_unix(path, state).await,
+    };
+    if let Err(err) = result {
+        error!("Failed to start admin API on {}: {}", listen, err);
+    }
+}
+
+async fn serve_tcp(addr: SocketAddr, state: Arc<AdminState>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => spawn(stream, state.clone()),
+            Err(err) => accept_failed(err).await,
+        }
+    }
+}
+
+async fn serve_unix(path: &Path, state: Arc<AdminState>) -> io::Result<()> {
+    let permissions = UnixPermissions {
+        mode: Some(SOCKET_MODE),
+        ..Default::default()
+    };
+    let listener = bind(path, &permissions)?;
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => spawn(stream, state.clone()),
+            Err(err) => accept_failed(err).await,
+        }
+    }
+}
+
+async fn accept_failed(err: io::Error) {
+    error!("Failed to accept admin API connection: {}", err);
+    // Out of file descriptors, give connections time to close
+    time::sleep(ACCEPT_DELAY).await;
+}
+
+fn spawn<S>(stream: S, state: Arc<AdminState>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(err) = handle(stream, &state).await {
+            debug!("Admin API request failed: {}", err);
+        }
+    });
+}
+
+async fn handle<S>(mut stream: S, state: &AdminState) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let head = match read_head(&mut stream).await? {
+        Some((head, _)) => head,
+        None => return reply(&mut stream, "400 Bad Request", error("Bad request")).await,
+    };
+    let mut request_line = head.split("\r\n").next().unwrap_or("").split(' ');
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("");
+
+    let (status, body) = match authorized(&head, state.token.as_ref().map(Secret::expose)) {
+        true => route(method, path, state).await,
+        false => ("401 Unauthorized", error("Invalid token")),
+    };
+    info!("[ADMIN] {} {} {}", method, path, status);
+    reply(&mut stream, status, body).await
+}
+
+/// `Authorization: Bearer <token>`, compared in constant time
+fn authorized(head: &str, token: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let given = header(head, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or("");
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn route(method: &str, path: &str, state: &AdminState) -> (&'static str, Value) {
+    let path = path.split('?').next().unwrap_or(path);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        ("GET", ["servers"]) => ("200 OK", servers(state)),
+        ("POST", ["servers", name, action @ ("enable" | "disable")]) => {
+            let known = state
+                .proxies
+                .read()
+                .unwrap()
+                .iter()
+                .any(|proxy| proxy.name == *name);
+            if !known {
+                return ("404 Not Found", error("No such server"));
+            }
+            let enabled = *action == "enable";
+            state.registry.set_enabled(name, enabled);
+            ("200 OK", json!({ "server": name, "enabled": enabled }))
+        }
+        ("GET", ["connections"]) => ("200 OK", connections(state)),
+        ("DELETE", ["connections", id]) => match id.parse() {
+            Ok(id) if state.registry.kill(id) => ("200 OK", json!({ "killed": id })),
+            _ => ("404 Not Found", error("No such connection")),
+        },
+        ("GET", ["kcp"]) => ("200 OK", kcp(state).await),
+        ("GET", ["upstreams"]) => ("200 OK", upstreams(state)),
+        ("POST", ["upstreams", name, action @ ("drain" | "undrain")]) => {
+            if !state.upstream.read().unwrap().contains_key(*name) {
+                return ("404 Not Found", error("No such upstream"));
+            }
+            let draining = *action == "drain";
+            let upstream = state.registry.upstream(name);
+            upstream.draining.store(draining, Ordering::Relaxed);
+            ("200 OK", json!({ "upstream": name, "draining": draining }))
+        }
+        ("POST", ["reload"]) => {
+            let (tx, rx) = oneshot::channel();
+            if state.reload.send(tx).await.is_err() {
+                return ("500 Internal Server Error", error("Reload unavailable"));
+            }
+            match rx.await {
+                Ok(Ok(())) => ("200 OK", json!({ "reloaded": true })),
+                Ok(Err(err)) => ("500 Internal Server Error", error(&err)),
+                Err(_) => ("500 Internal Server Error", error("Reload aborted")),
+            }
+        }
+        _ => ("404 Not Found", error("Not found")),
+    }
+}
+
+fn error(message: &str) -> Value {
+    json!({ "error": message })
+}
+
+fn servers(state: &AdminState) -> Value {
+    let proxies = state.proxies.read().unwrap();
+    let mut servers: BTreeMap<&str, Vec<&Arc<Proxy>>> = BTreeMap::new();
+    for proxy in proxies.iter() {
+        servers.entry(&proxy.name).or_default().push(proxy);
+    }
+    let servers: Vec<Value> = servers
+        .into_iter()
+        .map(|(name, listeners)| {
+            let proxy = listeners[0];
+            json!({
+                "name": name,
+                "protocol": proxy.protocol,
+                "enabled": proxy.enabled(),
+                "listen": listeners.iter().map(|proxy| proxy.listen.to_string()).collect::<Vec<_>>(),
+                "default": proxy.default,
+                "connections": proxy.stats.connections.load(Ordering::Relaxed),
+                "bans": proxy.stats.bans.load(Ordering::Relaxed),
+                "errors": proxy.stats.errors.load(Ordering::Relaxed),
+            })
+        })
+        .collect();
+    json!(servers)
+}
+
+fn connections(state: &AdminState) -> Value {
+    let connections: Vec<Value> = state
+        .registry
+        .connections()
+        .iter()
+        .map(|entry| {
+            json!({
+                "id": entry.id,
+                "server": entry.server,
+                "client": entry.peer.map(|peer| peer.to_string()),
+                "upstream": entry.upstream,
+                "received": entry.received.load(Ordering::Relaxed),
+                "sent": entry.sent.load(Ordering::Relaxed),
+                "age": entry.started.elapsed().as_secs(),
+            })
+        })
+        .collect();
+    json!(connections)
+}
+
+async fn kcp(state: &AdminState) -> Value {
+    let mut listeners = Vec::new();
+    for listener in state.registry.kcp_listeners() {
+        let (total, peers, sessions) = {
+            let counts = listener.counts.lock().unwrap();
+            let peers: BTreeMap<String, usize> = counts
+                .peers()
+                .iter()
+                .map(|(ip, count)| (ip.to_string(), *count))
+                .collect();
+            (counts.total(), peers, counts.sessions())
+        };
+        let mut session_stats = Vec::new();
+        for session in sessions {
+            // Closed since they were listed
+            if let Some(stats) = session.stats().await {
+                session_stats.push(json!({
+                    "conv": session.conv,
+                    "peer": session.peer.to_string(),
+                    "stats": stats,
+                }));
+            }
+        }
+        let stats = &listener.stats;
+        listeners.push(json!({
+            "server": listener.server,
+            "listen": listener.listen.to_string(),
+            "total": total,
+            "sessions": session_stats,
+            "peers": peers,
+            "refused": {
+                "malformed": stats.rejected_malformed.load(Ordering::Relaxed),
+                "cookie": stats.rejected_cookie.load(Ordering::Relaxed),
+                "table_full": stats.rejected_table_full.load(Ordering::Relaxed),
+                "peer_limit": stats.rejected_peer_limit.load(Ordering::Relaxed),
+                "peer_mismatch": stats.rejected_peer_mismatch.load(Ordering::Relaxed),
+                "input_dropped": stats.dropped_input.load(Ordering::Relaxed),
+            },
+            "migrated": stats.migrated.load(Ordering::Relaxed),
+        }));
+    }
+    json!(listeners)
+}
+
+fn upstreams(state: &AdminState) -> Value {
+    let upstream = state.upstream.read().unwrap();
+    let mut names: Vec<&String> = upstream.keys().collect();
+    names.sort();
+    let upstreams: Vec<Value> = names
+        .into_iter()
+        .map(|name| {
+            let (protocol, addr, backends) = match &upstream[name] {
+                Upstream::Custom(custom) => (
+                    custom.protocol.as_str(),
+                    Some(custom.addr.as_str()),
+                    custom.backends.as_ref().map(|backends| backends.list()),
+                ),
+                builtin => (builtin.name(), None, None),
+            };
+            let health = state.registry.upstream(name);
+            let last_error = health.last_error.lock().unwrap().clone();
+            json!({
+                "name": name,
+                "protocol": protocol,
+                "addr": addr,
+                "backends": backends,
+                "draining": health.draining.load(Ordering::Relaxed),
+                "healthy": health.healthy.load(Ordering::Relaxed),
+                "active": health.active.load(Ordering::Relaxed),
+                "connections": health.connections.load(Ordering::Relaxed),
+                "failures": health.failures.load(Ordering::Relaxed),
+                "last_error": last_error,
+            })
+        })
+        .collect();
+    json!(upstreams)
+}
+
+async fn reply<S>(stream: &mut S, status: &str, body: Value) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::testing;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpStream;
+
+    /// Status and JSON body of a request to the admin API on `addr`
+    async fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+    ) -> (u16, Value) {
+        let mut conn = testing::connect_tcp(addr).await;
+        let authorization = token
+            .map(|token| format!("Authorization: Bearer {}\r\n", token))
+            .unwrap_or_default();
+        let request = format!("{} {} HTTP/1.1\r\n{}\r\n", method, path, authorization);
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        conn.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    /// The entry of an admin API list whose `key` is `name`
+    fn find<'a>(list: &'a Value, key: &str, name: &str) -> &'a Value {
+        let list = list.as_array().unwrap();
+        list.iter().find(|entry| entry[key] == name).unwrap()
+    }
+
+    #[test]
+    fn test_authorized() {
+        let head = "GET /servers HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n";
+        assert!(authorized(head, Some("secret")));
+        assert!(authorized(head, None));
+        assert!(!authorized(head, Some("secreT")));
+        assert!(!authorized(head, Some("secret2")));
+        assert!(!authorized("GET / HTTP/1.1\r\n\r\n", Some("secret")));
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() {
+        let echo = testing::echo_server().await;
+        let (admin, echo_listen, relay) = (
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+        );
+        let config = r#"
+version: 2
+log: disable
+admin:
+  listen: "{admin}"
+  token: file:tests/secrets/admin-token
+servers:
+  tcp_echo_server:
+    listen:
+      - "{echo_listen}"
+    default: echo
+  tcp_server:
+    listen:
+      - "{relay}"
+    default: tester
+upstream:
+  tester: "tcp://{echo}"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("admin", admin),
+                ("echo_listen", echo_listen),
+                ("relay", relay),
+                ("echo", echo),
+            ],
+        ));
+        let token = Some("test-token");
+
+        let (status, _) = request(admin, "GET", "/servers", None).await;
+        assert_eq!(status, 401);
+        let (status, servers) = request(admin, "GET", "/servers", token).await;
+        assert_eq!(status, 200);
+        let server = find(&servers, "name", "tcp_echo_server");
+        assert_eq!(server["enabled"], true);
+        assert_eq!(server["listen"][0], echo_listen.to_string());
+
+        // Connections are listed and killed
+        let mut conn = testing::connect_tcp(echo_listen).await;
+        testing::echo(&mut conn, b"a").await;
+        let (_, connections) = request(admin, "GET", "/connections", token).await;
+        let connection = find(&connections, "server", "tcp_echo_server");
+        assert_eq!(connection["upstream"], "echo");
+        assert_eq!(connection["received"], 1);
+        let path = format!("/connections/{}", connection["id"]);
+        let (status, _) = request(admin, "DELETE", &path, token).await;
+        assert_eq!(status, 200);
+        let mut buf = [0u8; 1];
+        assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+
+        // A disabled server closes new connections
+        let path = "/servers/tcp_echo_server/disable";
+        let (status, _) = request(admin, "POST", path, token).await;
+        assert_eq!(status, 200);
+        let mut conn = TcpStream::connect(echo_listen).await.unwrap();
+        assert_eq!(conn.read(&mut buf).await.unwrap(), 0);
+        request(admin, "POST", "/servers/tcp_echo_server/enable", token).await;
+        let mut conn = TcpStream::connect(echo_listen).await.unwrap();
+        testing::echo(&mut conn, b"b").await;
+
+        // A draining upstream gets no new connections
+        let (status, _) = request(admin, "POST", "/upstreams/tester/drain", token).await;
+        assert_eq!(status, 200);
+        let mut conn = TcpStream::connect(relay).await.unwrap();
+        let _ = conn.write_all(b"hi").await;
+        assert_eq!(conn.read(&mut buf).await.unwrap_or(0), 0);
+        let (_, upstreams) = request(admin, "GET", "/upstreams", token).await;
+        let tester = find(&upstreams, "name", "tester");
+        assert_eq!(tester["draining"], true);
+        assert_eq!(tester["protocol"], "tcp");
+        request(admin, "POST", "/upstreams/tester/undrain", token).await;
+        let mut conn = TcpStream::connect(relay).await.unwrap();
+        testing::echo(&mut conn, b"hi").await;
+        let (status, _) = request(admin, "POST", "/upstreams/nothing/drain", token).await;
+        assert_eq!(status, 404);
+
+        let (status, _) = request(admin, "POST", "/reload", token).await;
+        assert_eq!(status, 200);
+    }
+}
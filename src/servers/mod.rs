@@ -1,197 +1,856 @@
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
 use tokio::task::JoinHandle;
+use tokio::time;
 
+mod admin;
 mod protocol;
-use crate::config::{ParsedConfig, Upstream};
-use protocol::{kcp, tcp};
+mod registry;
+#[cfg(test)]
+pub(crate) mod testing;
+
+use crate::config::{
+    Config, ConfigError, Overrides, ParsedConfig, Protocol, Routing, Secret, SniRoute, Transport,
+    Upstream,
+};
+use crate::plugins::acl::Acl;
+use crate::plugins::ban::BanPolicy;
+use crate::plugins::discovery;
+use crate::plugins::kcp::{KcpConfig, KcpListenerStats};
+use crate::plugins::resolver::Resolver;
+use crate::plugins::tls::{EchKeys, TlsTermination};
+use crate::plugins::unix::UnixPermissions;
+use admin::AdminState;
+use protocol::http::HttpConnect;
+use protocol::socks5::Socks5;
+use protocol::tcp::Plain;
+use protocol::websocket::WebSocket;
+use protocol::{forward, kcp, tcp, unix};
+use registry::Registry;
+
+/// How often counters are logged
+const STATS_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Server {
     pub proxies: Vec<Arc<Proxy>>,
     pub config: ParsedConfig,
+    /// Read again on reload
+    pub config_path: Option<String>,
+    /// Applied again on reload
+    pub overrides: Overrides,
+    registry: Arc<Registry>,
+    upstream: Arc<RwLock<HashMap<String, Upstream>>>,
+    /// Replaced on reload when its options change
+    resolver: Arc<Resolver>,
+    /// Task re-resolving the names of `resolver`
+    refresh: Option<JoinHandle<()>>,
+}
+
+/// Tasks of a server, stopped together on reload
+struct ServerTasks {
+    listeners: Vec<JoinHandle<()>>,
+    background: Vec<JoinHandle<()>>,
+}
+
+impl ServerTasks {
+    async fn stop(self) {
+        for handle in self.listeners.into_iter().chain(self.background) {
+            handle.abort();
+            let _ = handle.await;
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Proxy {
     pub name: String,
-    pub listen: SocketAddr,
-    pub protocol: String,
+    pub listen: Listen,
+    pub protocol: Protocol,
     pub tls: bool,
-    pub sni: Option<HashMap<String, String>>,
+    pub sni: Option<HashMap<String, SniRoute>>,
     pub default: String,
-    pub upstream: HashMap<String, Upstream>,
+    /// Shared by every server and replaced on reload
+    pub upstream: Arc<RwLock<HashMap<String, Upstream>>>,
+    pub kcp: KcpConfig,
+    pub terminate: Option<TlsTermination>,
+    pub ban: BanPolicy,
+    pub ech: Option<Arc<EchKeys>>,
+    /// Listener under proxy protocols like socks5
+    pub transport: Transport,
+    /// Credentials of proxy protocols, none required when empty
+    pub users: HashMap<String, Secret>,
+    pub acl: Acl,
+    /// Socket file of Unix listeners
+    pub unix: UnixPermissions,
+    /// Path of WebSocket upgrades, any when unset
+    pub websocket_path: Option<String>,
+    /// Shared by every listener of the server
+    pub stats: Arc<ProxyStats>,
+    pub registry: Arc<Registry>,
+    pub resolver: Arc<Resolver>,
+}
+
+impl Proxy {
+    /// Whether new connections are accepted, servers are disabled through
+    /// the admin API
+    pub fn enabled(&self) -> bool {
+        self.registry.enabled(&self.name)
+    }
+}
+
+/// Listen address of a server, `unix:///path` for Unix sockets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Listen {
+    Inet(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Listen {
+    /// The socket address, for protocols without Unix sockets
+    pub fn inet(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listen::Inet(addr) => Ok(*addr),
+            Listen::Unix(..) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} is not a socket address", self),
+            )),
+        }
+    }
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(listen: &str) -> Result<Listen, String> {
+        match listen.strip_prefix("unix://") {
+            Some(path) if path.starts_with('/') => Ok(Listen::Unix(PathBuf::from(path))),
+            Some(..) => Err(format!("Invalid listen address: {}", listen)),
+            None => listen
+                .parse()
+                .map(Listen::Inet)
+                .map_err(|_| format!("Invalid listen address: {}", listen)),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Listen::Inet(addr) => write!(f, "{}", addr),
+            Listen::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// Connection counters of a server
+#[derive(Debug, Default)]
+pub struct ProxyStats {
+    pub connections: AtomicU64,
+    /// Connections routed to `ban`, not counted as errors
+    pub bans: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+impl fmt::Display for ProxyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "connections={} bans={} errors={}",
+            self.connections.load(Ordering::Relaxed),
+            self.bans.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed)
+        )
+    }
 }
 
 impl Server {
-    pub fn new(config: ParsedConfig) -> Self {
-        let mut new_server = Server {
-            proxies: Vec::new(),
-            config: config.clone(),
+    pub fn new(config: ParsedConfig) -> Result<Self, ConfigError> {
+        let registry = Arc::new(Registry::default());
+        let upstream = Arc::new(RwLock::new(config.upstream.clone()));
+        let resolver = Arc::new(Resolver::new(&config.resolver));
+        Ok(Server {
+            proxies: build_proxies(&config, &registry, &upstream, &resolver)?,
+            config,
+            config_path: None,
+            overrides: Overrides::default(),
+            registry,
+            upstream,
+            resolver,
+            refresh: None,
+        })
+    }
+
+    #[tokio::main]
+    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let proxies = Arc::new(RwLock::new(self.proxies.clone()));
+        let (reload_tx, mut reload_rx) = mpsc::channel(1);
+        if let Some(admin) = &self.config.admin {
+            let state = AdminState {
+                token: admin.token.clone(),
+                registry: self.registry.clone(),
+                proxies: proxies.clone(),
+                upstream: self.upstream.clone(),
+                reload: reload_tx,
+            };
+            tokio::spawn(admin::serve(admin.build()?, Arc::new(state)));
+        } else {
+            drop(reload_tx);
+        }
+
+        self.refresh = Some(tokio::spawn(self.resolver.clone().refresh()));
+        discover(&mut self.config.upstream, &HashMap::new(), &self.resolver);
+        *self.upstream.write().unwrap() = self.config.upstream.clone();
+        let mut tasks = start(&self.proxies);
+
+        // Reloads are requested through the admin API
+        while let Some(reply) = reload_rx.recv().await {
+            let result = self.reload(&mut tasks).await;
+            if let Err(err) = &result {
+                error!("Failed to reload config: {}", err);
+            }
+            *proxies.write().unwrap() = self.proxies.clone();
+            let _ = reply.send(result);
+        }
+
+        for server in tasks.into_values() {
+            for handle in server.listeners {
+                handle.await?;
+            }
+        }
+        if let Some(refresh) = self.refresh.take() {
+            refresh.abort();
+        }
+        Ok(())
+    }
+
+    /// Read the config again, restart the servers whose config changed and
+    /// replace the upstreams of all of them
+    async fn reload(&mut self, tasks: &mut HashMap<String, ServerTasks>) -> Result<(), String> {
+        let path = match &self.config_path {
+            Some(path) => path.clone(),
+            None => return Err("No config file to reload".to_string()),
+        };
+        let config = Config::load(&path, &self.overrides).map_err(|err| err.to_string())?;
+        for warning in &config.warnings {
+            warn!("{}", warning);
+        }
+        let mut config = config.base;
+        // Every server and discovered upstream uses the new resolver
+        let new_resolver = config.resolver != self.config.resolver;
+        let (resolver, previous) = match new_resolver {
+            true => (Arc::new(Resolver::new(&config.resolver)), HashMap::new()),
+            false => (self.resolver.clone(), self.config.upstream.clone()),
         };
+        discover(&mut config.upstream, &previous, &resolver);
 
-        for (name, proxy) in config.servers.iter() {
-            let protocol = proxy.protocol.clone().unwrap_or_else(|| "tcp".to_string());
-            let tls = proxy.tls.unwrap_or(false);
-            let sni = proxy.sni.clone();
-            let default = proxy.default.clone().unwrap_or_else(|| "ban".to_string());
-            let upstream = config.upstream.clone();
-            let mut upstream_set: HashSet<String> = HashSet::new();
-            for key in upstream.keys() {
-                if key.eq("ban") || key.eq("echo") {
+        let changed: HashSet<String> = self
+            .config
+            .servers
+            .keys()
+            .chain(config.servers.keys())
+            .filter(|name| {
+                new_resolver || self.config.servers.get(*name) != config.servers.get(*name)
+            })
+            .cloned()
+            .collect();
+        // Unchanged servers are not built again, nor their ACME managers
+        let mut started = Vec::new();
+        for name in &changed {
+            if !config.servers.contains_key(name) {
+                continue;
+            }
+            let server = build_server(&config, name, &self.registry, &self.upstream, &resolver);
+            started.extend(server.map_err(|err| err.to_string())?);
+        }
+        for name in &changed {
+            if let Some(server) = tasks.remove(name) {
+                server.stop().await;
+            }
+        }
+
+        if new_resolver {
+            if let Some(refresh) = self.refresh.take() {
+                refresh.abort();
+            }
+            self.refresh = Some(tokio::spawn(resolver.clone().refresh()));
+            self.resolver = resolver;
+        }
+        *self.upstream.write().unwrap() = config.upstream.clone();
+        tasks.extend(start(&started));
+        self.proxies.retain(|proxy| !changed.contains(&proxy.name));
+        self.proxies.extend(started);
+        self.config = config;
+
+        info!("Reloaded {}, restarted servers {:?}", path, changed);
+        Ok(())
+    }
+}
+
+/// Watch the backends of discovered upstreams, keeping those of `previous`
+/// upstreams with the same source and interval
+fn discover(
+    upstream: &mut HashMap<String, Upstream>,
+    previous: &HashMap<String, Upstream>,
+    resolver: &Arc<Resolver>,
+) {
+    for (name, upstream) in upstream.iter_mut() {
+        let custom = match upstream {
+            Upstream::Custom(custom) => custom,
+            _ => continue,
+        };
+        let backends = match &custom.backends {
+            Some(backends) => backends,
+            None => continue,
+        };
+        if let Some(Upstream::Custom(old)) = previous.get(name) {
+            if let Some(old) = &old.backends {
+                if old.source == backends.source && old.interval == backends.interval {
+                    custom.backends = Some(old.clone());
                     continue;
                 }
-                upstream_set.insert(key.clone());
-            }
-            for listen in proxy.listen.clone() {
-                let listen_addr: SocketAddr = match listen.parse() {
-                    Ok(addr) => addr,
-                    Err(_) => {
-                        error!("Invalid listen address: {}", listen);
-                        continue;
-                    }
-                };
-
-                let proxy = Proxy {
-                    name: name.clone(),
-                    listen: listen_addr,
-                    protocol: protocol.clone(),
-                    tls,
-                    sni: sni.clone(),
-                    default: default.clone(),
-                    upstream: upstream.clone(),
-                };
-                new_server.proxies.push(Arc::new(proxy));
             }
         }
+        tokio::spawn(discovery::watch(
+            name.clone(),
+            Arc::downgrade(backends),
+            resolver.clone(),
+        ));
+    }
+}
 
-        new_server
+fn build_proxies(
+    config: &ParsedConfig,
+    registry: &Arc<Registry>,
+    shared_upstream: &Arc<RwLock<HashMap<String, Upstream>>>,
+    resolver: &Arc<Resolver>,
+) -> Result<Vec<Arc<Proxy>>, ConfigError> {
+    let mut proxies = Vec::new();
+    for name in config.servers.keys() {
+        proxies.extend(build_server(
+            config,
+            name,
+            registry,
+            shared_upstream,
+            resolver,
+        )?);
     }
+    Ok(proxies)
+}
 
-    #[tokio::main]
-    pub async fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let proxies = self.proxies.clone();
-        let mut handles: Vec<JoinHandle<()>> = Vec::new();
+/// The listeners of server `name`, none when some of its options are
+/// invalid and it is skipped
+fn build_server(
+    config: &ParsedConfig,
+    name: &str,
+    registry: &Arc<Registry>,
+    shared_upstream: &Arc<RwLock<HashMap<String, Upstream>>>,
+    resolver: &Arc<Resolver>,
+) -> Result<Vec<Arc<Proxy>>, ConfigError> {
+    let proxy = &config.servers[name];
+    let protocol = proxy.protocol.unwrap_or_default();
+    let tls = proxy.routing == Some(Routing::Sni);
+    let sni = proxy.sni.clone();
+    let default = proxy.default.clone().unwrap_or_else(|| "ban".to_string());
+    let kcp = match &proxy.kcp {
+        Some(kcp) => kcp.build().map_err(|err| {
+            ConfigError::Custom(format!("Invalid KCP options of {}: {}", name, err))
+        })?,
+        None => KcpConfig::default(),
+    };
+    let terminate = match &proxy.terminate {
+        Some(terminate) => Some(terminate.build().map_err(|err| {
+            ConfigError::Custom(format!("Invalid TLS termination of {}: {}", name, err))
+        })?),
+        None => None,
+    };
+    let ban = match &proxy.ban {
+        Some(ban) => ban.build(),
+        None => BanPolicy::default(),
+    };
+    let ech = match &proxy.ech {
+        Some(ech) => Some(Arc::new(ech.build().map_err(|err| {
+            ConfigError::Custom(format!("Invalid ECH keys of {}: {}", name, err))
+        })?)),
+        None => None,
+    };
+    let transport = proxy.transport.unwrap_or_default();
+    let users = proxy.users.clone().unwrap_or_default();
+    let acl = match &proxy.acl {
+        Some(acl) => acl
+            .build()
+            .map_err(|err| ConfigError::Custom(format!("Invalid ACL of {}: {}", name, err)))?,
+        None => Acl::default(),
+    };
+    let unix = match &proxy.unix {
+        Some(unix) => unix.build().map_err(|err| {
+            ConfigError::Custom(format!("Invalid Unix socket options of {}: {}", name, err))
+        })?,
+        None => UnixPermissions::default(),
+    };
+    let websocket_path = proxy.websocket.as_ref().and_then(|ws| ws.path.clone());
+    let stats = Arc::new(ProxyStats::default());
+    let upstream = config.upstream.clone();
+    let mut upstream_set: HashSet<String> = HashSet::new();
+    for key in upstream.keys() {
+        if key.eq("ban") || key.eq("echo") {
+            continue;
+        }
+        upstream_set.insert(key.clone());
+    }
+    let mut proxies = Vec::new();
+    for listen in proxy.listen.clone() {
+        let listen_addr: Listen = listen
+            .parse()
+            .map_err(|err| ConfigError::Custom(format!("{}: {}", name, err)))?;
 
-        for config in proxies {
-            info!(
-                "Starting {} server {} on {}",
-                config.protocol, config.name, config.listen
-            );
-            let handle = tokio::spawn(async move {
-                match config.protocol.as_ref() {
-                    "tcp" => {
-                        let res = tcp::proxy(config.clone()).await;
-                        if res.is_err() {
-                            error!("Failed to start {}: {}", config.name, res.err().unwrap());
-                        }
+        let proxy = Proxy {
+            name: name.to_string(),
+            listen: listen_addr,
+            protocol,
+            tls,
+            sni: sni.clone(),
+            default: default.clone(),
+            upstream: shared_upstream.clone(),
+            kcp,
+            terminate: terminate.clone(),
+            ban: ban.clone(),
+            ech: ech.clone(),
+            transport,
+            users: users.clone(),
+            acl: acl.clone(),
+            unix,
+            websocket_path: websocket_path.clone(),
+            stats: stats.clone(),
+            registry: registry.clone(),
+            resolver: resolver.clone(),
+        };
+        proxies.push(Arc::new(proxy));
+    }
+    Ok(proxies)
+}
+
+fn start(proxies: &[Arc<Proxy>]) -> HashMap<String, ServerTasks> {
+    let mut tasks: HashMap<String, ServerTasks> = HashMap::new();
+    for config in proxies {
+        // Listeners of a server share its certificate manager and counters
+        let server = tasks.entry(config.name.clone()).or_insert_with(|| {
+            let mut background = vec![tokio::spawn(log_stats(
+                config.name.clone(),
+                config.stats.clone(),
+                config.registry.clone(),
+            ))];
+            if let Some(acme) = config.terminate.as_ref().and_then(|t| t.acme()) {
+                background.push(tokio::spawn(acme.clone().run()));
+            }
+            ServerTasks {
+                listeners: Vec::new(),
+                background,
+            }
+        });
+
+        info!(
+            "Starting {} server {} on {}",
+            config.protocol, config.name, config.listen
+        );
+        let config = config.clone();
+        server.listeners.push(tokio::spawn(async move {
+            match config.protocol {
+                Protocol::Tcp if matches!(config.listen, Listen::Unix(..)) => {
+                    let res = unix::proxy::<Plain>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
+                    }
+                }
+                Protocol::Tcp => {
+                    let res = tcp::proxy::<Plain>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
+                    }
+                }
+                Protocol::Websocket if matches!(config.listen, Listen::Unix(..)) => {
+                    let res = unix::proxy::<WebSocket>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
+                    }
+                }
+                Protocol::Websocket => {
+                    let res = tcp::proxy::<WebSocket>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
+                    }
+                }
+                Protocol::Kcp => {
+                    let res = kcp::proxy(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
                     }
-                    "kcp" => {
-                        let res = kcp::proxy(config.clone()).await;
-                        if res.is_err() {
-                            error!("Failed to start {}: {}", config.name, res.err().unwrap());
-                        }
+                }
+                Protocol::Socks5 => {
+                    let res = forward::proxy::<Socks5>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
                     }
-                    _ => {
-                        error!("Invalid protocol: {}", config.protocol)
+                }
+                Protocol::Http => {
+                    let res = forward::proxy::<HttpConnect>(config.clone()).await;
+                    if res.is_err() {
+                        error!("Failed to start {}: {}", config.name, res.err().unwrap());
                     }
                 }
-            });
-            handles.push(handle);
+            }
+        }));
+    }
+    tasks
+}
+
+/// Log the counters of a server, and the packets refused by its KCP
+/// listeners, when they changed
+async fn log_stats(name: String, stats: Arc<ProxyStats>, registry: Arc<Registry>) {
+    let mut last = stats.to_string();
+    let mut last_kcp: HashMap<SocketAddr, String> = HashMap::new();
+    let mut interval = time::interval(STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let current = stats.to_string();
+        if current != last {
+            info!("Server {} {}", name, current);
+            last = current;
         }
 
-        for handle in handles {
-            handle.await?;
+        for listener in registry.kcp_listeners() {
+            if listener.server != name {
+                continue;
+            }
+            let current = listener.stats.to_string();
+            let last = last_kcp
+                .insert(listener.listen, current.clone())
+                .unwrap_or_else(|| KcpListenerStats::default().to_string());
+            if current != last {
+                info!(
+                    "KCP server {} on {} refused packets, {}",
+                    name, listener.listen, current
+                );
+            }
         }
-        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::plugins::kcp::{KcpConfig, KcpStream};
-    use std::net::SocketAddr;
-    use std::thread::{self, sleep};
+    use crate::servers::testing;
     use std::time::Duration;
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
-    use tokio::net::{TcpListener, TcpStream};
+    use tokio::net::TcpListener;
 
     use super::*;
 
-    #[tokio::main]
-    async fn tcp_mock_server() {
-        let server_addr: SocketAddr = "127.0.0.1:54599".parse().unwrap();
-        let listener = TcpListener::bind(server_addr).await.unwrap();
+    /// Answers HTTP requests with the current backend list
+    async fn backends_mock_server(listener: TcpListener, backends: Arc<std::sync::Mutex<String>>) {
         loop {
             let (mut stream, _) = listener.accept().await.unwrap();
-            let mut buf = [0u8; 2];
-            let mut n = stream.read(&mut buf).await.unwrap();
-            while n > 0 {
-                stream.write(b"hello").await.unwrap();
-                if buf.eq(b"by") {
-                    stream.shutdown().await.unwrap();
-                    break;
-                }
-                n = stream.read(&mut buf).await.unwrap();
-            }
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            let body = backends.lock().unwrap().clone();
+            let response = format!(
+                "HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}",
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
             stream.shutdown().await.unwrap();
         }
     }
 
+    #[test]
+    fn test_invalid_server_options() {
+        use crate::config::{Config, Overrides};
+        let config = Config::load("tests/config.yaml", &Overrides::default()).unwrap();
+        let mut base = config.base;
+        let server = base.servers.get_mut("kcp_server").unwrap();
+        server.kcp.as_mut().unwrap().shards = Some(0);
+        let err = Server::new(base).unwrap_err();
+        assert!(err.to_string().contains("kcp_server"), "{}", err);
+
+        // Certificates removed since the config was checked
+        let mut base = Config::load("tests/config.yaml", &Overrides::default())
+            .unwrap()
+            .base;
+        let server = base.servers.get_mut("mtls_server").unwrap();
+        let certs = server.terminate.as_mut().unwrap().certs.as_mut().unwrap();
+        certs[0].cert = "tests/certs/missing.pem".to_string();
+        let err = Server::new(base).unwrap_err();
+        assert!(err.to_string().contains("mtls_server"), "{}", err);
+    }
+
+    /// The entry named `name` of an admin API list
+    fn find<'a>(list: &'a serde_json::Value, key: &str, name: &str) -> &'a serde_json::Value {
+        let list = list.as_array().unwrap();
+        list.iter().find(|entry| entry[key] == name).unwrap()
+    }
+
     #[tokio::test]
-    async fn test_proxy() {
-        use crate::config::Config;
-        let config = Config::new("tests/config.yaml").unwrap();
-        let mut server = Server::new(config.base);
-        thread::spawn(move || {
-            tcp_mock_server();
-        });
-        sleep(Duration::from_secs(1)); // wait for server to start
-        thread::spawn(move || {
-            let _ = server.run();
-        });
-        sleep(Duration::from_secs(1)); // wait for server to start
-
-        // test TCP proxy
-        let mut conn = TcpStream::connect("127.0.0.1:54500").await.unwrap();
-        let mut buf = [0u8; 5];
-        conn.write(b"hi").await.unwrap();
-        conn.read(&mut buf).await.unwrap();
-        assert_eq!(&buf, b"hello");
+    async fn test_reload() {
+        let config = r#"
+version: 2
+log: disable
+admin:
+  listen: "unix://{dir}/admin.sock"
+servers:
+  kept:
+    listen:
+      - "unix://{dir}/kept.sock"
+    default: echo
+  changed:
+    listen:
+      - "unix://{dir}/changed.sock"
+    default: echo
+upstream:
+  relay: "unix://{dir}/kept.sock"
+"#;
+        let dir = testing::start(config);
+        let admin = dir.path().join("admin.sock");
+        let kept = dir.path().join("kept.sock");
+        let changed = dir.path().join("changed.sock");
+        let mut kept_conn = testing::connect_unix(&kept).await;
+        testing::echo(&mut kept_conn, b"hi").await;
+        let mut changed_conn = testing::connect_unix(&changed).await;
+        testing::echo(&mut changed_conn, b"hi").await;
+        testing::connect_unix(&admin).await;
+
+        let (head, tail) = config.rsplit_once("default: echo").unwrap();
+        testing::write_config(dir.path(), &format!("{}default: relay{}", head, tail));
+        let (status, _) = testing::admin_request(&admin, "POST", "/reload").await;
+        assert_eq!(status, 200);
+
+        // The changed server started again with new counters, the other one
+        // kept its connection
+        let (_, servers) = testing::admin_request(&admin, "GET", "/servers").await;
+        assert_eq!(find(&servers, "name", "kept")["connections"], 1);
+        assert_eq!(find(&servers, "name", "changed")["connections"], 0);
+        assert_eq!(find(&servers, "name", "changed")["default"], "relay");
+        testing::echo(&mut kept_conn, b"still").await;
+        let mut conn = testing::connect_unix(&changed).await;
+        testing::echo(&mut conn, b"relayed").await;
+        let (_, servers) = testing::admin_request(&admin, "GET", "/servers").await;
+        assert_eq!(find(&servers, "name", "kept")["connections"], 2);
+
+        // Every server uses the new resolver
+        let resolver = "resolver:\n  nameservers: [\"127.0.0.1\"]\n";
+        testing::write_config(
+            dir.path(),
+            &format!("{}default: relay{}{}", head, tail, resolver),
+        );
+        let (status, _) = testing::admin_request(&admin, "POST", "/reload").await;
+        assert_eq!(status, 200);
+        let (_, servers) = testing::admin_request(&admin, "GET", "/servers").await;
+        assert_eq!(find(&servers, "name", "kept")["connections"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_health() {
+        let dir = testing::start(
+            r#"
+version: 2
+log: disable
+admin:
+  listen: "unix://{dir}/admin.sock"
+servers:
+  echo_server:
+    listen:
+      - "unix://{dir}/echo.sock"
+    default: echo
+  ws_server:
+    protocol: websocket
+    listen:
+      - "unix://{dir}/ws.sock"
+    default: echo_socket
+  broken_server:
+    listen:
+      - "unix://{dir}/broken.sock"
+    default: missing
+upstream:
+  echo_socket: "unix://{dir}/echo.sock"
+  missing: "unix://{dir}/missing.sock"
+"#,
+        );
+        let admin = dir.path().join("admin.sock");
+        testing::connect_unix(&dir.path().join("echo.sock")).await;
+
+        // A client leaving during the upgrade is not the upstream's failure
+        let mut conn = testing::connect_unix(&dir.path().join("ws.sock")).await;
+        conn.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
         conn.shutdown().await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(conn.read(&mut buf).await.unwrap_or(0), 0);
+        let mut conn = testing::connect_unix(&dir.path().join("broken.sock")).await;
+        assert_eq!(conn.read(&mut buf).await.unwrap_or(0), 0);
+        let upstreams = loop {
+            let (_, upstreams) = testing::admin_request(&admin, "GET", "/upstreams").await;
+            if find(&upstreams, "name", "missing")["failures"] == 1
+                && find(&upstreams, "name", "echo_socket")["active"] == 0
+            {
+                break upstreams;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        let echo_socket = find(&upstreams, "name", "echo_socket");
+        assert_eq!(echo_socket["connections"], 1);
+        assert_eq!(echo_socket["failures"], 0);
+        assert_eq!(echo_socket["healthy"], true);
+        assert_eq!(find(&upstreams, "name", "missing")["healthy"], false);
+
+        // Drained connections are closed, not banned
+        let path = "/upstreams/echo_socket/drain";
+        let (status, _) = testing::admin_request(&admin, "POST", path).await;
+        assert_eq!(status, 200);
+        let mut conn = testing::connect_unix(&dir.path().join("ws.sock")).await;
+        assert_eq!(conn.read(&mut buf).await.unwrap_or(0), 0);
+        let (_, servers) = testing::admin_request(&admin, "GET", "/servers").await;
+        assert_eq!(find(&servers, "name", "ws_server")["connections"], 2);
+        assert_eq!(find(&servers, "name", "ws_server")["bans"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_discovered_upstreams() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let live = backend.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = backend.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // Connections fail over from the refused backend
+        let list = format!("[\"{}\", \"{}\"]", dead, live);
+        let body = Arc::new(std::sync::Mutex::new(list.clone()));
+        let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+        tokio::spawn(backends_mock_server(endpoint, body.clone()));
 
-        // test TCP echo
-        let mut conn = TcpStream::connect("127.0.0.1:54956").await.unwrap();
-        let mut buf = [0u8; 1];
-        for i in 0..=10u8 {
-            conn.write(&[i]).await.unwrap();
-            conn.read(&mut buf).await.unwrap();
-            assert_eq!(&buf, &[i]);
+        let config = r#"
+version: 2
+log: disable
+admin:
+  listen: "unix://{dir}/admin.sock"
+servers:
+  file_server:
+    listen:
+      - "unix://{dir}/file.sock"
+    default: file_backends
+  http_server:
+    listen:
+      - "unix://{dir}/http.sock"
+    default: http_backends
+upstream:
+  file_backends: "file+tcp://{dir}/backends.json"
+  http_backends: "http+tcp://{endpoint}/backends?interval=1"
+"#;
+        let dir = testing::start(&config.replace("{endpoint}", &endpoint_addr.to_string()));
+        let backends_file = dir.path().join("backends.json");
+        std::fs::write(&backends_file, &list).unwrap();
+        let admin = dir.path().join("admin.sock");
+        let sockets = [dir.path().join("file.sock"), dir.path().join("http.sock")];
+        let relayed = |socket: &std::path::Path| {
+            let socket = socket.to_path_buf();
+            async move {
+                let mut conn = testing::connect_unix(&socket).await;
+                let mut buf = [0u8; 2];
+                // Closed, maybe before the write, when there are no backends
+                conn.write_all(b"hi").await.is_ok()
+                    && conn.read_exact(&mut buf).await.is_ok()
+                    && &buf == b"hi"
+            }
+        };
+
+        for socket in &sockets {
+            testing::connect_unix(&admin).await;
+            // Until the backends are first read
+            while !relayed(socket).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            for _ in 0..2 {
+                assert!(relayed(socket).await);
+            }
         }
-        conn.shutdown().await.unwrap();
+        let (_, upstreams) = testing::admin_request(&admin, "GET", "/upstreams").await;
+        let http_backends = find(&upstreams, "name", "http_backends");
+        assert_eq!(http_backends["backends"][1], live.to_string());
 
-        // test KCP echo
-        let kcp_config = KcpConfig::default();
-        let server_addr: SocketAddr = "127.0.0.1:54959".parse().unwrap();
-        let mut conn = KcpStream::connect(&kcp_config, server_addr).await.unwrap();
-        let mut buf = [0u8; 1];
-        for i in 0..=10u8 {
-            conn.write(&[i]).await.unwrap();
-            conn.read(&mut buf).await.unwrap();
-            assert_eq!(&buf, &[i]);
+        // The backends change at runtime
+        std::fs::write(&backends_file, "[]").unwrap();
+        *body.lock().unwrap() = "[]".to_string();
+        for socket in &sockets {
+            while relayed(socket).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
         }
-        conn.shutdown().await.unwrap();
+        std::fs::write(&backends_file, format!("- \"{}\"\n", live)).unwrap();
+        *body.lock().unwrap() = format!("- {}", live);
+        for socket in &sockets {
+            while !relayed(socket).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+    }
 
-        // test KCP proxy and close mock server
-        let kcp_config = KcpConfig::default();
-        let server_addr: SocketAddr = "127.0.0.1:54958".parse().unwrap();
-        let mut conn = KcpStream::connect(&kcp_config, server_addr).await.unwrap();
-        let mut buf = [0u8; 5];
-        conn.write(b"by").await.unwrap();
-        conn.read(&mut buf).await.unwrap();
-        assert_eq!(&buf, b"hello");
-        conn.shutdown().await.unwrap();
+    #[tokio::test]
+    async fn test_kcp_sessions() {
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config = r#"
+version: 2
+log: disable
+admin:
+  listen: "unix://{dir}/admin.sock"
+servers:
+  kcp_server:
+    protocol: kcp
+    listen:
+      - "{addr}"
+    default: echo
+"#;
+        let dir = testing::start(&config.replace("{addr}", &addr.to_string()));
+        let admin = dir.path().join("admin.sock");
+        testing::connect_unix(&admin).await;
+        loop {
+            let (_, kcp) = testing::admin_request(&admin, "GET", "/kcp").await;
+            if !kcp.as_array().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut conn = KcpStream::connect(&KcpConfig::default(), addr)
+            .await
+            .unwrap();
+        testing::echo(&mut conn, b"hello").await;
+        let (status, kcp) = testing::admin_request(&admin, "GET", "/kcp").await;
+        assert_eq!(status, 200);
+        let listener = find(&kcp, "server", "kcp_server");
+        assert_eq!(listener["total"], 1);
+        let session = &listener["sessions"][0];
+        assert_ne!(session["conv"], 0);
+        assert!(session["stats"]["bytes_in"].as_u64().unwrap() > 0);
+        assert!(session["stats"]["packets_out"].as_u64().unwrap() > 0);
     }
 }
@@ -0,0 +1,147 @@
+//! Listeners and destinations of the forward proxy servers, SOCKS5 and
+//! HTTP CONNECT
+
+use crate::config::Transport;
+use crate::plugins::acl::Destination;
+use crate::plugins::kcp::KcpListener;
+use crate::plugins::resolver::{self, ConnectOptions};
+use crate::servers::Proxy;
+use log::{debug, error, warn};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+/// Time allowed to authenticate and send the request
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after a failed accept
+pub(crate) const ACCEPT_DELAY: Duration = Duration::from_millis(100);
+
+/// A forward proxy protocol, serving the connections of its server
+pub(crate) trait ForwardProxy: 'static {
+    /// Name in logs
+    const NAME: &'static str;
+
+    fn accept<S>(
+        inbound: S,
+        peer: SocketAddr,
+        local: SocketAddr,
+        proxy: &Proxy,
+    ) -> impl Future<Output = io::Result<()>> + Send
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send;
+}
+
+/// Accept the connections of a forward proxy server, over TCP or KCP
+pub async fn proxy<P: ForwardProxy>(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
+    match config.transport {
+        Transport::Kcp => {
+            let listen = config.listen.inet()?;
+            let mut listener = KcpListener::bind(config.kcp, listen).await?;
+            let _kcp = config.registry.track_kcp(&config.name, listen, &listener);
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => spawn::<P, _>(stream, peer, listen, config.clone()),
+                    Err(err) => {
+                        error!(
+                            "Failed to accept connection on server {}: {}",
+                            config.name, err
+                        );
+                        time::sleep(ACCEPT_DELAY).await;
+                    }
+                }
+            }
+        }
+        Transport::Tcp => {
+            let listener = TcpListener::bind(config.listen.inet()?).await?;
+            loop {
+                match listener.accept().await {
+                    Ok((stream, peer)) => match stream.local_addr() {
+                        Ok(local) => spawn::<P, _>(stream, peer, local, config.clone()),
+                        Err(err) => warn!("Failed to accept connection from {}: {}", peer, err),
+                    },
+                    Err(err) => {
+                        error!(
+                            "Failed to accept connection on server {}: {}",
+                            config.name, err
+                        );
+                        // Out of file descriptors, give connections time to close
+                        time::sleep(ACCEPT_DELAY).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn spawn<P, S>(stream: S, peer: SocketAddr, local: SocketAddr, proxy: Arc<Proxy>)
+where
+    P: ForwardProxy,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if !proxy.enabled() {
+        debug!("Server {} is disabled, connection closed", proxy.name);
+        return;
+    }
+    tokio::spawn(async move {
+        proxy.stats.connections.fetch_add(1, Ordering::Relaxed);
+        if let Err(err) = P::accept(stream, peer, local, &proxy).await {
+            proxy.stats.errors.fetch_add(1, Ordering::Relaxed);
+            error!("{} connection from {} failed: {}", P::NAME, peer, err);
+        }
+    });
+}
+
+/// Why a destination was not connected
+#[derive(Debug)]
+pub(crate) enum DialError {
+    /// Refused by the ACL, counted as a ban
+    Denied,
+    /// The name did not resolve
+    Unresolved,
+    Connect(io::Error),
+}
+
+/// Connect to a destination asked by a client, when the ACL allows the
+/// name and at least one of its addresses
+pub(crate) async fn dial(destination: &Destination, proxy: &Proxy) -> Result<TcpStream, DialError> {
+    if let Destination::Name(name, port) = destination {
+        if proxy.acl.denies_name(name, *port) {
+            return Err(deny(destination, proxy));
+        }
+    }
+    let (name, addrs) = match destination {
+        Destination::Addr(addr) => (None, vec![*addr]),
+        Destination::Name(name, port) => match proxy.resolver.lookup_host(name, *port).await {
+            Ok(addrs) => (Some(name.as_str()), addrs),
+            Err(err) => {
+                debug!("Failed to resolve {}: {}", destination, err);
+                return Err(DialError::Unresolved);
+            }
+        },
+    };
+    let allowed: Vec<SocketAddr> = addrs
+        .into_iter()
+        .filter(|addr| proxy.acl.allows(name, addr))
+        .collect();
+    if allowed.is_empty() {
+        return Err(deny(destination, proxy));
+    }
+
+    // Destinations have no connect options, those of upstreams by default
+    let options = ConnectOptions::default();
+    let allowed = resolver::interleave(allowed, options.prefer);
+    resolver::connect(allowed, options.delay)
+        .await
+        .map_err(DialError::Connect)
+}
+
+fn deny(destination: &Destination, proxy: &Proxy) -> DialError {
+    proxy.stats.bans.fetch_add(1, Ordering::Relaxed);
+    debug!("[BAN] server={} destination={}", proxy.name, destination);
+    DialError::Denied
+}
@@ -0,0 +1,347 @@
+//! HTTP CONNECT proxy server
+
+use crate::plugins::acl::Destination;
+use crate::servers::protocol::forward::{dial, DialError, ForwardProxy, HANDSHAKE_TIMEOUT};
+use crate::servers::protocol::prefixed::PrefixedStream;
+use crate::servers::protocol::tcp::relay_destination;
+use crate::servers::Proxy;
+use base64::Engine;
+use log::{debug, info, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// Longest request head accepted
+const MAX_HEAD_LEN: usize = 8192;
+
+/// HTTP CONNECT proxy servers
+pub struct HttpConnect;
+
+impl ForwardProxy for HttpConnect {
+    const NAME: &'static str = "HTTP";
+
+    async fn accept<S>(
+        inbound: S,
+        peer: SocketAddr,
+        local: SocketAddr,
+        proxy: &Proxy,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        accept(inbound, peer, local, proxy).await
+    }
+}
+
+/// A parsed request head
+#[derive(Debug)]
+struct Request {
+    method: String,
+    target: String,
+    credentials: Option<(String, String)>,
+}
+
+async fn accept<S>(
+    mut inbound: S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    proxy: &Proxy,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("New HTTP connection from {:?}", peer);
+    let (head, rest) = match read_head(&mut inbound).await? {
+        Some(head) => head,
+        None => return respond(&mut inbound, "400 Bad Request", None).await,
+    };
+    let request = match parse_request(&head) {
+        Some(request) => request,
+        None => return respond(&mut inbound, "400 Bad Request", None).await,
+    };
+
+    let user = match authenticate(&request, proxy) {
+        Ok(user) => user,
+        Err(username) => {
+            proxy.stats.bans.fetch_add(1, Ordering::Relaxed);
+            if let Some(username) = username {
+                warn!(
+                    "HTTP proxy authentication of {} failed on server {}",
+                    username, proxy.name
+                );
+            }
+            let challenge = "Proxy-Authenticate: Basic realm=\"fourth\"\r\n";
+            return respond(
+                &mut inbound,
+                "407 Proxy Authentication Required",
+                Some(challenge),
+            )
+            .await;
+        }
+    };
+
+    info!(
+        "[ACCESS] server={} {} -> {} user={} method={} destination={}",
+        proxy.name,
+        peer,
+        local,
+        user.as_deref().unwrap_or("-"),
+        request.method,
+        request.target
+    );
+
+    if !request.method.eq_ignore_ascii_case("CONNECT") {
+        return respond(
+            &mut inbound,
+            "405 Method Not Allowed",
+            Some("Allow: CONNECT\r\n"),
+        )
+        .await;
+    }
+    let destination = match parse_authority(&request.target) {
+        Some(destination) => destination,
+        None => return respond(&mut inbound, "400 Bad Request", None).await,
+    };
+
+    match dial(&destination, proxy).await {
+        Ok(outbound) => {
+            inbound
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+            // Clients may send before reading the response
+            let inbound = PrefixedStream::new(rest, inbound);
+            relay_destination(inbound, outbound, proxy, peer, &request.target).await
+        }
+        Err(DialError::Denied) => respond(&mut inbound, "403 Forbidden", None).await,
+        Err(DialError::Unresolved) => respond(&mut inbound, "502 Bad Gateway", None).await,
+        Err(DialError::Connect(err)) => {
+            let status = match err.kind() {
+                io::ErrorKind::TimedOut => "504 Gateway Timeout",
+                _ => "502 Bad Gateway",
+            };
+            respond(&mut inbound, status, None).await
+        }
+    }
+}
+
+/// The user of valid credentials, `None` when there are no users. The
+/// error carries the username of invalid credentials.
+fn authenticate(request: &Request, proxy: &Proxy) -> Result<Option<String>, Option<String>> {
+    if proxy.users.is_empty() {
+        return Ok(None);
+    }
+    match &request.credentials {
+        Some((username, password))
+            if proxy.users.get(username).map(|user| user.expose()) == Some(password.as_str()) =>
+        {
+            Ok(Some(username.clone()))
+        }
+        Some((username, _)) => Err(Some(username.clone())),
+        None => Err(None),
+    }
+}
+
+/// Read until the end of the request head, returning the head and what was
+/// read after it. `None` when the head is too long.
+pub(crate) async fn read_head<S>(inbound: &mut S) -> io::Result<Option<(String, Vec<u8>)>>
+where
+    S: AsyncRead + Unpin,
+{
+    time::timeout(HANDSHAKE_TIMEOUT, read_head_until_end(inbound))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "HTTP request head timed out"))?
+}
+
+async fn read_head_until_end<S>(inbound: &mut S) -> io::Result<Option<(String, Vec<u8>)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    loop {
+        let n = inbound.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let searched = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf[searched..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let end = searched + pos + 4;
+            let rest = buf.split_off(end);
+            return Ok(String::from_utf8(buf).ok().map(|head| (head, rest)));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            return Ok(None);
+        }
+    }
+}
+
+/// Value of the first header named `name`
+pub(crate) fn header<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+fn parse_request(head: &str) -> Option<Request> {
+    let mut lines = head.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let target = request_line.next()?.to_string();
+    if !request_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    let mut credentials = None;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some(header) => header,
+            None => continue,
+        };
+        if name.trim().eq_ignore_ascii_case("Proxy-Authorization") {
+            credentials = parse_basic(value.trim());
+        }
+    }
+
+    Some(Request {
+        method,
+        target,
+        credentials,
+    })
+}
+
+fn parse_basic(value: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return None;
+    }
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(encoded.trim())
+        .ok()?;
+    let (username, password) = String::from_utf8(decoded)
+        .ok()?
+        .split_once(':')
+        .map(|(username, password)| (username.to_string(), password.to_string()))?;
+    Some((username, password))
+}
+
+/// `host:port` of a CONNECT request, IPv6 in brackets
+fn parse_authority(target: &str) -> Option<Destination> {
+    if let Ok(addr) = target.parse::<SocketAddr>() {
+        return Some(Destination::Addr(addr));
+    }
+    let (host, port) = target.rsplit_once(':')?;
+    if host.is_empty() || host.contains(['[', ']', ':', '/']) {
+        return None;
+    }
+    Some(Destination::Name(host.to_string(), port.parse().ok()?))
+}
+
+pub(crate) async fn respond<S>(
+    inbound: &mut S,
+    status: &str,
+    headers: Option<&str>,
+) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\n{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+        status,
+        headers.unwrap_or("")
+    );
+    inbound.write_all(response.as_bytes()).await?;
+    inbound.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::testing;
+
+    #[test]
+    fn test_parse_request() {
+        let head = "CONNECT db.test.com:5432 HTTP/1.1\r\nHost: db.test.com:5432\r\n\
+                    Proxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n";
+        let request = parse_request(head).unwrap();
+        assert_eq!(request.method, "CONNECT");
+        assert_eq!(
+            request.credentials,
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            parse_authority(&request.target),
+            Some(Destination::Name("db.test.com".to_string(), 5432))
+        );
+        assert_eq!(
+            parse_authority("[::1]:443"),
+            Some(Destination::Addr("[::1]:443".parse().unwrap()))
+        );
+        assert_eq!(parse_authority("::1:443"), None);
+        assert_eq!(parse_authority("db.test.com"), None);
+        assert!(parse_request("CONNECT db.test.com:5432\r\n\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_http_connect() {
+        let echo = testing::echo_server().await;
+        let denied = testing::tcp_addr();
+        let proxy = testing::tcp_addr();
+        let config = r#"
+version: 2
+log: disable
+servers:
+  http_server:
+    protocol: http
+    listen:
+      - "{proxy}"
+    users:
+      alice: secret
+    acl:
+      allow:
+        - "{echo}"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[("proxy", proxy), ("echo", echo)],
+        ));
+
+        // Bytes sent with the request are relayed
+        let mut conn = testing::connect_tcp(proxy).await;
+        let request = format!(
+            "CONNECT {} HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\nhello",
+            echo
+        );
+        conn.write_all(request.as_bytes()).await.unwrap();
+        let established = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+        let mut buf = vec![0u8; established.len() + 5];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf[..established.len()], established);
+        assert_eq!(&buf[established.len()..], b"hello");
+
+        for (request, status) in [
+            (format!("CONNECT {} HTTP/1.1\r\n\r\n", echo), "HTTP/1.1 407"),
+            (
+                format!(
+                    "CONNECT {} HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n",
+                    denied
+                ),
+                "HTTP/1.1 403",
+            ),
+            (
+                "GET / HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6c2VjcmV0\r\n\r\n".to_string(),
+                "HTTP/1.1 405",
+            ),
+        ] {
+            let mut conn = testing::connect_tcp(proxy).await;
+            conn.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            conn.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with(status), "{}", response);
+        }
+    }
+}
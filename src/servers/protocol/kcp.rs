@@ -1,17 +1,16 @@
-use crate::config::Upstream;
-use crate::plugins::kcp::{KcpConfig, KcpListener, KcpStream};
+use crate::plugins::kcp::{KcpListener, Mux};
+use crate::servers::protocol::tcp::{get_upstream, process, ConnectionInfo, Plain};
 use crate::servers::Proxy;
-use futures::future::try_join;
-use log::{debug, error, warn};
+use log::{debug, error, info};
 use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tokio::io;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncWrite};
 
 pub async fn proxy(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
-    let kcp_config = KcpConfig::default();
-    let mut listener = KcpListener::bind(kcp_config, config.listen).await?;
+    let listen = config.listen.inet()?;
+    let mut listener = KcpListener::bind(config.kcp, listen).await?;
+    let _kcp = config.registry.track_kcp(&config.name, listen, &listener);
     let config = config.clone();
 
     loop {
@@ -21,92 +20,249 @@ pub async fn proxy(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>>
                 error!("Failed to accept connection: {}", err);
                 return Err(Box::new(err));
             }
-            Ok((stream, peer)) => {
+            Ok(_) if !config.enabled() => {
+                debug!("Server {} is disabled, connection closed", config.name);
+            }
+            Ok((stream, peer)) if config.kcp.mux => {
+                // Every stream of the session is a connection
                 tokio::spawn(async move {
-                    match accept(stream, peer, thread_proxy).await {
-                        Ok(_) => {}
-                        Err(err) => {
-                            error!("Relay thread returned an error: {}", err);
-                        }
-                    };
+                    let (_mux, mut streams) = Mux::server(stream);
+                    while let Some(stream) = streams.recv().await {
+                        tokio::spawn(relay(stream, peer, listen, thread_proxy.clone()));
+                    }
                 });
             }
+            Ok((stream, peer)) => {
+                tokio::spawn(relay(stream, peer, listen, thread_proxy));
+            }
         }
     }
 }
 
-async fn accept(
-    inbound: KcpStream,
+async fn relay<S>(inbound: S, peer: SocketAddr, local: SocketAddr, proxy: Arc<Proxy>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    proxy.stats.connections.fetch_add(1, Ordering::Relaxed);
+    if let Err(err) = accept(inbound, peer, local, proxy.clone()).await {
+        proxy.stats.errors.fetch_add(1, Ordering::Relaxed);
+        error!("Relay thread returned an error: {}", err);
+    }
+}
+
+async fn accept<S>(
+    inbound: S,
     peer: SocketAddr,
+    local: SocketAddr,
     proxy: Arc<Proxy>,
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
     debug!("New connection from {:?}", peer);
 
+    let info = ConnectionInfo::new(peer, local);
     let upstream_name = proxy.default.clone();
 
-    debug!("Upstream: {}", upstream_name);
-
-    let upstream = match proxy.upstream.get(&upstream_name) {
-        Some(upstream) => upstream,
-        None => {
-            warn!(
-                "No upstream named {:?} on server {:?}",
-                proxy.default, proxy.name
-            );
-            return process(inbound, proxy.upstream.get(&proxy.default).unwrap()).await;
-            // ToDo: Remove unwrap and check default option
-        }
-    };
-    return process(inbound, upstream).await;
+    info!(
+        "[ACCESS] server={} {} upstream={}",
+        proxy.name, info, upstream_name
+    );
+    process::<Plain, _>(
+        inbound,
+        &get_upstream(&proxy, &upstream_name),
+        &proxy,
+        &info,
+    )
+    .await
 }
 
-async fn process(
-    mut inbound: KcpStream,
-    upstream: &Upstream,
-) -> Result<(), Box<dyn std::error::Error>> {
-    match upstream {
-        Upstream::Ban => {
-            let _ = inbound.shutdown();
+#[cfg(test)]
+mod tests {
+    use crate::plugins::kcp::{KcpConfig, KcpStream};
+    use crate::servers::testing;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Bytes sent by `flood_server` to the connections starting with `f`
+    const FLOOD: usize = 2 * 1024 * 1024;
+
+    /// An upstream sending `FLOOD` bytes to the connections starting with
+    /// `f`, and echoing the others
+    async fn flood_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut first = [0u8; 1];
+                    stream.read_exact(&mut first).await.unwrap();
+                    if &first == b"f" {
+                        let _ = stream.write_all(&vec![0u8; FLOOD]).await;
+                        return;
+                    }
+                    stream.write_all(&first).await.unwrap();
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_kcp_proxy() {
+        let echo = testing::echo_server().await;
+        let udp_echo = testing::udp_echo_server().await;
+        let (kcp, kcp_echo, message) = (
+            testing::udp_addr(),
+            testing::udp_addr(),
+            testing::udp_addr(),
+        );
+        let client = testing::tcp_addr();
+        let config = r#"
+version: 2
+log: disable
+servers:
+  kcp_server:
+    protocol: kcp
+    listen:
+      - "{kcp}"
+    kcp:
+      shards: 2
+    default: tester
+  kcp_echo_server:
+    protocol: kcp
+    listen:
+      - "{kcp_echo}"
+    kcp:
+      cookie: true
+      max_sessions_per_peer: 16
+      migration: same_ip
+    default: echo
+  kcp_client_server:
+    listen:
+      - "{client}"
+    default: kcp_tunnel
+  kcp_message_server:
+    protocol: kcp
+    listen:
+      - "{message}"
+    kcp:
+      mode: message
+    default: udp_tester
+upstream:
+  tester: "tcp://{echo}"
+  udp_tester: "udp://{udp_echo}"
+  kcp_tunnel: "kcp://{kcp_echo}?keepalive=5&cookie=true"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("kcp", kcp),
+                ("kcp_echo", kcp_echo),
+                ("client", client),
+                ("message", message),
+                ("echo", echo),
+                ("udp_echo", udp_echo),
+            ],
+        ));
+        for addr in [kcp, kcp_echo, message] {
+            testing::wait_for_udp(addr).await;
         }
-        Upstream::Echo => {
-            let (mut ri, mut wi) = io::split(inbound);
-            let inbound_to_inbound = copy(&mut ri, &mut wi);
-            let bytes_tx = inbound_to_inbound.await;
-            debug!("Bytes read: {:?}", bytes_tx);
+
+        let mut conn = KcpStream::connect(&KcpConfig::default(), kcp)
+            .await
+            .unwrap();
+        testing::echo(&mut conn, b"hello").await;
+        conn.shutdown().await.unwrap();
+
+        let kcp_config = KcpConfig {
+            cookie: true,
+            ..Default::default()
+        };
+        let mut conn = KcpStream::connect(&kcp_config, kcp_echo).await.unwrap();
+        for i in 0..=10u8 {
+            testing::echo(&mut conn, &[i]).await;
         }
-        Upstream::Custom(custom) => match custom.protocol.as_ref() {
-            "tcp" => {
-                let outbound = TcpStream::connect(custom.addr.clone()).await?;
+        conn.shutdown().await.unwrap();
 
-                let (mut ri, mut wi) = io::split(inbound);
-                let (mut ro, mut wo) = io::split(outbound);
+        // TCP to KCP tunnel
+        let mut conn = testing::connect_tcp(client).await;
+        for i in 0..=10u8 {
+            testing::echo(&mut conn, &[i]).await;
+        }
 
-                let inbound_to_outbound = copy(&mut ri, &mut wo);
-                let outbound_to_inbound = copy(&mut ro, &mut wi);
+        // Message mode to UDP
+        let kcp_config = KcpConfig {
+            stream: false,
+            ..Default::default()
+        };
+        let mut conn = KcpStream::connect(&kcp_config, message).await.unwrap();
+        let mut buf = [0u8; 4096];
+        for size in [1usize, 100, 1400, 3000] {
+            let message = vec![size as u8; size];
+            conn.send(&message).await.unwrap();
+            let n = conn.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &message[..]);
+        }
+        conn.shutdown().await.unwrap();
+    }
 
-                let (bytes_tx, bytes_rx) =
-                    try_join(inbound_to_outbound, outbound_to_inbound).await?;
+    #[tokio::test]
+    async fn test_kcp_mux() {
+        let upstream = flood_server().await;
+        let kcp = testing::udp_addr();
+        let config = r#"
+version: 2
+log: disable
+servers:
+  kcp_server:
+    protocol: kcp
+    listen:
+      - "{kcp}"
+    kcp:
+      mux: true
+    default: flood
+  kcp_client_server:
+    listen:
+      - "unix://{dir}/client.sock"
+    default: kcp_mux
+upstream:
+  flood: "tcp://{upstream}"
+  kcp_mux: "kcp://{kcp}?mux=true"
+"#;
+        let dir = testing::start(&testing::with_addrs(
+            config,
+            &[("kcp", kcp), ("upstream", upstream)],
+        ));
+        let client = dir.path().join("client.sock");
+        testing::wait_for_udp(kcp).await;
 
-                debug!("Bytes read: {:?} write: {:?}", bytes_tx, bytes_rx);
-            }
-            _ => {
-                error!("Reached unknown protocol: {:?}", custom.protocol);
+        // A stream left unread does not hold back the others of its session
+        let mut flooded = testing::connect_unix(&client).await;
+        flooded.write_all(b"f").await.unwrap();
+        let mut buf = [0u8; 1024];
+        flooded.read_exact(&mut buf).await.unwrap();
+        let mut conn = testing::connect_unix(&client).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            for i in 0..=10u8 {
+                testing::echo(&mut conn, &[i; 100]).await;
             }
-        },
-    };
-    Ok(())
-}
+        })
+        .await
+        .unwrap();
 
-async fn copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
-where
-    R: AsyncRead + Unpin + ?Sized,
-    W: AsyncWrite + Unpin + ?Sized,
-{
-    match io::copy(reader, writer).await {
-        Ok(u64) => {
-            let _ = writer.shutdown().await;
-            Ok(u64)
+        // and gets the rest once read
+        let mut received = buf.len();
+        while received < FLOOD {
+            let n = flooded.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0);
+            received += n;
         }
-        Err(_) => Ok(0),
+        assert_eq!(received, FLOOD);
     }
 }
@@ -1,3 +1,10 @@
+pub mod forward;
+pub mod http;
 pub mod kcp;
+pub mod prefixed;
+pub mod socks5;
 pub mod tcp;
 pub mod tls;
+pub mod udp;
+pub mod unix;
+pub mod websocket;
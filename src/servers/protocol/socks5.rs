@@ -0,0 +1,537 @@
+//! SOCKS5 server, RFC 1928, with the username/password authentication of
+//! RFC 1929
+
+use crate::plugins::acl::Destination;
+use crate::servers::protocol::forward::{dial, DialError, ForwardProxy, HANDSHAKE_TIMEOUT};
+use crate::servers::protocol::tcp::relay_destination;
+use crate::servers::Proxy;
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Duration, Instant};
+
+const VERSION: u8 = 5;
+const AUTH_VERSION: u8 = 1;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+
+/// Destinations and names a UDP association remembers at most
+const MAX_DESTINATIONS: usize = 1024;
+/// Time a destination may answer after the last datagram sent to it
+const DESTINATION_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// SOCKS5 servers
+pub struct Socks5;
+
+impl ForwardProxy for Socks5 {
+    const NAME: &'static str = "SOCKS5";
+
+    async fn accept<S>(
+        inbound: S,
+        peer: SocketAddr,
+        local: SocketAddr,
+        proxy: &Proxy,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        accept(inbound, peer, local, proxy).await
+    }
+}
+
+async fn accept<S>(
+    mut inbound: S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    proxy: &Proxy,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!("New SOCKS5 connection from {:?}", peer);
+    let handshake = time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut inbound, proxy))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "SOCKS5 handshake timed out"))?;
+    let (user, command, destination) = match handshake? {
+        Some(request) => request,
+        None => {
+            proxy.stats.bans.fetch_add(1, Ordering::Relaxed);
+            return inbound.shutdown().await;
+        }
+    };
+
+    info!(
+        "[ACCESS] server={} {} -> {} user={} command={} destination={}",
+        proxy.name,
+        peer,
+        local,
+        user.as_deref().unwrap_or("-"),
+        command,
+        destination
+    );
+
+    match command {
+        CMD_CONNECT => connect(inbound, peer, &destination, proxy).await,
+        CMD_UDP_ASSOCIATE => associate(inbound, peer, local, proxy).await,
+        _ => reply(&mut inbound, REP_COMMAND_NOT_SUPPORTED, None).await,
+    }
+}
+
+/// Authenticate and read the request: the user, the command and the
+/// destination. `None` when refused.
+async fn handshake<S>(
+    inbound: &mut S,
+    proxy: &Proxy,
+) -> io::Result<Option<(Option<String>, u8, Destination)>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let user = match authenticate(inbound, proxy).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+
+    let mut request = [0u8; 3];
+    inbound.read_exact(&mut request).await?;
+    if request[0] != VERSION {
+        return Err(invalid("unsupported SOCKS version"));
+    }
+    let destination = read_destination(inbound).await?;
+    Ok(Some((user, request[1], destination)))
+}
+
+/// Negotiate the method and check credentials, `None` when refused and
+/// `Some(None)` without authentication
+async fn authenticate<S>(inbound: &mut S, proxy: &Proxy) -> io::Result<Option<Option<String>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut greeting = [0u8; 2];
+    inbound.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        return Err(invalid("unsupported SOCKS version"));
+    }
+    let mut methods = vec![0u8; greeting[1] as usize];
+    inbound.read_exact(&mut methods).await?;
+
+    let method = match proxy.users.is_empty() {
+        true => METHOD_NONE,
+        false => METHOD_PASSWORD,
+    };
+    if !methods.contains(&method) {
+        inbound.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Ok(None);
+    }
+    inbound.write_all(&[VERSION, method]).await?;
+    if method == METHOD_NONE {
+        return Ok(Some(None));
+    }
+
+    let mut version = [0u8; 1];
+    inbound.read_exact(&mut version).await?;
+    if version[0] != AUTH_VERSION {
+        return Err(invalid("unsupported authentication version"));
+    }
+    let username = read_string(inbound).await?;
+    let password = read_string(inbound).await?;
+    if proxy.users.get(&username).map(|user| user.expose()) != Some(password.as_str()) {
+        warn!(
+            "SOCKS5 authentication of {} failed on server {}",
+            username, proxy.name
+        );
+        inbound.write_all(&[AUTH_VERSION, 1]).await?;
+        return Ok(None);
+    }
+    inbound.write_all(&[AUTH_VERSION, 0]).await?;
+    Ok(Some(Some(username)))
+}
+
+async fn connect<S>(
+    mut inbound: S,
+    peer: SocketAddr,
+    destination: &Destination,
+    proxy: &Proxy,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match dial(destination, proxy).await {
+        Ok(outbound) => {
+            reply(&mut inbound, REP_SUCCEEDED, Some(outbound.local_addr()?)).await?;
+            let destination = destination.to_string();
+            relay_destination(inbound, outbound, proxy, peer, &destination).await
+        }
+        Err(DialError::Denied) => reply(&mut inbound, REP_NOT_ALLOWED, None).await,
+        Err(DialError::Unresolved) => reply(&mut inbound, REP_HOST_UNREACHABLE, None).await,
+        Err(DialError::Connect(err)) => {
+            let rep = match err.kind() {
+                io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+                io::ErrorKind::TimedOut => REP_HOST_UNREACHABLE,
+                _ => REP_FAILURE,
+            };
+            reply(&mut inbound, rep, None).await
+        }
+    }
+}
+
+/// Relay datagrams of the client until the control connection closes.
+/// Only destinations the client sent to may answer.
+async fn associate<S>(
+    mut inbound: S,
+    peer: SocketAddr,
+    local: SocketAddr,
+    proxy: &Proxy,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let socket = UdpSocket::bind(SocketAddr::new(local.ip(), 0)).await?;
+    reply(&mut inbound, REP_SUCCEEDED, Some(socket.local_addr()?)).await?;
+
+    let relay = async {
+        let mut buf = vec![0u8; 65536];
+        let mut client: Option<SocketAddr> = None;
+        let mut contacted: HashMap<SocketAddr, Instant> = HashMap::new();
+        let mut resolved: HashMap<(String, u16), Option<SocketAddr>> = HashMap::new();
+        loop {
+            let (n, from) = socket.recv_from(&mut buf).await?;
+            if from.ip() == peer.ip() && !contacted.contains_key(&from) {
+                let (destination, data) = match parse_datagram(&buf[..n]) {
+                    Some(datagram) => datagram,
+                    None => continue,
+                };
+                let addr = match &destination {
+                    Destination::Addr(addr) => Some(*addr),
+                    Destination::Name(name, port) if proxy.acl.denies_name(name, *port) => None,
+                    Destination::Name(name, port) => match resolved.get(&(name.clone(), *port)) {
+                        Some(addr) => *addr,
+                        None => {
+                            let addr = proxy
+                                .resolver
+                                .lookup_host(name, *port)
+                                .await
+                                .ok()
+                                .and_then(|addrs| addrs.first().copied());
+                            if resolved.len() >= MAX_DESTINATIONS {
+                                resolved.clear();
+                            }
+                            resolved.insert((name.clone(), *port), addr);
+                            addr
+                        }
+                    },
+                };
+                let name = match &destination {
+                    Destination::Name(name, _) => Some(name.as_str()),
+                    Destination::Addr(..) => None,
+                };
+                match addr {
+                    Some(addr) if proxy.acl.allows(name, &addr) => {
+                        if !remember(&mut contacted, addr) {
+                            debug!("Datagram to {} dropped, too many destinations", addr);
+                            continue;
+                        }
+                        client = Some(from);
+                        // Refused by the destination, e.g. an ICMP port unreachable
+                        if let Err(err) = socket.send_to(data, addr).await {
+                            debug!("Failed to send datagram to {}: {}", addr, err);
+                        }
+                    }
+                    _ => debug!("Datagram to {} dropped", destination),
+                }
+            } else if let Some(client) = client.filter(|_| {
+                contacted
+                    .get(&from)
+                    .is_some_and(|sent| sent.elapsed() < DESTINATION_TIMEOUT)
+            }) {
+                let mut datagram = vec![0, 0, 0];
+                encode_addr(&mut datagram, from);
+                datagram.extend_from_slice(&buf[..n]);
+                if let Err(err) = socket.send_to(&datagram, client).await {
+                    debug!("Failed to send datagram to {}: {}", client, err);
+                }
+            }
+        }
+    };
+
+    let mut buf = [0u8; 256];
+    let control = async {
+        while inbound.read(&mut buf).await? > 0 {}
+        Ok::<(), io::Error>(())
+    };
+
+    tokio::select! {
+        res = relay => res,
+        res = control => res,
+    }
+}
+
+/// Record a datagram sent to `addr`, forgetting the destinations that
+/// timed out once `MAX_DESTINATIONS` are remembered. Returns false when all
+/// of them are still in use.
+fn remember(contacted: &mut HashMap<SocketAddr, Instant>, addr: SocketAddr) -> bool {
+    if contacted.len() >= MAX_DESTINATIONS && !contacted.contains_key(&addr) {
+        contacted.retain(|_, sent| sent.elapsed() < DESTINATION_TIMEOUT);
+        if contacted.len() >= MAX_DESTINATIONS {
+            return false;
+        }
+    }
+    contacted.insert(addr, Instant::now());
+    true
+}
+
+/// Destination and data of a UDP request, fragments are not supported
+fn parse_datagram(buf: &[u8]) -> Option<(Destination, &[u8])> {
+    if buf.len() < 4 || buf[2] != 0 {
+        return None;
+    }
+    let (destination, len) = match buf[3] {
+        ATYP_IPV4 => {
+            let ip: [u8; 4] = buf.get(4..8)?.try_into().ok()?;
+            let port = u16::from_be_bytes(buf.get(8..10)?.try_into().ok()?);
+            (Destination::Addr(SocketAddr::new(ip.into(), port)), 10)
+        }
+        ATYP_IPV6 => {
+            let ip: [u8; 16] = buf.get(4..20)?.try_into().ok()?;
+            let port = u16::from_be_bytes(buf.get(20..22)?.try_into().ok()?);
+            (Destination::Addr(SocketAddr::new(ip.into(), port)), 22)
+        }
+        ATYP_DOMAIN => {
+            let end = 5 + *buf.get(4)? as usize;
+            let name = String::from_utf8(buf.get(5..end)?.to_vec()).ok()?;
+            let port = u16::from_be_bytes(buf.get(end..end + 2)?.try_into().ok()?);
+            (Destination::Name(name, port), end + 2)
+        }
+        _ => return None,
+    };
+    Some((destination, &buf[len..]))
+}
+
+async fn read_destination<S>(inbound: &mut S) -> io::Result<Destination>
+where
+    S: AsyncRead + Unpin,
+{
+    let atyp = inbound.read_u8().await?;
+    let destination = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0u8; 4];
+            inbound.read_exact(&mut ip).await?;
+            Destination::Addr(SocketAddr::new(ip.into(), inbound.read_u16().await?))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0u8; 16];
+            inbound.read_exact(&mut ip).await?;
+            Destination::Addr(SocketAddr::new(ip.into(), inbound.read_u16().await?))
+        }
+        ATYP_DOMAIN => {
+            let name = read_string(inbound).await?;
+            Destination::Name(name, inbound.read_u16().await?)
+        }
+        _ => return Err(invalid("unsupported address type")),
+    };
+    Ok(destination)
+}
+
+async fn read_string<S>(inbound: &mut S) -> io::Result<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = inbound.read_u8().await?;
+    let mut buf = vec![0u8; len as usize];
+    inbound.read_exact(&mut buf).await?;
+    String::from_utf8(buf).map_err(|_| invalid("invalid string"))
+}
+
+async fn reply<S>(inbound: &mut S, rep: u8, bound: Option<SocketAddr>) -> io::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let bound = bound.unwrap_or_else(|| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+    let mut buf = vec![VERSION, rep, 0];
+    encode_addr(&mut buf, bound);
+    inbound.write_all(&buf).await?;
+    if rep != REP_SUCCEEDED {
+        inbound.shutdown().await?;
+    }
+    Ok(())
+}
+
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::kcp::{KcpConfig, KcpStream};
+    use crate::servers::testing;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, UdpSocket};
+
+    /// Connect to a SOCKS5 server as alice
+    async fn connect(server: SocketAddr, password: &[u8]) -> (TcpStream, u8) {
+        let mut conn = testing::connect_tcp(server).await;
+        conn.write_all(&[5, 1, 2]).await.unwrap();
+        let mut buf = [0u8; 2];
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, [5, 2]);
+        let mut auth = vec![1, 5];
+        auth.extend_from_slice(b"alice");
+        auth.push(password.len() as u8);
+        auth.extend_from_slice(password);
+        conn.write_all(&auth).await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        (conn, buf[1])
+    }
+
+    /// A request of `command` to an IPv4 `addr`
+    fn request(command: u8, addr: SocketAddr) -> Vec<u8> {
+        let mut request = vec![5, command, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&addr.port().to_be_bytes());
+        request
+    }
+
+    #[test]
+    fn test_remember_destinations() {
+        let mut contacted = HashMap::new();
+        for port in 0..MAX_DESTINATIONS as u16 {
+            assert!(remember(&mut contacted, ([127, 0, 0, 1], port).into()));
+        }
+        assert!(remember(&mut contacted, ([127, 0, 0, 1], 0).into()));
+        assert!(!remember(&mut contacted, ([127, 0, 0, 2], 0).into()));
+
+        // Destinations that timed out make room
+        let expired = Instant::now() - DESTINATION_TIMEOUT;
+        contacted.insert(([127, 0, 0, 1], 1).into(), expired);
+        assert!(remember(&mut contacted, ([127, 0, 0, 2], 0).into()));
+        assert_eq!(contacted.len(), MAX_DESTINATIONS);
+    }
+
+    #[tokio::test]
+    async fn test_socks5() {
+        let echo = testing::echo_server().await;
+        let udp_echo = testing::udp_echo_server().await;
+        let (socks, kcp) = (testing::tcp_addr(), testing::udp_addr());
+        let config = r#"
+version: 2
+log: disable
+servers:
+  socks5_server:
+    protocol: socks5
+    listen:
+      - "{socks}"
+    users:
+      alice: secret
+    acl:
+      allow:
+        - "{echo}"
+        - "{udp_echo}"
+      deny:
+        - "*.test.com"
+  socks5_kcp_server:
+    protocol: socks5
+    transport: kcp
+    listen:
+      - "{kcp}"
+    acl:
+      allow:
+        - "{echo}"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("socks", socks),
+                ("kcp", kcp),
+                ("echo", echo),
+                ("udp_echo", udp_echo),
+            ],
+        ));
+
+        let (mut conn, status) = connect(socks, b"secret").await;
+        assert_eq!(status, 0);
+        conn.write_all(&request(1, echo)).await.unwrap();
+        let mut reply = [0u8; 10];
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        testing::echo(&mut conn, b"hello").await;
+
+        // Destinations outside the ACL and wrong passwords are refused
+        let (mut conn, _) = connect(socks, b"secret").await;
+        let mut by_name = vec![5, 1, 0, 3, 12];
+        by_name.extend_from_slice(b"www.test.com");
+        by_name.extend_from_slice(&echo.port().to_be_bytes());
+        conn.write_all(&by_name).await.unwrap();
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 2);
+        let (_, status) = connect(socks, b"wrong").await;
+        assert_eq!(status, 1);
+
+        // Over KCP, without authentication
+        testing::wait_for_udp(kcp).await;
+        let mut conn = KcpStream::connect(&KcpConfig::default(), kcp)
+            .await
+            .unwrap();
+        conn.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        conn.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+        conn.write_all(&request(1, echo)).await.unwrap();
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        testing::echo(&mut conn, b"hello").await;
+
+        // UDP ASSOCIATE, replies carry the header of their source
+        let (mut conn, _) = connect(socks, b"secret").await;
+        conn.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        conn.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        let relay = SocketAddr::new(
+            [127, 0, 0, 1].into(),
+            u16::from_be_bytes([reply[8], reply[9]]),
+        );
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut datagram = vec![0, 0, 0, 1, 127, 0, 0, 1];
+        datagram.extend_from_slice(&udp_echo.port().to_be_bytes());
+        datagram.extend_from_slice(b"ping");
+        socket.send_to(&datagram, relay).await.unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &datagram[..]);
+        conn.shutdown().await.unwrap();
+    }
+}
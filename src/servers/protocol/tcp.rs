@@ -1,15 +1,61 @@
-use crate::config::Upstream;
-use crate::servers::protocol::tls::get_sni;
-use crate::servers::Proxy;
+use crate::config::{CustomUpstream, Upstream, UpstreamProtocol};
+use crate::plugins::ban::ban;
+use crate::plugins::kcp::KcpStream;
+use crate::plugins::proxy_protocol::{ProxyHeader, SslInfo};
+use crate::plugins::resolver::Target;
+use crate::plugins::tls::{ClientIdentity, EchStream, ACME_TLS_ALPN};
+use crate::servers::protocol::prefixed::PrefixedStream;
+use crate::servers::protocol::tls::{get_alpn, get_ech, get_sni, Ech};
+use crate::servers::protocol::{udp, websocket};
+use crate::servers::{Listen, Proxy};
 use futures::future::try_join;
-use log::{debug, error, warn};
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use tokio::io;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
 
-pub async fn proxy(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
-    let listener = TcpListener::bind(config.listen).await?;
+/// First byte of a TLS handshake record
+const TLS_HANDSHAKE: u8 = 0x16;
+/// Largest TLS plaintext record
+const MAX_RECORD_LEN: usize = 16384;
+
+/// What a tcp server does with a routed connection before relaying it,
+/// websocket servers upgrade it
+pub(crate) trait Handler: 'static {
+    fn handle<S>(
+        inbound: S,
+        upstream: &Upstream,
+        proxy: &Proxy,
+        info: &ConnectionInfo,
+    ) -> impl Future<Output = Result<(), Box<dyn std::error::Error>>> + Send
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send;
+}
+
+/// Connections relayed as they are
+pub(crate) struct Plain;
+
+impl Handler for Plain {
+    async fn handle<S>(
+        inbound: S,
+        upstream: &Upstream,
+        proxy: &Proxy,
+        info: &ConnectionInfo,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        forward(inbound, upstream, proxy, info).await
+    }
+}
+
+pub async fn proxy<H: Handler>(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = TcpListener::bind(config.listen.inet()?).await?;
     let config = config.clone();
 
     loop {
@@ -19,11 +65,16 @@ pub async fn proxy(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>>
                 error!("Failed to accept connection: {}", err);
                 return Err(Box::new(err));
             }
+            Ok(_) if !config.enabled() => {
+                debug!("Server {} is disabled, connection closed", config.name);
+            }
             Ok((stream, _)) => {
                 tokio::spawn(async move {
-                    match accept(stream, thread_proxy).await {
+                    thread_proxy.stats.connections.fetch_add(1, Ordering::Relaxed);
+                    match accept::<H>(stream, thread_proxy.clone()).await {
                         Ok(_) => {}
                         Err(err) => {
+                            thread_proxy.stats.errors.fetch_add(1, Ordering::Relaxed);
                             error!("Relay thread returned an error: {}", err);
                         }
                     };
@@ -33,59 +84,308 @@ pub async fn proxy(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>>
     }
 }
 
-async fn accept(inbound: TcpStream, proxy: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("New connection from {:?}", inbound.peer_addr()?);
+/// What is known of an inbound connection, for access logs and PROXY
+/// protocol headers
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionInfo {
+    /// Unknown for Unix socket clients
+    pub peer: Option<SocketAddr>,
+    pub local: Listen,
+    pub sni: Option<String>,
+    /// A TLS ClientHello was seen and not terminated by fourth
+    pub tls_hello: bool,
+    pub ech: Ech,
+    pub tls_version: Option<String>,
+    pub identity: Option<ClientIdentity>,
+}
+
+impl ConnectionInfo {
+    pub fn new(peer: SocketAddr, local: SocketAddr) -> ConnectionInfo {
+        ConnectionInfo::with_endpoints(Some(peer), Listen::Inet(local))
+    }
+
+    pub fn unix(local: Listen) -> ConnectionInfo {
+        ConnectionInfo::with_endpoints(None, local)
+    }
+
+    fn with_endpoints(peer: Option<SocketAddr>, local: Listen) -> ConnectionInfo {
+        ConnectionInfo {
+            peer,
+            local,
+            sni: None,
+            tls_hello: false,
+            ech: Ech::None,
+            tls_version: None,
+            identity: None,
+        }
+    }
+
+    fn proxy_header(&self) -> ProxyHeader {
+        let ssl = self.tls_version.as_ref().map(|version| SslInfo {
+            version: Some(version.clone()),
+            cn: self.identity.as_ref().and_then(|identity| identity.cn.clone()),
+            identity: self.identity.as_ref().map(|identity| identity.to_string()),
+        });
+        let addresses = match (self.peer, &self.local) {
+            (Some(peer), Listen::Inet(local)) => Some((peer, *local)),
+            _ => None,
+        };
+        ProxyHeader {
+            addresses,
+            authority: self.sni.clone(),
+            ssl,
+        }
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} -> {} sni={} ech={} tls={} client={}",
+            self.peer.map_or("-".to_string(), |peer| peer.to_string()),
+            self.local,
+            self.sni.as_deref().unwrap_or("-"),
+            self.ech,
+            self.tls_version.as_deref().unwrap_or("-"),
+            self.identity
+                .as_ref()
+                .map_or("-".to_string(), |identity| identity.to_string()),
+        )
+    }
+}
+
+async fn accept<H: Handler>(
+    inbound: TcpStream,
+    proxy: Arc<Proxy>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let peer = inbound.peer_addr()?;
+    let info = ConnectionInfo::new(peer, inbound.local_addr()?);
+    debug!("New connection from {:?}", peer);
+    serve::<H, _>(inbound, proxy, info).await
+}
+
+/// Route a new connection of a tcp server, by its ClientHello and the ECH
+/// inside it when the server routes on SNI
+pub(crate) async fn serve<H, S>(
+    mut inbound: S,
+    proxy: Arc<Proxy>,
+    mut info: ConnectionInfo,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    if !proxy.tls {
+        return route::<H, _>(inbound, &[], proxy, info).await;
+    }
+
+    // Whole records are needed to decrypt ECH, what is read is replayed
+    let hello = read_hello(&mut inbound).await?;
+    info.tls_hello = hello.first() == Some(&TLS_HANDSHAKE);
+    info.ech = get_ech(&hello);
+
+    if let (Some(keys), Ech::Outer) = (&proxy.ech, info.ech) {
+        match keys.decrypt(&hello) {
+            Some(accepted) => {
+                info.ech = Ech::Inner;
+                let inner = accepted.hello.clone();
+                let inbound = EchStream::new(accepted, inbound);
+                return route::<H, _>(inbound, &inner, proxy, info).await;
+            }
+            None => debug!("ECH of {} not decrypted, routed on the outer SNI", info),
+        }
+    }
+
+    let inbound = PrefixedStream::new(hello.clone(), inbound);
+    route::<H, _>(inbound, &hello, proxy, info).await
+}
+
+/// Read the first TLS record, or the first bytes other clients send
+async fn read_hello<S>(inbound: &mut S) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut hello = vec![0u8; 5];
+    let n = inbound.read(&mut hello).await?;
+    if n == 0 || hello[0] != TLS_HANDSHAKE {
+        hello.truncate(n);
+        return Ok(hello);
+    }
+    inbound.read_exact(&mut hello[n..]).await?;
+    let len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+    if len > MAX_RECORD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "TLS record too long",
+        ));
+    }
+    hello.resize(5 + len, 0);
+    inbound.read_exact(&mut hello[5..]).await?;
+    Ok(hello)
+}
 
-    let upstream_name = match proxy.tls {
+/// Pick the upstream by the ClientHello `hello`, empty without TLS, then
+/// terminate TLS or relay
+async fn route<H, S>(
+    inbound: S,
+    hello: &[u8],
+    proxy: Arc<Proxy>,
+    mut info: ConnectionInfo,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let mut snis = Vec::new();
+    let mut alpn = Vec::new();
+    let mut upstream_name = match proxy.tls {
         false => proxy.default.clone(),
         true => {
-            let mut hello_buf = [0u8; 1024];
-            inbound.peek(&mut hello_buf).await?;
-            let snis = get_sni(&hello_buf);
-            if snis.is_empty() {
-                proxy.default.clone()
-            } else {
-                match proxy.sni.clone() {
-                    Some(sni_map) => {
-                        let mut upstream = proxy.default.clone();
-                        for sni in snis {
-                            let m = sni_map.get(&sni);
-                            if m.is_some() {
-                                upstream = m.unwrap().clone();
-                                break;
-                            }
-                        }
-                        upstream
-                    }
-                    None => proxy.default.clone(),
-                }
+            snis = get_sni(hello);
+            alpn = get_alpn(hello);
+            let ech = info.ech != Ech::None;
+            match &proxy.sni {
+                Some(sni_map) => snis
+                    .iter()
+                    .find_map(|sni| sni_map.get(sni).and_then(|route| route.upstream(ech)))
+                    .map_or_else(|| proxy.default.clone(), |upstream| upstream.to_string()),
+                None => proxy.default.clone(),
             }
         }
     };
+    info.sni = snis.first().cloned();
+
+    if let Some(terminate) = &proxy.terminate {
+        if let Some(acme) = terminate.acme() {
+            if alpn.iter().any(|protocol| protocol == ACME_TLS_ALPN) {
+                return Ok(acme.accept_challenge(inbound).await?);
+            }
+        }
+
+        // Decrypted ECH is confirmed by the backend, in split mode
+        if info.ech != Ech::Inner && terminate.terminates(&snis) {
+            let accepted = match terminate.accept(inbound, &snis).await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    proxy.stats.errors.fetch_add(1, Ordering::Relaxed);
+                    warn!("TLS handshake failed on server {}: {}", proxy.name, err);
+                    return Ok(());
+                }
+            };
+            info.tls_hello = false;
+            info.tls_version = accepted.version;
+            info.identity = accepted.identity;
+            if let Some(name) = accepted.upstream {
+                upstream_name = name;
+            }
 
-    debug!("Upstream: {}", upstream_name);
+            info!(
+                "[ACCESS] server={} {} upstream={}",
+                proxy.name, info, upstream_name
+            );
+            let upstream = get_upstream(&proxy, &upstream_name);
+            return process::<H, _>(accepted.stream, &upstream, &proxy, &info).await;
+        }
+    }
 
-    let upstream = match proxy.upstream.get(&upstream_name) {
-        Some(upstream) => upstream,
+    info!(
+        "[ACCESS] server={} {} upstream={}",
+        proxy.name, info, upstream_name
+    );
+    process::<H, _>(
+        inbound,
+        &get_upstream(&proxy, &upstream_name),
+        &proxy,
+        &info,
+    )
+    .await
+}
+
+/// The upstream named `upstream_name`, or the default one
+pub(crate) fn get_upstream(proxy: &Proxy, upstream_name: &str) -> Upstream {
+    let upstream = proxy.upstream.read().unwrap();
+    let upstream = match upstream.get(upstream_name) {
+        Some(upstream) => upstream.clone(),
         None => {
             warn!(
                 "No upstream named {:?} on server {:?}",
                 proxy.default, proxy.name
             );
-            return process(inbound, proxy.upstream.get(&proxy.default).unwrap()).await;
+            upstream.get(&proxy.default).unwrap().clone()
             // ToDo: Remove unwrap and check default option
         }
     };
-    return process(inbound, upstream).await;
+    upstream
 }
 
-async fn process(
-    mut inbound: TcpStream,
+pub(crate) async fn process<H, S>(
+    inbound: S,
     upstream: &Upstream,
-) -> Result<(), Box<dyn std::error::Error>> {
+    proxy: &Proxy,
+    info: &ConnectionInfo,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    H: Handler,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    // Closed as they are, draining is not a ban
+    let state = proxy.registry.upstream(upstream.name());
+    if state.draining.load(Ordering::Relaxed) {
+        debug!("Upstream {} is draining, closed {}", upstream.name(), info);
+        return Ok(());
+    }
+
+    let tracked = proxy
+        .registry
+        .track(&proxy.name, info.peer, upstream.name());
+    let inbound = tracked.stream(inbound);
+    let result = tokio::select! {
+        result = H::handle(inbound, upstream, proxy, info) => result,
+        _ = tracked.killed() => {
+            info!("Connection {} on server {} killed", tracked.id(), proxy.name);
+            return Ok(());
+        }
+    };
+    // Failures of clients, as WebSocket upgrades, say nothing of the upstream
+    let outcome = match &result {
+        Ok(()) => Some(Ok(())),
+        Err(err) => err.downcast_ref::<UpstreamError>().map(Err),
+    };
+    if let (Upstream::Custom(_), Some(outcome)) = (upstream, outcome) {
+        tracked.finish(outcome);
+    }
+    result
+}
+
+/// Failure to connect to an upstream or to talk to it, which makes it
+/// unhealthy
+#[derive(Debug)]
+pub(crate) struct UpstreamError(io::Error);
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Relay to `upstream`, or ban or echo
+pub(crate) async fn forward<S>(
+    inbound: S,
+    upstream: &Upstream,
+    proxy: &Proxy,
+    info: &ConnectionInfo,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     match upstream {
         Upstream::Ban => {
-            let _ = inbound.shutdown();
+            proxy.stats.bans.fetch_add(1, Ordering::Relaxed);
+            debug!("[BAN] server={} {}", proxy.name, info);
+            ban(inbound, &proxy.ban, info.tls_hello).await?;
         }
         Upstream::Echo => {
             let (mut ri, mut wi) = io::split(inbound);
@@ -93,29 +393,194 @@ async fn process(
             let bytes_tx = inbound_to_inbound.await;
             debug!("Bytes read: {:?}", bytes_tx);
         }
-        Upstream::Custom(custom) => match custom.protocol.as_ref() {
-            "tcp" => {
-                let outbound = TcpStream::connect(custom.addr.clone()).await?;
-
-                let (mut ri, mut wi) = io::split(inbound);
-                let (mut ro, mut wo) = io::split(outbound);
+        Upstream::Custom(custom) => relay_upstream(inbound, custom, proxy, info)
+            .await
+            .map_err(UpstreamError)?,
+    };
+    Ok(())
+}
 
-                let inbound_to_outbound = copy(&mut ri, &mut wo);
-                let outbound_to_inbound = copy(&mut ro, &mut wi);
+/// Connect to `custom` and relay, where every error is the upstream's since
+/// the relay itself ends quietly
+async fn relay_upstream<S>(
+    inbound: S,
+    custom: &CustomUpstream,
+    proxy: &Proxy,
+    info: &ConnectionInfo,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match custom.protocol {
+        UpstreamProtocol::Tcp => {
+            let mut outbound = connect_upstream(custom, proxy).await?;
+            if custom.proxy_protocol {
+                outbound.write_all(&info.proxy_header().encode()).await?;
+            }
+            relay(inbound, outbound).await?;
+        }
+        UpstreamProtocol::Tls => {
+            let mut outbound = connect_upstream(custom, proxy).await?;
+            if custom.proxy_protocol {
+                outbound.write_all(&info.proxy_header().encode()).await?;
+            }
+            let tls = match &custom.tls {
+                Some(tls) => tls,
+                None => {
+                    error!("Missing TLS options of upstream {}", custom.name);
+                    return Ok(());
+                }
+            };
+            let outbound = tls.connect(outbound).await?;
+            relay(inbound, outbound).await?;
+        }
+        UpstreamProtocol::Ws => {
+            let outbound = connect_upstream(custom, proxy).await?;
+            let path = custom.path.as_deref().unwrap_or("/");
+            let outbound = websocket::connect(outbound, &custom.addr, path).await?;
+            relay(inbound, outbound).await?;
+        }
+        UpstreamProtocol::Wss => {
+            let outbound = connect_upstream(custom, proxy).await?;
+            let tls = match &custom.tls {
+                Some(tls) => tls,
+                None => {
+                    error!("Missing TLS options of upstream {}", custom.name);
+                    return Ok(());
+                }
+            };
+            let outbound = tls.connect(outbound).await?;
+            let path = custom.path.as_deref().unwrap_or("/");
+            let outbound = websocket::connect(outbound, &custom.addr, path).await?;
+            relay(inbound, outbound).await?;
+        }
+        UpstreamProtocol::Unix => {
+            let mut outbound = UnixStream::connect(&custom.addr).await?;
+            if custom.proxy_protocol {
+                outbound.write_all(&info.proxy_header().encode()).await?;
+            }
+            relay(inbound, outbound).await?;
+        }
+        UpstreamProtocol::Kcp => match &custom.pool {
+            Some(pool) => {
+                let outbound = connect_kcp(custom, proxy, |addr| pool.open(addr)).await?;
+                relay(inbound, outbound).await?;
+            }
+            None => {
+                let kcp_config = custom.kcp.unwrap_or_default();
+                let outbound = connect_kcp(custom, proxy, |addr| async move {
+                    KcpStream::connect(&kcp_config, addr)
+                        .await
+                        .map_err(io::Error::other)
+                })
+                .await?;
+                relay(inbound, outbound).await?;
+            }
+        },
+        UpstreamProtocol::Udp => {
+            udp::relay(inbound, custom, &proxy.resolver).await?;
+        }
+    }
+    Ok(())
+}
 
-                let (bytes_tx, bytes_rx) =
-                    try_join(inbound_to_outbound, outbound_to_inbound).await?;
+/// Connect to a network upstream, failing over to the next backends of
+/// discovered upstreams
+async fn connect_upstream(custom: &CustomUpstream, proxy: &Proxy) -> io::Result<TcpStream> {
+    failover(custom, |target| async move {
+        proxy.resolver.connect(&target, &custom.connect).await
+    })
+    .await
+}
 
-                debug!("Bytes read: {:?} write: {:?}", bytes_tx, bytes_rx);
+/// Connect to a `kcp://` upstream, trying the addresses of each backend in
+/// turn as KCP has no Happy Eyeballs
+async fn connect_kcp<S, F, Fut>(custom: &CustomUpstream, proxy: &Proxy, connect: F) -> io::Result<S>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<S>>,
+{
+    let connect = &connect;
+    failover(custom, |target| async move {
+        let mut last_err = None;
+        for addr in proxy.resolver.addresses(&target, &custom.connect).await? {
+            match connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    debug!("Failed to connect to {} of {}: {}", addr, target, err);
+                    last_err = Some(err);
+                }
             }
-            _ => {
-                error!("Reached unknown protocol: {:?}", custom.protocol);
+        }
+        Err(last_err.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+    })
+    .await
+}
+
+/// Try `connect` on each backend of an upstream until one succeeds
+async fn failover<T, F, Fut>(custom: &CustomUpstream, connect: F) -> io::Result<T>
+where
+    F: Fn(Target) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut last_err = None;
+    for target in custom.targets()? {
+        match connect(target.clone()).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => {
+                debug!(
+                    "Failed to connect to {} of {}: {}",
+                    target, custom.name, err
+                );
+                last_err = Some(err);
             }
-        },
-    };
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::ErrorKind::NotFound.into()))
+}
+
+pub(crate) async fn relay<I, O>(inbound: I, outbound: O) -> io::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ri, mut wi) = io::split(inbound);
+    let (mut ro, mut wo) = io::split(outbound);
+
+    let inbound_to_outbound = copy(&mut ri, &mut wo);
+    let outbound_to_inbound = copy(&mut ro, &mut wi);
+
+    let (bytes_tx, bytes_rx) = try_join(inbound_to_outbound, outbound_to_inbound).await?;
+
+    debug!("Bytes read: {:?} write: {:?}", bytes_tx, bytes_rx);
     Ok(())
 }
 
+/// Relay a connection of a proxy server to the destination asked by its
+/// client, until it ends or is killed
+pub(crate) async fn relay_destination<I, O>(
+    inbound: I,
+    outbound: O,
+    proxy: &Proxy,
+    peer: SocketAddr,
+    destination: &str,
+) -> io::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + Unpin,
+{
+    let tracked = proxy
+        .registry
+        .track_destination(&proxy.name, Some(peer), destination);
+    tokio::select! {
+        result = relay(tracked.stream(inbound), outbound) => result,
+        _ = tracked.killed() => {
+            info!("Connection {} on server {} killed", tracked.id(), proxy.name);
+            Ok(())
+        }
+    }
+}
+
 async fn copy<'a, R, W>(reader: &'a mut R, writer: &'a mut W) -> io::Result<u64>
 where
     R: AsyncRead + Unpin + ?Sized,
@@ -129,3 +594,81 @@ where
         Err(_) => Ok(0),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::plugins::resolver::test_dns::{self, Zone};
+    use crate::servers::testing;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_tcp_proxy() {
+        let echo = testing::echo_server().await;
+        let (proxy, echo_listen, srv) = (
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+        );
+        // SRV records of the echo upstream, one of them refusing connections
+        let dns = testing::udp_addr();
+        let zone = Arc::new(Zone::default());
+        zone.set_a("tester.fourth.test", &["127.0.0.1".parse().unwrap()]);
+        zone.set_a("down.fourth.test", &["127.0.0.1".parse().unwrap()]);
+        zone.set_srv(
+            "_tester._tcp.fourth.test",
+            &[
+                (10, 5, echo.port(), "tester.fourth.test"),
+                (10, 5, testing::tcp_addr().port(), "down.fourth.test"),
+            ],
+        );
+        tokio::spawn(test_dns::serve(dns, zone.clone()));
+        let config = r#"
+version: 2
+log: disable
+resolver:
+  nameservers:
+    - "{dns}"
+servers:
+  tcp_server:
+    listen:
+      - "{proxy}"
+    default: tester
+  tcp_echo_server:
+    listen:
+      - "{echo_listen}"
+    default: echo
+  srv_client_server:
+    listen:
+      - "{srv}"
+    default: srv_tester
+upstream:
+  tester: "tcp://{echo}"
+  srv_tester: "srv+tcp://_tester._tcp.fourth.test"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("dns", dns),
+                ("proxy", proxy),
+                ("echo_listen", echo_listen),
+                ("srv", srv),
+                ("echo", echo),
+            ],
+        ));
+
+        let mut conn = testing::connect_tcp(proxy).await;
+        testing::echo(&mut conn, b"hello").await;
+
+        let mut conn = testing::connect_tcp(echo_listen).await;
+        for i in 0..=10u8 {
+            testing::echo(&mut conn, &[i]).await;
+        }
+
+        // Every SRV record is tried in turn
+        for _ in 0..2 {
+            let mut conn = testing::connect_tcp(srv).await;
+            testing::echo(&mut conn, b"hello").await;
+        }
+        assert!(zone.queries("_tester._tcp.fourth.test") > 0);
+    }
+}
@@ -1,11 +1,39 @@
 use log::{debug, warn};
+use std::fmt;
 use tls_parser::{
-    parse_tls_extensions, parse_tls_raw_record, parse_tls_record_with_header, TlsMessage,
-    TlsMessageHandshake,
+    parse_tls_extensions, parse_tls_raw_record, parse_tls_record_with_header, TlsExtension,
+    TlsExtensionType, TlsMessage, TlsMessageHandshake,
 };
 
-pub fn get_sni(buf: &[u8]) -> Vec<String> {
-    let mut snis: Vec<String> = Vec::new();
+const EXT_ECH: u16 = 0xfe0d;
+const ECH_OUTER: u8 = 0;
+
+/// Encrypted ClientHello of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Ech {
+    #[default]
+    None,
+    /// ECH or ESNI was offered, routed on the outer SNI
+    Outer,
+    /// Decrypted by fourth, routed on the inner SNI
+    Inner,
+}
+
+impl fmt::Display for Ech {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ech::None => write!(f, "-"),
+            Ech::Outer => write!(f, "outer"),
+            Ech::Inner => write!(f, "inner"),
+        }
+    }
+}
+
+/// Call `f` with every extension of a TLS ClientHello
+fn for_each_extension<F>(buf: &[u8], mut f: F)
+where
+    F: FnMut(&TlsExtension),
+{
     match parse_tls_raw_record(buf) {
         Ok((_, ref r)) => match parse_tls_record_with_header(r.data, &r.hdr) {
             Ok((_, ref msg_list)) => {
@@ -18,19 +46,7 @@ pub fn get_sni(buf: &[u8]) -> Vec<String> {
                         match ext {
                             Ok((_, ref extensions)) => {
                                 for ext in extensions {
-                                    if let tls_parser::TlsExtension::SNI(ref v) = *ext {
-                                        for &(t, sni) in v {
-                                            match String::from_utf8(sni.to_vec()) {
-                                                Ok(s) => {
-                                                    debug!("TLS SNI: {} {}", t, s);
-                                                    snis.push(s);
-                                                }
-                                                Err(e) => {
-                                                    warn!("Failed to parse SNI: {} {}", t, e);
-                                                }
-                                            }
-                                        }
-                                    }
+                                    f(ext);
                                 }
                             }
                             Err(e) => {
@@ -48,13 +64,106 @@ pub fn get_sni(buf: &[u8]) -> Vec<String> {
             warn!("Failed to parse TLS: {}", err);
         }
     }
+}
+
+pub fn get_sni(buf: &[u8]) -> Vec<String> {
+    let mut snis: Vec<String> = Vec::new();
+    for_each_extension(buf, |ext| {
+        if let TlsExtension::SNI(ref v) = *ext {
+            for &(t, sni) in v {
+                match String::from_utf8(sni.to_vec()) {
+                    Ok(s) => {
+                        debug!("TLS SNI: {} {}", t, s);
+                        snis.push(s);
+                    }
+                    Err(e) => {
+                        warn!("Failed to parse SNI: {} {}", t, e);
+                    }
+                }
+            }
+        }
+    });
 
     snis
 }
 
+pub fn get_alpn(buf: &[u8]) -> Vec<Vec<u8>> {
+    let mut protocols: Vec<Vec<u8>> = Vec::new();
+    for_each_extension(buf, |ext| {
+        if let TlsExtension::ALPN(ref v) = *ext {
+            protocols.extend(v.iter().map(|protocol| protocol.to_vec()));
+        }
+    });
+
+    protocols
+}
+
+/// Whether the ClientHello offers ECH or the older ESNI, GREASE included
+pub fn get_ech(buf: &[u8]) -> Ech {
+    let mut ech = Ech::None;
+    for_each_extension(buf, |ext| match *ext {
+        TlsExtension::EncryptedServerName { .. } => ech = Ech::Outer,
+        TlsExtension::Unknown(TlsExtensionType(EXT_ECH), data)
+            if data.first() == Some(&ECH_OUTER) =>
+        {
+            ech = Ech::Outer
+        }
+        _ => {}
+    });
+
+    ech
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::servers::testing;
+    use rustls::client::{EchConfig, EchMode};
+    use rustls::crypto::aws_lc_rs;
+    use rustls::pki_types::pem::{PemObject, SectionKind};
+    use rustls::pki_types::{EchConfigListBytes, ServerName};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// An upstream answering with the PROXY protocol header it received
+    async fn proxy_protocol_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut header = vec![0u8; 16];
+                stream.read_exact(&mut header).await.unwrap();
+                let len = u16::from_be_bytes([header[14], header[15]]) as usize;
+                header.resize(16 + len, 0);
+                stream.read_exact(&mut header[16..]).await.unwrap();
+                stream.write_all(&header).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn client_auth_config(roots: rustls::RootCertStore, name: &str) -> rustls::ClientConfig {
+        let certs = crate::plugins::tls::load_certs(&format!("tests/certs/{}.pem", name)).unwrap();
+        let key =
+            crate::plugins::tls::load_private_key(&format!("tests/certs/{}.key", name)).unwrap();
+        rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(certs, key)
+            .unwrap()
+    }
+
+    /// First flight of a client connecting to `sni`
+    fn client_hello(config: Arc<rustls::ClientConfig>, sni: &str) -> Vec<u8> {
+        let server_name = ServerName::try_from(sni.to_string()).unwrap();
+        let mut client = rustls::ClientConnection::new(config, server_name).unwrap();
+        let mut hello = Vec::new();
+        client.write_tls(&mut hello).unwrap();
+        hello
+    }
 
     #[test]
     fn test_sni_extract() {
@@ -98,6 +207,185 @@ mod tests {
             0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         ];
         let sni = get_sni(&BUF);
-        assert!(sni[0] == "www.lirui.tech".to_string());
+        assert!(sni[0] == "www.lirui.tech");
+        assert_eq!(get_alpn(&BUF), vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+        assert_eq!(get_ech(&BUF), Ech::None);
+    }
+
+    #[tokio::test]
+    async fn test_tls_routing() {
+        let proxy_protocol = proxy_protocol_server().await;
+        let (terminate, client, mtls, ech) = (
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+        );
+        let config = r#"
+version: 2
+log: disable
+servers:
+  tls_terminate_server:
+    listen:
+      - "{terminate}"
+    routing: sni
+    sni:
+      tls.test.com: echo
+      other.test.com: echo
+      pass.test.com: echo
+    terminate:
+      certs:
+        - cert: tests/certs/other.pem
+          key: tests/certs/other.key
+        - cert: tests/certs/tls.pem
+          key: tests/certs/tls.key
+      sni:
+        - tls.test.com
+        - other.test.com
+    ban:
+      alert: access_denied
+    default: ban
+  tls_client_server:
+    listen:
+      - "{client}"
+    default: tls_tunnel
+  mtls_server:
+    listen:
+      - "{mtls}"
+    routing: sni
+    terminate:
+      certs:
+        - cert: tests/certs/tls.pem
+          key: tests/certs/tls.key
+      client_auth:
+        tls.test.com:
+          ca: tests/certs/ca.pem
+          identity:
+            "CN=team-a": proxy_protocol_tester
+    default: echo
+  ech_server:
+    listen:
+      - "{ech}"
+    routing: sni
+    sni:
+      secret.test.com: echo
+      public.test.com:
+        - ech: true
+          upstream: echo
+        - upstream: ban
+    ech:
+      keys:
+        - tests/certs/ech.pem
+    default: ban
+upstream:
+  tls_tunnel: "tls://{terminate}?sni=tls.test.com&ca=tests/certs/ca.pem"
+  proxy_protocol_tester: "tcp://{proxy_protocol}?proxy_protocol=v2"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("terminate", terminate),
+                ("client", client),
+                ("mtls", mtls),
+                ("ech", ech),
+                ("proxy_protocol", proxy_protocol),
+            ],
+        ));
+        for addr in [terminate, client, mtls, ech] {
+            testing::connect_tcp(addr).await;
+        }
+
+        // Certificates are chosen by SNI
+        let mut roots = rustls::RootCertStore::empty();
+        let ca = std::fs::read("tests/certs/ca.pem").unwrap();
+        for cert in rustls_pemfile::certs(&mut &ca[..]) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let client_config = Arc::new(
+            rustls::ClientConfig::builder()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth(),
+        );
+        let connector = tokio_rustls::TlsConnector::from(client_config.clone());
+        for sni in ["tls.test.com", "other.test.com"] {
+            let conn = testing::connect_tcp(terminate).await;
+            let server_name = ServerName::try_from(sni).unwrap();
+            let mut conn = connector.connect(server_name, conn).await.unwrap();
+            testing::echo(&mut conn, b"hello").await;
+        }
+
+        // TCP to TLS upstream
+        let mut conn = testing::connect_tcp(client).await;
+        for i in 0..=10u8 {
+            testing::echo(&mut conn, &[i]).await;
+        }
+
+        // team-a is routed by its identity to a PROXY protocol upstream
+        let server_name = ServerName::try_from("tls.test.com").unwrap();
+        let connector =
+            tokio_rustls::TlsConnector::from(Arc::new(client_auth_config(roots.clone(), "team-a")));
+        let conn = testing::connect_tcp(mtls).await;
+        let mut conn = connector.connect(server_name.clone(), conn).await.unwrap();
+        let mut header = Vec::new();
+        conn.read_to_end(&mut header).await.unwrap();
+        assert_eq!(header[12], 0x21);
+        assert!(header.windows(6).any(|name| name == b"team-a"));
+        assert!(header.ends_with(b"CN=team-a,DNS:team-a.internal"));
+
+        let connector =
+            tokio_rustls::TlsConnector::from(Arc::new(client_auth_config(roots.clone(), "team-b")));
+        let conn = testing::connect_tcp(mtls).await;
+        let mut conn = connector.connect(server_name.clone(), conn).await.unwrap();
+        testing::echo(&mut conn, b"hello").await;
+
+        // Clients without certificate are refused
+        let connector = tokio_rustls::TlsConnector::from(client_config.clone());
+        let conn = testing::connect_tcp(mtls).await;
+        let res = async {
+            let mut conn = connector.connect(server_name, conn).await?;
+            let mut buf = [0u8; 5];
+            conn.write_all(b"hello").await?;
+            conn.read_exact(&mut buf).await
+        };
+        assert!(res.await.is_err());
+
+        // Passthrough on the same listener
+        let hello = client_hello(client_config.clone(), "pass.test.com");
+        let mut conn = testing::connect_tcp(terminate).await;
+        testing::echo(&mut conn, &hello).await;
+
+        // Banned SNI get a TLS alert
+        let hello = client_hello(client_config.clone(), "unknown.test.com");
+        let mut conn = testing::connect_tcp(terminate).await;
+        conn.write_all(&hello).await.unwrap();
+        let mut alert = Vec::new();
+        conn.read_to_end(&mut alert).await.unwrap();
+        assert_eq!(alert, [21, 3, 3, 0, 2, 2, 49]);
+
+        // The inner ClientHello of ECH is relayed and routed on its SNI
+        let sections = <(SectionKind, Vec<u8>)>::pem_file_iter("tests/certs/ech.pem").unwrap();
+        let (list, _) = EchConfigListBytes::config_and_key_from_iter(sections).unwrap();
+        let ech_config = EchConfig::new(list, aws_lc_rs::hpke::ALL_SUPPORTED_SUITES).unwrap();
+        let ech_client =
+            rustls::ClientConfig::builder_with_provider(Arc::new(aws_lc_rs::default_provider()))
+                .with_ech(EchMode::from(ech_config))
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        let hello = client_hello(Arc::new(ech_client), "secret.test.com");
+        let mut conn = testing::connect_tcp(ech).await;
+        conn.write_all(&hello).await.unwrap();
+        let mut buf = vec![0u8; 1024];
+        let n = conn.read(&mut buf).await.unwrap();
+        assert!(buf[..n].windows(15).any(|name| name == b"secret.test.com"));
+        assert!(!buf[..n].windows(15).any(|name| name == b"public.test.com"));
+
+        // Without ECH the public name is banned
+        let hello = client_hello(client_config, "public.test.com");
+        let mut conn = testing::connect_tcp(ech).await;
+        conn.write_all(&hello).await.unwrap();
+        let mut alert = Vec::new();
+        conn.read_to_end(&mut alert).await.unwrap();
+        assert_eq!(alert, [21, 3, 3, 0, 2, 2, 112]);
     }
 }
@@ -0,0 +1,81 @@
+use crate::config::CustomUpstream;
+use crate::plugins::resolver::Resolver;
+use log::{debug, warn};
+use std::net::SocketAddr;
+use tokio::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+/// Relay every message read from `inbound` as one datagram to a UDP upstream
+/// and every datagram back as one message. `inbound` must keep message
+/// boundaries, like a KCP stream in message mode.
+pub async fn relay<S>(inbound: S, upstream: &CustomUpstream, resolver: &Resolver) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let addr = match resolver
+        .addresses(&upstream.target()?, &upstream.connect)
+        .await?
+        .first()
+    {
+        Some(addr) => *addr,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Failed to resolve upstream {}", upstream.name),
+            ))
+        }
+    };
+
+    let local_addr: SocketAddr = match addr {
+        SocketAddr::V4(..) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(..) => "[::]:0".parse().unwrap(),
+    };
+    let outbound = UdpSocket::bind(local_addr).await?;
+    outbound.connect(addr).await?;
+
+    let (mut ri, mut wi) = io::split(inbound);
+
+    let inbound_to_outbound = async {
+        let mut buf = vec![0u8; 65536];
+        let mut messages = 0u64;
+        loop {
+            let n = ri.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            outbound.send(&buf[..n]).await?;
+            messages += 1;
+        }
+        Ok::<u64, io::Error>(messages)
+    };
+
+    let outbound_to_inbound = async {
+        let mut buf = vec![0u8; 65536];
+        let mut messages = 0u64;
+        loop {
+            let n = match outbound.recv(&mut buf).await {
+                Ok(n) => n,
+                Err(err) => return (messages, err),
+            };
+            match wi.write(&buf[..n]).await {
+                Ok(written) if written < n => warn!(
+                    "Datagram of {} bytes from {} truncated to {} bytes",
+                    n, upstream.name, written
+                ),
+                Ok(..) => {}
+                Err(err) => return (messages, err),
+            }
+            messages += 1;
+        }
+    };
+
+    // UDP has no end of stream, the relay ends with the inbound side
+    tokio::select! {
+        res = inbound_to_outbound => debug!("Messages read: {:?}", res),
+        (messages, err) = outbound_to_inbound => debug!("Messages write: {}, error: {}", messages, err),
+    }
+
+    let _ = wi.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Unix domain socket listeners of tcp servers
+
+use crate::plugins::unix::bind;
+use crate::servers::protocol::forward::ACCEPT_DELAY;
+use crate::servers::protocol::tcp::{serve, ConnectionInfo, Handler};
+use crate::servers::{Listen, Proxy};
+use log::{debug, error};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::UnixStream;
+use tokio::time;
+
+pub async fn proxy<H: Handler>(config: Arc<Proxy>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match &config.listen {
+        Listen::Unix(path) => path,
+        Listen::Inet(..) => return Err(format!("{} is not a Unix socket", config.listen).into()),
+    };
+    let listener = bind(path, &config.unix)?;
+
+    loop {
+        let thread_proxy = config.clone();
+        match listener.accept().await {
+            Err(err) => {
+                error!(
+                    "Failed to accept connection on server {}: {}",
+                    config.name, err
+                );
+                // Out of file descriptors, give connections time to close
+                time::sleep(ACCEPT_DELAY).await;
+            }
+            Ok(_) if !config.enabled() => {
+                debug!("Server {} is disabled, connection closed", config.name);
+            }
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    thread_proxy
+                        .stats
+                        .connections
+                        .fetch_add(1, Ordering::Relaxed);
+                    match accept::<H>(stream, thread_proxy.clone()).await {
+                        Ok(_) => {}
+                        Err(err) => {
+                            thread_proxy.stats.errors.fetch_add(1, Ordering::Relaxed);
+                            error!("Relay thread returned an error: {}", err);
+                        }
+                    };
+                });
+            }
+        }
+    }
+}
+
+async fn accept<H: Handler>(
+    inbound: UnixStream,
+    proxy: Arc<Proxy>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let info = ConnectionInfo::unix(proxy.listen.clone());
+    debug!("New connection on {}", info.local);
+    serve::<H, _>(inbound, proxy, info).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::servers::testing;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn test_unix_listener_and_upstream() {
+        let dir = testing::start(
+            r#"
+version: 2
+log: disable
+servers:
+  unix_server:
+    listen:
+      - "unix://{dir}/echo.sock"
+    unix:
+      mode: "0600"
+    default: echo
+  unix_client_server:
+    listen:
+      - "unix://{dir}/client.sock"
+    default: unix_echo
+upstream:
+  unix_echo: "unix://{dir}/echo.sock"
+"#,
+        );
+        let echo = dir.path().join("echo.sock");
+        let client = dir.path().join("client.sock");
+        testing::wait_for(|| echo.exists() && client.exists()).await;
+
+        let mode = std::fs::metadata(&echo).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        for path in [&echo, &client] {
+            let mut conn = UnixStream::connect(path).await.unwrap();
+            let mut buf = [0u8; 2];
+            conn.write_all(b"hi").await.unwrap();
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi");
+            conn.shutdown().await.unwrap();
+        }
+    }
+}
@@ -0,0 +1,592 @@
+//! WebSocket tunnels, binary messages carrying a byte stream
+
+use crate::config::Upstream;
+use crate::servers::protocol::http::{header, read_head, respond};
+use crate::servers::protocol::tcp::{forward, ConnectionInfo, Handler};
+use crate::servers::Proxy;
+use base64::Engine;
+use bytes::{Buf, BytesMut};
+use log::debug;
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Largest payload of the frames sent
+const MAX_FRAME_PAYLOAD: usize = 16384;
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+
+/// Frame being read
+#[derive(Debug)]
+struct Frame {
+    opcode: u8,
+    remaining: u64,
+    mask: Option<[u8; 4]>,
+    /// Payload bytes already unmasked
+    offset: usize,
+}
+
+impl Frame {
+    fn unmask(&mut self, data: &mut [u8]) {
+        if let Some(mask) = self.mask {
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte ^= mask[(self.offset + i) % 4];
+            }
+        }
+        self.offset += data.len();
+    }
+}
+
+enum Decoded {
+    Data,
+    Eof,
+    Again,
+    NeedMore,
+    /// The peer broke the protocol, a close frame is queued
+    Failed,
+}
+
+/// Byte stream over the data frames of a WebSocket. A close frame is read as
+/// EOF and one is sent on shutdown, so each direction closes on its own.
+#[derive(Debug)]
+pub struct WebSocketStream<S> {
+    inner: S,
+    /// Clients mask the frames they send
+    client: bool,
+    /// Read and not decoded yet
+    rbuf: BytesMut,
+    frame: Option<Frame>,
+    /// Encoded and not written yet
+    wbuf: BytesMut,
+    read_closed: bool,
+    close_sent: bool,
+    /// Why the peer's frames are no longer read
+    error: Option<String>,
+    rng: SystemRandom,
+}
+
+impl<S> WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// `read` holds what was read after the handshake
+    fn new(inner: S, client: bool, read: Vec<u8>) -> WebSocketStream<S> {
+        WebSocketStream {
+            inner,
+            client,
+            rbuf: BytesMut::from(&read[..]),
+            frame: None,
+            wbuf: BytesMut::new(),
+            read_closed: false,
+            close_sent: false,
+            error: None,
+            rng: SystemRandom::new(),
+        }
+    }
+
+    fn encode(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mask_bit = if self.client { 0x80 } else { 0 };
+        self.wbuf.extend_from_slice(&[0x80 | opcode]);
+        match payload.len() {
+            len if len < 126 => self.wbuf.extend_from_slice(&[mask_bit | len as u8]),
+            len if len <= u16::MAX as usize => {
+                self.wbuf.extend_from_slice(&[mask_bit | 126]);
+                self.wbuf.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                self.wbuf.extend_from_slice(&[mask_bit | 127]);
+                self.wbuf.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        if !self.client {
+            self.wbuf.extend_from_slice(payload);
+            return Ok(());
+        }
+        let mut mask = [0u8; 4];
+        self.rng
+            .fill(&mut mask)
+            .map_err(|_| io::Error::other("Failed to generate a WebSocket mask"))?;
+        self.wbuf.extend_from_slice(&mask);
+        self.wbuf.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+        Ok(())
+    }
+
+    fn decode(&mut self, buf: &mut ReadBuf<'_>) -> io::Result<Decoded> {
+        if let Some(frame) = &mut self.frame {
+            if frame.opcode < OPCODE_CLOSE {
+                if frame.remaining == 0 {
+                    self.frame = None;
+                    return Ok(Decoded::Again);
+                }
+                if self.rbuf.is_empty() {
+                    return Ok(Decoded::NeedMore);
+                }
+                let len =
+                    (frame.remaining.min(self.rbuf.len() as u64) as usize).min(buf.remaining());
+                let mut data = self.rbuf.split_to(len);
+                frame.unmask(&mut data);
+                frame.remaining -= len as u64;
+                buf.put_slice(&data);
+                return Ok(Decoded::Data);
+            }
+
+            // Control frames are handled whole
+            if (self.rbuf.len() as u64) < frame.remaining {
+                return Ok(Decoded::NeedMore);
+            }
+            let mut payload = self.rbuf.split_to(frame.remaining as usize);
+            frame.unmask(&mut payload);
+            let opcode = frame.opcode;
+            self.frame = None;
+            return match opcode {
+                OPCODE_PING if !self.close_sent => {
+                    self.encode(OPCODE_PONG, &payload)?;
+                    Ok(Decoded::Again)
+                }
+                OPCODE_CLOSE => Ok(Decoded::Eof),
+                _ => Ok(Decoded::Again),
+            };
+        }
+
+        if self.rbuf.len() < 2 {
+            return Ok(Decoded::NeedMore);
+        }
+        let fin = self.rbuf[0] & 0x80 != 0;
+        let reserved = self.rbuf[0] & 0x70;
+        let opcode = self.rbuf[0] & 0x0f;
+        let masked = self.rbuf[1] & 0x80 != 0;
+        let len_len = match self.rbuf[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let header_len = 2 + len_len + if masked { 4 } else { 0 };
+        if self.rbuf.len() < header_len {
+            return Ok(Decoded::NeedMore);
+        }
+
+        let header = self.rbuf.split_to(header_len);
+        let len = match len_len {
+            2 => u16::from_be_bytes([header[2], header[3]]) as u64,
+            8 => u64::from_be_bytes(header[2..10].try_into().unwrap()),
+            _ => (header[1] & 0x7f) as u64,
+        };
+        let mask = match masked {
+            true => Some(header[header_len - 4..].try_into().unwrap()),
+            false => None,
+        };
+        // Clients mask their frames and servers do not, RFC 6455 section 5.1
+        let invalid = match opcode {
+            _ if reserved != 0 => Some("reserved bits set"),
+            _ if masked == self.client => Some("wrong masking"),
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => None,
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG if !fin => Some("fragmented control frame"),
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG if len < 126 => None,
+            OPCODE_CLOSE | OPCODE_PING | OPCODE_PONG => Some("control frame too long"),
+            _ => Some("unknown opcode"),
+        };
+        if let Some(invalid) = invalid {
+            return self.fail(format!(
+                "Invalid WebSocket frame, opcode {} length {}: {}",
+                opcode, len, invalid
+            ));
+        }
+
+        self.frame = Some(Frame {
+            opcode,
+            remaining: len,
+            mask,
+            offset: 0,
+        });
+        Ok(Decoded::Again)
+    }
+
+    /// Stop reading and close with a protocol error
+    fn fail(&mut self, error: String) -> io::Result<Decoded> {
+        if !self.close_sent {
+            self.encode(OPCODE_CLOSE, &CLOSE_PROTOCOL_ERROR.to_be_bytes())?;
+            self.close_sent = true;
+        }
+        self.error = Some(error);
+        Ok(Decoded::Failed)
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.wbuf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.wbuf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.wbuf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncRead for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            if let Some(error) = &this.error {
+                let error = io::Error::new(io::ErrorKind::InvalidData, error.clone());
+                ready!(this.poll_drain(cx))?;
+                return Poll::Ready(Err(error));
+            }
+            if this.read_closed {
+                return Poll::Ready(Ok(()));
+            }
+            // Pongs are sent while reading
+            if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+                return Poll::Ready(Err(err));
+            }
+
+            match this.decode(buf)? {
+                Decoded::Data => return Poll::Ready(Ok(())),
+                Decoded::Eof => this.read_closed = true,
+                Decoded::Again | Decoded::Failed => {}
+                Decoded::NeedMore => {
+                    let mut chunk = [0u8; 8192];
+                    let mut read = ReadBuf::new(&mut chunk);
+                    ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+                    if read.filled().is_empty() {
+                        if this.frame.is_some() || !this.rbuf.is_empty() {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        // Closed without a close frame
+                        this.read_closed = true;
+                    }
+                    this.rbuf.extend_from_slice(read.filled());
+                }
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.close_sent {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        ready!(this.poll_drain(cx))?;
+        if data.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        // Accepted once encoded, written by the next calls if not now
+        let len = data.len().min(MAX_FRAME_PAYLOAD);
+        this.encode(OPCODE_BINARY, &data[..len])?;
+        if let Poll::Ready(Err(err)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.close_sent {
+            this.encode(OPCODE_CLOSE, &CLOSE_NORMAL.to_be_bytes())?;
+            this.close_sent = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let hash = digest(
+        &SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}{}", key, ACCEPT_GUID).as_bytes(),
+    );
+    base64::engine::general_purpose::STANDARD.encode(hash)
+}
+
+/// Connections of websocket servers, upgraded before they are relayed
+pub(crate) struct WebSocket;
+
+impl Handler for WebSocket {
+    async fn handle<S>(
+        inbound: S,
+        upstream: &Upstream,
+        proxy: &Proxy,
+        info: &ConnectionInfo,
+    ) -> Result<(), Box<dyn std::error::Error>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        // Banned connections are not upgraded
+        if matches!(upstream, Upstream::Ban) {
+            return forward(inbound, upstream, proxy, info).await;
+        }
+        match accept(inbound, proxy.websocket_path.as_deref()).await? {
+            Some(inbound) => forward(inbound, upstream, proxy, info).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Answer the upgrade request of a client, to any path when `path` is
+/// `None`. `None` when the request was refused.
+pub(crate) async fn accept<S>(
+    mut inbound: S,
+    path: Option<&str>,
+) -> io::Result<Option<WebSocketStream<S>>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (head, rest) = match read_head(&mut inbound).await? {
+        Some(head) => head,
+        None => {
+            respond(&mut inbound, "400 Bad Request", None).await?;
+            return Ok(None);
+        }
+    };
+    let mut request_line = head.split("\r\n").next().unwrap_or("").split(' ');
+    let (method, target) = (request_line.next(), request_line.next().unwrap_or(""));
+    let requested = target.split('?').next().unwrap_or(target);
+
+    let upgrade =
+        header(&head, "Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+    let key = header(&head, "Sec-WebSocket-Key");
+    let (status, headers) = if method != Some("GET") {
+        ("405 Method Not Allowed", Some("Allow: GET\r\n"))
+    } else if path.is_some_and(|path| path != requested) {
+        ("404 Not Found", None)
+    } else if !upgrade || key.is_none() {
+        ("400 Bad Request", None)
+    } else if header(&head, "Sec-WebSocket-Version") != Some("13") {
+        (
+            "426 Upgrade Required",
+            Some("Sec-WebSocket-Version: 13\r\n"),
+        )
+    } else {
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(key.unwrap_or_default())
+        );
+        inbound.write_all(response.as_bytes()).await?;
+        return Ok(Some(WebSocketStream::new(inbound, false, rest)));
+    };
+
+    debug!("WebSocket upgrade to {} refused: {}", target, status);
+    respond(&mut inbound, status, headers).await?;
+    Ok(None)
+}
+
+/// Upgrade a connection to the server `host`, `path` including the query
+pub(crate) async fn connect<S>(
+    mut outbound: S,
+    host: &str,
+    path: &str,
+) -> io::Result<WebSocketStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut nonce = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| io::Error::other("Failed to generate a WebSocket key"))?;
+    let key = base64::engine::general_purpose::STANDARD.encode(nonce);
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        path, host, key
+    );
+    outbound.write_all(request.as_bytes()).await?;
+
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (head, rest) = read_head(&mut outbound)
+        .await?
+        .ok_or_else(|| invalid("WebSocket response too long".to_string()))?;
+    let status = head.split("\r\n").next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("101") {
+        return Err(invalid(format!("WebSocket upgrade refused: {}", status)));
+    }
+    if header(&head, "Sec-WebSocket-Accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid("Invalid Sec-WebSocket-Accept".to_string()));
+    }
+    Ok(WebSocketStream::new(outbound, true, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::servers::testing;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_accept_key() {
+        // RFC 6455 section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_websocket_stream() {
+        let (client, server) = io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut ws = accept(server, Some("/tunnel")).await.unwrap().unwrap();
+            let mut data = Vec::new();
+            ws.read_to_end(&mut data).await.unwrap();
+            ws.write_all(&data).await.unwrap();
+            ws.shutdown().await.unwrap();
+        });
+
+        let mut ws = connect(client, "127.0.0.1", "/tunnel?id=1").await.unwrap();
+        let data: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        ws.write_all(&data).await.unwrap();
+        // Answered while the server is still reading
+        ws.encode(OPCODE_PING, b"ping").unwrap();
+        ws.shutdown().await.unwrap();
+        let mut echoed = Vec::new();
+        ws.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, data);
+        server.await.unwrap();
+
+        let (client, server) = io::duplex(4096);
+        tokio::spawn(async move {
+            assert!(accept(server, Some("/tunnel")).await.unwrap().is_none());
+        });
+        assert!(connect(client, "127.0.0.1", "/other").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frames() {
+        let mask = [1, 2, 3, 4];
+        for frame in [
+            // Unmasked from the client
+            vec![0x82, 0x02, b'h', b'i'],
+            // Reserved bit
+            [&[0xc2, 0x82][..], &mask, &[b'h' ^ 1, b'i' ^ 2]].concat(),
+            // Ping without FIN
+            [&[0x09, 0x80][..], &mask].concat(),
+        ] {
+            let (mut client, server) = io::duplex(4096);
+            let mut ws = WebSocketStream::new(server, false, Vec::new());
+            client.write_all(&frame).await.unwrap();
+            let mut buf = [0u8; 2];
+            let err = ws.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            // Closed with a protocol error
+            let mut close = [0u8; 4];
+            client.read_exact(&mut close).await.unwrap();
+            assert_eq!(close, [0x88, 0x02, 0x03, 0xea]);
+        }
+
+        // Masked from the server
+        let (mut server, client) = io::duplex(4096);
+        let mut ws = WebSocketStream::new(client, true, Vec::new());
+        let frame = [&[0x82, 0x82][..], &mask, &[b'h' ^ 1, b'i' ^ 2]].concat();
+        server.write_all(&frame).await.unwrap();
+        let mut buf = [0u8; 2];
+        assert!(ws.read(&mut buf).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_tunnels() {
+        let echo = testing::echo_server().await;
+        let (ws, ws_client, wss, wss_client) = (
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+            testing::tcp_addr(),
+        );
+        let config = r#"
+version: 2
+log: disable
+servers:
+  ws_server:
+    protocol: websocket
+    listen:
+      - "{ws}"
+    websocket:
+      path: /tunnel
+    default: tester
+  ws_client_server:
+    listen:
+      - "{ws_client}"
+    default: ws_tunnel
+  wss_server:
+    protocol: websocket
+    listen:
+      - "{wss}"
+    routing: sni
+    terminate:
+      certs:
+        - cert: tests/certs/tls.pem
+          key: tests/certs/tls.key
+    default: echo
+  wss_client_server:
+    listen:
+      - "{wss_client}"
+    default: wss_tunnel
+upstream:
+  tester: "tcp://{echo}"
+  ws_tunnel: "ws://{ws}/tunnel"
+  wss_tunnel: "wss://{wss}/?sni=tls.test.com&ca=tests/certs/ca.pem"
+"#;
+        let _dir = testing::start(&testing::with_addrs(
+            config,
+            &[
+                ("ws", ws),
+                ("ws_client", ws_client),
+                ("wss", wss),
+                ("wss_client", wss_client),
+                ("echo", echo),
+            ],
+        ));
+        testing::connect_tcp(ws).await;
+        testing::connect_tcp(wss).await;
+
+        for client in [ws_client, wss_client] {
+            let mut conn = testing::connect_tcp(client).await;
+            testing::echo(&mut conn, b"hello").await;
+            for i in 0..=10u8 {
+                testing::echo(&mut conn, &[i]).await;
+            }
+        }
+    }
+}
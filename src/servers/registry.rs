@@ -0,0 +1,345 @@
+//! Runtime state of servers, shown and changed by the admin API
+
+use crate::plugins::kcp::{KcpListener, KcpListenerStats, SessionCounts};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+/// Shared by every server and kept across reloads
+#[derive(Debug, Default)]
+pub struct Registry {
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Arc<ConnectionEntry>>>,
+    upstreams: Mutex<HashMap<String, Arc<UpstreamState>>>,
+    kcp: Mutex<HashMap<u64, KcpListenerEntry>>,
+    /// Servers not accepting connections, by name
+    disabled: Mutex<HashSet<String>>,
+}
+
+/// An active connection
+#[derive(Debug)]
+pub struct ConnectionEntry {
+    pub id: u64,
+    pub server: String,
+    pub peer: Option<SocketAddr>,
+    pub upstream: String,
+    pub started: Instant,
+    /// Bytes read from the client
+    pub received: AtomicU64,
+    /// Bytes written to the client
+    pub sent: AtomicU64,
+    kill: Notify,
+}
+
+/// Passive health of an upstream, from the outcome of its connections
+#[derive(Debug)]
+pub struct UpstreamState {
+    /// New connections are banned while existing ones go on
+    pub draining: AtomicBool,
+    pub active: AtomicU64,
+    pub connections: AtomicU64,
+    pub failures: AtomicU64,
+    /// Whether the last connection ended without error
+    pub healthy: AtomicBool,
+    pub last_error: Mutex<Option<String>>,
+}
+
+impl Default for UpstreamState {
+    fn default() -> UpstreamState {
+        UpstreamState {
+            draining: AtomicBool::new(false),
+            active: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            healthy: AtomicBool::new(true),
+            last_error: Mutex::new(None),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KcpListenerEntry {
+    pub server: String,
+    pub listen: SocketAddr,
+    pub stats: Arc<KcpListenerStats>,
+    pub counts: Arc<Mutex<SessionCounts>>,
+}
+
+impl Registry {
+    pub fn enabled(&self, server: &str) -> bool {
+        !self.disabled.lock().unwrap().contains(server)
+    }
+
+    pub fn set_enabled(&self, server: &str, enabled: bool) {
+        let mut disabled = self.disabled.lock().unwrap();
+        match enabled {
+            true => disabled.remove(server),
+            false => disabled.insert(server.to_string()),
+        };
+    }
+
+    pub fn upstream(&self, name: &str) -> Arc<UpstreamState> {
+        self.upstreams
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn connections(&self) -> Vec<Arc<ConnectionEntry>> {
+        let mut connections: Vec<_> = self.connections.lock().unwrap().values().cloned().collect();
+        connections.sort_by_key(|entry| entry.id);
+        connections
+    }
+
+    /// Close a connection, false when it is not active
+    pub fn kill(&self, id: u64) -> bool {
+        match self.connections.lock().unwrap().get(&id) {
+            Some(entry) => {
+                entry.kill.notify_one();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add a connection, removed when the result is dropped
+    pub fn track(
+        self: &Arc<Registry>,
+        server: &str,
+        peer: Option<SocketAddr>,
+        upstream: &str,
+    ) -> Tracked {
+        let state = self.upstream(upstream);
+        state.active.fetch_add(1, Ordering::Relaxed);
+        state.connections.fetch_add(1, Ordering::Relaxed);
+        self.insert(server, peer, upstream, Some(state))
+    }
+
+    /// Add a connection of a proxy server, to a destination chosen by the
+    /// client rather than an upstream
+    pub fn track_destination(
+        self: &Arc<Registry>,
+        server: &str,
+        peer: Option<SocketAddr>,
+        destination: &str,
+    ) -> Tracked {
+        self.insert(server, peer, destination, None)
+    }
+
+    fn insert(
+        self: &Arc<Registry>,
+        server: &str,
+        peer: Option<SocketAddr>,
+        upstream: &str,
+        state: Option<Arc<UpstreamState>>,
+    ) -> Tracked {
+        let entry = Arc::new(ConnectionEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            server: server.to_string(),
+            peer,
+            upstream: upstream.to_string(),
+            started: Instant::now(),
+            received: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            kill: Notify::new(),
+        });
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(entry.id, entry.clone());
+        Tracked {
+            registry: self.clone(),
+            entry,
+            upstream: state,
+        }
+    }
+
+    pub fn kcp_listeners(&self) -> Vec<KcpListenerEntry> {
+        let mut listeners: Vec<_> = self.kcp.lock().unwrap().values().cloned().collect();
+        listeners.sort_by(|a, b| (&a.server, a.listen).cmp(&(&b.server, b.listen)));
+        listeners
+    }
+
+    /// Add a KCP listener, removed when the result is dropped
+    pub fn track_kcp(
+        self: &Arc<Registry>,
+        server: &str,
+        listen: SocketAddr,
+        listener: &KcpListener,
+    ) -> TrackedKcp {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.kcp.lock().unwrap().insert(
+            id,
+            KcpListenerEntry {
+                server: server.to_string(),
+                listen,
+                stats: listener.stats(),
+                counts: listener.session_counts(),
+            },
+        );
+        TrackedKcp {
+            registry: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Registration of an active connection
+pub struct Tracked {
+    registry: Arc<Registry>,
+    entry: Arc<ConnectionEntry>,
+    upstream: Option<Arc<UpstreamState>>,
+}
+
+impl Tracked {
+    pub fn id(&self) -> u64 {
+        self.entry.id
+    }
+
+    /// Count the bytes going through the client side of the connection
+    pub fn stream<S>(&self, inner: S) -> CountedStream<S> {
+        CountedStream {
+            inner,
+            entry: self.entry.clone(),
+        }
+    }
+
+    /// Wait for the connection to be killed
+    pub async fn killed(&self) {
+        self.entry.kill.notified().await
+    }
+
+    /// Update the health of the upstream with the outcome of the connection
+    pub fn finish<E: ToString>(&self, result: Result<(), E>) {
+        let upstream = match &self.upstream {
+            Some(upstream) => upstream,
+            None => return,
+        };
+        match result {
+            Ok(()) => upstream.healthy.store(true, Ordering::Relaxed),
+            Err(err) => {
+                upstream.failures.fetch_add(1, Ordering::Relaxed);
+                upstream.healthy.store(false, Ordering::Relaxed);
+                *upstream.last_error.lock().unwrap() = Some(err.to_string());
+            }
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        if let Some(upstream) = &self.upstream {
+            upstream.active.fetch_sub(1, Ordering::Relaxed);
+        }
+        self.registry
+            .connections
+            .lock()
+            .unwrap()
+            .remove(&self.entry.id);
+    }
+}
+
+pub struct TrackedKcp {
+    registry: Arc<Registry>,
+    id: u64,
+}
+
+impl Drop for TrackedKcp {
+    fn drop(&mut self) {
+        self.registry.kcp.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct CountedStream<S> {
+    inner: S,
+    entry: Arc<ConnectionEntry>,
+}
+
+impl<S> AsyncRead for CountedStream<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = buf.filled().len() - filled;
+        self.entry.received.fetch_add(n as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S> AsyncWrite for CountedStream<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.entry.sent.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_track_connection() {
+        let registry = Arc::new(Registry::default());
+        let tracked = registry.track("server", None, "backend");
+        let (client, server) = io::duplex(64);
+        let mut server = tracked.stream(server);
+        let mut client = client;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        server.write_all(b"pong!").await.unwrap();
+
+        let entry = &registry.connections()[0];
+        assert_eq!(entry.received.load(Ordering::Relaxed), 4);
+        assert_eq!(entry.sent.load(Ordering::Relaxed), 5);
+        let upstream = registry.upstream("backend");
+        assert_eq!(upstream.active.load(Ordering::Relaxed), 1);
+
+        assert!(registry.kill(tracked.id()));
+        tracked.killed().await;
+        tracked.finish(Err("refused"));
+        drop(tracked);
+        assert!(registry.connections().is_empty());
+        assert!(!registry.kill(1));
+        assert_eq!(upstream.active.load(Ordering::Relaxed), 0);
+        assert!(!upstream.healthy.load(Ordering::Relaxed));
+
+        registry.set_enabled("server", false);
+        assert!(!registry.enabled("server"));
+        registry.set_enabled("server", true);
+        assert!(registry.enabled("server"));
+    }
+}
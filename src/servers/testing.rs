@@ -0,0 +1,148 @@
+//! Servers of tests, started from a config written in a temporary directory
+
+use crate::config::{Config, Overrides};
+use crate::servers::Server;
+use serde_json::Value;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixStream};
+use tokio::time;
+
+/// Start the servers of the YAML `config`, where `{dir}` stands for the
+/// returned directory. They run until the test process exits.
+pub(crate) fn start(config: &str) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    write_config(dir.path(), config);
+    let path = dir.path().join("config.yaml");
+    let path = path.to_str().unwrap().to_string();
+    let config = Config::load(&path, &Overrides::default()).unwrap();
+    let mut server = Server::new(config.base).unwrap();
+    server.config_path = Some(path);
+    thread::spawn(move || {
+        let _ = server.run();
+    });
+    dir
+}
+
+/// Replace the config of the servers started in `dir`, to be reloaded
+pub(crate) fn write_config(dir: &Path, config: &str) {
+    let config = config.replace("{dir}", dir.to_str().unwrap());
+    fs::write(dir.join("config.yaml"), config).unwrap();
+}
+
+/// Wait for `ready` to hold, failing the test after 5 seconds
+pub(crate) async fn wait_for(mut ready: impl FnMut() -> bool) {
+    for _ in 0..500 {
+        if ready() {
+            return;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Timed out waiting for the test servers");
+}
+
+/// Connect to a Unix socket once it is listening
+pub(crate) async fn connect_unix(path: &Path) -> UnixStream {
+    for _ in 0..500 {
+        if let Ok(conn) = UnixStream::connect(path).await {
+            return conn;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Timed out connecting to {}", path.display());
+}
+
+/// `config` with each `{name}` replaced by the address given for it
+pub(crate) fn with_addrs(config: &str, addrs: &[(&str, SocketAddr)]) -> String {
+    addrs
+        .iter()
+        .fold(config.to_string(), |config, (name, addr)| {
+            config.replace(&format!("{{{}}}", name), &addr.to_string())
+        })
+}
+
+/// A free TCP address for servers to listen on
+pub(crate) fn tcp_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// A free UDP address for KCP servers to listen on
+pub(crate) fn udp_addr() -> SocketAddr {
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap()
+}
+
+/// Wait for a KCP server to listen on `addr`
+pub(crate) async fn wait_for_udp(addr: SocketAddr) {
+    wait_for(|| std::net::UdpSocket::bind(addr).is_err()).await;
+}
+
+/// Connect to a TCP server once it is listening
+pub(crate) async fn connect_tcp(addr: SocketAddr) -> TcpStream {
+    for _ in 0..500 {
+        if let Ok(conn) = TcpStream::connect(addr).await {
+            return conn;
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("Timed out connecting to {}", addr);
+}
+
+/// A TCP upstream sending back what it reads
+pub(crate) async fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    addr
+}
+
+/// A UDP upstream sending back every datagram
+pub(crate) async fn udp_echo_server() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 65536];
+        loop {
+            let (n, peer) = socket.recv_from(&mut buf).await.unwrap();
+            socket.send_to(&buf[..n], peer).await.unwrap();
+        }
+    });
+    addr
+}
+
+/// Status and JSON body of a request to the admin API on `socket`
+pub(crate) async fn admin_request(socket: &Path, method: &str, path: &str) -> (u16, Value) {
+    let mut conn = UnixStream::connect(socket).await.unwrap();
+    let request = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+    conn.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).await.unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1;
+    (status, serde_json::from_str(body).unwrap())
+}
+
+/// Send `data` and read it back
+pub(crate) async fn echo<S>(conn: &mut S, data: &[u8])
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; data.len()];
+    conn.write_all(data).await.unwrap();
+    conn.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, data);
+}